@@ -0,0 +1,82 @@
+use eyre::{eyre, Result};
+
+use calc::settings::Lang;
+
+/// Command line flags
+#[derive(Debug, Default)]
+pub struct Args {
+  /// Don't read or write the history file
+  pub no_history: bool,
+  /// Evaluate lines from standard input without prompts, even if it's a terminal
+  pub stdin: bool,
+  /// Print how long each stage of evaluation took
+  pub time: bool,
+  /// Don't print the startup banner
+  pub quiet: bool,
+  /// Prompt text, with an empty string meaning no prompt
+  pub prompt: Option<String>,
+  /// Language of the banner and help text
+  pub lang: Option<Lang>,
+  /// Copy the last result of standard input to the clipboard
+  pub copy: bool,
+  /// Print the syntax tree of each line of standard input as JSON instead of evaluating it
+  #[cfg(feature = "serde")]
+  pub ast_json: bool,
+}
+
+impl Args {
+  pub fn parse() -> Result<Self> {
+    Self::parse_from(std::env::args().skip(1))
+  }
+
+  fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Self> {
+    let mut result = Self::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--no-history" => result.no_history = true,
+        "--stdin" => result.stdin = true,
+        "--time" => result.time = true,
+        "--quiet" => result.quiet = true,
+        "--copy" => result.copy = true,
+        #[cfg(feature = "serde")]
+        "--ast-json" => result.ast_json = true,
+        "--prompt" => match args.next() {
+          Some(prompt) => result.prompt = Some(prompt),
+          None => return Err(eyre!("Missing value for {}", arg)),
+        },
+        "--lang" => match args.next() {
+          Some(lang) => result.lang = Some(lang.parse()?),
+          None => return Err(eyre!("Missing value for {}", arg)),
+        },
+        _ => return Err(eyre!("Unknown argument: {}", arg)),
+      }
+    }
+
+    Ok(result)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Args;
+  use calc::settings::Lang;
+
+  fn parse(args: &[&str]) -> eyre::Result<Args> {
+    Args::parse_from(args.iter().map(|arg| arg.to_string()))
+  }
+
+  #[test]
+  fn test_parse() {
+    let args = parse(&["--quiet", "--prompt", "calc> ", "--no-history"]).unwrap();
+    assert!(args.quiet && args.no_history && !args.stdin);
+    assert_eq!(args.prompt.as_deref(), Some("calc> "));
+
+    assert_eq!(parse(&["--prompt", ""]).unwrap().prompt.as_deref(), Some(""));
+    assert!(parse(&["--prompt"]).is_err());
+    assert_eq!(parse(&["--lang", "ru"]).unwrap().lang, Some(Lang::Ru));
+    assert!(parse(&["--lang", "fr"]).is_err());
+    assert!(parse(&["--loud"]).is_err());
+  }
+}
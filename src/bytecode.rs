@@ -0,0 +1,397 @@
+use eyre::{eyre, Result};
+
+use crate::{error::EvalError, eval::{builtin, EvalOptions}, lexer::{Func, Op}, parser::Node};
+
+/// Programs needing at most this many stack slots don't allocate when run
+const INLINE_STACK: usize = 32;
+
+/// Stack machine instruction, operating on the values pushed before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+  PushConst(f64),
+  /// Pushes the value of the variable in the given slot
+  LoadVar(usize),
+  /// Pops a value into the local slot, for a variable bound by `let`
+  StoreLocal(usize),
+  /// Pushes the value of the local slot
+  LoadLocal(usize),
+  Neg,
+  BinOp(Op),
+  /// Replaces the given number of arguments with the function's result
+  Func(Func, usize),
+  /// Continues at the instruction with the given index
+  Jump(usize),
+  /// Pops a condition, continuing at the instruction with the given index if it's zero
+  JumpIfZero(usize),
+  /// Fails, reached by `piecewise` without a default when none of its conditions hold
+  NoMatchingCase,
+}
+
+/// Expression flattened into postfix instructions, for evaluating it many times with different variable values
+#[derive(Debug, Clone)]
+pub struct Program {
+  instructions: Vec<Instruction>,
+  /// Names of the variables by slot, in order of first use
+  variables: Vec<String>,
+  /// Number of local slots, one per `let`
+  locals: usize,
+  /// Names bound by the `let`s enclosing the node being compiled, innermost last, with their local slots
+  scope: Vec<(String, usize)>,
+  max_stack: usize,
+  /// Options without the variables, whose values are passed to `run` instead
+  options: EvalOptions<'static>,
+}
+
+/// Compiles an expression with the default evaluation options
+pub fn compile(node: &Node) -> Result<Program> {
+  compile_with_options(node, EvalOptions::default())
+}
+
+/// Compiles an expression, which must not contain calls of user-defined functions
+/// and must be no deeper than `MAX_DEPTH`
+pub fn compile_with_options(node: &Node, options: EvalOptions) -> Result<Program> {
+  node.check_depth()?;
+  let mut program = Program { instructions: vec![], variables: vec![], locals: 0, scope: vec![], max_stack: 0, options: EvalOptions { variables: None, ..options } };
+  program.emit(node, 0)?;
+  Ok(program)
+}
+
+impl Program {
+  /// Appends the instructions for `node`, given the number of values already on the stack
+  fn emit(&mut self, node: &Node, depth: usize) -> Result<()> {
+    self.max_stack = self.max_stack.max(depth + 1);
+
+    match node {
+      Node::Immediate(value) => self.instructions.push(Instruction::PushConst(*value)),
+      Node::Variable(name) => {
+        if let Some(&(_, local)) = self.scope.iter().rev().find(|(bound, _)| bound == name) {
+          self.instructions.push(Instruction::LoadLocal(local));
+          return Ok(())
+        }
+
+        let slot = match self.variables.iter().position(|variable| variable == name) {
+          Some(slot) => slot,
+          None => {
+            self.variables.push(name.clone());
+            self.variables.len() - 1
+          },
+        };
+        self.instructions.push(Instruction::LoadVar(slot));
+      },
+      Node::Neg(node) => {
+        self.emit(node, depth)?;
+        self.instructions.push(Instruction::Neg);
+      },
+      Node::BinOp(op, left, right) => {
+        self.emit(left, depth)?;
+        self.emit(right, depth + 1)?;
+        self.instructions.push(Instruction::BinOp(*op));
+      },
+      // Numbers are treated like lists of one element
+      Node::Func(func @ (Func::Map | Func::Zip), args) => {
+        let Some((function, args)) = args.split_first() else {
+          return Err(EvalError::WrongArgumentCount { func: *func, count: 0 }.into())
+        };
+        for (index, arg) in args.iter().enumerate() {
+          self.emit(arg, depth + index)?;
+        }
+        match function {
+          Node::FuncRef(func) => self.instructions.push(Instruction::Func(*func, args.len())),
+          Node::OpRef(op) if args.len() == 2 => self.instructions.push(Instruction::BinOp(*op)),
+          Node::OpRef(op) => return Err(EvalError::OperatorArgumentCount { op: *op, count: args.len() }.into()),
+          function => return Err(EvalError::NotAFunction(function.to_string()).into()),
+        }
+      },
+      Node::Func(func, _) if func.takes_lists() => return Err(eyre!("Invalid operation: lists can't be compiled")),
+      Node::Func(func, _) if func.returns_text() => return Err(eyre!("Invalid operation: text can't be compiled")),
+      Node::Func(func, args) => {
+        for (index, arg) in args.iter().enumerate() {
+          self.emit(arg, depth + index)?;
+        }
+        self.instructions.push(Instruction::Func(*func, args.len()));
+      },
+      Node::Call(name, _) => return Err(EvalError::UndefinedFunction(name.clone()).into()),
+      Node::FuncRef(func) => return Err(EvalError::FunctionAsValue(*func).into()),
+      Node::OpRef(op) => return Err(EvalError::OperatorAsValue(*op).into()),
+      Node::List(_) => return Err(eyre!("Invalid operation: lists can't be compiled")),
+      // Jumps over the branch not taken, whose target is patched in once it's known
+      Node::If { cond, then, else_ } => {
+        self.emit(cond, depth)?;
+        let jump_to_else = self.instructions.len();
+        self.instructions.push(Instruction::JumpIfZero(0));
+        self.emit(then, depth)?;
+        let jump_to_end = self.instructions.len();
+        self.instructions.push(Instruction::Jump(0));
+        self.instructions[jump_to_else] = Instruction::JumpIfZero(self.instructions.len());
+        self.emit(else_, depth)?;
+        self.instructions[jump_to_end] = Instruction::Jump(self.instructions.len());
+      },
+      Node::Let { name, value, body } => {
+        self.emit(value, depth)?;
+        let local = self.locals;
+        self.locals += 1;
+        self.instructions.push(Instruction::StoreLocal(local));
+        self.scope.push((name.clone(), local));
+        self.emit(body, depth)?;
+        self.scope.pop();
+      },
+      // Each case falls through to the next one if its condition doesn't hold
+      Node::Piecewise(cases, default) => {
+        let mut jumps_to_end = vec![];
+        for (cond, value) in cases {
+          self.emit(cond, depth)?;
+          let jump_to_next = self.instructions.len();
+          self.instructions.push(Instruction::JumpIfZero(0));
+          self.emit(value, depth)?;
+          jumps_to_end.push(self.instructions.len());
+          self.instructions.push(Instruction::Jump(0));
+          self.instructions[jump_to_next] = Instruction::JumpIfZero(self.instructions.len());
+        }
+        match default {
+          Some(default) => self.emit(default, depth)?,
+          None => self.instructions.push(Instruction::NoMatchingCase),
+        }
+        for jump in jumps_to_end {
+          self.instructions[jump] = Instruction::Jump(self.instructions.len());
+        }
+      },
+    }
+
+    Ok(())
+  }
+
+  /// Names of the variables, in the order their values are passed to `run`
+  pub fn variables(&self) -> &[String] {
+    &self.variables
+  }
+
+  pub fn instructions(&self) -> &[Instruction] {
+    &self.instructions
+  }
+
+  /// Evaluates the program with `vars[i]` as the value of `variables()[i]`.
+  /// Gives the same result as evaluating the tree, including which error is reported
+  pub fn run(&self, vars: &[f64]) -> Result<f64> {
+    if vars.len() < self.variables.len() {
+      return Err(eyre!("Expected {} variable value(s), got {}", self.variables.len(), vars.len()))
+    }
+
+    let mut inline = [0.; INLINE_STACK];
+    let mut heap = vec![];
+    let stack: &mut [f64] = if self.max_stack <= INLINE_STACK {
+      &mut inline
+    } else {
+      heap.resize(self.max_stack, 0.);
+      &mut heap
+    };
+
+    let mut locals = vec![0.; self.locals];
+    let mut len = 0;
+    let mut next = 0;
+    while let Some(instruction) = self.instructions.get(next) {
+      next += 1;
+      match *instruction {
+        Instruction::PushConst(value) => {
+          stack[len] = value;
+          len += 1;
+        },
+        Instruction::LoadVar(slot) => {
+          stack[len] = vars[slot];
+          len += 1;
+        },
+        Instruction::StoreLocal(local) => {
+          len -= 1;
+          locals[local] = stack[len];
+        },
+        Instruction::LoadLocal(local) => {
+          stack[len] = locals[local];
+          len += 1;
+        },
+        Instruction::Neg => stack[len - 1] = -stack[len - 1],
+        Instruction::BinOp(op) => {
+          len -= 1;
+          let (left, right) = (stack[len - 1], stack[len]);
+          stack[len - 1] = match op {
+            Op::Div if !self.options.strict_division => left / right,
+            op => op.evaluate(left, right)?,
+          };
+        },
+        Instruction::Func(func, count) => {
+          len -= count;
+          stack[len] = func.evaluate(&stack[len..len + count])?;
+          len += 1;
+        },
+        Instruction::Jump(target) => next = target,
+        Instruction::JumpIfZero(target) => {
+          len -= 1;
+          if stack[len] == 0. {
+            next = target;
+          }
+        },
+        Instruction::NoMatchingCase => return Err(EvalError::NoMatchingCase.into()),
+      }
+    }
+
+    Ok(stack[0])
+  }
+}
+
+impl Node {
+  /// Compiles the expression into a function taking the values of `params` in that order,
+  /// e.g. for handing it to a solver. Fails up front if any other variable is used, except for `inf` and `nan`
+  pub fn bind(&self, params: &[&str]) -> Result<impl Fn(&[f64]) -> Result<f64> + Send + Sync + 'static> {
+    let mut program = compile(self)?;
+    let loads = program.variables.iter()
+      .map(|name| match params.iter().position(|param| param == name) {
+        Some(slot) => Ok(Instruction::LoadVar(slot)),
+        None => builtin(name).map(Instruction::PushConst).ok_or_else(|| EvalError::UndefinedVariable(name.clone())),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    // Renumbered so that the arguments can be passed to `run` as they are
+    for instruction in &mut program.instructions {
+      if let Instruction::LoadVar(slot) = *instruction {
+        *instruction = loads[slot];
+      }
+    }
+    program.variables = params.iter().map(|param| param.to_string()).collect();
+
+    Ok(move |args: &[f64]| {
+      if args.len() != program.variables.len() {
+        return Err(eyre!("Expected {} argument(s), got {}", program.variables.len(), args.len()))
+      }
+      program.run(args)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, time::Instant};
+  use super::{compile, Instruction};
+  use crate::{error::EvalError, eval::EvalOptions, lexer::{tokenize, Op}, parser::{parse_expression, Node}, testing::assert_agree};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_compile() {
+    let program = compile(&parse("-x * (y + x) ^ 2")).unwrap();
+    assert_eq!(program.variables(), ["x", "y"]);
+    assert_eq!(program.instructions(), [
+      Instruction::LoadVar(0),
+      Instruction::Neg,
+      Instruction::LoadVar(1),
+      Instruction::LoadVar(0),
+      Instruction::BinOp(Op::Add),
+      Instruction::PushConst(2.),
+      Instruction::BinOp(Op::Pow),
+      Instruction::BinOp(Op::Mul),
+    ]);
+    assert_eq!(program.run(&[3., 1.]).unwrap(), -48.);
+    assert!(program.run(&[3.]).is_err());
+    assert!(compile(&parse("f(x)")).is_err());
+  }
+
+  #[test]
+  fn test_matches_tree() {
+    let corpus = [
+      "x + y * 2 - x / y",
+      "-(x ^ 3) % y + 50%",
+      "sqrt(abs(x)) * log2(abs(y) + 1) - atan2(y, x)",
+      "lerp(x, y, 0.3) + choose(5, 2) / sum_list(x, y, 1) - prod_list() + map(abs, y) - zip(/, x, y) * zip(max, x, y)",
+      "log2(x) + 1 / (y - y)",
+      "x ^ 0.5 + y ^ -2",
+      "sin(x) ^ 2 + cos(x) ^ 2 - smoothstep(-1, 1, y) * wrap(x, 0, 2)",
+      "((((x + 1) * 2 + 3) * 4 + 5) * 6 + y) ^ ((x - 1) / 7)",
+      "(x > y) * x + (x <= y) * y - (x == x) + zip(!=, x, y)",
+      "if(x > y, x - y, sqrt(y - x)) + if(x, 1 / y, if(y < 0, log2(y), 2))",
+      "piecewise(x < -5, -x, x < 5, x * y, sqrt(y)) + piecewise(y > 0, log2(y))",
+      "(let z = x * y in z ^ 2 - (let x = z + 1 in x / y)) + (let z = 2 in z * x)",
+    ];
+    // Bit for bit, with the same errors
+    let same = |a: &Result<f64, EvalError>, b: &eyre::Result<f64>| match (a, b) {
+      (Ok(a), Ok(b)) => a.to_bits() == b.to_bits(),
+      (Err(a), Err(b)) => a.to_string() == b.to_string(),
+      _ => false,
+    };
+
+    for input in corpus {
+      let node = parse(input);
+      let program = compile(&node).unwrap();
+      let run = |options: EvalOptions| {
+        let ctx = options.variables.unwrap();
+        program.run(&program.variables().iter().map(|name| ctx[name]).collect::<Vec<_>>())
+      };
+      assert_agree(input, 3, |options| node.evaluate(options), run, same);
+    }
+
+    // Deep enough to need the heap
+    let mut input = "x".to_string();
+    for index in 0..40 {
+      input = format!("{} - ({}", index, input);
+    }
+    input.push_str(&")".repeat(40));
+    let node = parse(&input);
+    let ctx = HashMap::from([("x".to_string(), 2.)]);
+    assert_eq!(compile(&node).unwrap().run(&[2.]).unwrap(), node.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap());
+  }
+
+  #[test]
+  fn test_bind() {
+    let node = parse("x^2 + y");
+    let f = node.bind(&["x", "y"]).unwrap();
+    for (x, y) in [(0., 0.), (3., 1.), (-2.5, 4.), (1e3, -1e6)] {
+      let ctx = HashMap::from([("x".to_string(), x), ("y".to_string(), y)]);
+      assert_eq!(f(&[x, y]).unwrap(), node.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap());
+    }
+
+    // Parameters may come in any order, and not all of them have to be used
+    let g = node.bind(&["t", "y", "x"]).unwrap();
+    assert_eq!(g(&[7., 1., 3.]).unwrap(), 10.);
+
+    // `inf` is only a value when it isn't a parameter
+    let h = parse("x - inf").bind(&["x"]).unwrap();
+    assert_eq!(h(&[1.]).unwrap(), f64::NEG_INFINITY);
+    let h = parse("x - inf").bind(&["x", "inf"]).unwrap();
+    assert_eq!(h(&[1., 2.]).unwrap(), -1.);
+
+    assert_eq!(f(&[1.]).unwrap_err().to_string(), "Expected 2 argument(s), got 1");
+    assert_eq!(f(&[1., 2., 3.]).unwrap_err().to_string(), "Expected 2 argument(s), got 3");
+    assert_eq!(node.bind(&["x"]).err().unwrap().to_string(), "Undefined variable: y");
+
+    let handle = std::thread::spawn(move || f(&[2., 1.]).unwrap());
+    assert_eq!(handle.join().unwrap(), 5.);
+  }
+
+  /// Run with `cargo test --release -- --ignored bench_program`
+  #[test]
+  #[ignore]
+  fn bench_program() {
+    let node = parse("sqrt(x ^ 2 + y ^ 2) * sin(x) - cos(y) / (1 + abs(x * y)) + log2(1 + x ^ 2) * exp(-y ^ 2) - atan2(y, x) + (x - y) ^ 3 % 7 + lerp(x, y, 0.25)");
+    assert!(node.count_nodes() >= 50);
+    let program = compile(&node).unwrap();
+    const RUNS: usize = 1_000_000;
+
+    let start = Instant::now();
+    let mut ctx = HashMap::from([("x".to_string(), 0.), ("y".to_string(), 0.)]);
+    let mut tree_sum = 0.;
+    for index in 0..RUNS {
+      ctx.insert("x".to_string(), index as f64 * 1e-6);
+      ctx.insert("y".to_string(), 1. - index as f64 * 1e-6);
+      tree_sum += node.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap();
+    }
+    let tree = start.elapsed();
+
+    let start = Instant::now();
+    let mut program_sum = 0.;
+    for index in 0..RUNS {
+      program_sum += program.run(&[index as f64 * 1e-6, 1. - index as f64 * 1e-6]).unwrap();
+    }
+    let compiled = start.elapsed();
+
+    assert_eq!(tree_sum.to_bits(), program_sum.to_bits());
+    assert!(compiled < tree, "{} evaluations: tree {:?}, bytecode {:?}", RUNS, tree, compiled);
+  }
+}
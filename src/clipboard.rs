@@ -0,0 +1,37 @@
+use std::fmt;
+use eyre::{eyre, Result};
+
+/// Connection to the system clipboard, opened on first use.
+/// On X11 the copied text only outlives the program if a clipboard manager is running
+#[derive(Default)]
+pub struct Clipboard {
+  #[cfg(feature = "clipboard")]
+  inner: Option<arboard::Clipboard>,
+}
+
+impl fmt::Debug for Clipboard {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Clipboard")
+  }
+}
+
+impl Clipboard {
+  /// Replaces the clipboard contents, failing if no clipboard is available (e.g. on headless systems)
+  #[cfg(feature = "clipboard")]
+  pub fn set(&mut self, text: &str) -> Result<()> {
+    let clipboard = match &mut self.inner {
+      Some(clipboard) => clipboard,
+      None => {
+        let clipboard = arboard::Clipboard::new().map_err(|err| eyre!("Clipboard unavailable: {}", err))?;
+        self.inner.insert(clipboard)
+      },
+    };
+
+    clipboard.set_text(text).map_err(|err| eyre!("Couldn't copy to the clipboard: {}", err))
+  }
+
+  #[cfg(not(feature = "clipboard"))]
+  pub fn set(&mut self, _text: &str) -> Result<()> {
+    Err(eyre!("Clipboard unavailable: built without the `clipboard` feature"))
+  }
+}
@@ -0,0 +1,227 @@
+use std::f64::consts::{E, PI};
+use eyre::{eyre, Result};
+
+use crate::{lexer::{Func, Op}, parser::Node};
+
+fn binop(op: Op, left: Node, right: Node) -> Node {
+  Node::BinOp(op, Box::new(left), Box::new(right))
+}
+
+fn mul(left: Node, right: Node) -> Node {
+  binop(Op::Mul, left, right)
+}
+
+fn div(left: Node, right: Node) -> Node {
+  binop(Op::Div, left, right)
+}
+
+fn neg(node: Node) -> Node {
+  Node::Neg(Box::new(node))
+}
+
+fn func(func: Func, arg: Node) -> Node {
+  Node::Func(func, vec![arg])
+}
+
+fn number(value: f64) -> Node {
+  Node::Immediate(value)
+}
+
+impl Node {
+  /// Whether the variable occurs anywhere in the expression
+  fn contains(&self, var: &str) -> bool {
+    match self {
+      Node::Immediate(_) | Node::FuncRef(_) | Node::OpRef(_) => false,
+      Node::Variable(name) => name == var,
+      Node::Neg(node) => node.contains(var),
+      Node::BinOp(_, left, right) => left.contains(var) || right.contains(var),
+      Node::If { cond, then, else_ } => cond.contains(var) || then.contains(var) || else_.contains(var),
+      // The body's `name` is the bound one
+      Node::Let { name, value, body } => value.contains(var) || (name != var && body.contains(var)),
+      Node::Piecewise(cases, default) => {
+        cases.iter().any(|(cond, value)| cond.contains(var) || value.contains(var)) || default.as_ref().is_some_and(|default| default.contains(var))
+      },
+      Node::Func(_, args) | Node::Call(_, args) | Node::List(args) => args.iter().any(|arg| arg.contains(var)),
+    }
+  }
+
+  /// Derivative of the expression with respect to `var`, unsimplified.
+  /// User-defined functions have to be inlined first, and trees deeper than `MAX_DEPTH` fail
+  pub fn differentiate(&self, var: &str) -> Result<Node> {
+    self.check_depth()?;
+    self.differentiate_unchecked(var)
+  }
+
+  /// Rewritten trees, like the body of `let` with the value substituted, go through `differentiate` again,
+  /// since they can be deeper than the original
+  fn differentiate_unchecked(&self, var: &str) -> Result<Node> {
+    if !self.contains(var) {
+      return Ok(number(0.))
+    }
+
+    match self {
+      Node::Immediate(_) | Node::FuncRef(_) | Node::OpRef(_) => Ok(number(0.)),
+      Node::Variable(_) => Ok(number(1.)),
+      Node::Neg(node) => Ok(neg(node.differentiate_unchecked(var)?)),
+      Node::BinOp(op, u, v) => {
+        let (u, v) = (&**u, &**v);
+        match op {
+          Op::Add | Op::Sub => Ok(binop(*op, u.differentiate_unchecked(var)?, v.differentiate_unchecked(var)?)),
+          Op::Mul => Ok(binop(
+            Op::Add,
+            mul(u.differentiate_unchecked(var)?, v.clone()),
+            mul(u.clone(), v.differentiate_unchecked(var)?),
+          )),
+          Op::Div => Ok(div(
+            binop(Op::Sub, mul(u.differentiate_unchecked(var)?, v.clone()), mul(u.clone(), v.differentiate_unchecked(var)?)),
+            binop(Op::Pow, v.clone(), number(2.)),
+          )),
+          // Power rule
+          Op::Pow if !v.contains(var) => Ok(mul(
+            mul(v.clone(), binop(Op::Pow, u.clone(), binop(Op::Sub, v.clone(), number(1.)))),
+            u.differentiate_unchecked(var)?,
+          )),
+          // Exponential rule
+          Op::Pow if !u.contains(var) => Ok(mul(
+            mul(self.clone(), func(Func::Log(E), u.clone())),
+            v.differentiate_unchecked(var)?,
+          )),
+          // u ^ v = e ^ (v * ln(u))
+          Op::Pow => Ok(mul(
+            self.clone(),
+            binop(
+              Op::Add,
+              mul(v.differentiate_unchecked(var)?, func(Func::Log(E), u.clone())),
+              div(mul(v.clone(), u.differentiate_unchecked(var)?), u.clone()),
+            ),
+          )),
+          // Comparisons jump wherever they switch between 0 and 1
+          Op::Mod | Op::Gt | Op::Lt | Op::Eq | Op::Ge | Op::Le | Op::Ne => Err(eyre!("No derivative rule for {}", op)),
+        }
+      },
+      Node::Func(function, args) => differentiate_func(*function, args, var),
+      Node::Call(name, _) => Err(eyre!("Undefined function: {}", name)),
+      Node::List(items) => Ok(Node::List(items.iter().map(|item| item.differentiate_unchecked(var)).collect::<Result<_>>()?)),
+      // Piecewise, leaving out the points where the condition switches
+      Node::If { cond, then, else_ } => Ok(Node::If {
+        cond: cond.clone(),
+        then: Box::new(then.differentiate_unchecked(var)?),
+        else_: Box::new(else_.differentiate_unchecked(var)?),
+      }),
+      Node::Let { name, value, body } => body.substitute(name, value).differentiate(var),
+      Node::Piecewise(cases, default) => Ok(Node::Piecewise(
+        cases.iter().map(|(cond, value)| Ok((cond.clone(), value.differentiate_unchecked(var)?))).collect::<Result<_>>()?,
+        default.as_ref().map(|default| default.differentiate_unchecked(var).map(Box::new)).transpose()?,
+      )),
+    }
+  }
+}
+
+/// Chain rule for built-in functions
+fn differentiate_func(function: Func, args: &[Node], var: &str) -> Result<Node> {
+  let u = args[0].clone();
+  let chain = |outer: Node| -> Result<Node> { Ok(mul(outer, args[0].differentiate_unchecked(var)?)) };
+
+  match function {
+    Func::Abs => chain(div(u.clone(), func(Func::Abs, u))),
+    Func::Sqrt => chain(div(number(1.), mul(number(2.), func(Func::Sqrt, u)))),
+    Func::Log(base) => chain(div(number(1.), mul(u, number(base.ln())))),
+    // log(b, u) = ln(u) / ln(b)
+    Func::LogBase => div(func(Func::Log(E), args[1].clone()), func(Func::Log(E), u)).differentiate(var),
+    Func::Sin => chain(func(Func::Cos, u)),
+    Func::Cos => chain(neg(func(Func::Sin, u))),
+    Func::SinPi => chain(mul(number(PI), func(Func::CosPi, u))),
+    Func::CosPi => chain(neg(mul(number(PI), func(Func::SinPi, u)))),
+    Func::Tg => chain(div(number(1.), binop(Op::Pow, func(Func::Cos, u), number(2.)))),
+    Func::Ctg => chain(neg(div(number(1.), binop(Op::Pow, func(Func::Sin, u), number(2.))))),
+    Func::Asin | Func::Acos => {
+      let derivative = div(number(1.), func(Func::Sqrt, binop(Op::Sub, number(1.), binop(Op::Pow, u, number(2.)))));
+      chain(if function == Func::Asin { derivative } else { neg(derivative) })
+    },
+    Func::Atan => chain(div(number(1.), binop(Op::Add, number(1.), binop(Op::Pow, u, number(2.))))),
+    // d atan2(y, x) = (x dy - y dx) / (x^2 + y^2)
+    Func::Atan2 => {
+      let (y, x) = (u, args[1].clone());
+      Ok(div(
+        binop(Op::Sub, mul(x.clone(), y.differentiate_unchecked(var)?), mul(y.clone(), x.differentiate_unchecked(var)?)),
+        binop(Op::Add, binop(Op::Pow, x, number(2.)), binop(Op::Pow, y, number(2.))),
+      ))
+    },
+    Func::Exp => chain(func(Func::Exp, u)),
+    // u ^ (1 / b) differentiates to u ^ (1 / b) / (b * u)
+    Func::Root(base) => chain(div(func(Func::Root(base), u.clone()), mul(number(base), u))),
+    Func::Lerp => {
+      let (a, b, t) = (u, args[1].clone(), args[2].clone());
+      binop(Op::Add, a.clone(), mul(t, binop(Op::Sub, b, a))).differentiate(var)
+    },
+    Func::SumList => Ok(Node::Func(Func::SumList, args.iter().map(|arg| arg.differentiate_unchecked(var)).collect::<Result<_>>()?)),
+    function => Err(eyre!("No derivative rule for {}", function)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use crate::{eval::EvalOptions, lexer::tokenize, parser::{parse_expression, Node}};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  fn derive(input: &str) -> String {
+    parse(input).differentiate("x").unwrap().simplify().to_string()
+  }
+
+  #[test]
+  fn test_differentiate() {
+    assert_eq!(derive("x^2 + sin(x)"), "2 * x + cos(x)");
+    assert_eq!(derive("3 * x - y"), "3");
+    assert_eq!(derive("exp(2 * x)"), "exp(2 * x) * 2");
+    assert_eq!(derive("x ^ x"), "x ^ x * (log(e, x) + x / x)");
+    assert_eq!(derive("y ^ 2"), "0");
+    assert_eq!(derive("if(x > 0, x ^ 2, -x)"), "if(x > 0, 2 * x, -1)");
+    assert_eq!(derive("let u = 3 * x in u + y"), "3");
+    assert_eq!(derive("let x = 2 in x * y"), "0");
+    assert_eq!(derive("piecewise(x < 0, -x, x < 1, x ^ 3, 1)"), "piecewise(x < 0, -1, x < 1, 3 * x ^ 2, 0)");
+
+    assert_eq!(parse("x % 2").differentiate("x").unwrap_err().to_string(), "No derivative rule for %");
+    assert_eq!(parse("round_to(x, 2)").differentiate("x").unwrap_err().to_string(), "No derivative rule for round_to");
+    assert!(parse("rand() * 0 + 1").differentiate("x").is_ok());
+  }
+
+  #[test]
+  fn test_central_difference() {
+    let expressions = [
+      "x^3 - 2 * x + 1",
+      "sin(x) * cos(x)",
+      "x / (1 + x^2)",
+      "sqrt(x) + root3(x) + abs(x)",
+      "log2(x) + log10(x ^ 2) + log(3, x)",
+      "tg(x) + ctg(x)",
+      "asin(x / 2) + acos(x / 3) + atan(x)",
+      "atan2(x, y) + atan2(y, x ^ 2)",
+      "exp(-x^2)",
+      "2 ^ x + x ^ x",
+      "sinpi(x) * cospi(x)",
+      "lerp(x, x^2, 0.5)",
+      "sum_list(x, x ^ 2, y, sin(x))",
+      "-(x * y)",
+    ];
+    let h = 1e-6;
+
+    for input in expressions {
+      let node = parse(input);
+      let derivative = node.differentiate("x").unwrap().simplify();
+
+      for x in [0.3, 0.7, 1.1, 1.6] {
+        let at = |node: &Node, x: f64| {
+          let ctx = HashMap::from([("x".to_string(), x), ("y".to_string(), 2.)]);
+          node.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap()
+        };
+        let expected = (at(&node, x + h) - at(&node, x - h)) / (2. * h);
+        let actual = at(&derivative, x);
+        assert!((actual - expected).abs() < 1e-5 * expected.abs().max(1.), "d/dx {} at {}: {} ≠ {} ({})", input, x, actual, expected, derivative);
+      }
+    }
+  }
+}
@@ -0,0 +1,258 @@
+use std::{fmt, ops::Range};
+use eyre::Report;
+
+use crate::{error::{EvalError, LexError, ParseError, SpanError}, lexer::NAMES};
+
+/// Error prepared for showing next to the line it came from: what went wrong, where, and possibly how to fix it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+  /// Full message, starting with the stage that failed
+  pub message: String,
+  pub source: String,
+  /// Range of characters in `source` the error points at
+  pub span: Option<Range<usize>>,
+  /// Short note on the span
+  pub label: Option<String>,
+  pub help: Option<String>,
+}
+
+/// Number of single-character edits needed to turn `a` into `b`
+pub fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+
+  for (i, ca) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+
+    for (j, &cb) in b.iter().enumerate() {
+      let substitution = diagonal + (ca != cb) as usize;
+      diagonal = row[j + 1];
+      row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+    }
+  }
+
+  row[b.len()]
+}
+
+/// Closest name to `name` among `known` and the built-in ones.
+/// Only names differing in at most half of the characters count, so single letters get no suggestions
+fn suggest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+  known.iter().chain(NAMES)
+    .map(|candidate| (edit_distance(name, candidate), *candidate))
+    .filter(|(distance, _)| distance * 2 <= name.chars().count())
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, candidate)| candidate)
+}
+
+fn lex_label(error: &LexError) -> String {
+  match error {
+    LexError::UnknownToken(_) => "unknown character".to_string(),
+    LexError::InvalidNumber(_) => "not a number".to_string(),
+    LexError::InvalidFunctionArgument => "expected a number".to_string(),
+    LexError::UnterminatedString => "no closing quote".to_string(),
+    LexError::Limit(_) => "over the limit".to_string(),
+  }
+}
+
+fn parse_label(error: &ParseError) -> String {
+  match error {
+    ParseError::UnexpectedToken { expected, .. } | ParseError::TrailingToken { expected, .. }
+      | ParseError::UnbalancedParenthesis { expected, .. } | ParseError::UnbalancedBrackets { expected, .. } => format!("expected {}", expected),
+    ParseError::UnmatchedClosing(_) => "unmatched".to_string(),
+    ParseError::Unclosed { .. } => "never closed".to_string(),
+    ParseError::MissingParentheses { .. } => "expected '(' after it".to_string(),
+    ParseError::ReservedName(_) => "built-in function".to_string(),
+    ParseError::EmptyParentheses => "empty".to_string(),
+    ParseError::EmptyArguments(_) => "called without arguments".to_string(),
+    ParseError::DanglingOperator(_) => "expected a value after it".to_string(),
+    ParseError::InvalidEval(_) => "not an expression".to_string(),
+    ParseError::TooDeep(_) => "too deeply nested".to_string(),
+    ParseError::WrongArgumentCount { count, .. } | ParseError::IfArgumentCount(count)
+      | ParseError::PiecewiseArgumentCount(count) => format!("called with {} argument(s)", count),
+    ParseError::FunctionArity { takes, .. } => format!("takes {} argument(s)", takes),
+    ParseError::ExpectedArgument { expected, .. } => format!("expected a {}", expected),
+    ParseError::FunctionAsValue(_) | ParseError::OperatorAsValue(_) => "not a value".to_string(),
+    ParseError::Limit(_) => "over the limit".to_string(),
+  }
+}
+
+fn parse_help(error: &ParseError) -> Option<String> {
+  match error {
+    ParseError::FunctionAsValue(_) | ParseError::OperatorAsValue(_) => {
+      Some("functions and operators can only be passed by name to `map` and `zip`".to_string())
+    },
+    _ => None,
+  }
+}
+
+fn eval_label(error: &EvalError) -> Option<String> {
+  match error {
+    EvalError::UndefinedVariable(_) | EvalError::UndefinedFunction(_) => Some("not defined".to_string()),
+    _ => None,
+  }
+}
+
+fn eval_help(error: &EvalError, known: &[&str]) -> Option<String> {
+  match error {
+    EvalError::UndefinedVariable(name) | EvalError::UndefinedFunction(name) => {
+      suggest(name, known).map(|name| format!("did you mean `{}`?", name))
+    },
+    _ => None,
+  }
+}
+
+impl Diagnostic {
+  /// Describes `report`, an error from evaluating `source`.
+  /// Undefined names are matched against `known`, such as the names defined in a session, and the built-in ones
+  pub fn new(source: &str, report: &Report, known: &[&str]) -> Self {
+    let (span, label, help) = report.chain().find_map(|error| {
+      if let Some(error) = error.downcast_ref::<SpanError<LexError>>() {
+        Some((Some(error.span.clone()), Some(lex_label(&error.error)), None))
+      } else if let Some(error) = error.downcast_ref::<SpanError<ParseError>>() {
+        Some((Some(error.span.clone()), Some(parse_label(&error.error)), parse_help(&error.error)))
+      } else if let Some(error) = error.downcast_ref::<SpanError<EvalError>>() {
+        Some((Some(error.span.clone()), eval_label(&error.error), eval_help(&error.error, known)))
+      } else {
+        // Names inside user-defined functions aren't in the input, but may still have a suggestion
+        error.downcast_ref::<EvalError>().map(|error| (None, None, eval_help(error, known)))
+      }
+    }).unwrap_or_default();
+
+    Diagnostic {
+      message: format!("{:#}", report),
+      source: source.to_string(),
+      span,
+      label,
+      help,
+    }
+  }
+
+  /// Plain text rendering: the message, then the source with the span underlined and labeled, then the help
+  pub fn render(&self) -> String {
+    let mut output = self.message.clone();
+    if let Some(span) = &self.span {
+      let marker = format!("{}{}", " ".repeat(span.start), "^".repeat(span.len().max(1)));
+      output += &format!("\n{}\n{}", self.source, marker);
+      if let Some(label) = &self.label {
+        output += &format!(" {}", label);
+      }
+    }
+    if let Some(help) = &self.help {
+      output += &format!("\nhelp: {}", help);
+    }
+    output
+  }
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(feature = "miette")]
+impl miette::Diagnostic for Diagnostic {
+  fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+    Some(&self.source)
+  }
+
+  fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+    // Spans count characters, while miette counts bytes
+    let offset = |index| self.source.char_indices().nth(index).map_or(self.source.len(), |(offset, _)| offset);
+    let span = self.span.as_ref()?;
+    let (start, end) = (offset(span.start), offset(span.end));
+    Some(Box::new(std::iter::once(miette::LabeledSpan::new(self.label.clone(), start, end - start))))
+  }
+
+  fn help(&self) -> Option<Box<dyn fmt::Display + '_>> {
+    self.help.as_ref().map(|help| Box::new(help) as Box<dyn fmt::Display>)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{calculate, session::Session};
+  use super::{edit_distance, Diagnostic};
+
+  fn render(input: &str) -> String {
+    Diagnostic::new(input, &calculate(input).unwrap_err(), &[]).render()
+  }
+
+  #[test]
+  fn test_edit_distance() {
+    assert_eq!(edit_distance("sqrt", "sqrt"), 0);
+    assert_eq!(edit_distance("sqr", "sqrt"), 1);
+    assert_eq!(edit_distance("cso", "cos"), 2);
+    assert_eq!(edit_distance("", "abs"), 3);
+  }
+
+  #[test]
+  fn test_render() {
+    assert_eq!(render("2 + sqr(4)"), "\
+Error during evaluation: Undefined function: sqr
+2 + sqr(4)
+    ^^^ not defined
+help: did you mean `sqrt`?");
+    assert_eq!(render("1 + $ 2"), "\
+Error during tokenization: Unknown character $
+1 + $ 2
+    ^ unknown character");
+    assert_eq!(render("(1 + 2"), "\
+Error during AST construction: Unclosed '(' opened at column 1
+(1 + 2
+^ never closed");
+    assert_eq!(render("sqrt(1, 2)"), "\
+Error during AST construction: sqrt expects 1 argument(s), got 2
+sqrt(1, 2)
+^^^^^^^^^^ called with 2 argument(s)");
+    assert_eq!(render("abs(sin)"), "\
+Error during AST construction: Expected a value, got the function sin
+abs(sin)
+^^^^^^^^ not a value
+help: functions and operators can only be passed by name to `map` and `zip`");
+    assert_eq!(render("1 / 0"), "Error during evaluation: Invalid operation: division by zero while evaluating `1 / 0`");
+  }
+
+  #[test]
+  fn test_suggestions() {
+    let mut session = Session::default();
+    session.execute("width = 3").unwrap();
+    session.execute("area(w, h) = w * h").unwrap();
+
+    let mut help = |input: &str| {
+      let report = session.execute(input).unwrap_err();
+      session.diagnose(input, &report).help
+    };
+    assert_eq!(help("widht * 2"), Some("did you mean `width`?".to_string()));
+    assert_eq!(help("aera(1, 2)"), Some("did you mean `area`?".to_string()));
+    assert_eq!(help("lenn([1])"), Some("did you mean `len`?".to_string()));
+    assert_eq!(help("y + 1"), None);
+  }
+
+  #[cfg(feature = "miette")]
+  #[test]
+  fn test_miette() {
+    use miette::{GraphicalReportHandler, GraphicalTheme};
+
+    let input = "2 * sqr(4)";
+    let diagnostic = Diagnostic::new(input, &calculate(input).unwrap_err(), &[]);
+    let mut output = String::new();
+    GraphicalReportHandler::new_themed(GraphicalTheme::unicode_nocolor())
+      .with_width(80)
+      .render_report(&mut output, &diagnostic)
+      .unwrap();
+    assert_eq!(output, [
+      "  × Error during evaluation: Undefined function: sqr",
+      "   ╭────",
+      " 1 │ 2 * sqr(4)",
+      "   ·     ─┬─",
+      "   ·      ╰── not defined",
+      "   ╰────",
+      "  help: did you mean `sqrt`?",
+      "",
+    ].join("\n"));
+  }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+
+use crate::parser::{piecewise_arguments, Node};
+
+/// Subtrees at the same place in two trees which differ in their outermost node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+  /// Way down from the root, like `right.args[0]`, empty for the root itself
+  pub path: String,
+  pub left: Node,
+  pub right: Node,
+}
+
+/// Renders as a hunk of a patch, the left subtree removed and the right one added
+impl fmt::Display for Difference {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let path = if self.path.is_empty() { "root" } else { &self.path };
+    write!(f, "@ {}\n- {}\n+ {}", path, self.left, self.right)
+  }
+}
+
+/// Whether the nodes are the same apart from their children, which they have as many of
+fn same_node(left: &Node, right: &Node) -> bool {
+  match (left, right) {
+    (Node::Immediate(a), Node::Immediate(b)) => a == b || (a.is_nan() && b.is_nan()),
+    (Node::Variable(a), Node::Variable(b)) => a == b,
+    (Node::Neg(_), Node::Neg(_)) | (Node::If { .. }, Node::If { .. }) => true,
+    (Node::BinOp(a, _, _), Node::BinOp(b, _, _)) | (Node::OpRef(a), Node::OpRef(b)) => a == b,
+    (Node::Func(a, a_args), Node::Func(b, b_args)) => a == b && a_args.len() == b_args.len(),
+    (Node::Call(a, a_args), Node::Call(b, b_args)) => a == b && a_args.len() == b_args.len(),
+    (Node::FuncRef(a), Node::FuncRef(b)) => a == b,
+    (Node::List(a), Node::List(b)) => a.len() == b.len(),
+    (Node::Piecewise(a, a_default), Node::Piecewise(b, b_default)) => a.len() == b.len() && a_default.is_some() == b_default.is_some(),
+    (Node::Let { name: a, .. }, Node::Let { name: b, .. }) => a == b,
+    _ => false,
+  }
+}
+
+/// Arguments with path segments like `args[0]`
+fn indexed<'a>(name: &str, args: impl Iterator<Item = &'a Node>) -> Vec<(String, &'a Node)> {
+  args.enumerate().map(|(index, arg)| (format!("{}[{}]", name, index), arg)).collect()
+}
+
+/// Children of the node along with the path segment leading to each
+fn children(node: &Node) -> Vec<(String, &Node)> {
+  match node {
+    Node::Immediate(_) | Node::Variable(_) | Node::FuncRef(_) | Node::OpRef(_) => vec![],
+    Node::Neg(operand) => vec![("operand".to_string(), &**operand)],
+    Node::BinOp(_, left, right) => vec![("left".to_string(), &**left), ("right".to_string(), &**right)],
+    Node::Func(_, args) | Node::Call(_, args) => indexed("args", args.iter()),
+    Node::List(items) => indexed("items", items.iter()),
+    Node::If { cond, then, else_ } => vec![("cond".to_string(), &**cond), ("then".to_string(), &**then), ("else".to_string(), &**else_)],
+    Node::Piecewise(cases, default) => indexed("args", piecewise_arguments(cases, default)),
+    Node::Let { value, body, .. } => vec![("value".to_string(), &**value), ("body".to_string(), &**body)],
+  }
+}
+
+/// Compares the trees from the root down, reporting the outermost subtrees which differ, left to right.
+/// Where the nodes match, only their children are compared, so `2 + 3` and `2 + 4` differ only in `right`
+pub fn diff_ast(left: &Node, right: &Node) -> Vec<Difference> {
+  let mut differences = vec![];
+  // Kept on a stack in reverse, so that trees of any depth can be compared
+  let mut pending = vec![(String::new(), left, right)];
+
+  while let Some((path, left, right)) = pending.pop() {
+    if !same_node(left, right) {
+      differences.push(Difference { path, left: left.clone(), right: right.clone() });
+      continue
+    }
+
+    let pairs = children(left).into_iter().zip(children(right)).rev();
+    pending.extend(pairs.map(|((segment, left), (_, right))| {
+      let path = if path.is_empty() { segment } else { format!("{}.{}", path, segment) };
+      (path, left, right)
+    }));
+  }
+
+  differences
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{lexer::tokenize, parser::{parse_expression, Node}};
+  use super::diff_ast;
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  fn diff(left: &str, right: &str) -> Vec<String> {
+    diff_ast(&parse(left), &parse(right)).iter().map(ToString::to_string).collect()
+  }
+
+  #[test]
+  fn test_diff_ast() {
+    assert_eq!(diff("2 + 3", "2 + 4"), ["@ right\n- 3\n+ 4"]);
+    assert_eq!(diff("sin(x) * 2", "sin(y) * 2 ^ 2"), ["@ left.args[0]\n- x\n+ y", "@ right\n- 2\n+ 2 ^ 2"]);
+    assert_eq!(diff("1 + 2", "1 - 2"), ["@ root\n- 1 + 2\n+ 1 - 2"]);
+    assert_eq!(diff("max(1, 2)", "max(1, 2, 3)"), ["@ root\n- max(1, 2)\n+ max(1, 2, 3)"]);
+    assert_eq!(diff("[1, -x]", "[1, -y]"), ["@ items[1].operand\n- x\n+ y"]);
+    assert_eq!(diff("let a = 1 in piecewise(a, 2, 3)", "let a = 1 in piecewise(a, 2, 4)"), ["@ body.args[2]\n- 3\n+ 4"]);
+    assert!(diff("x ^ 2 + f(x)", "x^2+f(x)").is_empty());
+
+    // Compared without recursion
+    let nested = |leaf| (0..20_000).fold(Node::Immediate(leaf), |node, _| Node::Neg(Box::new(node)));
+    let differences = diff_ast(&nested(1.), &nested(2.));
+    assert_eq!(differences.len(), 1);
+    assert_eq!(differences[0].path.matches("operand").count(), 20_000);
+  }
+}
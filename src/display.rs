@@ -0,0 +1,366 @@
+use std::{f64::consts::E, fmt};
+use crate::{eval::{Step, Value}, lexer::{Func, Op}, parser::{piecewise_arguments, Node}, settings::Settings};
+
+/// Number of steps shown by `render_steps` before the rest are summarized
+const MAX_STEPS: usize = 50;
+
+/// Binding strength of the node's outermost operation, higher binds tighter
+fn precedence(node: &Node) -> u8 {
+  match node {
+    // Printed as a negation
+    Node::Immediate(value) if value.is_sign_negative() => 5,
+    // The body takes everything after it
+    Node::Let { .. } => 0,
+    Node::BinOp(op, _, _) if op.is_comparison() => 1,
+    Node::BinOp(Op::Add | Op::Sub, _, _) => 2,
+    Node::BinOp(Op::Mul | Op::Div | Op::Mod, _, _) => 3,
+    Node::BinOp(Op::Pow, _, _) => 4,
+    _ => 5,
+  }
+}
+
+/// Part of the text of a tree still to be written by `Node::fmt`
+enum Piece<'a> {
+  Text(&'static str),
+  /// Operator with a space on either side
+  Op(Op),
+  /// Node, wrapped in parenthesis if the flag is set
+  Operand(&'a Node, bool),
+}
+
+/// Queues the nodes separated by commas, followed by `close`
+fn push_separated<'a>(pending: &mut Vec<Piece<'a>>, nodes: impl IntoIterator<Item = &'a Node>, close: &'static str) {
+  pending.push(Piece::Text(close));
+  let nodes: Vec<_> = nodes.into_iter().collect();
+  for (index, node) in nodes.into_iter().enumerate().rev() {
+    pending.push(Piece::Operand(node, false));
+    if index > 0 {
+      pending.push(Piece::Text(", "));
+    }
+  }
+}
+
+impl Node {
+  /// Renders the tree with one node per line, children indented under their parent
+  pub fn render_tree(&self) -> String {
+    let mut lines = vec![];
+    // Nodes still to write with their depth, kept on a stack so that trees of any depth can be rendered
+    let mut pending = vec![(self, 0)];
+
+    while let Some((node, depth)) = pending.pop() {
+      let indent = "  ".repeat(depth);
+      let children: Vec<&Node> = match node {
+        Node::Immediate(value) => {
+          lines.push(format!("{}Immediate({})", indent, value));
+          vec![]
+        },
+        Node::Variable(name) => {
+          lines.push(format!("{}Variable({})", indent, name));
+          vec![]
+        },
+        Node::Neg(node) => {
+          lines.push(format!("{}Neg", indent));
+          vec![node]
+        },
+        Node::BinOp(op, left, right) => {
+          lines.push(format!("{}BinOp({:?})", indent, op));
+          vec![left, right]
+        },
+        Node::Func(func, args) => {
+          lines.push(format!("{}Func({:?})", indent, func));
+          args.iter().collect()
+        },
+        Node::Call(name, args) => {
+          lines.push(format!("{}Call({})", indent, name));
+          args.iter().collect()
+        },
+        Node::FuncRef(func) => {
+          lines.push(format!("{}FuncRef({:?})", indent, func));
+          vec![]
+        },
+        Node::OpRef(op) => {
+          lines.push(format!("{}OpRef({:?})", indent, op));
+          vec![]
+        },
+        Node::List(items) => {
+          lines.push(format!("{}List", indent));
+          items.iter().collect()
+        },
+        Node::If { cond, then, else_ } => {
+          lines.push(format!("{}If", indent));
+          vec![cond, then, else_]
+        },
+        Node::Let { name, value, body } => {
+          lines.push(format!("{}Let({})", indent, name));
+          vec![value, body]
+        },
+        Node::Piecewise(cases, default) => {
+          lines.push(format!("{}Piecewise", indent));
+          piecewise_arguments(cases, default).collect()
+        },
+      };
+      // Pushed in reverse so that the first child is written first
+      pending.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+    }
+
+    lines.join("\n")
+  }
+}
+
+impl Step {
+  /// Renders the operation with its operands and result, e.g. `3 * 16 = 48`
+  pub fn render(&self, settings: &Settings) -> String {
+    let value = |node: &Node| match node {
+      Node::Immediate(value) => settings.format_number(*value),
+      node => node.to_string(),
+    };
+    // Negative operands are parenthesized to tell them apart from the operators
+    let operand = |node: &Node| match node {
+      Node::Immediate(number) if *number < 0. => format!("({})", value(node)),
+      node => value(node),
+    };
+
+    let operation = match &self.node {
+      Node::Neg(node) => format!("-({})", value(node)),
+      Node::BinOp(op, left, right) => format!("{} {} {}", operand(left), op, operand(right)),
+      Node::Func(func, args) => format!("{}({})", func, args.iter().map(value).collect::<Vec<_>>().join(", ")),
+      node => node.to_string(),
+    };
+
+    format!("{} = {}", operation, settings.format_number(self.result))
+  }
+}
+
+/// One step per line, with the rest summarized after `MAX_STEPS`
+pub fn render_steps(steps: &[Step], settings: &Settings) -> String {
+  let mut lines: Vec<_> = steps.iter().take(MAX_STEPS).map(|step| step.render(settings)).collect();
+  if steps.len() > MAX_STEPS {
+    lines.push(format!("… {} more steps", steps.len() - MAX_STEPS));
+  }
+  lines.join("\n")
+}
+
+/// Numbers and text as they are, lists in brackets like `[1, 2, 3]`
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Value::Number(value) => write!(f, "{}", value),
+      Value::List(values) => write!(f, "[{}]", values.iter().map(f64::to_string).collect::<Vec<_>>().join(", ")),
+      Value::Text(text) => write!(f, "{}", text),
+    }
+  }
+}
+
+/// Formats the expression as text which parses back into the same tree,
+/// with parenthesis only where precedence requires them.
+/// Pending pieces are kept on a stack, so trees of any depth can be written
+impl fmt::Display for Node {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut pending = vec![Piece::Operand(self, false)];
+
+    while let Some(piece) = pending.pop() {
+      let node = match piece {
+        Piece::Text(text) => {
+          f.write_str(text)?;
+          continue
+        },
+        Piece::Op(op) => {
+          write!(f, " {} ", op)?;
+          continue
+        },
+        Piece::Operand(node, true) => {
+          f.write_str("(")?;
+          pending.push(Piece::Text(")"));
+          node
+        },
+        Piece::Operand(node, false) => node,
+      };
+
+      match node {
+        // Produced by folding rather than parsing, so printed as the equivalent negation
+        Node::Immediate(value) if value.is_sign_negative() => write!(f, "-{}", -value)?,
+        // Shortest text that parses back to the same value, without a fraction for integers (`1`, not `1.0`).
+        // Infinity and NaN print as `inf` and `NaN`, which read back as such unless variables have these names
+        Node::Immediate(value) => write!(f, "{}", value)?,
+        Node::Variable(name) => write!(f, "{}", name)?,
+        // Unary minus only applies to literals, variables, calls, brackets and other negations
+        Node::Neg(operand) => {
+          f.write_str("-")?;
+          let primary = matches!(**operand, Node::Immediate(_) | Node::Variable(_) | Node::Call(_, _) | Node::Neg(_) | Node::List(_) | Node::If { .. } | Node::Piecewise(..));
+          pending.push(Piece::Operand(operand, !primary));
+        },
+        Node::BinOp(op, left, right) => {
+          // All operators are left-associative
+          let precedence = precedence(node);
+          // `x % -y` would parse as a percentage minus y
+          let negative = matches!(**right, Node::Neg(_)) || matches!(**right, Node::Immediate(value) if value.is_sign_negative());
+          let negated_modulus = *op == Op::Mod && negative;
+          pending.extend([
+            Piece::Operand(right, self::precedence(right) <= precedence || negated_modulus),
+            Piece::Op(*op),
+            Piece::Operand(left, self::precedence(left) < precedence),
+          ]);
+        },
+        // There's no name for the natural logarithm that differentiation introduces, so it's written with `e`
+        Node::Func(Func::Log(base), args) if *base == E => {
+          f.write_str("log(e, ")?;
+          push_separated(&mut pending, args, ")");
+        },
+        Node::Func(func, args) => {
+          write!(f, "{}(", func)?;
+          push_separated(&mut pending, args, ")");
+        },
+        Node::Call(name, args) => {
+          write!(f, "{}(", name)?;
+          push_separated(&mut pending, args, ")");
+        },
+        Node::FuncRef(func) => write!(f, "{}", func)?,
+        Node::OpRef(op) => write!(f, "{}", op)?,
+        Node::List(items) => {
+          f.write_str("[")?;
+          push_separated(&mut pending, items, "]");
+        },
+        Node::If { cond, then, else_ } => {
+          f.write_str("if(")?;
+          push_separated(&mut pending, [&**cond, &**then, &**else_], ")");
+        },
+        Node::Let { name, value, body } => {
+          write!(f, "let {} = ", name)?;
+          pending.extend([Piece::Operand(body, false), Piece::Text(" in "), Piece::Operand(value, false)]);
+        },
+        Node::Piecewise(cases, default) => {
+          f.write_str("piecewise(")?;
+          push_separated(&mut pending, piecewise_arguments(cases, default), ")");
+        },
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::E;
+  use crate::{eval::EvalOptions, lexer::{tokenize, Func, Op}, parser::{parse_expression, Node}};
+
+  fn tree(input: &str) -> String {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap().render_tree()
+  }
+
+  #[test]
+  fn test_display_modulo() {
+    let display = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap().to_string();
+    assert_eq!(display("7 % (-3)"), "7 % (-3)");
+    assert_eq!(display("(7 % 3) % 2"), "7 % 3 % 2");
+    assert_eq!(display("50% - 1"), "50 / 100 - 1");
+  }
+
+  #[test]
+  fn test_display() {
+    let display = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap().to_string();
+    assert_eq!(display("(1 + 2) * 3"), "(1 + 2) * 3");
+    assert_eq!(display("1 + (2 * 3)"), "1 + 2 * 3");
+    assert_eq!(display("1 - (2 - 3)"), "1 - (2 - 3)");
+    assert_eq!(display("2 ^ (3 ^ 2)"), "2 ^ (3 ^ 2)");
+    assert_eq!(display("log2(x) + root3(y) + log(3, w)"), "log2(x) + root3(y) + log(3, w)");
+    assert_eq!(Node::Func(Func::Log(E), vec![Node::Variable("z".to_string())]).to_string(), "log(e, z)");
+    assert_eq!(display("-(x ^ 2) * -.2"), "-(x ^ 2) * -0.2");
+    assert_eq!(display("1.0 + 2.50"), "1 + 2.5");
+    assert_eq!(display("(1 + 2 > x) == (y <= 3)"), "1 + 2 > x == (y <= 3)");
+    assert_eq!(display("(x != 1) * 2"), "(x != 1) * 2");
+
+    let node = |value| Node::BinOp(Op::Mod, Box::new(Node::Variable("x".to_string())), Box::new(Node::Immediate(value)));
+    assert_eq!(node(-2.).to_string(), "x % (-2)");
+    assert_eq!(node(0.5).to_string(), "x % 0.5");
+  }
+
+  #[test]
+  fn test_lists() {
+    let display = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap().to_string();
+    assert_eq!(display("[1,2 ,3]"), "[1, 2, 3]");
+    assert_eq!(display("-[ ]"), "-[]");
+    assert_eq!(display("zip(+,[1],[2 * (3 + 4)])"), "zip(+, [1], [2 * (3 + 4)])");
+  }
+
+  #[test]
+  fn test_display_deep() {
+    let input = "1 - ".repeat(100_000) + "x";
+    let node = parse_expression(&mut tokenize(&input).unwrap()).unwrap();
+    assert_eq!(node.to_string(), input);
+
+    let mut node = Node::Variable("x".to_string());
+    for _ in 0..100_000 {
+      node = Node::BinOp(Op::Pow, Box::new(Node::Immediate(2.)), Box::new(node));
+    }
+    let text = node.to_string();
+    assert!(text.starts_with("2 ^ (2 ^ (") && text.trim_end_matches(')').ends_with("2 ^ x"));
+    assert_eq!(text.matches('(').count(), 99_999);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let corpus = [
+      "1 + 2 * 3 - 4 / 5",
+      "(1 + 2) * (3 - 4) / (5 % 3)",
+      "2 ^ 3 ^ 2 + 2 ^ (3 ^ 2)",
+      "-2 ^ 2 + -(2 ^ 2) - --3",
+      "0.2 + 0.1 * 3",
+      "50% * 8 + 7 % (-3) + 7 % -3",
+      "log2(8) + log0.5(4) + log(3, 81) + log(e, e) + root3(-27) + sqrt(16)",
+      "sin(pi / 6) * cos(pi) - tg(1) / ctg(1)",
+      "abs(-3) * exp(-(1 - 2)) ^ -2",
+      "choose(5, 2) + wrap(370, 0, 360) + lerp(1, 2, 0.5) + round_to(7, 5)",
+      "1 - (2 - (3 - (4 - 5)))",
+      "8 / (4 / 2) / 2",
+      "10 - 2 ^ -1 * 3",
+      "zip(%, 7, 3) + map(log2, 8) * zip(max, 1, 2)",
+      "(1 < 2) * 3 + (2 >= 2 == 1) - zip(!=, 1, 2)",
+      "if(1 > 2, 3, -if(0, 1, 2)) ^ 2",
+      "-piecewise(1 < 0, 1, 2 < 3, 2 + 1, 4) * piecewise(1, 5)",
+      "(let a = 2 in a * a) + 2 ^ (let b = 1 in b) > let c = let d = 3 in d in c - 1",
+      "inf - 2 ^ -inf + max(1, -INF)",
+    ];
+
+    // Folding may produce values which only `inf` and `nan` stand for
+    for value in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+      let text = Node::Immediate(value).to_string();
+      let reparsed = parse_expression(&mut tokenize(&text).unwrap()).unwrap().evaluate(EvalOptions::default()).unwrap();
+      assert!(reparsed == value || (value.is_nan() && reparsed.is_nan()), "{} → {}", value, text);
+    }
+
+    for input in corpus {
+      let original = parse_expression(&mut tokenize(input).unwrap()).unwrap();
+      let text = original.to_string();
+      let reparsed = parse_expression(&mut tokenize(&text).unwrap()).unwrap_or_else(|err| panic!("{} → {}: {}", input, text, err));
+
+      assert_eq!(reparsed.to_string(), text, "{}", input);
+      assert_eq!(reparsed.evaluate(EvalOptions::default()).unwrap(), original.evaluate(EvalOptions::default()).unwrap(), "{} → {}", input, text);
+    }
+  }
+
+  #[test]
+  fn test_render_tree() {
+    assert_eq!(tree("2 + 3 * 4"), [
+      "BinOp(Add)",
+      "  Immediate(2)",
+      "  BinOp(Mul)",
+      "    Immediate(3)",
+      "    Immediate(4)",
+    ].join("\n"));
+    assert_eq!(tree("-2^2"), [
+      "BinOp(Pow)",
+      "  Neg",
+      "    Immediate(2)",
+      "  Immediate(2)",
+    ].join("\n"));
+    assert_eq!(tree("log2(x) + f(1, 0.5)"), [
+      "BinOp(Add)",
+      "  Func(Log(2.0))",
+      "    Variable(x)",
+      "  Call(f)",
+      "    Immediate(1)",
+      "    Immediate(0.5)",
+    ].join("\n"));
+  }
+}
@@ -0,0 +1,199 @@
+use std::ops::Range;
+use eyre::Report;
+use thiserror::Error;
+
+use crate::lexer::{Func, Op, Token};
+
+/// Error pointing at a range of characters in the input
+#[derive(Debug, Error, Clone, PartialEq)]
+#[error("{error}")]
+pub struct SpanError<E> {
+  pub error: E,
+  pub span: Range<usize>,
+}
+
+impl<E> SpanError<E> {
+  pub fn new(error: E, span: Range<usize>) -> Self {
+    Self { error, span }
+  }
+}
+
+/// Span of the first error in the chain which points into the input
+pub fn find_span(report: &Report) -> Option<Range<usize>> {
+  report.chain().find_map(|err| {
+    err.downcast_ref::<SpanError<LexError>>().map(|err| err.span.clone())
+      .or_else(|| err.downcast_ref::<SpanError<ParseError>>().map(|err| err.span.clone()))
+      .or_else(|| err.downcast_ref::<SpanError<EvalError>>().map(|err| err.span.clone()))
+  })
+}
+
+/// Printable ASCII characters as they are, others by code point, since they may be invisible or look like ones expected
+fn describe_char(c: char) -> String {
+  if c.is_ascii_graphic() {
+    c.to_string()
+  } else {
+    format!("U+{:04X}", c as u32)
+  }
+}
+
+/// Input exceeding one of the size limits, carrying the limit
+#[derive(Debug, Error, Clone, Copy, PartialEq)]
+pub enum LimitExceeded {
+  #[error("Input too long (limit {0} characters)")]
+  Length(usize),
+  #[error("Too many tokens (limit {0})")]
+  Tokens(usize),
+  #[error("Expression too large (limit {0} nodes)")]
+  Nodes(usize),
+  /// Tree deeper than the recursive passes like `simplify` and `differentiate` take on, see `walk::MAX_DEPTH`
+  #[error("Expression too deeply nested (limit {0} levels)")]
+  Depth(usize),
+}
+
+/// Failure to split the input into tokens
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum LexError {
+  #[error("Unknown character {}", describe_char(*.0))]
+  UnknownToken(char),
+  /// Digits and dots which don't form a number, like `1.2.3`
+  #[error("Invalid numeric literal: {0}")]
+  InvalidNumber(String),
+  /// Base of `log` or `root` which isn't a number
+  #[error("Unable to parse function argument")]
+  InvalidFunctionArgument,
+  /// Double quote without a closing one
+  #[error("Unterminated string")]
+  UnterminatedString,
+  #[error(transparent)]
+  Limit(LimitExceeded),
+}
+
+/// Failure to build a syntax tree out of the tokens
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ParseError {
+  /// Token `found` where something from the `expected` set, like an operand, had to be
+  #[error("Unexpected token: expected {expected}, found {found}")]
+  UnexpectedToken { found: Token, expected: &'static str },
+  /// Input ending right after an operator, which still needs an operand
+  #[error("Expression ends after '{0}'; expected a value")]
+  DanglingOperator(Op),
+  /// Function name not followed by `(`, with the call it likely stands for if its operand is a number or a name
+  #[error("Function `{func}` must be called with parentheses{}", .call.as_ref().map(|call| format!(": {}", call)).unwrap_or_default())]
+  MissingParentheses { func: Func, call: Option<String> },
+  /// Built-in function's name given to a variable, a function or a parameter, as in `min = 3`
+  #[error("`{0}` is the name of a built-in function and can't be used as a name")]
+  ReservedName(Func),
+  /// Token left over after a complete expression
+  #[error("Unexpected token: expected {expected}, found {found}")]
+  TrailingToken { found: Token, expected: &'static str },
+  #[error("Parenthesis don't match: expected {expected}, found {found}")]
+  UnbalancedParenthesis { found: Token, expected: &'static str },
+  #[error("Brackets don't match: expected {expected}, found {found}")]
+  UnbalancedBrackets { found: Token, expected: &'static str },
+  /// Closing parenthesis or bracket with nothing open before it
+  #[error("Unmatched {0}: nothing open to close")]
+  UnmatchedClosing(Token),
+  /// Input ending before the parenthesis or bracket `open` at the 1-based `column` is closed
+  #[error("Unclosed {open} opened at column {column}")]
+  Unclosed { open: Token, column: usize },
+  #[error("Expression too deeply nested (limit {0})")]
+  TooDeep(usize),
+  #[error("{}", .func.arity_error(*.count))]
+  WrongArgumentCount { func: Func, count: usize },
+  #[error("Empty parentheses: expected an expression between ( and )")]
+  EmptyParentheses,
+  /// Function called with `()` which needs arguments
+  #[error("{0}() requires {}", if .0.arity() == 1 { "an argument".to_string() } else { format!("{} arguments", .0.arity()) })]
+  EmptyArguments(Func),
+  #[error("if expects 3 argument(s), got {0}")]
+  IfArgumentCount(usize),
+  #[error("piecewise expects at least 2 argument(s), got {0}")]
+  PiecewiseArgumentCount(usize),
+  /// Function passed by name, `passed`, taking a different number of arguments than `func` calls it with
+  #[error("{func} expects a function of {arity} argument(s), {passed} takes {takes}")]
+  FunctionArity { func: Func, arity: usize, passed: String, takes: usize },
+  /// Argument at the 1-based `index` which isn't of the `expected` kind, a function or a variable
+  #[error("{func} expects a {expected} as argument {index}")]
+  ExpectedArgument { func: Func, index: usize, expected: &'static str },
+  /// String passed to `eval` which doesn't hold a valid expression, with the reason
+  #[error("Invalid expression in eval: {0}")]
+  InvalidEval(String),
+  #[error("Expected a value, got the function {0}")]
+  FunctionAsValue(Func),
+  #[error("Expected a value, got the operator {0}")]
+  OperatorAsValue(Op),
+  #[error(transparent)]
+  Limit(LimitExceeded),
+}
+
+/// Evaluation error along with the sub-expression whose operation failed, if there is one
+#[derive(Debug, Error, Clone, PartialEq)]
+#[error("{error}{}", .expression.as_ref().map(|expression| format!(" while evaluating `{}`", expression)).unwrap_or_default())]
+pub struct ExpressionError {
+  pub error: EvalError,
+  /// Sub-expression as it's printed, like `1 / (x - 3)`
+  pub expression: Option<String>,
+}
+
+impl From<EvalError> for ExpressionError {
+  fn from(error: EvalError) -> Self {
+    Self { error, expression: None }
+  }
+}
+
+/// Failure to evaluate a syntax tree
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum EvalError {
+  #[error("Undefined variable: {0}")]
+  UndefinedVariable(String),
+  #[error("Undefined function: {0}")]
+  UndefinedFunction(String),
+  /// Evaluation still running at its deadline
+  #[error("Evaluation timed out")]
+  TimedOut,
+  #[error("Invalid operation: division by zero")]
+  DivisionByZero,
+  /// Argument outside of the domain of `operation`, a function or an operator
+  #[error("Invalid operation: {reason}")]
+  Domain { operation: String, value: f64, reason: &'static str },
+  #[error("Invalid operation: expected a number, got a list")]
+  ExpectedNumber,
+  #[error("Invalid operation: expected a list, got a number")]
+  ExpectedList,
+  #[error("Invalid operation: lists can't contain lists")]
+  NestedList,
+  /// Text, as given by `format_sci` and `format_eng`, anywhere but as the result
+  #[error("Invalid operation: expected a number or a list, got text")]
+  UnexpectedText,
+  #[error("Invalid operation: {0} of an empty list")]
+  EmptyList(Func),
+  #[error("Invalid operation: {0} of too few elements")]
+  TooFewElements(Func),
+  #[error("Invalid operation: index {0} isn't a whole number")]
+  FractionalIndex(f64),
+  #[error("Invalid operation: index {index} out of bounds for a list of {length} element(s)")]
+  IndexOutOfBounds { index: f64, length: usize },
+  #[error("Invalid operation: slice from {from} to {to} is backwards")]
+  BackwardsSlice { from: usize, to: usize },
+  #[error("Invalid operation: {func} of lists with different lengths ({first} and {other})")]
+  LengthMismatch { func: Func, first: usize, other: usize },
+  #[error("Invalid operation: {0} doesn't take lists")]
+  TakesNoLists(Func),
+  #[error("{}", .func.arity_error(*.count))]
+  WrongArgumentCount { func: Func, count: usize },
+  #[error("Invalid operation: {op} expects 2 argument(s), got {count}")]
+  OperatorArgumentCount { op: Op, count: usize },
+  /// Argument at the 1-based `index` which isn't of the `expected` kind
+  #[error("Invalid operation: {func} expects a {expected} as argument {index}")]
+  ExpectedArgument { func: Func, index: usize, expected: &'static str },
+  /// Value passed where `map` or `zip` expect a function
+  #[error("Invalid operation: expected a function, got {0}")]
+  NotAFunction(String),
+  #[error("Invalid operation: {0} is a function, not a value")]
+  FunctionAsValue(Func),
+  #[error("Invalid operation: {0} is an operator, not a value")]
+  OperatorAsValue(Op),
+  /// `piecewise` without a default, none of whose conditions hold
+  #[error("Invalid operation: no condition of piecewise holds")]
+  NoMatchingCase,
+}
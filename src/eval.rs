@@ -1,6 +1,118 @@
-use crate::{lexer::{Func, Op}, parser::Node};
-use eyre::{eyre, Result};
+use std::{borrow::Cow, collections::{HashMap, HashSet}, f64::consts::{E, PI}, fmt, time::Instant};
+use crate::{error::{EvalError, ExpressionError}, lexer::{Func, Op}, parser::Node, random};
 
+type Result<T> = std::result::Result<T, EvalError>;
+/// Result of evaluating a tree, naming the sub-expression which failed
+type Located<T> = std::result::Result<T, ExpressionError>;
+
+/// Number of operations between checks of the deadline
+pub(crate) const DEADLINE_INTERVAL: usize = 1024;
+
+/// Evaluation behavior adjustable through settings, along with the values of variables
+#[derive(Debug, Clone, Copy)]
+pub struct EvalOptions<'a> {
+  /// Whether division by zero is an error rather than an infinity or NaN
+  pub strict_division: bool,
+  /// When to give up with `EvalError::TimedOut`, checked every so many operations
+  pub deadline: Option<Instant>,
+  /// Values of the variables, none of which are defined if `None`
+  pub variables: Option<&'a HashMap<String, f64>>,
+}
+
+impl Default for EvalOptions<'_> {
+  fn default() -> Self {
+    Self { strict_division: true, deadline: None, variables: None }
+  }
+}
+
+/// Value of `inf` or `nan`, in any case so that printed values like `NaN` read back.
+/// They're looked up after variables rather than being constants, so variables with these names keep working
+pub fn builtin(name: &str) -> Option<f64> {
+  match name.to_ascii_lowercase().as_str() {
+    "inf" => Some(f64::INFINITY),
+    "nan" => Some(f64::NAN),
+    _ => None,
+  }
+}
+
+impl EvalOptions<'_> {
+  pub(crate) fn check_deadline(self) -> Result<()> {
+    match self.deadline {
+      Some(deadline) if Instant::now() >= deadline => Err(EvalError::TimedOut),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Result of evaluating an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Number(f64),
+  List(Vec<f64>),
+  /// Number formatted by `format_sci` or `format_eng`, which can only be the result
+  Text(String),
+}
+
+impl Value {
+  /// The value as a number, failing for a list or text
+  pub fn into_number(self) -> Result<f64> {
+    match self {
+      Value::Number(value) => Ok(value),
+      Value::List(_) => Err(EvalError::ExpectedNumber),
+      Value::Text(_) => Err(EvalError::UnexpectedText),
+    }
+  }
+
+  /// Applies `f` to the number or each element of the list, leaving text as it is
+  pub fn map(self, mut f: impl FnMut(f64) -> f64) -> Value {
+    match self {
+      Value::Number(value) => Value::Number(f(value)),
+      Value::List(values) => Value::List(values.into_iter().map(f).collect()),
+      text @ Value::Text(_) => text,
+    }
+  }
+
+  /// Same as `map`, stopping at the first error. Unlike formatting the value, a fallible step
+  /// such as a unit conversion can't be skipped, so text is an error rather than left as it is
+  pub fn try_map<E: From<EvalError>>(self, mut f: impl FnMut(f64) -> std::result::Result<f64, E>) -> std::result::Result<Value, E> {
+    match self {
+      Value::Number(value) => Ok(Value::Number(f(value)?)),
+      Value::List(values) => values.into_iter().map(f).collect::<std::result::Result<_, _>>().map(Value::List),
+      Value::Text(_) => Err(EvalError::UnexpectedText.into()),
+    }
+  }
+}
+
+/// Literal with the value, which text has none of
+impl TryFrom<&Value> for Node {
+  type Error = EvalError;
+
+  fn try_from(value: &Value) -> Result<Self> {
+    match value {
+      Value::Number(value) => Ok(Node::Immediate(*value)),
+      Value::List(values) => Ok(Node::List(values.iter().copied().map(Node::Immediate).collect())),
+      Value::Text(_) => Err(EvalError::UnexpectedText),
+    }
+  }
+}
+
+impl From<f64> for Value {
+  fn from(value: f64) -> Self {
+    Value::Number(value)
+  }
+}
+
+/// Operation applied during evaluation, with operands replaced by their values
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+  pub node: Node,
+  pub result: f64,
+}
+
+/// Error for `value` outside of the domain of `operation`
+fn domain_error(operation: impl fmt::Display, value: f64, reason: &'static str) -> EvalError {
+  EvalError::Domain { operation: operation.to_string(), value, reason }
+}
 
 impl Op {
   pub fn evaluate(self, left: f64, right: f64) -> Result<f64> {
@@ -10,28 +122,383 @@ impl Op {
       Op::Mul => Ok(left * right),
       Op::Div => {
         if right == 0.0 {
-          Err(eyre!("Invalid operation: division by zero"))
+          Err(EvalError::DivisionByZero)
         } else {
           Ok(left / right)
         }
       },
+      // The result takes the sign of the divisor, so `-1 % 3` is `2`
+      Op::Mod => {
+        if right == 0.0 {
+          return Err(domain_error(self, right, "remainder of division by zero"))
+        }
+
+        let remainder = left % right;
+        if remainder != 0.0 && (remainder < 0.0) != (right < 0.0) {
+          Ok(remainder + right)
+        } else {
+          Ok(remainder)
+        }
+      },
+      // Repeated squaring is faster and exact where `powf` may be off by an ULP.
+      // Follows the `0 ^ 0 = 1` convention
+      Op::Pow if right.fract() == 0. && (-63. ..=63.).contains(&right) => Ok(left.powi(right as i32)),
+      Op::Pow if left < 0. && right.is_finite() && right.fract() != 0. => {
+        Err(domain_error(self, left, "fractional power of negative number"))
+      },
       Op::Pow => Ok(left.powf(right)),
+      Op::Gt => Ok(f64::from(left > right)),
+      Op::Lt => Ok(f64::from(left < right)),
+      Op::Eq => Ok(f64::from(left == right)),
+      Op::Ge => Ok(f64::from(left >= right)),
+      Op::Le => Ok(f64::from(left <= right)),
+      Op::Ne => Ok(f64::from(left != right)),
     }
   }
 }
 
+/// `sin(πx)`, exact at multiples of 1/2
+fn sin_pi(x: f64) -> f64 {
+  // Reduction modulo the period is exact for floats
+  let r = x.rem_euclid(2.);
+  if r == 0. || r == 1. {
+    0.
+  } else if r == 0.5 {
+    1.
+  } else if r == 1.5 {
+    -1.
+  } else {
+    (PI * r).sin()
+  }
+}
+
+/// `cos(πx)`, exact at multiples of 1/2
+fn cos_pi(x: f64) -> f64 {
+  let r = x.rem_euclid(2.);
+  if r == 0.5 || r == 1.5 {
+    0.
+  } else if r == 0. {
+    1.
+  } else if r == 1. {
+    -1.
+  } else {
+    (PI * r).cos()
+  }
+}
+
+/// Coefficients of the Lanczos approximation with g = 7, n = 9
+const LANCZOS: [f64; 9] = [
+  0.999_999_999_999_809_9,
+  676.520_368_121_885_1,
+  -1_259.139_216_722_402_8,
+  771.323_428_777_653_1,
+  -176.615_029_162_140_6,
+  12.507_343_278_686_905,
+  -0.138_571_095_265_720_12,
+  9.984_369_578_019_572e-6,
+  1.505_632_735_149_311_6e-7,
+];
+
+/// Gamma function, using the reflection formula below 1/2. Infinite at the poles
+fn gamma(x: f64) -> f64 {
+  if x <= 0. && x.fract() == 0. {
+    return f64::INFINITY
+  }
+  if x < 0.5 {
+    return PI / (sin_pi(x) * gamma(1. - x))
+  }
+
+  let x = x - 1.;
+  let t = x + 7.5;
+  let sum = LANCZOS[1..].iter().enumerate()
+    .fold(LANCZOS[0], |sum, (index, c)| sum + c / (x + index as f64 + 1.));
+  (2. * PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+/// Natural logarithm of the absolute value of the gamma function, which doesn't overflow for large `x`.
+/// Infinite at the poles
+fn ln_gamma(x: f64) -> f64 {
+  if x <= 0. && x.fract() == 0. {
+    return f64::INFINITY
+  }
+  if x < 0.5 {
+    return (PI / sin_pi(x).abs()).ln() - ln_gamma(1. - x)
+  }
+
+  let x = x - 1.;
+  let t = x + 7.5;
+  let sum = LANCZOS[1..].iter().enumerate()
+    .fold(LANCZOS[0], |sum, (index, c)| sum + c / (x + index as f64 + 1.));
+  0.5 * (2. * PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+/// Sign of the gamma function away from the poles, which alternates between them below 0
+fn gamma_sign(x: f64) -> f64 {
+  if x > 0. || (-x).ceil() % 2. == 0. { 1. } else { -1. }
+}
+
+/// Beta function as a sign and the logarithm of its absolute value, computed through log-gamma to avoid overflow
+fn beta(a: f64, b: f64) -> Result<(f64, f64)> {
+  let is_pole = |x: f64| x <= 0. && x.fract() == 0.;
+  if is_pole(a) || is_pole(b) {
+    return Err(domain_error(Func::Beta, if is_pole(a) { a } else { b }, "beta of a non-positive integer"))
+  }
+  // Γ(a + b) is infinite, so the beta function is 0
+  if is_pole(a + b) {
+    return Ok((1., f64::NEG_INFINITY))
+  }
+
+  let sign = gamma_sign(a) * gamma_sign(b) * gamma_sign(a + b);
+  Ok((sign, ln_gamma(a) + ln_gamma(b) - ln_gamma(a + b)))
+}
+
+/// `B(2k) / (2k)!` for k from 1 to 6, the Euler–Maclaurin correction coefficients
+const EULER_MACLAURIN: [f64; 6] = [
+  1. / 12.,
+  -1. / 720.,
+  1. / 30_240.,
+  -1. / 1_209_600.,
+  1. / 47_900_160.,
+  -691. / 1_307_674_368_000.,
+];
+
+/// Riemann zeta function for `s > 1`, exact at even integers up to 10.
+/// Sums the first terms directly and approximates the tail with the Euler–Maclaurin formula,
+/// which is accurate to about 1e-14 relative error over the whole domain.
+/// The analytic continuation to `s < 1` isn't supported
+fn zeta(s: f64) -> Result<f64> {
+  if s <= 1. {
+    return Err(domain_error(Func::Zeta, s, "zeta is only defined for s > 1"))
+  }
+
+  match s {
+    2. => return Ok(PI.powi(2) / 6.),
+    4. => return Ok(PI.powi(4) / 90.),
+    6. => return Ok(PI.powi(6) / 945.),
+    8. => return Ok(PI.powi(8) / 9_450.),
+    10. => return Ok(PI.powi(10) / 93_555.),
+    f64::INFINITY => return Ok(1.),
+    _ => {},
+  }
+
+  const N: f64 = 10.;
+  let mut sum: f64 = (1..N as u32).map(|n| f64::from(n).powf(-s)).sum();
+  sum += N.powf(1. - s) / (s - 1.) + N.powf(-s) / 2.;
+
+  // Terms of s(s + 1)…(s + 2k - 2) * N^(-s - 2k + 1)
+  let mut rising = s;
+  let mut power = N.powf(-s - 1.);
+  for (index, coefficient) in EULER_MACLAURIN.iter().enumerate() {
+    sum += coefficient * rising * power;
+    let k = index as f64 + 1.;
+    rising *= (s + 2. * k - 1.) * (s + 2. * k);
+    power /= N * N;
+  }
+
+  Ok(sum)
+}
+
+/// Principal branch of the Lambert W function, the `w >= -1` solving `w * e^w = x`, by Halley's method
+fn lambert_w(x: f64) -> Result<f64> {
+  let branch_point = -1. / E;
+  if x < branch_point {
+    return Err(domain_error(Func::LambertW, x, "lambertw of a number below -1/e"))
+  }
+  if x == branch_point {
+    return Ok(-1.)
+  }
+  if x == 0. || x == f64::INFINITY {
+    return Ok(x)
+  }
+
+  // Series around the branch point, `ln(1 + x)` for moderate values and the asymptotic expansion for large ones
+  let mut w = if x < -0.25 {
+    let p = (2. * (E * x + 1.)).sqrt();
+    -1. + p - p * p / 3. + 11. / 72. * p * p * p
+  } else if x < 3. {
+    x.ln_1p()
+  } else {
+    x.ln() - x.ln().ln()
+  };
+
+  for _ in 0..50 {
+    let ew = w.exp();
+    let f = w * ew - x;
+    let step = f / (ew * (w + 1.) - (w + 2.) * f / (2. * w + 2.));
+    if !step.is_finite() {
+      break
+    }
+    w -= step;
+    if step.abs() <= 1e-15 * (1. + w.abs()) {
+      break
+    }
+  }
+
+  Ok(w)
+}
+
+/// Arithmetic-geometric mean, the common limit of repeatedly taking the arithmetic and geometric means
+fn agm(a: f64, b: f64) -> Result<f64> {
+  if a < 0. || b < 0. {
+    return Err(domain_error(Func::Agm, a.min(b), "agm of a negative number"))
+  }
+  if a == 0. || b == 0. {
+    return Ok(0.)
+  }
+  if a.is_infinite() || b.is_infinite() {
+    return Ok(f64::INFINITY)
+  }
+
+  // Converges quadratically, so a handful of iterations is enough for any finite input
+  let (mut a, mut b) = (a, b);
+  for _ in 0..64 {
+    if (a - b).abs() <= 1e-15 * a.max(b) {
+      break
+    }
+    // `sqrt(a) * sqrt(b)` doesn't overflow for large values
+    (a, b) = ((a + b) / 2., a.sqrt() * b.sqrt());
+  }
+  Ok(a)
+}
+
+/// Generalized binomial coefficient, by the product formula for integer `k` and through gamma otherwise
+fn choose(n: f64, k: f64) -> Result<f64> {
+  let is_pole = |x: f64| x <= 0. && x.fract() == 0.;
+
+  if k.fract() != 0. {
+    if is_pole(n + 1.) {
+      return Err(domain_error(Func::Choose, n, "choose of a negative integer and a non-integer"))
+    }
+    if is_pole(k + 1.) || is_pole(n - k + 1.) {
+      return Ok(0.)
+    }
+    return Ok(gamma(n + 1.) / (gamma(k + 1.) * gamma(n - k + 1.)))
+  }
+
+  let natural = n >= 0. && n.fract() == 0.;
+  if k < 0. || (natural && k > n) {
+    return Ok(0.)
+  }
+  // Fewer steps by symmetry
+  let k = if natural { k.min(n - k) } else { k };
+
+  let mut result = 1_f64;
+  let mut i = 0.;
+  // Stops early once the result can't change anymore
+  while i < k && result.is_finite() && result != 0. {
+    result = result * (n - i) / (i + 1.);
+    i += 1.;
+  }
+
+  Ok(if natural { result.round() } else { result })
+}
+
 impl Func {
-  pub fn evaluate(self, arg: f64) -> Result<f64> {
+  pub fn evaluate(self, args: &[f64]) -> Result<f64> {
+    if !self.accepts(args.len()) {
+      return Err(EvalError::WrongArgumentCount { func: self, count: args.len() })
+    }
+
+    if self.takes_lists() {
+      return Err(EvalError::ExpectedList)
+    }
+
+    if self.returns_text() {
+      return Err(EvalError::UnexpectedText)
+    }
+
+    // Every function is defined on the reals, so NaN propagates rather than failing domain checks.
+    // Logic functions are the exception: like `if`, they take anything nonzero, NaN included, as true
+    if !matches!(self, Func::And | Func::Or | Func::Not) && args.iter().any(|arg| arg.is_nan()) {
+      return Ok(f64::NAN)
+    }
+
+    // `rand()` takes no arguments
+    let arg = args.first().copied().unwrap_or_default();
     match self {
+      Func::Random => Ok(random::next()),
+      Func::RandomRange => Ok(arg + (args[1] - arg) * random::next()),
+      Func::Choose => choose(arg, args[1]),
+      Func::Beta => beta(arg, args[1]).map(|(sign, ln)| sign * ln.exp()),
+      Func::Lbeta => beta(arg, args[1]).map(|(_, ln)| ln),
+      Func::Zeta => zeta(arg),
+      Func::LambertW => lambert_w(arg),
+      Func::Agm => agm(arg, args[1]),
+      // `y` comes first
+      Func::Atan2 => Ok(arg.atan2(args[1])),
+      // Floor modulo like `%`, so values below `lo` wrap around from `hi`
+      Func::Wrap => {
+        let (lo, hi) = (args[1], args[2]);
+        if lo == hi {
+          return Err(domain_error(self, lo, "wrapping into an empty range"))
+        }
+        Ok(lo + Op::Mod.evaluate(arg - lo, hi - lo)?)
+      },
+      Func::Lerp => Ok(arg + args[2] * (args[1] - arg)),
+      Func::LerpClamped => Ok(arg + args[2].clamp(0., 1.) * (args[1] - arg)),
+      Func::Smoothstep | Func::Smootherstep => {
+        let (edge0, edge1, x) = (arg, args[1], args[2]);
+        if edge0 == edge1 {
+          return Err(domain_error(self, edge0, "smoothstep edges must differ"))
+        }
+
+        let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+        match self {
+          Func::Smoothstep => Ok(t * t * (3. - 2. * t)),
+          _ => Ok(t * t * t * (t * (t * 6. - 15.) + 10.)),
+        }
+      },
+      Func::RoundTo | Func::FloorTo | Func::CeilTo => {
+        let multiple = args[1];
+        if multiple == 0.0 {
+          return Err(domain_error(self, multiple, "rounding to a multiple of zero"))
+        }
+
+        let quotient = arg / multiple;
+        let quotient = match self {
+          Func::RoundTo => quotient.round(),
+          Func::FloorTo => quotient.floor(),
+          _ => quotient.ceil(),
+        };
+        Ok(quotient * multiple)
+      },
+      Func::SumList => Ok(args.iter().sum()),
+      Func::ProdList => Ok(args.iter().product()),
+      Func::Max => Ok(args.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+      Func::Min => Ok(args.iter().copied().fold(f64::INFINITY, f64::min)),
+      // Any non-zero value is true, as in `if`
+      Func::And => Ok(f64::from(arg != 0. && args[1] != 0.)),
+      Func::Or => Ok(f64::from(arg != 0. || args[1] != 0.)),
+      Func::Not => Ok(f64::from(arg == 0.)),
+      // Need the function itself, which `Node::evaluate` passes on
+      Func::Map | Func::Zip => Err(EvalError::ExpectedArgument { func: self, index: 1, expected: "function" }),
+      Func::Mean | Func::Variance | Func::SampleVariance | Func::Stdev | Func::SampleStdev | Func::Median | Func::Mode
+        | Func::Sort | Func::Reverse | Func::Unique | Func::Len | Func::Get | Func::Slice | Func::Filter => unreachable!("functions taking lists return early"),
+      Func::FormatSci | Func::FormatEng => unreachable!("functions giving text return early"),
+      Func::Seed => {
+        if !arg.is_finite() {
+          return Err(domain_error(self, arg, "seed must be finite"))
+        }
+        random::seed(arg.to_bits());
+        Ok(arg)
+      },
       Func::Abs => Ok(arg.abs()),
       Func::Sqrt => {
         if arg < 0.0 {
-          Err(eyre!("Invalid operation: square root of negative number"))
+          Err(domain_error(self, arg, "square root of negative number"))
         } else {
           Ok(arg.sqrt())
         }
       }
+      Func::LogBase => Func::Log(arg).evaluate(&args[1..]),
       Func::Log(base) => {
+        if base <= 0.0 || base == 1.0 {
+          return Err(domain_error(self, base, "logarithm base must be positive and not equal to 1"))
+        }
+        if arg <= 0.0 {
+          return Err(domain_error(self, arg, "logarithm of non-positive number"))
+        }
+
         // Use log2 or log10 if possible for better accuracy
         if base == 2. {
           Ok(arg.log2())
@@ -43,52 +510,589 @@ impl Func {
       },
       Func::Cos => Ok(arg.cos()),
       Func::Sin => Ok(arg.sin()),
+      Func::SinPi => Ok(sin_pi(arg)),
+      Func::CosPi => Ok(cos_pi(arg)),
       Func::Tg => Ok(arg.tan()),
-      Func::Ctg => Op::Div.evaluate(1.0, arg.tan()),
+      // cos/sin rather than 1/tan, which overflows near pi/2.
+      // `pi` itself is off by rounding, so within rounding of a multiple of pi counts as one
+      Func::Ctg => {
+        let remainder = arg.rem_euclid(PI);
+        if remainder.min(PI - remainder) <= f64::EPSILON * arg.abs().max(1.) {
+          Err(domain_error(self, arg, "cotangent of a multiple of pi"))
+        } else {
+          Ok(arg.cos() / arg.sin())
+        }
+      },
+      #[allow(clippy::manual_range_contains)]
       Func::Asin => {
         if arg < -1.0 || arg > 1.0 {
-          Err(eyre!("Invalid operation: arcsine out of range"))
+          Err(domain_error(self, arg, "arcsine out of range"))
         } else {
           Ok(arg.asin())
         }
       },
+      #[allow(clippy::manual_range_contains)]
       Func::Acos => {
         if arg < -1.0 || arg > 1.0 {
-          Err(eyre!("Invalid operation: arccosine out of range"))
+          Err(domain_error(self, arg, "arccosine out of range"))
         } else {
           Ok(arg.acos())
         }
       }
       Func::Atan => Ok(arg.atan()),
       Func::Exp => Ok(arg.exp()),
-      Func::Root(base) => Ok(arg.powf(Op::Div.evaluate(1.0, base)?)),
+      Func::Root(base) => {
+        let exponent = Op::Div.evaluate(1.0, base)?;
+        if arg >= 0.0 {
+          Ok(arg.powf(exponent))
+        } else if base.fract() == 0.0 && base % 2.0 == 1.0 {
+          // Odd roots of negative numbers are real
+          Ok(-(-arg).powf(exponent))
+        } else {
+          Err(domain_error(self, arg, "even or fractional root of negative number"))
+        }
+      },
     }
   }
 }
 
-impl Node {
-  pub fn evaluate(self) -> Result<f64> {
+/// Arithmetic mean
+fn mean(values: &[f64]) -> f64 {
+  values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Variance by the two-pass algorithm, which first finds the mean and then sums squared deviations from it,
+/// avoiding the cancellation of subtracting the squared mean from the mean of squares.
+/// The sample variance of `func` divides by `n - 1` rather than `n`
+fn variance(values: &[f64], func: Func) -> Result<f64> {
+  let sample = matches!(func, Func::SampleVariance | Func::SampleStdev);
+  let count = values.len() - usize::from(sample);
+  if count == 0 {
+    return Err(EvalError::TooFewElements(func))
+  }
+
+  let mean = mean(values);
+  Ok(values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / count as f64)
+}
+
+/// Copy of the list in ascending order
+fn sorted(values: &[f64]) -> Vec<f64> {
+  let mut sorted = values.to_vec();
+  sorted.sort_by(f64::total_cmp);
+  sorted
+}
+
+/// Middle element of the sorted list, or the mean of the two middle ones for an even length
+fn median(values: &[f64]) -> f64 {
+  let sorted = sorted(values);
+  let middle = sorted.len() / 2;
+  if sorted.len().is_multiple_of(2) {
+    (sorted[middle - 1] + sorted[middle]) / 2.
+  } else {
+    sorted[middle]
+  }
+}
+
+/// Most frequent element rounded to an integer, the smallest one among equally frequent elements.
+/// Elements are compared as integers, so `0.9` and `1.2` both count as `1`
+fn mode(values: &[f64]) -> f64 {
+  // Adding zero turns the `-0` that rounding small negative values gives into `0`
+  let rounded = values.iter().map(|value| value.round() + 0.).collect::<Vec<_>>();
+  let sorted = sorted(&rounded);
+  // Equal elements are adjacent once sorted, and the first longest run is the smallest
+  let (mut mode, mut count) = (sorted[0], 0);
+  for run in sorted.chunk_by(|a, b| a == b) {
+    if run.len() > count {
+      (mode, count) = (run[0], run.len());
+    }
+  }
+  mode
+}
+
+/// Elements without the ones equal to an earlier element
+fn unique(values: &[f64]) -> Vec<f64> {
+  let mut seen = HashSet::new();
+  // Adding zero turns `-0` into `0`, which are equal but differ in bits
+  values.iter().copied().filter(|value| seen.insert((value + 0.).to_bits())).collect()
+}
+
+/// Position in a list of `length` elements, which may be `length` itself only if `inclusive`
+fn index(value: &Value, length: usize, inclusive: bool) -> Result<usize> {
+  let value = value.clone().into_number()?;
+  if value.fract() != 0. {
+    return Err(EvalError::FractionalIndex(value))
+  }
+  if value < 0. || value > length as f64 || (value == length as f64 && !inclusive) {
+    return Err(EvalError::IndexOutOfBounds { index: value, length })
+  }
+  Ok(value as usize)
+}
+
+impl Func {
+  /// Evaluates a function taking lists, as marked by `Func::list_argument`
+  pub fn evaluate_list(self, args: &[Value]) -> Result<Value> {
+    if !self.accepts(args.len()) {
+      return Err(EvalError::WrongArgumentCount { func: self, count: args.len() })
+    }
+    let Some(Value::List(list)) = args.first() else {
+      return Err(EvalError::ExpectedList)
+    };
+
     match self {
-      Node::Immediate(value) => Ok(value),
-      Node::BinOp(op, left, right) => op.evaluate(left.evaluate()?, right.evaluate()?),
-      Node::Func(func, node) => func.evaluate(node.evaluate()?),
+      Func::Mean | Func::Median | Func::Mode if list.is_empty() => Err(EvalError::EmptyList(self)),
+      // NaN has no place in the order
+      Func::Median | Func::Mode if list.iter().any(|value| value.is_nan()) => Ok(f64::NAN.into()),
+      Func::Median => Ok(median(list).into()),
+      Func::Mode => Ok(mode(list).into()),
+      // NaN goes last
+      Func::Sort => Ok(Value::List(sorted(list))),
+      Func::Reverse => Ok(Value::List(list.iter().copied().rev().collect())),
+      Func::Unique => Ok(Value::List(unique(list))),
+      Func::Len => Ok((list.len() as f64).into()),
+      Func::Get => Ok(list[index(&args[1], list.len(), false)?].into()),
+      Func::Slice => {
+        let (from, to) = (index(&args[1], list.len(), true)?, index(&args[2], list.len(), true)?);
+        if from > to {
+          return Err(EvalError::BackwardsSlice { from, to })
+        }
+        Ok(Value::List(list[from..to].to_vec()))
+      },
+      Func::Mean => Ok(mean(list).into()),
+      Func::Variance | Func::SampleVariance => variance(list, self).map(Value::Number),
+      Func::Stdev | Func::SampleStdev => variance(list, self).map(|variance| variance.sqrt().into()),
+      // Needs the condition itself, which `Node::evaluate` passes on
+      Func::Filter => Err(EvalError::ExpectedArgument { func: self, index: 1, expected: "condition" }),
+      func => Err(EvalError::TakesNoLists(func)),
+    }
+  }
+}
+
+/// Engineering notation: like scientific, but with the exponent divisible by 3
+/// and so from 1 to 3 digits before the decimal point, with 3 after it
+fn format_eng(value: f64) -> String {
+  if value == 0. || !value.is_finite() {
+    return format!("{:.3e}", value)
+  }
+
+  let scientific = format!("{:e}", value);
+  let (_, exponent) = scientific.split_once('e').expect("scientific notation has an exponent");
+  let mut exponent = exponent.parse::<i32>().expect("the exponent is an integer").div_euclid(3) * 3;
+  // Split in two, since a power of ten for subnormal values is out of range
+  let scale = |exponent: i32| value / 10_f64.powi(exponent / 2) / 10_f64.powi(exponent - exponent / 2);
+  let mut mantissa = format!("{:.3}", scale(exponent));
+  // Rounding may carry into a fourth digit, as for 999999.9
+  if mantissa.trim_start_matches('-').starts_with("1000") {
+    exponent += 3;
+    mantissa = format!("{:.3}", scale(exponent));
+  }
+  format!("{}e{}", mantissa, exponent)
+}
+
+impl Func {
+  /// Evaluates a function giving text, as marked by `Func::returns_text`
+  pub fn format(self, args: &[f64]) -> Result<String> {
+    if !self.accepts(args.len()) {
+      return Err(EvalError::WrongArgumentCount { func: self, count: args.len() })
+    }
+
+    match self {
+      Func::FormatSci => {
+        let digits = args[1];
+        if !(0. ..=17.).contains(&digits) || digits.fract() != 0. {
+          return Err(domain_error(self, digits, "digits must be an integer from 0 to 17"))
+        }
+        Ok(format!("{:.*e}", digits as usize, args[0]))
+      },
+      Func::FormatEng => Ok(format_eng(args[0])),
+      func => func.evaluate(args).map(|value| value.to_string()),
+    }
+  }
+}
+
+/// Call of a function passed by name, like `sin` in `map(sin, x)`, with already evaluated arguments
+fn apply(function: &Node, args: &[f64]) -> Result<Node> {
+  let args: Vec<Node> = args.iter().copied().map(Node::Immediate).collect();
+  match (function, <[Node; 2]>::try_from(args)) {
+    (Node::FuncRef(func), Ok(args)) => Ok(Node::Func(*func, args.into())),
+    (Node::FuncRef(func), Err(args)) => Ok(Node::Func(*func, args)),
+    (Node::OpRef(op), Ok([left, right])) => Ok(Node::BinOp(*op, Box::new(left), Box::new(right))),
+    (Node::OpRef(op), Err(args)) => Err(EvalError::OperatorArgumentCount { op: *op, count: args.len() }),
+    _ => Err(EvalError::NotAFunction(function.to_string())),
+  }
+}
+
+/// Adds a step if they're being recorded, building its node only then
+fn record(steps: &mut Option<&mut Vec<Step>>, node: impl FnOnce() -> Node, result: f64) -> Result<Value> {
+  if let Some(steps) = steps {
+    steps.push(Step { node: node(), result });
+  }
+  Ok(Value::Number(result))
+}
+
+impl Node {
+  /// Evaluates the expression, looking up variables in `options.variables`
+  pub fn evaluate(&self, options: EvalOptions) -> Result<f64> {
+    self.evaluate_value(options)?.into_number()
+  }
+
+  /// Same as `evaluate`, also allowing the result to be a list
+  pub fn evaluate_value(&self, options: EvalOptions) -> Result<Value> {
+    self.evaluate_located(options).map_err(|error| error.error)
+  }
+
+  /// Same as `evaluate_value`, naming the sub-expression whose operation failed
+  pub fn evaluate_located(&self, options: EvalOptions) -> Located<Value> {
+    self.evaluate_recording(options, &mut None)
+  }
+
+  /// Same as `evaluate`, also recording each operation in the order it was applied.
+  /// Steps up to a failed operation are kept, and the error names the sub-expression which failed
+  pub fn evaluate_steps(&self, options: EvalOptions, steps: &mut Vec<Step>) -> Located<f64> {
+    self.evaluate_number(options, &mut Some(steps))
+  }
+
+  fn evaluate_number(&self, options: EvalOptions, steps: &mut Option<&mut Vec<Step>>) -> Located<f64> {
+    let value = self.evaluate_recording(options, steps)?;
+    value.into_number().map_err(|error| locate(error.into(), self))
+  }
+
+  /// Evaluates operands before the operations applied to them using explicit stacks,
+  /// so that the depth of the tree isn't limited by the call stack
+  fn evaluate_recording(&self, options: EvalOptions, steps: &mut Option<&mut Vec<Step>>) -> Located<Value> {
+    let empty = HashMap::new();
+    let ctx = options.variables.unwrap_or(&empty);
+    let mut tasks = vec![Task::Visit(self, Expected::Any)];
+    let mut values: Vec<Value> = vec![];
+    // Names bound by the `let`s whose body is being evaluated, innermost last
+    let mut scope: Vec<(&str, f64)> = vec![];
+    let mut operations = 0_usize;
+
+    while let Some(task) = tasks.pop() {
+      // Checked on the first operation too, since `map` evaluates a small tree per element
+      if operations.is_multiple_of(DEADLINE_INTERVAL) {
+        options.check_deadline()?;
+      }
+      operations += 1;
+
+      match task {
+        Task::Visit(node, expected) => {
+          // Operands are pushed in reverse so that the leftmost one is evaluated first
+          let (operands, expected_operand) = match node {
+            Node::Immediate(value) => {
+              values.push(Value::Number(*value));
+              continue
+            },
+            Node::Variable(name) => match scope.iter().rev().find(|(bound, _)| bound == name).map(|(_, value)| value).or_else(|| ctx.get(name)).copied().or_else(|| builtin(name)) {
+              Some(value) => {
+                values.push(Value::Number(value));
+                continue
+              },
+              None => return Err(EvalError::UndefinedVariable(name.clone()).into()),
+            },
+            Node::Neg(operand) => (std::slice::from_ref(&**operand), Expected::Number),
+            Node::BinOp(_, left, right) => {
+              tasks.push(Task::Apply(node, expected));
+              tasks.push(Task::Visit(right, Expected::Number));
+              tasks.push(Task::Visit(left, Expected::Number));
+              continue
+            },
+            // Only the condition is evaluated here, the branch once it's known
+            Node::If { cond, .. } => {
+              tasks.push(Task::Apply(node, expected));
+              tasks.push(Task::Visit(cond, Expected::Number));
+              continue
+            },
+            Node::Let { value, .. } => (std::slice::from_ref(&**value), Expected::Number),
+            // Conditions are evaluated one at a time, until one holds
+            Node::Piecewise(cases, _) => match cases.first() {
+              Some((cond, _)) => {
+                tasks.push(Task::Case(node, 0, expected));
+                tasks.push(Task::Visit(cond, Expected::Number));
+                continue
+              },
+              None => return Err(EvalError::NoMatchingCase.into()),
+            },
+            Node::Func(func @ (Func::Map | Func::Zip), args) => match args.split_first() {
+              Some((_, args)) => (args, Expected::Any),
+              None => return Err(EvalError::WrongArgumentCount { func: *func, count: 0 }.into()),
+            },
+            // Only the list is evaluated here, the condition once per element
+            Node::Func(Func::Filter, args) => match args.as_slice() {
+              [_, Node::Variable(_), list] => {
+                tasks.push(Task::Apply(node, expected));
+                tasks.push(Task::Visit(list, Expected::List));
+                continue
+              },
+              [_, _, _] => return Err(EvalError::ExpectedArgument { func: Func::Filter, index: 2, expected: "variable" }.into()),
+              args => return Err(EvalError::WrongArgumentCount { func: Func::Filter, count: args.len() }.into()),
+            },
+            Node::Func(func, args) if func.takes_lists() => {
+              tasks.push(Task::Apply(node, expected));
+              tasks.extend(args.iter().enumerate().rev().map(|(index, arg)| {
+                Task::Visit(arg, if func.list_argument(index) { Expected::List } else { Expected::Number })
+              }));
+              continue
+            },
+            Node::Func(_, args) => (args.as_slice(), Expected::Number),
+            Node::List(items) => (items.as_slice(), Expected::Element),
+            Node::Call(name, _) => return Err(EvalError::UndefinedFunction(name.clone()).into()),
+            Node::FuncRef(func) => return Err(EvalError::FunctionAsValue(*func).into()),
+            Node::OpRef(op) => return Err(EvalError::OperatorAsValue(*op).into()),
+          };
+
+          tasks.push(Task::Apply(node, expected));
+          tasks.extend(operands.iter().rev().map(|operand| Task::Visit(operand, expected_operand)));
+        },
+        Task::Apply(node, expected) => {
+          if let Some(value) = apply_node(node, expected, &mut values, &mut tasks, &mut scope, options, steps).map_err(|error| locate(error, node))? {
+            values.push(expected.check(value).map_err(|error| locate(error.into(), node))?);
+          }
+        },
+        Task::Case(node, index, expected) => {
+          let Node::Piecewise(cases, default) = node else {
+            unreachable!("only piecewise has cases")
+          };
+          let cond = pop_numbers(&mut values, 1).map_err(|error| locate(error.into(), node))?[0];
+          if cond != 0. {
+            tasks.push(Task::Visit(&cases[index].1, expected));
+          } else if let Some((cond, _)) = cases.get(index + 1) {
+            tasks.push(Task::Case(node, index + 1, expected));
+            tasks.push(Task::Visit(cond, Expected::Number));
+          } else if let Some(default) = default {
+            tasks.push(Task::Visit(default, expected));
+          } else {
+            return Err(locate(EvalError::NoMatchingCase.into(), node))
+          }
+        },
+        Task::Unbind => {
+          scope.pop();
+        },
+      }
+    }
+
+    Ok(values.pop().expect("evaluation leaves a single value"))
+  }
+}
+
+/// What the parent of a node requires its value to be
+#[derive(Clone, Copy)]
+enum Expected {
+  Any,
+  Number,
+  List,
+  /// Element of a list, which can't be a list itself
+  Element,
+}
+
+impl Expected {
+  fn check(self, value: Value) -> Result<Value> {
+    match (self, value) {
+      (Expected::Number, Value::List(_)) => Err(EvalError::ExpectedNumber),
+      (Expected::Number | Expected::List | Expected::Element, Value::Text(_)) => Err(EvalError::UnexpectedText),
+      (Expected::List, Value::Number(_)) => Err(EvalError::ExpectedList),
+      (Expected::Element, Value::List(_)) => Err(EvalError::NestedList),
+      (_, value) => Ok(value),
+    }
+  }
+}
+
+/// Pending work of `Node::evaluate_recording`
+enum Task<'a> {
+  /// Evaluates the node, pushing its value
+  Visit(&'a Node, Expected),
+  /// Replaces the values of the node's operands with the value of the node,
+  /// or for `if`, the value of the condition with a visit of the branch taken
+  Apply(&'a Node, Expected),
+  /// Checks the value of the condition of the case at the index of a `piecewise`,
+  /// visiting its value if it holds and otherwise the next condition or the default
+  Case(&'a Node, usize, Expected),
+  /// Leaves the body of a `let`, unbinding its name
+  Unbind,
+}
+
+/// Replaces the values of the node's operands with the value of the node, or for `if`,
+/// the value of the condition with a visit of the branch taken, and for `let`, the bound value with a visit of the body, giving `None`
+#[allow(clippy::too_many_arguments)]
+fn apply_node<'a>(
+  node: &'a Node,
+  expected: Expected,
+  values: &mut Vec<Value>,
+  tasks: &mut Vec<Task<'a>>,
+  scope: &mut Vec<(&'a str, f64)>,
+  options: EvalOptions,
+  steps: &mut Option<&mut Vec<Step>>,
+) -> Located<Option<Value>> {
+  let value = match node {
+    Node::Neg(operand) => {
+      let value = pop_numbers(values, 1)?[0];
+      // Negative literals aren't worth a step
+      match **operand {
+        Node::Immediate(_) => Value::Number(-value),
+        _ => record(steps, || Node::Neg(Box::new(Node::Immediate(value))), -value)?,
+      }
+    },
+    Node::BinOp(op, _, _) => {
+      let operands = pop_numbers(values, 2)?;
+      let (left, right) = (operands[0], operands[1]);
+      let result = match op {
+        // IEEE 754 division, e.g. `1 / 0 = inf` and `0 / 0 = NaN`
+        Op::Div if !options.strict_division => left / right,
+        op => op.evaluate(left, right)?,
+      };
+      record(steps, || Node::BinOp(*op, Box::new(Node::Immediate(left)), Box::new(Node::Immediate(right))), result)?
+    },
+    Node::Func(func @ (Func::Map | Func::Zip), args) => {
+      let operands = values.split_off(values.len() - (args.len() - 1));
+      apply_pointwise(*func, &args[0], operands, EvalOptions { variables: Some(&scoped(options, scope)), ..options }, steps)?
+    },
+    Node::Func(Func::Filter, args) => {
+      let Node::Variable(var) = &args[1] else {
+        unreachable!("the variable was checked when visiting")
+      };
+      // Literals and variables are pushed without checking what's expected of them
+      match values.pop() {
+        Some(Value::List(list)) => filter(&args[0], var, list, EvalOptions { variables: Some(&scoped(options, scope)), ..options }, steps)?,
+        _ => return Err(EvalError::ExpectedList.into()),
+      }
+    },
+    Node::Func(func, args) if func.takes_lists() => {
+      let args = values.split_off(values.len() - args.len());
+      match func.evaluate_list(&args)? {
+        Value::Number(result) => {
+          let literal = |arg| Node::try_from(arg).expect("arguments were checked to be numbers or lists");
+          record(steps, || Node::Func(*func, args.iter().map(literal).collect()), result)?
+        },
+        list => list,
+      }
+    },
+    Node::Func(func, args) if func.returns_text() => Value::Text(func.format(&pop_numbers(values, args.len())?)?),
+    Node::Func(func, args) => {
+      let args = pop_numbers(values, args.len())?;
+      let result = func.evaluate(&args)?;
+      record(steps, || Node::Func(*func, args.iter().copied().map(Node::Immediate).collect()), result)?
+    },
+    Node::List(items) => Value::List(pop_numbers(values, items.len())?),
+    // The body sees the bound value over any variable of the same name, until it's evaluated
+    Node::Let { name, body, .. } => {
+      let value = pop_numbers(values, 1)?[0];
+      scope.push((name, value));
+      tasks.extend([Task::Unbind, Task::Visit(body, expected)]);
+      return Ok(None)
+    },
+    // The branch's value is checked when it's visited
+    Node::If { then, else_, .. } => {
+      let cond = pop_numbers(values, 1)?[0];
+      tasks.push(Task::Visit(if cond != 0. { then } else { else_ }, expected));
+      return Ok(None)
+    },
+    _ => unreachable!("only nodes with operands are applied"),
+  };
+  Ok(Some(value))
+}
+
+/// Variables along with the names bound by the enclosing `let`s, for evaluating a tree of its own
+fn scoped<'c>(options: EvalOptions<'c>, scope: &[(&str, f64)]) -> Cow<'c, HashMap<String, f64>> {
+  let mut ctx = options.variables.map_or_else(|| Cow::Owned(HashMap::new()), Cow::Borrowed);
+  for (name, value) in scope {
+    ctx.to_mut().insert(name.to_string(), *value);
+  }
+  ctx
+}
+
+/// Names `node` as where the error occurred, unless a smaller tree evaluated for it already is.
+/// Undefined names and timeouts aren't tied to an operation
+fn locate(mut error: ExpressionError, node: &Node) -> ExpressionError {
+  let tied = !matches!(error.error, EvalError::UndefinedVariable(_) | EvalError::UndefinedFunction(_) | EvalError::TimedOut);
+  if error.expression.is_none() && tied {
+    error.expression = Some(node.to_string());
+  }
+  error
+}
+
+/// Removes the values of the last `count` operands, which were checked to be numbers
+fn pop_numbers(values: &mut Vec<Value>, count: usize) -> Result<Vec<f64>> {
+  values.split_off(values.len() - count).into_iter().map(Value::into_number).collect()
+}
+
+/// Applies a function passed to `map` or `zip` to each element of the lists, in order.
+/// Numbers are treated like lists of one element, and give a number if there are no lists
+fn apply_pointwise(
+  func: Func,
+  function: &Node,
+  args: Vec<Value>,
+  options: EvalOptions,
+  steps: &mut Option<&mut Vec<Step>>,
+) -> Located<Value> {
+  if let Some(args) = args.iter().map(|arg| arg.clone().into_number().ok()).collect::<Option<Vec<_>>>() {
+    return apply(function, &args)?.evaluate_recording(options, steps)
+  }
+
+  let lists = args.into_iter().map(|arg| match arg {
+    Value::Number(value) => Ok(vec![value]),
+    Value::List(values) => Ok(values),
+    Value::Text(_) => Err(EvalError::UnexpectedText),
+  }).collect::<Result<Vec<_>>>()?;
+  let length = lists[0].len();
+  if let Some(list) = lists.iter().find(|list| list.len() != length) {
+    return Err(EvalError::LengthMismatch { func, first: length, other: list.len() }.into())
+  }
+
+  let values = (0..length)
+    .map(|index| {
+      let args: Vec<f64> = lists.iter().map(|list| list[index]).collect();
+      apply(function, &args)?.evaluate_number(options, steps)
+    })
+    .collect::<Located<_>>()?;
+  Ok(Value::List(values))
+}
+
+/// Elements of the list for which the condition is non-zero, with `var` bound to the element
+fn filter(
+  condition: &Node,
+  var: &str,
+  list: Vec<f64>,
+  options: EvalOptions,
+  steps: &mut Option<&mut Vec<Step>>,
+) -> Located<Value> {
+  let mut ctx = options.variables.cloned().unwrap_or_default();
+  let mut values = vec![];
+  for value in list {
+    ctx.insert(var.to_string(), value);
+    if condition.evaluate_number(EvalOptions { variables: Some(&ctx), ..options }, steps)? != 0. {
+      values.push(value);
     }
   }
+  Ok(Value::List(values))
 }
 
 #[cfg(test)]
 #[allow(dead_code)]
 mod tests {
-  use crate::{lexer::tokenize, parser::parse_expression};
+  use std::{collections::HashMap, f64::consts::PI, time::{Duration, Instant}};
+  use crate::{calculate, error::{EvalError, ExpressionError}, lexer::{tokenize, Func, Op, Token}, parser::{parse_expression, parse_expression_partial, Node}};
+  use super::{EvalOptions, Value};
 
   fn test(input: &str, expected: f64) {
     let mut lexer = tokenize(input).unwrap();
     let ast = parse_expression(&mut lexer).unwrap();
-    let result = ast.evaluate().unwrap();
+    let result = ast.evaluate(EvalOptions::default()).unwrap();
 
     assert_eq!(result, expected)
   }
 
+  fn test_approx(input: &str, expected: f64) {
+    let mut lexer = tokenize(input).unwrap();
+    let result = parse_expression(&mut lexer).unwrap().evaluate(EvalOptions::default()).unwrap();
+
+    assert!((result - expected).abs() < 1e-12, "{} = {}, expected {}", input, result, expected)
+  }
+
+  fn test_fail(input: &str) {
+    let result = parse_expression(&mut tokenize(input).unwrap()).map(|ast| ast.evaluate(EvalOptions::default()));
+
+    assert!(!matches!(result, Ok(Ok(_))))
+  }
+
   #[test]
   fn test_operators() {
     test("1 + 2 - 4", -1.);
@@ -96,6 +1100,79 @@ mod tests {
     test("2 * 3 ^ 2", 18.);
   }
 
+  #[test]
+  fn test_errors() {
+    assert_eq!(test_value_fail("1 / (2 - 2)"), EvalError::DivisionByZero);
+    assert_eq!(test_value_fail("asin(2) + 1"), EvalError::Domain { operation: "asin".to_string(), value: 2., reason: "arcsine out of range" });
+    assert_eq!(test_value_fail("(-8) ^ 0.5"), EvalError::Domain { operation: "^".to_string(), value: -8., reason: "fractional power of negative number" });
+    assert_eq!(test_value_fail("x + 1"), EvalError::UndefinedVariable("x".to_string()));
+    assert_eq!(test_value_fail("f(1)"), EvalError::UndefinedFunction("f".to_string()));
+    assert_eq!(Func::Atan2.evaluate(&[1.]).unwrap_err(), EvalError::WrongArgumentCount { func: Func::Atan2, count: 1 });
+    assert_eq!(test_value_fail("sqrt(-4)").to_string(), "Invalid operation: square root of negative number");
+  }
+
+  #[test]
+  fn test_error_location() {
+    let located = |input: &str, x: f64| {
+      let node = parse_expression(&mut tokenize(input).unwrap()).unwrap();
+      node.evaluate_located(EvalOptions { variables: Some(&HashMap::from([("x".to_string(), x)])), ..Default::default() }).unwrap_err()
+    };
+
+    let input = "1 / x + 2 / (x - 1) + 3 / (x - 3) + 4 / (x - 4) + 5 / (x - 5)";
+    assert_eq!(located(input, 3.).expression.as_deref(), Some("3 / (x - 3)"));
+    assert_eq!(located(input, 5.).to_string(), "Invalid operation: division by zero while evaluating `5 / (x - 5)`");
+    assert_eq!(located(input, 0.).error, EvalError::DivisionByZero);
+    assert_eq!(located("sqrt(x) + log2(x - 2)", 1.).expression.as_deref(), Some("log2(x - 2)"));
+    assert_eq!(located("[1, 2] + x", 0.).expression.as_deref(), Some("[1, 2]"));
+    assert_eq!(located("zip(+, [1], [1, x])", 0.).expression.as_deref(), Some("zip(+, [1], [1, x])"));
+    // Functions passed by name are named with the element they failed on
+    assert_eq!(located("map(sqrt, [1, x])", -4.).expression.as_deref(), Some("sqrt(-4)"));
+    // Undefined names aren't tied to an operation
+    assert_eq!(located("2 * (y + 1)", 0.), ExpressionError::from(EvalError::UndefinedVariable("y".to_string())));
+  }
+
+  #[test]
+  fn test_comparisons() {
+    test("3 > 2", 1.);
+    test("2 > 2", 0.);
+    test("2 >= 2", 1.);
+    test("1 < 2", 1.);
+    test("3 <= 2", 0.);
+    test("0.1 + 0.2 == 0.3", 0.);
+    test("2 != 3", 1.);
+    test("1 + 2 > 2", 1.);
+    test("(1 < 2) + (3 < 2)", 1.);
+    // Chains compare the result of the previous comparison
+    test("3 > 2 > 1", 0.);
+    test("1 < 2 == 1", 1.);
+    assert_eq!(Op::Eq.evaluate(f64::NAN, f64::NAN).unwrap(), 0.);
+    assert_eq!(Op::Ne.evaluate(f64::NAN, f64::NAN).unwrap(), 1.);
+    assert_eq!(Op::Ge.evaluate(f64::NAN, 1.).unwrap(), 0.);
+  }
+
+  #[test]
+  fn test_integer_powers() {
+    test("2 ^ 53", 9007199254740992.);
+    test("2 ^ -3", 0.125);
+    test("(-2) ^ 63", -9223372036854775808.);
+    test("3 ^ 0", 1.);
+    test("2 ^ 0.5", 2_f64.sqrt());
+    test("2 ^ 64", 18446744073709551616.);
+
+    for n in -63..=63 {
+      assert_eq!(Op::Pow.evaluate(2., n as f64).unwrap(), 2_f64.powi(n));
+    }
+  }
+
+  #[test]
+  fn test_power_domain() {
+    test("0 ^ 0", 1.);
+    test("0 ^ 0.5", 0.);
+    test("(-8) ^ 3", -512.);
+    test_fail("(-1) ^ 0.5");
+    test_fail("(-8) ^ (1 / 3)");
+  }
+
   #[test]
   fn test_parenthesis() {
     test("(4 * 5) / 2", 10.);
@@ -103,10 +1180,667 @@ mod tests {
     test("(4 * (3 + 2)) / 5", 4.);
   }
 
+  #[test]
+  fn test_percent() {
+    test("50%", 0.5);
+    test("200 * 15%", 30.);
+    test("(10 + 40)% + 1", 1.5);
+    test("50%%", 0.005);
+    test("2 ^ 300%", 8.);
+  }
+
+  #[test]
+  fn test_modulo() {
+    test("10 % 3", 1.);
+    test("10 % 3 + 1", 2.);
+    test("2 * 10 % 3", 2.);
+    test("10 % 3 * 2", 2.);
+    test("2 + 10 % 4", 4.);
+    test("5.5 % 2", 1.5);
+    test("-7 % 3", 2.);
+    test("7 % (-3)", -2.);
+    test("7 % -3", -2.);
+    test("-7 % -3", -1.);
+    test("7 % +3", 1.);
+    // Subtraction from a percentage when the sign isn't written against the divisor
+    test("7% - 3", 0.07 - 3.);
+    test("7%-3", 0.07 - 3.);
+    test("50% % 3", 0.5);
+    test("200% % (3)", 2.);
+    test_fail("1 % 0");
+  }
+
   #[test]
   fn test_functions() {
     test("sqrt(abs(-2))", 2_f64.sqrt());
     test("cos(pi)", -1.);
     test("sin(log2(10))", 10_f64.log2().sin());
   }
+
+  #[test]
+  fn test_atan2() {
+    test("atan2(1, 1)", PI / 4.);
+    test("atan2(0, -1)", PI);
+    test("atan2(-1, 0)", -PI / 2.);
+    test("atan2(-1, -1)", -3. * PI / 4.);
+    test("atan2(0, 0)", 0.);
+    test_fail("atan2(1)");
+  }
+
+  #[test]
+  fn test_cotangent() {
+    test_approx("ctg(pi / 4)", 1.);
+    test_approx("ctg(pi / 2)", 0.);
+    test_approx("ctg(-pi / 4)", -1.);
+    test_fail("ctg(0)");
+    test_fail("ctg(pi)");
+    test_fail("ctg(-pi)");
+    test_fail("ctg(1000 * pi)");
+    // Close to a multiple of pi is still a number
+    let ctg = Func::Ctg.evaluate(&[PI - 0.001]).unwrap();
+    assert!((ctg * 0.001_f64.tan() + 1.).abs() < 1e-9, "{}", ctg);
+  }
+
+  #[test]
+  fn test_negative_roots() {
+    test("root3(-8)", -2.);
+    test("root5(-32)", -2.);
+    test("root3(8)", 2.);
+    test_fail("root2(-4)");
+    test_fail("root2.5(-4)");
+  }
+
+  #[test]
+  fn test_log_base_argument() {
+    test("log(2, 8)", 3.);
+    test("log2(8)", 3.);
+    test("log(10, 1000) + 1", 4.);
+    test("log(1 + 1, 2 ^ 5)", 5.);
+    test_fail("log(8)");
+    test_fail("log(2, 8, 1)");
+  }
+
+  #[test]
+  fn test_log_domain() {
+    test_fail("log2(0)");
+    test_fail("log2(-1)");
+    test_fail("log1(5)");
+    test_fail("log0(5)");
+    test_fail("log(-2, 4)");
+    test("log2(0.25)", -2.);
+  }
+
+  #[test]
+  fn test_nan_propagation() {
+    let funcs = [
+      Func::Abs,
+      Func::Sqrt,
+      Func::Log(2.),
+      Func::LogBase,
+      Func::Sin,
+      Func::Cos,
+      Func::SinPi,
+      Func::CosPi,
+      Func::Tg,
+      Func::Ctg,
+      Func::Asin,
+      Func::Acos,
+      Func::Atan,
+      Func::Atan2,
+      Func::Exp,
+      Func::Root(3.),
+      Func::Beta,
+      Func::Lbeta,
+      Func::Zeta,
+      Func::LambertW,
+      Func::Agm,
+    ];
+
+    for func in funcs {
+      let args = vec![f64::NAN; func.arity()];
+      assert!(func.evaluate(&args).unwrap().is_nan(), "{}", func);
+    }
+
+    assert!(Func::LogBase.evaluate(&[f64::NAN, 8.]).unwrap().is_nan());
+    assert!(Func::LogBase.evaluate(&[2., f64::NAN]).unwrap().is_nan());
+  }
+
+  #[test]
+  fn test_trig_pi() {
+    test("sinpi(1)", 0.);
+    test("sinpi(0.5)", 1.);
+    test("sinpi(-0.5)", -1.);
+    test("sinpi(1000000)", 0.);
+    test("cospi(1)", -1.);
+    test("cospi(0.5)", 0.);
+    test("cospi(-2)", 1.);
+    test("sinpi(0.25)", (PI / 4.).sin());
+  }
+
+  #[test]
+  fn test_variables() {
+    let ctx = HashMap::from([("x".to_string(), 3.), ("y".to_string(), -2.)]);
+    let eval = |input: &str| {
+      let mut lexer = tokenize(input).unwrap();
+      parse_expression(&mut lexer).unwrap().evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() })
+    };
+
+    assert_eq!(eval("x * 2 + y").unwrap(), 4.);
+    assert_eq!(eval("-x ^ 2").unwrap(), 9.);
+    assert_eq!(eval("abs(y)").unwrap(), 2.);
+    assert!(eval("x + z").is_err());
+  }
+
+  #[test]
+  fn test_context_reuse() {
+    let mut lexer = tokenize("x ^ 2").unwrap();
+    let ast = parse_expression(&mut lexer).unwrap();
+
+    for x in [0., 1.5, -3.] {
+      let ctx = HashMap::from([("x".to_string(), x)]);
+      assert_eq!(ast.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap(), x * x);
+    }
+  }
+
+  #[test]
+  fn test_deadline() {
+    // 1 + 1 + ... with a million operands
+    let mut node = Node::Immediate(1.);
+    for _ in 0..1_000_000 {
+      node = Node::BinOp(Op::Add, Box::new(node), Box::new(Node::Immediate(1.)));
+    }
+    let evaluate = |timeout| {
+      let options = EvalOptions { deadline: Some(Instant::now() + timeout), ..Default::default() };
+      node.evaluate(options)
+    };
+
+    assert_eq!(evaluate(Duration::from_secs(600)).unwrap(), 1_000_001.);
+    let start = Instant::now();
+    assert_eq!(evaluate(Duration::from_millis(1)).unwrap_err(), EvalError::TimedOut);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(evaluate(Duration::ZERO).unwrap_err(), EvalError::TimedOut);
+
+    let elements = vec![Node::Immediate(4.); 100_000];
+    let node = Node::Func(Func::Map, vec![Node::FuncRef(Func::Sqrt), Node::List(elements)]);
+    let options = EvalOptions { deadline: Some(Instant::now()), ..Default::default() };
+    assert_eq!(node.evaluate_value(options).unwrap_err(), EvalError::TimedOut);
+  }
+
+  #[test]
+  fn test_lenient_division() {
+    let lenient = EvalOptions { strict_division: false, ..Default::default() };
+    let evaluate = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap().evaluate(lenient).unwrap();
+
+    assert_eq!(evaluate("1 / 0"), f64::INFINITY);
+    assert_eq!(evaluate("-1 / 0"), f64::NEG_INFINITY);
+    assert!(evaluate("0 / 0").is_nan());
+    assert_eq!(evaluate("6 / 3"), 2.);
+    test_fail("1 / 0");
+  }
+
+  #[test]
+  fn test_random() {
+    let sequence = || {
+      test("seed(42)", 42.);
+      (0..3).map(|_| calculate("rand()").unwrap()).collect::<Vec<_>>()
+    };
+    let first = sequence();
+    assert_eq!(first, sequence());
+    assert!(first[0] != first[1] && first[1] != first[2]);
+
+    for _ in 0..100 {
+      assert!((0.0..1.0).contains(&calculate("rand()").unwrap()));
+      assert!((5.0..7.0).contains(&calculate("rnd(5, 7)").unwrap()));
+    }
+    test_fail("rand(1)");
+    test_fail("seed(1 / 0)");
+  }
+
+  #[test]
+  fn test_sum_list() {
+    test("sum_list(1, 2, 3, 4, 5)", 15.);
+    test("sum_list(2 * 3)", 6.);
+    test("sum_list()", 0.);
+    test("prod_list(1, 2, 3, 4, 5)", 120.);
+    test("prod_list(-1, 0.5) + prod_list()", 0.5);
+    assert!(Func::SumList.evaluate(&[1., f64::NAN]).unwrap().is_nan());
+
+    let error = parse_expression(&mut tokenize("atan2(1, 2, 3)").unwrap()).unwrap_err();
+    assert_eq!(error.to_string(), "atan2 expects 2 argument(s), got 3");
+  }
+
+  #[test]
+  fn test_map() {
+    test("map(sqrt, 16)", 4.);
+    test("map(log2, 4 * 2) + 1", 4.);
+    test_fail("map(asin, 2)");
+    assert!(Node::FuncRef(Func::Sin).evaluate(EvalOptions::default()).is_err());
+  }
+
+  fn test_value(input: &str, expected: Value) {
+    let node = parse_expression(&mut tokenize(input).unwrap()).unwrap();
+    assert_eq!(node.evaluate_value(EvalOptions::default()).unwrap(), expected, "{}", input);
+  }
+
+  fn test_value_fail(input: &str) -> EvalError {
+    let node = parse_expression(&mut tokenize(input).unwrap()).unwrap();
+    node.evaluate_value(EvalOptions::default()).unwrap_err()
+  }
+
+  #[test]
+  fn test_deep_tree() {
+    // 1 + (1 + (1 + ... - x)), nested to the right
+    let mut node = Node::Neg(Box::new(Node::Variable("x".to_string())));
+    for _ in 0..100_000 {
+      node = Node::BinOp(Op::Add, Box::new(Node::Immediate(1.)), Box::new(node));
+    }
+    let ctx = HashMap::from([("x".to_string(), 0.5)]);
+    assert_eq!(node.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap(), 99_999.5);
+
+    let mut steps = vec![];
+    node.evaluate_steps(EvalOptions { variables: Some(&ctx), ..Default::default() }, &mut steps).unwrap();
+    assert_eq!(steps.len(), 100_001);
+    drop(node);
+
+    // Parsed chains nest to the left
+    let input = "1 + ".repeat(100_000) + "1";
+    test(&input, 100_001.);
+  }
+
+  #[test]
+  fn test_lists() {
+    test_value("[1, 2, 3]", Value::List(vec![1., 2., 3.]));
+    test_value("[]", Value::List(vec![]));
+    test_value("[2 ^ 3, -1, sqrt(4)]", Value::List(vec![8., -1., 2.]));
+    assert_eq!(test_value_fail("[[1]]"), EvalError::NestedList);
+    assert_eq!(test_value_fail("[1] + 1"), EvalError::ExpectedNumber);
+    assert_eq!(test_value_fail("sqrt([4])"), EvalError::ExpectedNumber);
+    test_fail("[1, 2]");
+  }
+
+  #[test]
+  fn test_map_lists() {
+    let Value::List(values) = parse_expression(&mut tokenize("map(sin, [0, pi/2, pi])").unwrap()).unwrap()
+      .evaluate_value(EvalOptions::default()).unwrap() else { panic!() };
+    assert_eq!(values.len(), 3);
+    for (value, expected) in values.iter().zip([0., 1., 0.]) {
+      assert!((value - expected).abs() < 1e-15, "{} ≠ {}", value, expected);
+    }
+
+    test_value("map(sqrt, [1, 4, 9])", Value::List(vec![1., 2., 3.]));
+    test_value("map(abs, [])", Value::List(vec![]));
+    assert_eq!(test_value_fail("map(sqrt, [4, -1])"), EvalError::Domain { operation: "sqrt".to_string(), value: -1., reason: "square root of negative number" });
+  }
+
+  #[test]
+  fn test_zip_lists() {
+    test_value("zip(+, [1, 2, 3], [4, 5, 6])", Value::List(vec![5., 7., 9.]));
+    test_value("zip(*, [1, 2, 3], [1, 2, 3])", Value::List(vec![1., 4., 9.]));
+    test_value("zip(max, [1, 5, 2], [3, 2, 6])", Value::List(vec![3., 5., 6.]));
+    test_value("zip(-, [], [])", Value::List(vec![]));
+    assert_eq!(test_value_fail("zip(+, [1, 2], [1, 2, 3])"), EvalError::LengthMismatch { func: Func::Zip, first: 2, other: 3 });
+    assert_eq!(test_value_fail("zip(+, [1, 2], 1)"), EvalError::LengthMismatch { func: Func::Zip, first: 2, other: 1 });
+  }
+
+  #[test]
+  fn test_zip() {
+    test("zip(+, 1, 4)", 5.);
+    test("zip(*, 3, 3)", 9.);
+    test("zip(max, 5, 2) + zip(min, 5, 2)", 7.);
+    test("zip(-, 1, 4) * zip(%, -1, 3)", -6.);
+    test("zip(atan2, 0, -1)", PI);
+    test("zip(>=, 2, 2) + zip(!=, 2, 2)", 1.);
+    test_value("zip(<, [1, 5], [3, 3])", Value::List(vec![1., 0.]));
+    test_fail("zip(/, 1, 0)");
+  }
+
+  #[test]
+  fn test_statistics() {
+    test("mean([1, 2, 3, 4, 5])", 3.);
+    test("mean([2, 4, 4, 4, 5, 5, 7, 9])", 5.);
+    test("variance([2, 4, 4, 4, 5, 5, 7, 9])", 4.);
+    test("stdev([2, 4, 4, 4, 5, 5, 7, 9])", 2.);
+    test_approx("svariance([2, 4, 4, 4, 5, 5, 7, 9])", 32. / 7.);
+    test_approx("sstdev([2, 4, 4, 4, 5, 5, 7, 9])", (32_f64 / 7.).sqrt());
+    test("stdev([3])", 0.);
+    // Summing squares first would lose the deviations to cancellation
+    test("svariance([1000000004, 1000000007, 1000000013, 1000000016])", 30.);
+    test("variance([1000000004, 1000000007, 1000000013, 1000000016])", 22.5);
+
+    assert_eq!(test_value_fail("mean([])"), EvalError::EmptyList(Func::Mean));
+    assert_eq!(test_value_fail("sstdev([1])"), EvalError::TooFewElements(Func::SampleStdev));
+    assert_eq!(test_value_fail("mean(5)"), EvalError::ExpectedList);
+    assert!(Func::Mean.evaluate(&[f64::NAN]).is_err());
+
+    let mut steps = vec![];
+    let node = parse_expression(&mut tokenize("mean([1, 3]) * 2").unwrap()).unwrap();
+    node.evaluate_steps(EvalOptions::default(), &mut steps).unwrap();
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0].node, Node::Func(Func::Mean, vec![Node::List(vec![Node::Immediate(1.), Node::Immediate(3.)])]));
+    assert_eq!(steps[0].result, 2.);
+  }
+
+  #[test]
+  fn test_median_mode() {
+    test("median([1, 3, 2])", 2.);
+    test("median([1, 2, 3, 4])", 2.5);
+    test("median([4, 1, 4, 2])", 3.);
+    test("median([-7])", -7.);
+    test("mode([1, 2, 2, 3])", 2.);
+    test("mode([3, 1, 3, 1, 2])", 1.);
+    test("mode([5, 4])", 4.);
+    test("mode([0.9, 1.2, 2, 2.4, 1])", 1.);
+    test("mode([-0.2, 0, 5])", 0.);
+    assert_eq!(test_value_fail("median([])"), EvalError::EmptyList(Func::Median));
+    assert_eq!(test_value_fail("mode([])"), EvalError::EmptyList(Func::Mode));
+    assert!(Func::Median.evaluate_list(&[Value::List(vec![1., f64::NAN])]).unwrap().into_number().unwrap().is_nan());
+  }
+
+  #[test]
+  fn test_sort_reverse_unique() {
+    test_value("sort([3, 1, 2])", Value::List(vec![1., 2., 3.]));
+    test_value("sort([2, -1, 2, 0])", Value::List(vec![-1., 0., 2., 2.]));
+    test_value("sort([])", Value::List(vec![]));
+    test_value("reverse([1, 2, 3])", Value::List(vec![3., 2., 1.]));
+    test_value("reverse(sort([3, 1, 2]))", Value::List(vec![3., 2., 1.]));
+    test_value("unique([1, 2, 1, 3, 2])", Value::List(vec![1., 2., 3.]));
+    test_value("unique([3, 3, 3])", Value::List(vec![3.]));
+    test_value("map(abs, unique([-0, 0]))", Value::List(vec![0.]));
+    assert_eq!(test_value_fail("sort(1)"), EvalError::ExpectedList);
+
+    let Value::List(sorted) = Func::Sort.evaluate_list(&[Value::List(vec![f64::NAN, 1., f64::NEG_INFINITY])]).unwrap() else { panic!() };
+    assert_eq!(sorted[..2], [f64::NEG_INFINITY, 1.]);
+    assert!(sorted[2].is_nan());
+  }
+
+  #[test]
+  fn test_len_get_slice() {
+    test("len([1, 2, 3])", 3.);
+    test("len([])", 0.);
+    test("get([10, 20, 30], 1)", 20.);
+    test("get([5, 6, 7], 2)", 7.);
+    test("get([5, 6, 7], len([5, 6, 7]) - 1) * 2", 14.);
+    test_value("slice([1, 2, 3, 4, 5], 1, 3)", Value::List(vec![2., 3.]));
+    test_value("slice([1, 2, 3], 0, 3)", Value::List(vec![1., 2., 3.]));
+    test_value("slice([1, 2, 3], 3, 3)", Value::List(vec![]));
+
+    assert_eq!(test_value_fail("get([5, 6, 7], 3)"), EvalError::IndexOutOfBounds { index: 3., length: 3 });
+    assert_eq!(test_value_fail("get([], 0)"), EvalError::IndexOutOfBounds { index: 0., length: 0 });
+    assert_eq!(test_value_fail("get([5, 6, 7], -1)"), EvalError::IndexOutOfBounds { index: -1., length: 3 });
+    assert_eq!(test_value_fail("get([5, 6, 7], 0.5)"), EvalError::FractionalIndex(0.5));
+    assert_eq!(test_value_fail("slice([1, 2, 3], 1, 4)"), EvalError::IndexOutOfBounds { index: 4., length: 3 });
+    assert_eq!(test_value_fail("slice([1, 2, 3], 2, 1)"), EvalError::BackwardsSlice { from: 2, to: 1 });
+    assert_eq!(test_value_fail("get([1], [0])"), EvalError::ExpectedNumber);
+  }
+
+  #[test]
+  fn test_filter() {
+    test_value("filter(x > 2, x, [1, 2, 3, 4, 5])", Value::List(vec![3., 4., 5.]));
+    test_value("filter(x % 2, x, [1, 2, 3, 4, 5])", Value::List(vec![1., 3., 5.]));
+    test_value("filter(x > 9, x, [1, 2])", Value::List(vec![]));
+    test("len(filter(x != 2, x, [2, 1, 2, 3])) + 1", 3.);
+
+    // The bound variable shadows one in the context
+    let ast = parse_expression(&mut tokenize("filter(x < y, x, [x, 1, 5])").unwrap()).unwrap();
+    let ctx = HashMap::from([("x".to_string(), 3.), ("y".to_string(), 4.)]);
+    assert_eq!(ast.evaluate_value(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap(), Value::List(vec![3., 1.]));
+
+    assert_eq!(test_value_fail("filter(x > 2, x, 3)"), EvalError::ExpectedList);
+    assert_eq!(test_value_fail("filter([x], x, [1])"), EvalError::ExpectedNumber);
+    assert_eq!(test_value_fail("filter(y > 2, x, [1])"), EvalError::UndefinedVariable("y".to_string()));
+  }
+
+  #[test]
+  fn test_if() {
+    test("if(0, 1, 2)", 2.);
+    test("if(1, 3, 4)", 3.);
+    test("if(2 > 1, 10, 20) + if(0.5, 1, 0)", 11.);
+    test_value("if(1, [1, 2], 3)", Value::List(vec![1., 2.]));
+
+    let ast = parse_expression(&mut tokenize("if(x > 0, x, -x)").unwrap()).unwrap();
+    for x in [-3., 0., 2.5] {
+      assert_eq!(ast.evaluate(EvalOptions { variables: Some(&HashMap::from([("x".to_string(), x)])), ..Default::default() }).unwrap(), x.abs());
+    }
+
+    // The branch not taken isn't evaluated
+    let strict = EvalOptions { strict_division: true, ..Default::default() };
+    let ast = parse_expression(&mut tokenize("if(1, 2, 1 / 0) + if(0, sqrt(-1), 3)").unwrap()).unwrap();
+    assert_eq!(ast.evaluate(strict).unwrap(), 5.);
+    test("if(1, 1, undefined)", 1.);
+    assert_eq!(test_value_fail("if(1, sqrt(-1), 0)").to_string(), "Invalid operation: square root of negative number");
+    assert_eq!(test_value_fail("if([1], 1, 2)"), EvalError::ExpectedNumber);
+    assert_eq!(test_value_fail("if(1, [1], 2) + 1"), EvalError::ExpectedNumber);
+  }
+
+  #[test]
+  fn test_piecewise() {
+    let ast = parse_expression(&mut tokenize("piecewise(x < 0, 0, 1)").unwrap()).unwrap();
+    assert_eq!(ast.evaluate(EvalOptions { variables: Some(&HashMap::from([("x".to_string(), 5.)])), ..Default::default() }).unwrap(), 1.);
+    let ast = parse_expression(&mut tokenize("piecewise(x < 0, -x, x)").unwrap()).unwrap();
+    for x in [-3., 0., 2.5] {
+      assert_eq!(ast.evaluate(EvalOptions { variables: Some(&HashMap::from([("x".to_string(), x)])), ..Default::default() }).unwrap(), x.abs());
+    }
+
+    test("piecewise(0, 1, 2 > 1, 2, 3)", 2.);
+    test("piecewise(1 > 2, 1, 2)", 2.);
+    test_value("piecewise(1, [1, 2])", Value::List(vec![1., 2.]));
+    // Only the conditions up to the first which holds and its value are evaluated
+    test("piecewise(0, 1 / 0, 1, 3, sqrt(-1), 4)", 3.);
+    assert_eq!(test_value_fail("piecewise(1 > 2, 1, 0, 2)"), EvalError::NoMatchingCase);
+    assert_eq!(test_value_fail("piecewise([1], 1, 2)"), EvalError::ExpectedNumber);
+  }
+
+  #[test]
+  fn test_let() {
+    test("let x = 3 in x ^ 2", 9.);
+    test("let a = 1 in let b = 2 in a + b", 3.);
+    test("(let x = 2 in x + x) * 3", 12.);
+    test("let x = 1 in let x = x + 1 in x * 10", 20.);
+    test_value("let n = 2 in [n, n ^ 2]", Value::List(vec![2., 4.]));
+
+    // The bound value shadows the variable outside only in the body
+    let ast = parse_expression(&mut tokenize("let x = x * 2 in x + y").unwrap()).unwrap();
+    let ctx = HashMap::from([("x".to_string(), 5.), ("y".to_string(), 1.)]);
+    assert_eq!(ast.evaluate(EvalOptions { variables: Some(&ctx), ..Default::default() }).unwrap(), 11.);
+    assert_eq!(test_value_fail("let x = [1] in x"), EvalError::ExpectedNumber);
+    assert_eq!(test_value_fail("let x = 1 in y"), EvalError::UndefinedVariable("y".to_string()));
+    // Bound names are seen by the conditions of `filter` and the functions of `map`
+    test("let k = 2 in len(filter(x > k, x, [1, 2, 3]))", 1.);
+    test("let x = 1 in (let x = 2 in x) + x", 3.);
+    test_value("let k = 4 in map(sqrt, [k, k ^ 2])", Value::List(vec![2., 4.]));
+  }
+
+  #[test]
+  fn test_eval() {
+    test("eval(\"1 + 2\")", 3.);
+    test_approx("eval(\"sin(pi)\")", 0.);
+    test("2 * eval(\"1 + 2\")", 6.);
+    test("eval(\"eval(\\\"2 ^ 3\\\") - 1\")", 7.);
+    test_value("eval(\"[1, 2]\")", Value::List(vec![1., 2.]));
+  }
+
+  #[test]
+  fn test_format() {
+    test_value("format_sci(12345.678, 3)", Value::Text("1.235e4".to_string()));
+    test_value("format_sci(-0.00042, 1)", Value::Text("-4.2e-4".to_string()));
+    test_value("format_sci(7, 0)", Value::Text("7e0".to_string()));
+    test_value("format_eng(12345.678)", Value::Text("12.346e3".to_string()));
+    test_value("format_eng(0.00042)", Value::Text("420.000e-6".to_string()));
+    test_value("format_eng(-1.5)", Value::Text("-1.500e0".to_string()));
+    test_value("format_eng(999999.9)", Value::Text("1.000e6".to_string()));
+    test_value("format_eng(0)", Value::Text("0.000e0".to_string()));
+    test_value("format_eng(2 ^ -1070)", Value::Text("79.051e-324".to_string()));
+    test_value("if(1, format_eng(2), 3)", Value::Text("2.000e0".to_string()));
+
+    assert!(matches!(test_value_fail("format_sci(1, -1)"), EvalError::Domain { .. }));
+    assert!(matches!(test_value_fail("format_sci(1, 2.5)"), EvalError::Domain { .. }));
+    assert_eq!(test_value_fail("1 + format_eng(1)"), EvalError::UnexpectedText);
+    assert_eq!(test_value_fail("[format_eng(1)]"), EvalError::UnexpectedText);
+    assert_eq!(test_value_fail("map(abs, format_eng(1))"), EvalError::UnexpectedText);
+    assert_eq!(Func::FormatEng.evaluate(&[1.]), Err(EvalError::UnexpectedText));
+  }
+
+  #[test]
+  fn test_logic() {
+    test("and(1, 0)", 0.);
+    test("and(2, -1)", 1.);
+    test("or(0, 1)", 1.);
+    test("or(0, 0)", 0.);
+    test("not(0)", 1.);
+    test("not(5)", 0.);
+    test("and(3 > 0, 3 < 10) + or(1 > 2, not(1 == 1))", 1.);
+    test("if(and(1, not(0)), 7, 8)", 7.);
+
+    // NaN is true, as in `if`
+    test("if(nan, 1, 2)", 1.);
+    test("and(0, nan)", 0.);
+    test("and(nan, nan)", 1.);
+    test("or(1, nan)", 1.);
+    test("or(nan, 0)", 1.);
+    test("not(nan)", 0.);
+  }
+
+  #[test]
+  fn test_max_min() {
+    test("max(3, -1, 7, 2)", 7.);
+    test("min(3, -1, 7, 2)", -1.);
+    test("max(-5) + min(2)", -3.);
+    assert!(parse_expression(&mut tokenize("max()").unwrap()).is_err());
+    assert!(Func::Max.evaluate(&[1., f64::NAN]).unwrap().is_nan());
+  }
+
+  #[test]
+  fn test_round_to() {
+    test("round_to(7, 5)", 5.);
+    test("round_to(8, 5)", 10.);
+    test("round_to(2.3, 0.5)", 2.5);
+    test("floor_to(7, 3)", 6.);
+    test("ceil_to(7, 3)", 9.);
+    test("floor_to(-7, 3)", -9.);
+    test_fail("round_to(7, 0)");
+    test_fail("ceil_to(7)");
+  }
+
+  #[test]
+  fn test_choose() {
+    test("choose(5, 2)", 10.);
+    test("nCr(52, 5)", 2598960.);
+    test("choose(5, 7)", 0.);
+    test("choose(0.5, 1)", 0.5);
+    test("choose(5.5, 2)", 12.375);
+    test("choose(-1, 2)", 1.);
+    test("choose(4, -1)", 0.);
+    test_approx("choose(5, 2.5)", 120. / 3.323_350_970_447_843_f64.powi(2));
+    test_approx("choose(5.5, 2.5)", 14.4375);
+    test_fail("choose(-2, 0.5)");
+  }
+
+  #[test]
+  fn test_beta() {
+    test_approx("beta(1, 1)", 1.);
+    test_approx("beta(2, 3)", 1. / 12.);
+    test_approx("beta(0.5, 0.5)", PI);
+    test_approx("beta(-0.5, 1)", -2.);
+    test_approx("beta(-0.5, -0.5)", 0.);
+    test("beta(0.5, -0.5)", 0.);
+    // Γ(500) alone overflows
+    test_approx("lbeta(500, 500) / 1000", -0.694_988_722_485_713);
+    test_approx("lbeta(2, 3)", -(12_f64.ln()));
+    test_fail("beta(0, 1)");
+    test_fail("lbeta(2, -3)");
+  }
+
+  #[test]
+  fn test_zeta() {
+    test("zeta(2)", PI * PI / 6.);
+    test_approx("zeta(4)", 1.082_323_233_711_138_2);
+    test_approx("zeta(3)", 1.202_056_903_159_594_2);
+    test_approx("riemann_zeta(1.5)", 2.612_375_348_685_488);
+    test_approx("zeta(5)", 1.036_927_755_143_37);
+    test_approx("zeta(60)", 1.);
+    assert_eq!(Func::Zeta.evaluate(&[f64::INFINITY]).unwrap(), 1.);
+    test_fail("zeta(1)");
+    test_fail("zeta(-2)");
+  }
+
+  #[test]
+  fn test_lambert_w() {
+    test("lambertw(0)", 0.);
+    test_approx("lambertw(e)", 1.);
+    test_approx("lambertw(1)", 0.567_143_290_409_783_8);
+    test_approx("lamw(2 * log(e, 2))", 2_f64.ln());
+    test("lambertw(-1 / e)", -1.);
+    test_fail("lambertw(-0.37)");
+
+    for x in [-0.367, -0.3, -0.1, 1e-10, 0.5, 10., 1e6, 1e300] {
+      let w = Func::LambertW.evaluate(&[x]).unwrap();
+      assert!(w >= -1. && (w * w.exp() - x).abs() <= 1e-12 * x.abs().max(1e-300), "lambertw({}) = {}", x, w);
+    }
+  }
+
+  #[test]
+  fn test_agm() {
+    test_approx("agm(1, sqrt(2))", 1.198_140_234_735_592_2);
+    test("agm(1, 1)", 1.);
+    test("agm(0, 5)", 0.);
+    test("agm(5, 0)", 0.);
+    test_approx("agm(24, 6) - agm(6, 24)", 0.);
+    test_approx("agm(24, 6)", 13.458_171_481_725_616);
+    assert!((Func::Agm.evaluate(&[1e300, 1e308]).unwrap() / 7.930_521_033_434_53e306 - 1.).abs() < 1e-12);
+    test_fail("agm(-1, 1)");
+  }
+
+  #[test]
+  fn test_wrap() {
+    test("wrap(370, 0, 360)", 10.);
+    test("wrap(-10, 0, 360)", 350.);
+    test("wrap(360, 0, 360)", 0.);
+    test("wrap(5, -1, 1)", -1.);
+    test_approx("wrap(4, -pi, pi)", 4. - 2. * PI);
+    test_fail("wrap(1, 2, 2)");
+  }
+
+  #[test]
+  fn test_lerp() {
+    test("lerp(0, 10, 0.5)", 5.);
+    test("lerp(0, 10, 0)", 0.);
+    test("lerp(0, 10, 1)", 10.);
+    test("mix(10, 0, 0.25)", 7.5);
+    test("lerp(0, 10, 1.5)", 15.);
+    test("lerp_clamped(0, 10, 1.5)", 10.);
+    test("lerp_clamped(0, 10, -1)", 0.);
+  }
+
+  #[test]
+  fn test_smoothstep() {
+    test("smoothstep(0, 1, 0)", 0.);
+    test("smoothstep(0, 1, 0.5)", 0.5);
+    test("smoothstep(0, 1, 0.25)", 0.15625);
+    test("smoothstep(0, 1, 1)", 1.);
+    test("smoothstep(0, 1, -3)", 0.);
+    test("smoothstep(0, 1, 3)", 1.);
+    test("smoothstep(1, 0, 0.25)", 0.84375);
+
+    test("smootherstep(0, 1, 0)", 0.);
+    test("smootherstep(0, 1, 0.5)", 0.5);
+    test("smootherstep(0, 1, 0.25)", 0.103515625);
+    test("smootherstep(0, 1, 1)", 1.);
+    test_fail("smoothstep(1, 1, 0.5)");
+  }
+
+  #[test]
+  fn test_parse_partial() {
+    let mut lexer = tokenize("2 + 3 garbage").unwrap();
+    let (node, index) = parse_expression_partial(&mut lexer).unwrap();
+    assert_eq!(node, Node::BinOp(Op::Add, Box::new(Node::Immediate(2.)), Box::new(Node::Immediate(3.))));
+    // `2`, `+` and `3` come before it
+    assert_eq!(index, 3);
+    assert_eq!(lexer.tokens()[index], Token::Variable("garbage".to_string()));
+
+    let (_, index) = parse_expression_partial(&mut tokenize("(1) 2 3").unwrap()).unwrap();
+    assert_eq!(index, 3);
+    let (_, index) = parse_expression_partial(&mut tokenize("x").unwrap()).unwrap();
+    assert_eq!(index, 1);
+    assert!(parse_expression_partial(&mut tokenize("* 2").unwrap()).is_err());
+  }
 }
@@ -0,0 +1,1015 @@
+use eyre::{eyre, Result};
+
+use calc::{diagnostic::edit_distance, eval::Value, session::Session, settings::Lang, RoundWithPrecision};
+
+/// Text in every supported language
+pub struct Text {
+  pub en: &'static str,
+  pub ru: &'static str,
+}
+
+impl Text {
+  pub fn get(&self, lang: Lang) -> &'static str {
+    match lang {
+      Lang::En => self.en,
+      Lang::Ru => self.ru,
+    }
+  }
+}
+
+pub const BANNER: Text = Text {
+  en: "Calculator. Use \"funcs\", \"ops\", or \"consts\" for help.\n\"exit\" to exit",
+  ru: "Калькулятор. Для справки используйте \"funcs\", \"ops\" или \"consts\".\n\"exit\" для выхода",
+};
+
+pub const AUTHOR: Text = Text {
+  en: "Author: Гаврилович Владислав\nFor help, type \"funcs\", \"ops\", \"consts\", or \"help <name>\"",
+  ru: "Автор: Гаврилович Владислав\nДля справки введите \"funcs\", \"ops\", \"consts\" или \"help <имя>\"",
+};
+
+pub const HELP: Text = Text {
+  en: "Use \"funcs\", \"ops\", or \"consts\" for a list, or \"help <name>\" for details",
+  ru: "Используйте \"funcs\", \"ops\" или \"consts\" для списка или \"help <имя>\" для подробностей",
+};
+
+pub const AVAILABLE_FUNCTIONS: Text = Text {
+  en: "Available functions:",
+  ru: "Доступные функции:",
+};
+
+pub const AVAILABLE_OPERATORS: Text = Text {
+  en: "Available operators:",
+  ru: "Доступные операторы:",
+};
+
+pub const AVAILABLE_CONSTANTS: Text = Text {
+  en: "Available constants:",
+  ru: "Доступные константы:",
+};
+
+const EXAMPLE: Text = Text {
+  en: "Example",
+  ru: "Пример",
+};
+
+const UNKNOWN_TOPIC: Text = Text {
+  en: "Unknown topic",
+  ru: "Неизвестная тема",
+};
+
+const DID_YOU_MEAN: Text = Text {
+  en: "Did you mean",
+  ru: "Возможно, вы имели в виду",
+};
+
+const LIST_HINT: Text = Text {
+  en: "Use \"funcs\", \"ops\", or \"consts\" for a list",
+  ru: "Используйте \"funcs\", \"ops\" или \"consts\" для списка",
+};
+
+/// Documentation entry for a function, operator or constant
+pub struct Topic {
+  /// Names the topic can be looked up by
+  pub names: &'static [&'static str],
+  pub usage: &'static str,
+  pub description: Text,
+  pub domain: Text,
+  /// Expression demonstrating the topic, evaluated when displayed
+  pub example: &'static str,
+}
+
+pub const FUNCTIONS: &[Topic] = &[
+  Topic {
+    names: &["abs"],
+    usage: "abs(x)",
+    description: Text {
+      en: "absolute value",
+      ru: "модуль числа",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "abs(-2)",
+  },
+  Topic {
+    names: &["sqrt"],
+    usage: "sqrt(x)",
+    description: Text {
+      en: "square root",
+      ru: "квадратный корень",
+    },
+    domain: Text {
+      en: "x ≥ 0",
+      ru: "x ≥ 0",
+    },
+    example: "sqrt(2)",
+  },
+  Topic {
+    names: &["log"],
+    usage: "logX(y) / log(X, y)",
+    description: Text {
+      en: "base X logarithm of y",
+      ru: "логарифм y по основанию X",
+    },
+    domain: Text {
+      en: "y > 0, X > 0, X ≠ 1",
+      ru: "y > 0, X > 0, X ≠ 1",
+    },
+    example: "log2(8)",
+  },
+  Topic {
+    names: &["sin"],
+    usage: "sin(x)",
+    description: Text {
+      en: "sine",
+      ru: "синус",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "sin(pi / 6)",
+  },
+  Topic {
+    names: &["cos"],
+    usage: "cos(x)",
+    description: Text {
+      en: "cosine",
+      ru: "косинус",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "cos(pi)",
+  },
+  Topic {
+    names: &["sinpi"],
+    usage: "sinpi(x)",
+    description: Text {
+      en: "sine of pi * x, exact at multiples of 1/2",
+      ru: "синус от pi * x, точный при кратных 1/2",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "sinpi(1)",
+  },
+  Topic {
+    names: &["cospi"],
+    usage: "cospi(x)",
+    description: Text {
+      en: "cosine of pi * x, exact at multiples of 1/2",
+      ru: "косинус от pi * x, точный при кратных 1/2",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "cospi(0.5)",
+  },
+  Topic {
+    names: &["tg", "tan"],
+    usage: "tg(x) / tan(x)",
+    description: Text {
+      en: "tangent",
+      ru: "тангенс",
+    },
+    domain: Text {
+      en: "x ≠ pi/2 + k*pi",
+      ru: "x ≠ pi/2 + k*pi",
+    },
+    example: "tg(pi / 4)",
+  },
+  Topic {
+    names: &["ctg", "cotan"],
+    usage: "ctg(x) / cotan(x)",
+    description: Text {
+      en: "cotangent",
+      ru: "котангенс",
+    },
+    domain: Text {
+      en: "x ≠ k*pi",
+      ru: "x ≠ k*pi",
+    },
+    example: "ctg(pi / 4)",
+  },
+  Topic {
+    names: &["asin", "arcsin"],
+    usage: "asin(x) / arcsin(x)",
+    description: Text {
+      en: "arcsine",
+      ru: "арксинус",
+    },
+    domain: Text {
+      en: "-1 ≤ x ≤ 1",
+      ru: "-1 ≤ x ≤ 1",
+    },
+    example: "asin(1)",
+  },
+  Topic {
+    names: &["acos", "arccos"],
+    usage: "acos(x) / arccos(x)",
+    description: Text {
+      en: "arccosine",
+      ru: "арккосинус",
+    },
+    domain: Text {
+      en: "-1 ≤ x ≤ 1",
+      ru: "-1 ≤ x ≤ 1",
+    },
+    example: "acos(0)",
+  },
+  Topic {
+    names: &["atan", "arctan"],
+    usage: "atan(x) / arctan(x)",
+    description: Text {
+      en: "arctangent",
+      ru: "арктангенс",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "atan(1)",
+  },
+  Topic {
+    names: &["atan2"],
+    usage: "atan2(y, x)",
+    description: Text {
+      en: "angle between the positive x axis and the point (x, y), from -pi to pi",
+      ru: "угол между положительной осью x и точкой (x, y), от -pi до pi",
+    },
+    domain: Text {
+      en: "any x and y",
+      ru: "любые x и y",
+    },
+    example: "atan2(1, -1)",
+  },
+  Topic {
+    names: &["exp"],
+    usage: "exp(x)",
+    description: Text {
+      en: "exponent (e^x)",
+      ru: "экспонента (e^x)",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "exp(1)",
+  },
+  Topic {
+    names: &["root"],
+    usage: "rootX(y)",
+    description: Text {
+      en: "root of y with base X",
+      ru: "корень степени X из y",
+    },
+    domain: Text {
+      en: "X ≠ 0, y ≥ 0 unless X is an odd integer",
+      ru: "X ≠ 0, y ≥ 0, если X не нечётное целое",
+    },
+    example: "root3(27)",
+  },
+  Topic {
+    names: &["rand", "random", "rnd"],
+    usage: "rand() / rand(a, b)",
+    description: Text {
+      en: "pseudo-random number from 0 to 1, or from a to b, excluding the upper bound",
+      ru: "псевдослучайное число от 0 до 1 или от a до b, не включая верхнюю границу",
+    },
+    domain: Text {
+      en: "any a and b",
+      ru: "любые a и b",
+    },
+    example: "rand(1, 7)",
+  },
+  Topic {
+    names: &["seed"],
+    usage: "seed(n)",
+    description: Text {
+      en: "restarts the rand sequence from n, for reproducible results",
+      ru: "перезапускает последовательность rand с n для воспроизводимых результатов",
+    },
+    domain: Text {
+      en: "finite n",
+      ru: "конечное n",
+    },
+    example: "seed(42)",
+  },
+  Topic {
+    names: &["choose", "ncr"],
+    usage: "choose(n, k) / nCr(n, k)",
+    description: Text {
+      en: "binomial coefficient, generalized to real n and k",
+      ru: "биномиальный коэффициент, обобщённый на вещественные n и k",
+    },
+    domain: Text {
+      en: "any n and k, except a negative integer n with a non-integer k",
+      ru: "любые n и k, кроме отрицательного целого n при нецелом k",
+    },
+    example: "choose(5, 2)",
+  },
+  Topic {
+    names: &["beta", "lbeta"],
+    usage: "beta(a, b) / lbeta(a, b)",
+    description: Text {
+      en: "beta function Γ(a)Γ(b)/Γ(a + b), or the logarithm of its absolute value",
+      ru: "бета-функция Γ(a)Γ(b)/Γ(a + b) или логарифм её модуля",
+    },
+    domain: Text {
+      en: "a and b other than 0 and negative integers",
+      ru: "a и b, кроме 0 и отрицательных целых",
+    },
+    example: "beta(2, 3)",
+  },
+  Topic {
+    names: &["zeta", "riemann_zeta"],
+    usage: "zeta(s) / riemann_zeta(s)",
+    description: Text {
+      en: "Riemann zeta function, the sum of 1 / n^s over positive integers n",
+      ru: "дзета-функция Римана, сумма 1 / n^s по натуральным n",
+    },
+    domain: Text {
+      en: "s > 1",
+      ru: "s > 1",
+    },
+    example: "zeta(2)",
+  },
+  Topic {
+    names: &["lambertw", "lamw"],
+    usage: "lambertw(x) / lamw(x)",
+    description: Text {
+      en: "Lambert W function, the solution w ≥ -1 of w * e^w = x",
+      ru: "W-функция Ламберта, решение w ≥ -1 уравнения w * e^w = x",
+    },
+    domain: Text {
+      en: "x ≥ -1/e",
+      ru: "x ≥ -1/e",
+    },
+    example: "lambertw(1)",
+  },
+  Topic {
+    names: &["agm"],
+    usage: "agm(a, b)",
+    description: Text {
+      en: "arithmetic-geometric mean",
+      ru: "арифметико-геометрическое среднее",
+    },
+    domain: Text {
+      en: "a ≥ 0 and b ≥ 0",
+      ru: "a ≥ 0 и b ≥ 0",
+    },
+    example: "agm(1, sqrt(2))",
+  },
+  Topic {
+    names: &["sum_list", "prod_list"],
+    usage: "sum_list(a, b, ...) / prod_list(a, b, ...)",
+    description: Text {
+      en: "sum or product of any number of arguments, 0 or 1 for none",
+      ru: "сумма или произведение любого числа аргументов, 0 или 1 без аргументов",
+    },
+    domain: Text {
+      en: "any arguments",
+      ru: "любые аргументы",
+    },
+    example: "sum_list(1, 2, 3, 4, 5)",
+  },
+  Topic {
+    names: &["map"],
+    usage: "map(f, list)",
+    description: Text {
+      en: "applies the function f, passed by name like sin, to each element",
+      ru: "применяет функцию f, переданную по имени, например sin, к каждому элементу",
+    },
+    domain: Text {
+      en: "f takes one argument",
+      ru: "f принимает один аргумент",
+    },
+    example: "map(sqrt, [1, 4, 9])",
+  },
+  Topic {
+    names: &["zip"],
+    usage: "zip(f, a, b)",
+    description: Text {
+      en: "applies the function f, passed by name like max or +, to the elements of a and b pairwise",
+      ru: "применяет функцию f, переданную по имени, например max или +, к парам элементов a и b",
+    },
+    domain: Text {
+      en: "f takes two arguments, a and b have the same length",
+      ru: "f принимает два аргумента, a и b одной длины",
+    },
+    example: "zip(+, [1, 2], [3, 4])",
+  },
+  Topic {
+    names: &["max", "min"],
+    usage: "max(a, b, ...) / min(a, b, ...)",
+    description: Text {
+      en: "largest or smallest of the arguments",
+      ru: "наибольший или наименьший из аргументов",
+    },
+    domain: Text {
+      en: "at least one argument",
+      ru: "хотя бы один аргумент",
+    },
+    example: "max(3, 7, 2)",
+  },
+  Topic {
+    names: &["mean"],
+    usage: "mean(list)",
+    description: Text {
+      en: "arithmetic mean of the elements",
+      ru: "среднее арифметическое элементов",
+    },
+    domain: Text {
+      en: "non-empty list",
+      ru: "непустой список",
+    },
+    example: "mean([1, 2, 3, 4, 5])",
+  },
+  Topic {
+    names: &["variance", "svariance"],
+    usage: "variance(list) / svariance(list)",
+    description: Text {
+      en: "population or sample variance of the elements",
+      ru: "дисперсия элементов по генеральной совокупности или по выборке",
+    },
+    domain: Text {
+      en: "non-empty list, at least two elements for svariance",
+      ru: "непустой список, для svariance хотя бы два элемента",
+    },
+    example: "variance([2, 4, 4, 4, 5, 5, 7, 9])",
+  },
+  Topic {
+    names: &["stdev", "sstdev"],
+    usage: "stdev(list) / sstdev(list)",
+    description: Text {
+      en: "population or sample standard deviation of the elements",
+      ru: "стандартное отклонение элементов по генеральной совокупности или по выборке",
+    },
+    domain: Text {
+      en: "non-empty list, at least two elements for sstdev",
+      ru: "непустой список, для sstdev хотя бы два элемента",
+    },
+    example: "stdev([2, 4, 4, 4, 5, 5, 7, 9])",
+  },
+  Topic {
+    names: &["median"],
+    usage: "median(list)",
+    description: Text {
+      en: "middle element in sorted order, the mean of the two middle ones for an even length",
+      ru: "средний элемент по порядку, среднее двух средних при чётной длине",
+    },
+    domain: Text {
+      en: "non-empty list",
+      ru: "непустой список",
+    },
+    example: "median([1, 2, 3, 4])",
+  },
+  Topic {
+    names: &["mode"],
+    usage: "mode(list)",
+    description: Text {
+      en: "most frequent element rounded to an integer, the smallest of equally frequent ones",
+      ru: "самый частый элемент, округлённый до целого, наименьший из одинаково частых",
+    },
+    domain: Text {
+      en: "non-empty list",
+      ru: "непустой список",
+    },
+    example: "mode([1, 2, 2, 3])",
+  },
+  Topic {
+    names: &["sort", "reverse"],
+    usage: "sort(list) / reverse(list)",
+    description: Text {
+      en: "elements in ascending or in reverse order",
+      ru: "элементы по возрастанию или в обратном порядке",
+    },
+    domain: Text {
+      en: "any list",
+      ru: "любой список",
+    },
+    example: "sort([3, 1, 2])",
+  },
+  Topic {
+    names: &["unique"],
+    usage: "unique(list)",
+    description: Text {
+      en: "elements without repetitions, keeping the first occurrence of each",
+      ru: "элементы без повторов, с сохранением первого вхождения",
+    },
+    domain: Text {
+      en: "any list",
+      ru: "любой список",
+    },
+    example: "unique([1, 2, 1, 3])",
+  },
+  Topic {
+    names: &["len"],
+    usage: "len(list)",
+    description: Text {
+      en: "number of elements",
+      ru: "число элементов",
+    },
+    domain: Text {
+      en: "any list",
+      ru: "любой список",
+    },
+    example: "len([1, 2, 3])",
+  },
+  Topic {
+    names: &["get"],
+    usage: "get(list, i)",
+    description: Text {
+      en: "element at index i, counting from 0",
+      ru: "элемент с индексом i, считая с 0",
+    },
+    domain: Text {
+      en: "whole i from 0 to len(list) - 1",
+      ru: "целое i от 0 до len(list) - 1",
+    },
+    example: "get([10, 20, 30], 1)",
+  },
+  Topic {
+    names: &["slice"],
+    usage: "slice(list, from, to)",
+    description: Text {
+      en: "elements from index from up to but not including to",
+      ru: "элементы с индекса from до индекса to, не включая его",
+    },
+    domain: Text {
+      en: "whole from and to with 0 ≤ from ≤ to ≤ len(list)",
+      ru: "целые from и to, 0 ≤ from ≤ to ≤ len(list)",
+    },
+    example: "slice([1, 2, 3, 4, 5], 1, 3)",
+  },
+  Topic {
+    names: &["filter"],
+    usage: "filter(condition, x, list)",
+    description: Text {
+      en: "elements of the list for which the condition with x set to the element isn't 0",
+      ru: "элементы списка, для которых условие при x, равном элементу, не равно 0",
+    },
+    domain: Text {
+      en: "variable x, a list",
+      ru: "переменная x, список",
+    },
+    example: "filter(x > 2, x, [1, 2, 3, 4, 5])",
+  },
+  Topic {
+    names: &["if"],
+    usage: "if(condition, a, b)",
+    description: Text {
+      en: "a if the condition isn't 0, otherwise b; only the branch taken is evaluated",
+      ru: "a, если условие не равно 0, иначе b; вычисляется только выбранная ветвь",
+    },
+    domain: Text {
+      en: "any condition",
+      ru: "любое условие",
+    },
+    example: "if(2 > 1, 10, 1 / 0)",
+  },
+  Topic {
+    names: &["piecewise"],
+    usage: "piecewise(condition, a, ..., default)",
+    description: Text {
+      en: "value of the first condition which isn't 0, otherwise the default; without a default, fails if none holds",
+      ru: "значение первого условия, не равного 0, иначе значение по умолчанию; без него — ошибка, если ни одно не выполнено",
+    },
+    domain: Text {
+      en: "condition and value pairs, optionally followed by a default",
+      ru: "пары из условия и значения, за которыми может следовать значение по умолчанию",
+    },
+    example: "piecewise(-2 < 0, 0, 1)",
+  },
+  Topic {
+    names: &["let"],
+    usage: "let name = value in expression",
+    description: Text {
+      en: "expression with name standing for value, without defining a variable",
+      ru: "выражение, в котором имя обозначает значение, без определения переменной",
+    },
+    domain: Text {
+      en: "a name, a number",
+      ru: "имя, число",
+    },
+    example: "let x = 3 in x ^ 2 + x",
+  },
+  Topic {
+    names: &["eval"],
+    usage: "eval(\"expression\")",
+    description: Text {
+      en: "value of the expression written in the string, which is read along with the rest of the line before anything is evaluated",
+      ru: "значение выражения, записанного в строке; строка читается вместе с остальной строкой ввода до вычисления",
+    },
+    domain: Text {
+      en: "a string in double quotes",
+      ru: "строка в двойных кавычках",
+    },
+    example: "eval(\"2 + 2\") * 3",
+  },
+  Topic {
+    names: &["and", "or", "not"],
+    usage: "and(a, b) / or(a, b) / not(x)",
+    description: Text {
+      en: "logical operations on conditions: 1 if true and 0 if false, with any non-zero argument counting as true",
+      ru: "логические операции над условиями: 1, если истинно, и 0, если ложно; любой ненулевой аргумент считается истинным",
+    },
+    domain: Text {
+      en: "any arguments",
+      ru: "любые аргументы",
+    },
+    example: "and(2 > 0, 2 < 10)",
+  },
+  Topic {
+    names: &["format_sci"],
+    usage: "format_sci(x, digits)",
+    description: Text {
+      en: "x as text in scientific notation, with the given number of digits after the decimal point",
+      ru: "x в виде текста в экспоненциальной записи с заданным числом знаков после запятой",
+    },
+    domain: Text {
+      en: "any x, digits from 0 to 17",
+      ru: "любое x, digits от 0 до 17",
+    },
+    example: "format_sci(12345.678, 3)",
+  },
+  Topic {
+    names: &["format_eng"],
+    usage: "format_eng(x)",
+    description: Text {
+      en: "x as text in engineering notation, with an exponent divisible by 3",
+      ru: "x в виде текста в инженерной записи, с показателем степени, кратным 3",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "format_eng(12345.678)",
+  },
+  Topic {
+    names: &["wrap"],
+    usage: "wrap(x, lo, hi)",
+    description: Text {
+      en: "x wrapped around into [lo, hi) by floor modulo, lo + (x - lo) % (hi - lo)",
+      ru: "x, свёрнутое в [lo, hi) по модулю с округлением вниз, lo + (x - lo) % (hi - lo)",
+    },
+    domain: Text {
+      en: "lo ≠ hi",
+      ru: "lo ≠ hi",
+    },
+    example: "wrap(-10, 0, 360)",
+  },
+  Topic {
+    names: &["lerp", "mix", "lerp_clamped"],
+    usage: "lerp(a, b, t) / mix(a, b, t) / lerp_clamped(a, b, t)",
+    description: Text {
+      en: "linear interpolation a + t * (b - a), with t clamped to [0, 1] by lerp_clamped",
+      ru: "линейная интерполяция a + t * (b - a), у lerp_clamped t ограничено отрезком [0, 1]",
+    },
+    domain: Text {
+      en: "any a, b and t",
+      ru: "любые a, b и t",
+    },
+    example: "lerp(0, 10, 0.25)",
+  },
+  Topic {
+    names: &["smoothstep", "smootherstep"],
+    usage: "smoothstep(a, b, x) / smootherstep(a, b, x)",
+    description: Text {
+      en: "smooth transition from 0 at x = a to 1 at x = b, cubic or quintic",
+      ru: "плавный переход от 0 при x = a к 1 при x = b, кубический или пятой степени",
+    },
+    domain: Text {
+      en: "a ≠ b",
+      ru: "a ≠ b",
+    },
+    example: "smoothstep(0, 1, 0.25)",
+  },
+  Topic {
+    names: &["round_to", "floor_to", "ceil_to"],
+    usage: "round_to(x, m) / floor_to(x, m) / ceil_to(x, m)",
+    description: Text {
+      en: "x rounded to the nearest, lower or higher multiple of m",
+      ru: "x, округлённое до ближайшего, меньшего или большего кратного m",
+    },
+    domain: Text {
+      en: "m ≠ 0",
+      ru: "m ≠ 0",
+    },
+    example: "round_to(2.3, 0.5)",
+  },
+];
+
+pub const OPERATORS: &[Topic] = &[
+  Topic {
+    names: &["+"],
+    usage: "x + y",
+    description: Text {
+      en: "addition",
+      ru: "сложение",
+    },
+    domain: Text {
+      en: "any x, y",
+      ru: "любые x, y",
+    },
+    example: "2 + 3",
+  },
+  Topic {
+    names: &["-"],
+    usage: "x - y / -x",
+    description: Text {
+      en: "subtraction / negation",
+      ru: "вычитание / отрицание",
+    },
+    domain: Text {
+      en: "any x, y",
+      ru: "любые x, y",
+    },
+    example: "2 - 3",
+  },
+  Topic {
+    names: &["*"],
+    usage: "x * y",
+    description: Text {
+      en: "multiplication",
+      ru: "умножение",
+    },
+    domain: Text {
+      en: "any x, y",
+      ru: "любые x, y",
+    },
+    example: "2 * 3",
+  },
+  Topic {
+    names: &["/"],
+    usage: "x / y",
+    description: Text {
+      en: "division",
+      ru: "деление",
+    },
+    domain: Text {
+      en: "y ≠ 0",
+      ru: "y ≠ 0",
+    },
+    example: "1 / 4",
+  },
+  Topic {
+    names: &["%", "mod"],
+    usage: "x % y",
+    description: Text {
+      en: "remainder, with the sign of y; a negative y is written as 7 % -3, since 7% - 3 subtracts from a percentage",
+      ru: "остаток от деления, со знаком y; отрицательное y записывается как 7 % -3, так как 7% - 3 вычитает из процента",
+    },
+    domain: Text {
+      en: "y ≠ 0",
+      ru: "y ≠ 0",
+    },
+    example: "10 % 3 + 1",
+  },
+  Topic {
+    names: &["^"],
+    usage: "x ^ y",
+    description: Text {
+      en: "power",
+      ru: "возведение в степень",
+    },
+    domain: Text {
+      en: "x ≥ 0 unless y is an integer, 0 ^ 0 = 1",
+      ru: "x ≥ 0, если y не целое, 0 ^ 0 = 1",
+    },
+    example: "2 ^ 10",
+  },
+  Topic {
+    names: &["%"],
+    usage: "x%",
+    description: Text {
+      en: "percent (postfix)",
+      ru: "процент (постфиксный)",
+    },
+    domain: Text {
+      en: "any x",
+      ru: "любое x",
+    },
+    example: "200 * 15%",
+  },
+  Topic {
+    names: &["<", "<=", ">", ">="],
+    usage: "x < y",
+    description: Text {
+      en: "comparison, 1 if it holds and 0 otherwise",
+      ru: "сравнение, 1 если верно и 0 иначе",
+    },
+    domain: Text {
+      en: "any x, y, false if either is NaN",
+      ru: "любые x, y, ложно если одно из них NaN",
+    },
+    example: "3 >= 2",
+  },
+  Topic {
+    names: &["==", "!="],
+    usage: "x == y",
+    description: Text {
+      en: "equality, 1 if it holds and 0 otherwise",
+      ru: "равенство, 1 если верно и 0 иначе",
+    },
+    domain: Text {
+      en: "any x, y, NaN isn't equal to anything",
+      ru: "любые x, y, NaN не равно ничему",
+    },
+    example: "0.5 + 0.5 == 1",
+  },
+];
+
+pub const CONSTANTS: &[Topic] = &[
+  Topic {
+    names: &["pi"],
+    usage: "pi",
+    description: Text {
+      en: "ratio of a circle's circumference to its diameter",
+      ru: "отношение длины окружности к её диаметру",
+    },
+    domain: Text {
+      en: "constant",
+      ru: "константа",
+    },
+    example: "pi",
+  },
+  Topic {
+    names: &["e"],
+    usage: "e",
+    description: Text {
+      en: "base of the natural logarithm",
+      ru: "основание натурального логарифма",
+    },
+    domain: Text {
+      en: "constant",
+      ru: "константа",
+    },
+    example: "e",
+  },
+  Topic {
+    names: &["phi"],
+    usage: "phi",
+    description: Text {
+      en: "golden ratio",
+      ru: "золотое сечение",
+    },
+    domain: Text {
+      en: "constant",
+      ru: "константа",
+    },
+    example: "phi",
+  },
+  Topic {
+    names: &["inf"],
+    usage: "inf",
+    description: Text {
+      en: "positive infinity, as given by 1 / 0 without strict division",
+      ru: "положительная бесконечность, как 1 / 0 без строгого деления",
+    },
+    domain: Text {
+      en: "constant, unless a variable has the name",
+      ru: "константа, если нет переменной с таким именем",
+    },
+    example: "-inf < 0",
+  },
+  Topic {
+    names: &["nan"],
+    usage: "nan",
+    description: Text {
+      en: "not a number, as given by 0 / 0 without strict division",
+      ru: "не число, как 0 / 0 без строгого деления",
+    },
+    domain: Text {
+      en: "constant, unless a variable has the name",
+      ru: "константа, если нет переменной с таким именем",
+    },
+    example: "nan == nan",
+  },
+];
+
+fn topics() -> impl Iterator<Item = &'static Topic> {
+  FUNCTIONS.iter().chain(OPERATORS).chain(CONSTANTS)
+}
+
+/// Evaluates an example in a fresh session, which unlike `calculate` may result in a list
+fn evaluate_example(example: &str) -> Result<Value> {
+  Session::default().execute(example)?.ok_or_else(|| eyre!("Expected an expression"))
+}
+
+fn example(topic: &Topic) -> String {
+  match evaluate_example(topic.example) {
+    Ok(result) => format!("{} = {}", topic.example, result.map(|value| value.round_with_precision(5))),
+    Err(report) => format!("{} ({:#})", topic.example, report),
+  }
+}
+
+/// Bulleted list of topics under a heading, as printed by `funcs`, `ops` and `consts`
+pub fn render_list(heading: &Text, topics: &[Topic], lang: Lang) -> String {
+  let items = topics.iter().map(|topic| format!("* {} - {}", topic.usage, topic.description.get(lang)));
+
+  std::iter::once(heading.get(lang).to_string())
+    .chain(items)
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// Detailed description of a single topic, with suggestions if it's unknown
+pub fn render_topic(name: &str, lang: Lang) -> String {
+  let name = name.trim().to_ascii_lowercase();
+  // `log2` and `root3` are documented under `log` and `root`
+  let stripped = name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+  // `%` is documented both as percent and remainder
+  let mut matching: Vec<_> = topics().filter(|topic| topic.names.contains(&name.as_str())).collect();
+  if matching.is_empty() {
+    matching = topics().filter(|topic| topic.names.contains(&stripped)).collect();
+  }
+
+  if !matching.is_empty() {
+    return matching.into_iter()
+      .map(|topic| format!(
+        "{}: {}, {}. {}: {}",
+        topic.usage,
+        topic.description.get(lang),
+        topic.domain.get(lang),
+        EXAMPLE.get(lang),
+        example(topic),
+      ))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+
+  // Suggest the closest names, treating prefixes as close matches
+  let distance = |candidate: &str| match candidate.starts_with(&name) && name.len() > 1 {
+    true => 1,
+    false => edit_distance(&name, candidate),
+  };
+  let names: Vec<_> = topics().flat_map(|topic| topic.names.iter()).collect();
+  let closest = names.iter().map(|candidate| distance(candidate)).min().unwrap_or(0);
+  let suggestions: Vec<_> = names.into_iter()
+    .filter(|candidate| closest <= 2 && distance(candidate) == closest)
+    .map(|candidate| candidate.to_string())
+    .collect();
+
+  if suggestions.is_empty() {
+    format!("{} \"{}\". {}", UNKNOWN_TOPIC.get(lang), name, LIST_HINT.get(lang))
+  } else {
+    format!("{} \"{}\". {}: {}?", UNKNOWN_TOPIC.get(lang), name, DID_YOU_MEAN.get(lang), suggestions.join(", "))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{evaluate_example, render_list, render_topic, topics, AVAILABLE_CONSTANTS, FUNCTIONS};
+  use calc::{lexer::{tokenize, Token}, settings::Lang};
+
+  #[test]
+  fn test_registry() {
+    for topic in topics() {
+      assert!(evaluate_example(topic.example).is_ok(), "{}", topic.example);
+    }
+
+    // `if`, `piecewise`, `let` and `eval` are parsed apart from the functions, since they evaluate only some of their arguments, bind names or take a string
+    for topic in FUNCTIONS.iter().filter(|topic| !matches!(topic.names, ["if"] | ["piecewise"] | ["let"] | ["eval"])) {
+      for name in topic.names {
+        let mut lexer = tokenize(&format!("{}2", name)).unwrap();
+        assert!(matches!(lexer.next(), Token::Function(_)), "{}", name);
+      }
+    }
+  }
+
+  #[test]
+  fn test_render_topic() {
+    assert_eq!(render_topic("sqrt", Lang::En), "sqrt(x): square root, x ≥ 0. Example: sqrt(2) = 1.41421");
+    assert_eq!(render_topic("TAN", Lang::En), render_topic("tg", Lang::En));
+    assert_eq!(render_topic("log2", Lang::En), render_topic("log", Lang::En));
+    assert_eq!(render_topic("^", Lang::En), "x ^ y: power, x ≥ 0 unless y is an integer, 0 ^ 0 = 1. Example: 2 ^ 10 = 1024");
+    assert_eq!(render_topic("pi", Lang::En), "pi: ratio of a circle's circumference to its diameter, constant. Example: pi = 3.14159");
+    assert_eq!(render_topic("%", Lang::En), [
+      "x % y: remainder, with the sign of y; a negative y is written as 7 % -3, since 7% - 3 subtracts from a percentage, y ≠ 0. Example: 10 % 3 + 1 = 2",
+      "x%: percent (postfix), any x. Example: 200 * 15% = 30",
+    ].join("\n"));
+    assert_eq!(render_topic("sqr", Lang::En), "Unknown topic \"sqr\". Did you mean: sqrt?");
+    assert_eq!(render_topic("nosuchfn", Lang::En), "Unknown topic \"nosuchfn\". Use \"funcs\", \"ops\", or \"consts\" for a list");
+  }
+
+  #[test]
+  fn test_lang() {
+    assert_eq!(render_topic("sqrt", Lang::Ru), "sqrt(x): квадратный корень, x ≥ 0. Пример: sqrt(2) = 1.41421");
+    assert_eq!(render_topic("sqr", Lang::Ru), "Неизвестная тема \"sqr\". Возможно, вы имели в виду: sqrt?");
+    assert_eq!(render_list(&AVAILABLE_CONSTANTS, &super::CONSTANTS[..1], Lang::Ru), [
+      "Доступные константы:",
+      "* pi - отношение длины окружности к её диаметру",
+    ].join("\n"));
+
+    for topic in topics() {
+      assert!(!topic.description.ru.is_empty() && !topic.domain.ru.is_empty(), "{}", topic.usage);
+    }
+  }
+}
@@ -0,0 +1,96 @@
+use calc::{eval::Value, settings::Settings};
+
+/// Maximum displayed width of an expression in the history table
+const MAX_INPUT_WIDTH: usize = 30;
+
+/// An evaluated line and its result (`None` for definitions) or error message
+#[derive(Debug)]
+pub struct Entry {
+  pub input: String,
+  pub outcome: Result<Option<Value>, String>,
+}
+
+/// Log of expressions evaluated during the session
+#[derive(Debug, Default)]
+pub struct History {
+  entries: Vec<Entry>,
+}
+
+fn truncate(input: &str, width: usize) -> String {
+  if input.chars().count() <= width {
+    input.to_string()
+  } else {
+    let mut result: String = input.chars().take(width - 1).collect();
+    result.push('…');
+    result
+  }
+}
+
+impl History {
+  pub fn push(&mut self, input: &str, outcome: Result<Option<Value>, String>) {
+    self.entries.push(Entry {
+      input: input.to_string(),
+      outcome,
+    })
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Renders a numbered table of entries, most recent last.
+  /// `last` limits the table to the specified number of entries
+  pub fn render(&self, last: Option<usize>, settings: &Settings) -> String {
+    if self.entries.is_empty() {
+      return "History is empty".to_string()
+    }
+
+    let skip = last.map_or(0, |last| self.entries.len().saturating_sub(last));
+    let entries: Vec<_> = self.entries.iter()
+      .enumerate()
+      .skip(skip)
+      .map(|(index, entry)| (index + 1, truncate(&entry.input, MAX_INPUT_WIDTH), &entry.outcome))
+      .collect();
+
+    let index_width = self.entries.len().to_string().len();
+    let input_width = entries.iter().map(|(_, input, _)| input.chars().count()).max().unwrap_or(0);
+
+    entries.into_iter()
+      .map(|(index, input, outcome)| {
+        let padding = " ".repeat(input_width - input.chars().count());
+        match outcome {
+          Ok(Some(result)) => format!("  {:>index_width$}: {}{} = {}", index, input, padding, settings.format_value(result)),
+          Ok(None) => format!("  {:>index_width$}: {}", index, input),
+          Err(message) => format!("  {:>index_width$}: {}{} ! {}", index, input, padding, message),
+        }
+      })
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::History;
+  use calc::settings::Settings;
+
+  #[test]
+  fn test_render() {
+    let settings = Settings::default();
+    let mut history = History::default();
+    assert_eq!(history.render(None, &settings), "History is empty");
+
+    history.push("2^10", Ok(Some(1024.0.into())));
+    history.push("1/0", Err("Invalid operation: division by zero".into()));
+    history.push("f(x) = x ^ 2", Ok(None));
+    history.push("1 + 2 + 3 + 4 + 5 + 6 + 7 + 8 + 9 + 10", Ok(Some(55.0.into())));
+
+    assert_eq!(history.render(None, &settings), [
+      "  1: 2^10                           = 1024",
+      "  2: 1/0                            ! Invalid operation: division by zero",
+      "  3: f(x) = x ^ 2",
+      "  4: 1 + 2 + 3 + 4 + 5 + 6 + 7 + 8… = 55",
+    ].join("\n"));
+    assert_eq!(history.render(Some(1), &settings), "  4: 1 + 2 + 3 + 4 + 5 + 6 + 7 + 8… = 55");
+  }
+}
@@ -0,0 +1,233 @@
+use std::{
+  fs,
+  io::{stdin, IsTerminal as _},
+  path::PathBuf,
+  sync::{atomic::{AtomicBool, Ordering}, Arc},
+};
+use eyre::Result;
+use rustyline::{
+  error::ReadlineError,
+  history::DefaultHistory,
+  Cmd,
+  ConditionalEventHandler,
+  Config,
+  Editor,
+  Event,
+  EventContext,
+  EventHandler,
+  KeyEvent,
+  RepeatCount,
+};
+
+/// Maximum number of entries kept in the history file
+const HISTORY_SIZE: usize = 1000;
+
+/// Prompt shown while an expression is continued onto the next line
+const CONTINUATION_PROMPT: &str = "... ";
+
+/// Continuation prompt matching `prompt`, hidden along with it
+fn continuation_prompt(prompt: &str) -> &'static str {
+  match prompt.is_empty() {
+    true => "",
+    false => CONTINUATION_PROMPT,
+  }
+}
+
+/// Line editor with input history, optionally persisted to a file
+pub struct Input {
+  editor: Editor<(), DefaultHistory>,
+  file: Option<PathBuf>,
+  /// Whether the last Ctrl-C was pressed on an empty line
+  interrupted_empty: Arc<AtomicBool>,
+}
+
+/// Records whether the line was empty when Ctrl-C was pressed
+struct InterruptHandler(Arc<AtomicBool>);
+
+impl ConditionalEventHandler for InterruptHandler {
+  fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+    self.0.store(ctx.line().is_empty(), Ordering::Relaxed);
+    Some(Cmd::Interrupt)
+  }
+}
+
+/// Depth of the parentheses and brackets still open at the end of `input`,
+/// and where its `#` comment starts, leaving out anything inside string literals
+fn scan(input: &str) -> (i32, Option<usize>) {
+  let mut depth = 0;
+  let mut chars = input.char_indices();
+  while let Some((index, c)) = chars.next() {
+    match c {
+      '(' | '[' => depth += 1,
+      ')' | ']' => depth -= 1,
+      // The character after a backslash is never the closing quote, as in the lexer
+      '"' => while let Some((_, c)) = chars.next() {
+        match c {
+          '"' => break,
+          '\\' => { chars.next(); },
+          _ => {},
+        }
+      },
+      '#' => return (depth, Some(index)),
+      _ => {},
+    }
+  }
+  (depth, None)
+}
+
+/// Whether the input needs more lines: either it ends with a backslash
+/// or it has parentheses or brackets which are still open outside of comments and strings
+pub fn needs_continuation(input: &str) -> bool {
+  input.trim_end().ends_with('\\') || scan(input).0 > 0
+}
+
+/// Platform-appropriate location of the history file,
+/// e.g. `~/.local/share/uni-calc/history` on Linux
+fn history_path() -> Option<PathBuf> {
+  dirs::data_dir().map(|dir| dir.join("uni-calc").join("history"))
+}
+
+impl Input {
+  pub fn new(persist_history: bool) -> Result<Self> {
+    let config = Config::builder()
+      .max_history_size(HISTORY_SIZE)?
+      .history_ignore_dups(true)?
+      .auto_add_history(false)
+      .build();
+    let mut editor = Editor::with_config(config)?;
+
+    let interrupted_empty = Arc::new(AtomicBool::new(false));
+    editor.bind_sequence(
+      KeyEvent::ctrl('C'),
+      EventHandler::Conditional(Box::new(InterruptHandler(interrupted_empty.clone()))),
+    );
+
+    let file = if persist_history {
+      Self::open_history(&mut editor)
+    } else {
+      None
+    };
+
+    Ok(Self {
+      editor,
+      file,
+      interrupted_empty,
+    })
+  }
+
+  /// Loads the history file, falling back to in-memory history if it's not accessible
+  fn open_history(editor: &mut Editor<(), DefaultHistory>) -> Option<PathBuf> {
+    let Some(path) = history_path() else {
+      eprintln!("Warning: unable to locate data directory, history won't be saved");
+      return None
+    };
+
+    if let Some(dir) = path.parent() {
+      if let Err(err) = fs::create_dir_all(dir) {
+        eprintln!("Warning: unable to create {} ({}), history won't be saved", dir.display(), err);
+        return None
+      }
+    }
+
+    if path.exists() {
+      if let Err(err) = editor.load_history(&path) {
+        eprintln!("Warning: unable to load history from {} ({})", path.display(), err);
+      }
+    }
+
+    Some(path)
+  }
+
+  pub fn read_line(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+    let result = self.editor.readline(prompt);
+
+    // Move past the abandoned line
+    if stdin().is_terminal() {
+      match result {
+        Err(ReadlineError::Interrupted) => println!("^C"),
+        Err(ReadlineError::Eof) => println!(),
+        _ => {},
+      }
+    }
+
+    result
+  }
+
+  /// Reads an expression which may span multiple lines, joining them with spaces.
+  /// Returns `None` if the input was cancelled with Ctrl-C, and `ReadlineError::Interrupted`
+  /// if Ctrl-C was pressed at an empty prompt
+  pub fn read_expression(&mut self, prompt: &str) -> Result<Option<String>, ReadlineError> {
+    let mut buffer = match self.read_line(prompt) {
+      Ok(line) => line,
+      Err(ReadlineError::Interrupted) if !self.interrupted_empty.load(Ordering::Relaxed) => return Ok(None),
+      Err(err) => return Err(err),
+    };
+
+    while needs_continuation(&buffer) {
+      if let Some(stripped) = buffer.trim_end().strip_suffix('\\') {
+        buffer.truncate(stripped.len());
+      }
+      // Joined with a space, the next line would end up in the comment
+      if let (_, Some(comment)) = scan(&buffer) {
+        buffer.truncate(comment);
+      }
+
+      match self.read_line(continuation_prompt(prompt)) {
+        Ok(line) => {
+          buffer.push(' ');
+          buffer.push_str(&line);
+        },
+        Err(ReadlineError::Interrupted) => return Ok(None),
+        Err(err) => return Err(err),
+      }
+    }
+
+    Ok(Some(buffer))
+  }
+
+  /// Records an accepted line, appending it to the history file if persistence is enabled
+  pub fn add_history(&mut self, line: &str) {
+    if line.trim().is_empty() {
+      return
+    }
+
+    // Consecutive duplicates are dropped by the editor
+    if !self.editor.add_history_entry(line).unwrap_or(false) {
+      return
+    }
+
+    if let Some(path) = &self.file {
+      if let Err(err) = self.editor.append_history(path) {
+        eprintln!("Warning: unable to save history to {} ({}), history won't be saved", path.display(), err);
+        self.file = None;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{continuation_prompt, needs_continuation, scan};
+
+  #[test]
+  fn test_continuation() {
+    assert!(!needs_continuation(""));
+    assert!(!needs_continuation("2 + 3"));
+    assert!(!needs_continuation("(2 + 3) * 4"));
+    assert!(!needs_continuation("2 + 3)"));
+    assert!(needs_continuation("2 + \\"));
+    assert!(needs_continuation("2 + \\  "));
+    assert!(needs_continuation("sqrt(2 +"));
+    assert!(needs_continuation("((1 + 2) * 3"));
+    assert!(needs_continuation("[1, max(2"));
+    assert!(!needs_continuation("[1, 2]"));
+    assert!(!needs_continuation("2 + 3 # see (a"));
+    assert!(needs_continuation("sqrt(2 # see b)"));
+    assert!(!needs_continuation(r#"eval("sqrt(2")"#));
+    assert!(!needs_continuation(r#"eval("\"(") + 1"#));
+    assert_eq!(scan(r##"sqrt("#" # x"##), (1, Some(9)));
+
+    assert_eq!(continuation_prompt("> "), "... ");
+    assert_eq!(continuation_prompt(""), "");
+  }
+}
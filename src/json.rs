@@ -0,0 +1,360 @@
+use std::cell::Cell;
+use eyre::Result;
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{error::LimitExceeded, lexer::{tokenize, Func}, parser::{parse_statement, Node, Statement}, walk::MAX_DEPTH};
+
+thread_local! {
+  /// Number of nodes being serialized or deserialized on this thread, each within the previous one
+  static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// One level of `NESTING`, left when dropped
+struct Nesting;
+
+impl Nesting {
+  fn enter() -> Result<Self, LimitExceeded> {
+    let depth = NESTING.get();
+    if depth >= MAX_DEPTH {
+      return Err(LimitExceeded::Depth(MAX_DEPTH))
+    }
+    NESTING.set(depth + 1);
+    Ok(Nesting)
+  }
+}
+
+impl Drop for Nesting {
+  fn drop(&mut self) {
+    NESTING.set(NESTING.get() - 1);
+  }
+}
+
+/// The derived impl, failing on trees deeper than `MAX_DEPTH` rather than overflowing the stack
+impl Serialize for Node {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let _nesting = Nesting::enter().map_err(ser::Error::custom)?;
+    Node::serialize(self, serializer)
+  }
+}
+
+/// The derived impl, failing on trees deeper than `MAX_DEPTH` rather than overflowing the stack
+impl<'de> Deserialize<'de> for Node {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let _nesting = Nesting::enter().map_err(de::Error::custom)?;
+    Node::deserialize(deserializer)
+  }
+}
+
+/// Serialized form of `Func`, with embedded bases as named fields,
+/// e.g. `{"type": "Log", "base": 2.0}`
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum FuncRepr {
+  Abs,
+  Sqrt,
+  Log {
+    #[serde(with = "float")]
+    base: f64,
+  },
+  LogBase,
+  Sin,
+  Cos,
+  SinPi,
+  CosPi,
+  Tg,
+  Ctg,
+  Asin,
+  Acos,
+  Atan,
+  Atan2,
+  Exp,
+  Root {
+    #[serde(with = "float")]
+    base: f64,
+  },
+  Random,
+  RandomRange,
+  Seed,
+  Choose,
+  Beta,
+  Lbeta,
+  Zeta,
+  LambertW,
+  Agm,
+  Wrap,
+  Lerp,
+  LerpClamped,
+  Smoothstep,
+  Smootherstep,
+  RoundTo,
+  FloorTo,
+  CeilTo,
+  SumList,
+  ProdList,
+  Max,
+  Min,
+  Map,
+  Zip,
+  Mean,
+  Variance,
+  SampleVariance,
+  Stdev,
+  SampleStdev,
+  Median,
+  Mode,
+  Sort,
+  Reverse,
+  Unique,
+  Len,
+  Get,
+  Slice,
+  Filter,
+  And,
+  Or,
+  Not,
+  FormatSci,
+  FormatEng,
+}
+
+impl From<Func> for FuncRepr {
+  fn from(func: Func) -> Self {
+    match func {
+      Func::Abs => FuncRepr::Abs,
+      Func::Sqrt => FuncRepr::Sqrt,
+      Func::Log(base) => FuncRepr::Log { base },
+      Func::LogBase => FuncRepr::LogBase,
+      Func::Sin => FuncRepr::Sin,
+      Func::Cos => FuncRepr::Cos,
+      Func::SinPi => FuncRepr::SinPi,
+      Func::CosPi => FuncRepr::CosPi,
+      Func::Tg => FuncRepr::Tg,
+      Func::Ctg => FuncRepr::Ctg,
+      Func::Asin => FuncRepr::Asin,
+      Func::Acos => FuncRepr::Acos,
+      Func::Atan => FuncRepr::Atan,
+      Func::Atan2 => FuncRepr::Atan2,
+      Func::Exp => FuncRepr::Exp,
+      Func::Root(base) => FuncRepr::Root { base },
+      Func::Random => FuncRepr::Random,
+      Func::RandomRange => FuncRepr::RandomRange,
+      Func::Seed => FuncRepr::Seed,
+      Func::Choose => FuncRepr::Choose,
+      Func::Beta => FuncRepr::Beta,
+      Func::Lbeta => FuncRepr::Lbeta,
+      Func::Zeta => FuncRepr::Zeta,
+      Func::LambertW => FuncRepr::LambertW,
+      Func::Agm => FuncRepr::Agm,
+      Func::Wrap => FuncRepr::Wrap,
+      Func::Lerp => FuncRepr::Lerp,
+      Func::LerpClamped => FuncRepr::LerpClamped,
+      Func::Smoothstep => FuncRepr::Smoothstep,
+      Func::Smootherstep => FuncRepr::Smootherstep,
+      Func::RoundTo => FuncRepr::RoundTo,
+      Func::FloorTo => FuncRepr::FloorTo,
+      Func::CeilTo => FuncRepr::CeilTo,
+      Func::SumList => FuncRepr::SumList,
+      Func::ProdList => FuncRepr::ProdList,
+      Func::Max => FuncRepr::Max,
+      Func::Min => FuncRepr::Min,
+      Func::Map => FuncRepr::Map,
+      Func::Zip => FuncRepr::Zip,
+      Func::Mean => FuncRepr::Mean,
+      Func::Variance => FuncRepr::Variance,
+      Func::SampleVariance => FuncRepr::SampleVariance,
+      Func::Stdev => FuncRepr::Stdev,
+      Func::SampleStdev => FuncRepr::SampleStdev,
+      Func::Median => FuncRepr::Median,
+      Func::Mode => FuncRepr::Mode,
+      Func::Sort => FuncRepr::Sort,
+      Func::Reverse => FuncRepr::Reverse,
+      Func::Unique => FuncRepr::Unique,
+      Func::Len => FuncRepr::Len,
+      Func::Get => FuncRepr::Get,
+      Func::Slice => FuncRepr::Slice,
+      Func::Filter => FuncRepr::Filter,
+      Func::And => FuncRepr::And,
+      Func::Or => FuncRepr::Or,
+      Func::Not => FuncRepr::Not,
+      Func::FormatSci => FuncRepr::FormatSci,
+      Func::FormatEng => FuncRepr::FormatEng,
+    }
+  }
+}
+
+impl From<FuncRepr> for Func {
+  fn from(repr: FuncRepr) -> Self {
+    match repr {
+      FuncRepr::Abs => Func::Abs,
+      FuncRepr::Sqrt => Func::Sqrt,
+      FuncRepr::Log { base } => Func::Log(base),
+      FuncRepr::LogBase => Func::LogBase,
+      FuncRepr::Sin => Func::Sin,
+      FuncRepr::Cos => Func::Cos,
+      FuncRepr::SinPi => Func::SinPi,
+      FuncRepr::CosPi => Func::CosPi,
+      FuncRepr::Tg => Func::Tg,
+      FuncRepr::Ctg => Func::Ctg,
+      FuncRepr::Asin => Func::Asin,
+      FuncRepr::Acos => Func::Acos,
+      FuncRepr::Atan => Func::Atan,
+      FuncRepr::Atan2 => Func::Atan2,
+      FuncRepr::Exp => Func::Exp,
+      FuncRepr::Root { base } => Func::Root(base),
+      FuncRepr::Random => Func::Random,
+      FuncRepr::RandomRange => Func::RandomRange,
+      FuncRepr::Seed => Func::Seed,
+      FuncRepr::Choose => Func::Choose,
+      FuncRepr::Beta => Func::Beta,
+      FuncRepr::Lbeta => Func::Lbeta,
+      FuncRepr::Zeta => Func::Zeta,
+      FuncRepr::LambertW => Func::LambertW,
+      FuncRepr::Agm => Func::Agm,
+      FuncRepr::Wrap => Func::Wrap,
+      FuncRepr::Lerp => Func::Lerp,
+      FuncRepr::LerpClamped => Func::LerpClamped,
+      FuncRepr::Smoothstep => Func::Smoothstep,
+      FuncRepr::Smootherstep => Func::Smootherstep,
+      FuncRepr::RoundTo => Func::RoundTo,
+      FuncRepr::FloorTo => Func::FloorTo,
+      FuncRepr::CeilTo => Func::CeilTo,
+      FuncRepr::SumList => Func::SumList,
+      FuncRepr::ProdList => Func::ProdList,
+      FuncRepr::Max => Func::Max,
+      FuncRepr::Min => Func::Min,
+      FuncRepr::Map => Func::Map,
+      FuncRepr::Zip => Func::Zip,
+      FuncRepr::Mean => Func::Mean,
+      FuncRepr::Variance => Func::Variance,
+      FuncRepr::SampleVariance => Func::SampleVariance,
+      FuncRepr::Stdev => Func::Stdev,
+      FuncRepr::SampleStdev => Func::SampleStdev,
+      FuncRepr::Median => Func::Median,
+      FuncRepr::Mode => Func::Mode,
+      FuncRepr::Sort => Func::Sort,
+      FuncRepr::Reverse => Func::Reverse,
+      FuncRepr::Unique => Func::Unique,
+      FuncRepr::Len => Func::Len,
+      FuncRepr::Get => Func::Get,
+      FuncRepr::Slice => Func::Slice,
+      FuncRepr::Filter => Func::Filter,
+      FuncRepr::And => Func::And,
+      FuncRepr::Or => Func::Or,
+      FuncRepr::Not => Func::Not,
+      FuncRepr::FormatSci => Func::FormatSci,
+      FuncRepr::FormatEng => Func::FormatEng,
+    }
+  }
+}
+
+/// Numbers which JSON can't represent are written as the strings `"NaN"`, `"inf"` and `"-inf"`
+pub mod float {
+  use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    match value.is_finite() {
+      true => serializer.serialize_f64(*value),
+      false => serializer.serialize_str(&value.to_string()),
+    }
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+      Number(f64),
+      Text(String),
+    }
+
+    match Repr::deserialize(deserializer)? {
+      Repr::Number(value) => Ok(value),
+      Repr::Text(text) => match text.as_str() {
+        "NaN" => Ok(f64::NAN),
+        "inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Err(D::Error::custom(format!("invalid number: {}", text))),
+      },
+    }
+  }
+}
+
+/// Parses a line and serializes the syntax tree of its expression, as printed by `--ast-json`
+pub fn ast_json(input: &str) -> Result<String> {
+  let mut lexer = tokenize(input)?;
+  let node = match parse_statement(&mut lexer)? {
+    Statement::Expression(node) | Statement::Assign(_, node) | Statement::Define(_, _, node) => node,
+  };
+  Ok(serde_json::to_string(&node)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use super::ast_json;
+  use crate::{eval::EvalOptions, lexer::{tokenize, Func, Op, Token}, parser::{parse_expression, Node}};
+
+  #[test]
+  fn test_func() {
+    assert_eq!(serde_json::to_string(&Func::Log(2.)).unwrap(), r#"{"type":"Log","base":2.0}"#);
+    assert_eq!(serde_json::to_string(&Func::Sqrt).unwrap(), r#"{"type":"Sqrt"}"#);
+    assert_eq!(serde_json::from_str::<Func>(r#"{"type":"Root","base":3.0}"#).unwrap(), Func::Root(3.));
+    assert!(serde_json::from_str::<Func>(r#"{"type":"Root"}"#).is_err());
+
+    let token = Token::Function(Func::Log(10.));
+    let json = serde_json::to_string(&token).unwrap();
+    assert_eq!(serde_json::from_str::<Token>(&json).unwrap(), token);
+  }
+
+  #[test]
+  fn test_ast_json() {
+    assert_eq!(
+      ast_json("-x + log2(8)").unwrap(),
+      r#"{"BinOp":["Add",{"Neg":{"Variable":"x"}},{"Func":[{"type":"Log","base":2.0},[{"Immediate":8.0}]]}]}"#,
+    );
+
+    let json = ast_json("f(1, 2) ^ 3").unwrap();
+    let node: Node = serde_json::from_str(&json).unwrap();
+    assert_eq!(node.to_string(), "f(1, 2) ^ 3");
+    assert!(ast_json("1 +").is_err());
+  }
+
+  #[test]
+  fn test_non_finite() {
+    let node = Node::BinOp(Op::Add, Box::new(Node::Immediate(f64::INFINITY)), Box::new(Node::Func(Func::Root(f64::NAN), vec![Node::Immediate(-0.5)])));
+    let json = serde_json::to_string(&node).unwrap();
+    assert_eq!(json, r#"{"BinOp":["Add",{"Immediate":"inf"},{"Func":[{"type":"Root","base":"NaN"},[{"Immediate":-0.5}]]}]}"#);
+    assert_eq!(serde_json::from_str::<Node>(&json).unwrap(), node);
+
+    let token = Token::Literal(f64::NEG_INFINITY);
+    assert_eq!(serde_json::from_str::<Token>(&serde_json::to_string(&token).unwrap()).unwrap(), token);
+    assert_eq!(serde_json::from_str::<Token>(r#"{"Literal":8}"#).unwrap(), Token::Literal(8.));
+    assert!(serde_json::from_str::<Token>(r#"{"Literal":"infinity"}"#).is_err());
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let corpus = [
+      "(1 + 2) * 3 - 4 / 5 % 3",
+      "-x ^ 2 + --y",
+      "log2(8) + log(e, x) + log0.5(4) + log(3, 81) + root3(-27)",
+      "atan2(y, x) * lerp(0, 10, 0.25) + choose(5, 2)",
+      "50% * f(x, 2)",
+      "if(x > y, x, [y]) + 1",
+    ];
+    let ctx = HashMap::from([("x".to_string(), 1.5), ("y".to_string(), -2.)]);
+    let options = EvalOptions { variables: Some(&ctx), ..Default::default() };
+
+    for input in corpus {
+      let original = parse_expression(&mut tokenize(input).unwrap()).unwrap();
+      let node: Node = serde_json::from_str(&serde_json::to_string(&original).unwrap()).unwrap();
+      assert_eq!(node.to_string(), original.to_string());
+      match (original.evaluate(options), node.evaluate(options)) {
+        (Ok(a), Ok(b)) => assert_eq!(a, b, "{}", input),
+        (a, b) => assert_eq!(a.is_err(), b.is_err(), "{}", input),
+      }
+
+      let tokens = tokenize(input).unwrap().collect_tokens();
+      let json = serde_json::to_string(&tokens).unwrap();
+      assert_eq!(serde_json::from_str::<Vec<Token>>(&json).unwrap(), tokens);
+    }
+  }
+}
@@ -0,0 +1,208 @@
+use std::f64::consts::{E, PI};
+
+use crate::{error::LimitExceeded, eval::builtin, lexer::{Func, Op}, parser::Node};
+
+/// Binding strength of the node's outermost operation as written in LaTeX.
+/// Fractions are delimited by `\frac`, so they bind like a literal
+fn precedence(node: &Node) -> u8 {
+  match node {
+    Node::Let { .. } => 0,
+    Node::BinOp(op, _, _) if op.is_comparison() => 1,
+    Node::BinOp(Op::Add | Op::Sub, _, _) => 2,
+    Node::BinOp(Op::Mul | Op::Mod, _, _) => 3,
+    Node::BinOp(Op::Pow, _, _) => 4,
+    _ => 5,
+  }
+}
+
+fn is_negative(node: &Node) -> bool {
+  matches!(node, Node::Neg(_)) || matches!(node, Node::Immediate(value) if value.is_sign_negative())
+}
+
+fn parenthesize(latex: String) -> String {
+  format!("\\left({}\\right)", latex)
+}
+
+fn operand(node: &Node, parenthesized: bool) -> String {
+  match parenthesized {
+    true => parenthesize(node.latex()),
+    false => node.latex(),
+  }
+}
+
+/// Single letters and Greek names as they are, other names upright
+fn identifier(name: &str) -> String {
+  const GREEK: &[&str] = &[
+    "alpha", "gamma", "delta", "epsilon", "eta", "theta", "iota", "kappa", "lambda",
+    "mu", "nu", "xi", "rho", "sigma", "tau", "upsilon", "chi", "psi", "omega",
+  ];
+
+  if name.chars().count() == 1 {
+    name.to_string()
+  } else if GREEK.contains(&name) {
+    format!("\\{}", name)
+  } else {
+    format!("\\mathrm{{{}}}", name.replace('_', "\\_"))
+  }
+}
+
+fn arguments(args: &[Node]) -> String {
+  parenthesize(args.iter().map(Node::latex).collect::<Vec<_>>().join(", "))
+}
+
+/// Operator like `\sin` applied to a single argument, which is only parenthesized if it isn't a literal or variable
+fn apply(operator: &str, arg: &Node) -> String {
+  match arg {
+    Node::Immediate(_) | Node::Variable(_) if !is_negative(arg) => format!("{}{{{}}}", operator, arg.latex()),
+    arg => format!("{}{}", operator, parenthesize(arg.latex())),
+  }
+}
+
+fn func_latex(func: Func, args: &[Node]) -> String {
+  match (func, args) {
+    (Func::Abs, [arg]) => format!("\\left|{}\\right|", arg.latex()),
+    (Func::Sqrt, [arg]) => format!("\\sqrt{{{}}}", arg.latex()),
+    (Func::Root(base), [arg]) => format!("\\sqrt[{}]{{{}}}", base, arg.latex()),
+    (Func::Log(base), [arg]) => apply(&format!("\\log_{{{}}}", base), arg),
+    (Func::LogBase, [base, arg]) => apply(&format!("\\log_{{{}}}", base.latex()), arg),
+    (Func::Sin, [arg]) => apply("\\sin", arg),
+    (Func::Cos, [arg]) => apply("\\cos", arg),
+    (Func::Tg, [arg]) => apply("\\tan", arg),
+    (Func::Ctg, [arg]) => apply("\\cot", arg),
+    (Func::Asin, [arg]) => apply("\\arcsin", arg),
+    (Func::Acos, [arg]) => apply("\\arccos", arg),
+    (Func::Atan, [arg]) => apply("\\arctan", arg),
+    (Func::Exp, [arg]) => format!("e^{{{}}}", arg.latex()),
+    (Func::Choose, [n, k]) => format!("\\binom{{{}}}{{{}}}", n.latex(), k.latex()),
+    // Everything else is written like a call
+    (func, args) => format!("{}{}", operator_name(func), arguments(args)),
+  }
+}
+
+/// Symbol of an operator written between its operands, `/` only occurring on its own
+fn operator_symbol(op: Op) -> &'static str {
+  match op {
+    Op::Add => "+",
+    Op::Sub => "-",
+    Op::Mul => "\\cdot",
+    Op::Div => "/",
+    Op::Mod => "\\bmod",
+    Op::Pow => "\\hat{}",
+    Op::Gt => ">",
+    Op::Lt => "<",
+    Op::Eq => "=",
+    Op::Ge => "\\geq",
+    Op::Le => "\\leq",
+    Op::Ne => "\\neq",
+  }
+}
+
+fn operator_name(func: Func) -> String {
+  format!("\\operatorname{{{}}}", func.to_string().replace('_', "\\_"))
+}
+
+impl Node {
+  /// Renders the expression as LaTeX math, with fractions for division
+  /// and parenthesis only where precedence requires them. Fails on trees deeper than `MAX_DEPTH`
+  pub fn to_latex(&self) -> Result<String, LimitExceeded> {
+    self.check_depth()?;
+    Ok(self.latex())
+  }
+
+  fn latex(&self) -> String {
+    match self {
+      Node::Immediate(value) if value.is_sign_negative() => format!("-{}", Node::Immediate(-value).latex()),
+      // Constants are replaced with their values while tokenizing
+      Node::Immediate(value) if *value == PI => "\\pi".to_string(),
+      Node::Immediate(value) if *value == E => "e".to_string(),
+      Node::Immediate(value) if *value == (1. + 5_f64.sqrt()) / 2. => "\\varphi".to_string(),
+      Node::Immediate(value) if value.is_infinite() => "\\infty".to_string(),
+      Node::Immediate(value) if value.is_nan() => "\\mathrm{NaN}".to_string(),
+      Node::Immediate(value) => value.to_string(),
+      Node::Variable(name) => match builtin(name) {
+        Some(value) => Node::Immediate(value).latex(),
+        None => identifier(name),
+      },
+      // `-x^{2}` reads as the negation of the power, just like the tree
+      Node::Neg(node) => format!("-{}", operand(node, precedence(node) < 4)),
+      Node::BinOp(Op::Div, left, right) => format!("\\frac{{{}}}{{{}}}", left.latex(), right.latex()),
+      // The base of a power is parenthesized unless it's a single symbol, so that `(-x)^{2}` and `(\frac{1}{2})^{2}` are clear
+      Node::BinOp(Op::Pow, base, exponent) => {
+        let atom = matches!(**base, Node::Immediate(_) | Node::Variable(_) | Node::Func(..) | Node::Call(..)) && !is_negative(base);
+        format!("{}^{{{}}}", operand(base, !atom), exponent.latex())
+      },
+      Node::BinOp(op, left, right) => {
+        let symbol = operator_symbol(*op);
+        // All operators are left-associative, and a negative right operand is parenthesized for readability
+        let precedence = self::precedence(self);
+        let left = operand(left, self::precedence(left) < precedence);
+        let right = operand(right, self::precedence(right) <= precedence || is_negative(right));
+        format!("{} {} {}", left, symbol, right)
+      },
+      Node::Func(func, args) => func_latex(*func, args),
+      Node::Call(name, args) => format!("{}{}", identifier(name), arguments(args)),
+      Node::FuncRef(func) => operator_name(*func),
+      Node::OpRef(op) => operator_symbol(*op).to_string(),
+      Node::List(items) => format!("\\left[{}\\right]", items.iter().map(Node::latex).collect::<Vec<_>>().join(", ")),
+      Node::If { cond, then, else_ } => format!(
+        "\\begin{{cases}} {} & \\text{{if }} {} \\\\ {} & \\text{{otherwise}} \\end{{cases}}",
+        then.latex(),
+        cond.latex(),
+        else_.latex(),
+      ),
+      Node::Let { name, value, body } => format!(
+        "\\text{{let }} {} = {} \\text{{ in }} {}",
+        identifier(name),
+        value.latex(),
+        body.latex(),
+      ),
+      Node::Piecewise(cases, default) => {
+        let mut rows: Vec<String> = cases.iter()
+          .map(|(cond, value)| format!("{} & \\text{{if }} {}", value.latex(), cond.latex()))
+          .collect();
+        rows.extend(default.iter().map(|default| format!("{} & \\text{{otherwise}}", default.latex())));
+        format!("\\begin{{cases}} {} \\end{{cases}}", rows.join(" \\\\ "))
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{lexer::tokenize, parser::parse_expression};
+
+  fn latex(input: &str) -> String {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap().to_latex().unwrap()
+  }
+
+  #[test]
+  fn test_latex() {
+    assert_eq!(latex("sqrt(2)/3 + x^2"), "\\frac{\\sqrt{2}}{3} + x^{2}");
+    assert_eq!(latex("log2(8)"), "\\log_{2}{8}");
+    assert_eq!(latex("root3(x)"), "\\sqrt[3]{x}");
+    assert_eq!(latex("(1 + 2) * 3 - 4 * (5 - 6)"), "\\left(1 + 2\\right) \\cdot 3 - 4 \\cdot \\left(5 - 6\\right)");
+    assert_eq!(latex("1 - (2 + 3) / 4"), "1 - \\frac{2 + 3}{4}");
+    assert_eq!(latex("2 * pi * r"), "2 \\cdot \\pi \\cdot r");
+    assert_eq!(latex("e ^ (x + 1) ^ 2"), "\\left(e^{x + 1}\\right)^{2}");
+    assert_eq!(latex("-x ^ 2 + (1 / 2) ^ 2"), "\\left(-x\\right)^{2} + \\left(\\frac{1}{2}\\right)^{2}");
+    assert_eq!(latex("-(a + b) * -c"), "-\\left(a + b\\right) \\cdot \\left(-c\\right)");
+    assert_eq!(latex("7 % 3"), "7 \\bmod 3");
+    assert_eq!(latex("50%"), "\\frac{50}{100}");
+    assert_eq!(latex("x + 1 >= 2 * y"), "x + 1 \\geq 2 \\cdot y");
+    assert_eq!(latex("(a != b) + (a < b)"), "\\left(a \\neq b\\right) + \\left(a < b\\right)");
+    assert_eq!(latex("-inf + NaN"), "-\\infty + \\mathrm{NaN}");
+  }
+
+  #[test]
+  fn test_latex_functions() {
+    assert_eq!(latex("sin(x) + cos(2 * x) + tg(alpha)"), "\\sin{x} + \\cos\\left(2 \\cdot x\\right) + \\tan{\\alpha}");
+    assert_eq!(latex("log(e, x) + log(b, 8) + exp(-t)"), "\\log_{e}{x} + \\log_{b}{8} + e^{-t}");
+    assert_eq!(latex("abs(x - 1) * choose(5, 2)"), "\\left|x - 1\\right| \\cdot \\binom{5}{2}");
+    // Unsupported constructs are written like calls
+    assert_eq!(latex("round_to(x, 5)"), "\\operatorname{round\\_to}\\left(x, 5\\right)");
+    assert_eq!(latex("f(x, rate)"), "f\\left(x, \\mathrm{rate}\\right)");
+    assert_eq!(latex("if(x >= 0, x, -x)"), "\\begin{cases} x & \\text{if } x \\geq 0 \\\\ -x & \\text{otherwise} \\end{cases}");
+    assert_eq!(latex("(let y = 2 in y) * x"), "\\left(\\text{let } y = 2 \\text{ in } y\\right) \\cdot x");
+    assert_eq!(latex("piecewise(x < 0, -x, x > 1, 1)"), "\\begin{cases} -x & \\text{if } x < 0 \\\\ 1 & \\text{if } x > 1 \\end{cases}");
+  }
+}
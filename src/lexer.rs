@@ -1,41 +1,424 @@
-use std::{f64::consts::{E, PI}, iter::{Fuse, Peekable}, str::Chars};
-use eyre::{Report, Result};
+use std::{f64::consts::{E, PI}, fmt, hash::{Hash, Hasher}, iter::{Fuse, Peekable}, mem, ops::Range, str::Chars};
+use crate::error::{LexError, LimitExceeded, SpanError};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Op {
   Add,
   Sub,
   Mul,
   Div,
+  /// Remainder, `%` followed by an operand
+  Mod,
   Pow,
+  /// Comparisons give 1 when they hold and 0 otherwise
+  Gt,
+  Lt,
+  Eq,
+  Ge,
+  Le,
+  Ne,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "crate::json::FuncRepr", into = "crate::json::FuncRepr"))]
 pub enum Func {
   Abs,
   Sqrt,
   Log(f64),
+  /// `log(base, x)`, with the base passed as the first argument
+  LogBase,
   Sin,
   Cos,
+  SinPi,
+  CosPi,
   Tg,
   Ctg,
   Asin,
   Acos,
   Atan,
+  /// `atan2(y, x)`, the angle of the point `(x, y)`
+  Atan2,
   Exp,
   Root(f64),
+  /// `rand()`, in `[0, 1)`
+  Random,
+  /// `rand(a, b)`, in `[a, b)`
+  RandomRange,
+  /// `seed(n)`, restarting the `rand` sequence
+  Seed,
+  /// `choose(n, k)`, the binomial coefficient generalized to real numbers
+  Choose,
+  /// `beta(a, b)`, the beta function
+  Beta,
+  /// `lbeta(a, b)`, the natural logarithm of the absolute value of `beta(a, b)`
+  Lbeta,
+  /// `zeta(s)`, the Riemann zeta function for `s > 1`
+  Zeta,
+  /// `lambertw(x)`, the principal branch of the Lambert W function
+  LambertW,
+  /// `agm(a, b)`, the arithmetic-geometric mean
+  Agm,
+  /// `wrap(x, lo, hi)`, `x` wrapped around into `[lo, hi)`
+  Wrap,
+  /// `lerp(a, b, t)`, linear interpolation from `a` at `t = 0` to `b` at `t = 1`
+  Lerp,
+  /// `lerp_clamped(a, b, t)`, with `t` clamped to `[0, 1]`
+  LerpClamped,
+  /// `smoothstep(edge0, edge1, x)`, cubic Hermite interpolation
+  Smoothstep,
+  /// `smootherstep(edge0, edge1, x)`, the quintic variant
+  Smootherstep,
+  /// `round_to(x, multiple)`
+  RoundTo,
+  FloorTo,
+  CeilTo,
+  /// `sum_list(a, b, ...)`, the sum of any number of arguments
+  SumList,
+  /// `prod_list(a, b, ...)`, the product of any number of arguments
+  ProdList,
+  /// `max(a, b, ...)`, the largest of at least one argument
+  Max,
+  /// `min(a, b, ...)`, the smallest of at least one argument
+  Min,
+  /// `map(f, list)`, `f` applied to each element
+  Map,
+  /// `zip(f, a, b)`, `f` applied to the elements of `a` and `b` pairwise
+  Zip,
+  /// `mean(list)`, the arithmetic mean
+  Mean,
+  /// `variance(list)`, the population variance
+  Variance,
+  /// `svariance(list)`, the sample variance
+  SampleVariance,
+  /// `stdev(list)`, the population standard deviation
+  Stdev,
+  /// `sstdev(list)`, the sample standard deviation
+  SampleStdev,
+  /// `median(list)`, the middle element once sorted
+  Median,
+  /// `mode(list)`, the most frequent element
+  Mode,
+  /// `sort(list)`, in ascending order
+  Sort,
+  /// `reverse(list)`
+  Reverse,
+  /// `unique(list)`, without repeated elements
+  Unique,
+  /// `len(list)`, the number of elements
+  Len,
+  /// `get(list, i)`, the element at zero-based index `i`
+  Get,
+  /// `slice(list, from, to)`, the elements from index `from` up to but excluding `to`
+  Slice,
+  /// `filter(condition, x, list)`, the elements for which `condition` holds with `x` bound to them
+  Filter,
+  /// `and(a, b)`, 1 if both arguments are non-zero and 0 otherwise
+  And,
+  /// `or(a, b)`, 1 if either argument is non-zero and 0 otherwise
+  Or,
+  /// `not(x)`, 1 if `x` is 0 and 0 otherwise
+  Not,
+  /// `format_sci(x, digits)`, text of `x` in scientific notation with `digits` after the decimal point
+  FormatSci,
+  /// `format_eng(x)`, text of `x` in engineering notation, with an exponent divisible by 3
+  FormatEng,
+}
+
+/// Named constant, kept apart from number literals so that messages can name it as it was typed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Constant {
+  Pi,
+  E,
+  /// Golden ratio
+  Phi,
+}
+
+impl Constant {
+  pub fn value(self) -> f64 {
+    match self {
+      Constant::Pi => PI,
+      Constant::E => E,
+      Constant::Phi => (1. + 5_f64.sqrt()) / 2.,
+    }
+  }
+}
+
+impl fmt::Display for Constant {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Constant::Pi => write!(f, "pi"),
+      Constant::E => write!(f, "e"),
+      Constant::Phi => write!(f, "phi"),
+    }
+  }
+}
+
+impl fmt::Display for Op {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Op::Add => write!(f, "+"),
+      Op::Sub => write!(f, "-"),
+      Op::Mul => write!(f, "*"),
+      Op::Div => write!(f, "/"),
+      Op::Mod => write!(f, "%"),
+      Op::Pow => write!(f, "^"),
+      Op::Gt => write!(f, ">"),
+      Op::Lt => write!(f, "<"),
+      Op::Eq => write!(f, "=="),
+      Op::Ge => write!(f, ">="),
+      Op::Le => write!(f, "<="),
+      Op::Ne => write!(f, "!="),
+    }
+  }
+}
+
+/// Bits of `value`, with both zeros alike since they're equal, and all NaNs alike
+fn float_bits(value: f64) -> u64 {
+  if value.is_nan() {
+    f64::NAN.to_bits()
+  } else if value == 0. {
+    0_f64.to_bits()
+  } else {
+    value.to_bits()
+  }
+}
+
+/// Hashes the bits of `value` as `same_float` compares them
+pub(crate) fn hash_float<H: Hasher>(value: f64, state: &mut H) {
+  float_bits(value).hash(state)
+}
+
+/// Compares floats by their bits, except for both zeros being alike,
+/// so that unlike with `==`, NaN is equal to itself and the comparison can back an `Eq`
+pub(crate) fn same_float(a: f64, b: f64) -> bool {
+  float_bits(a) == float_bits(b)
+}
+
+/// Bases compare with `same_float`
+impl PartialEq for Func {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Func::Log(a), Func::Log(b)) | (Func::Root(a), Func::Root(b)) => same_float(*a, *b),
+      _ => mem::discriminant(self) == mem::discriminant(other),
+    }
+  }
+}
+
+impl Eq for Func {}
+
+impl Hash for Func {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    mem::discriminant(self).hash(state);
+    if let Func::Log(base) | Func::Root(base) = self {
+      hash_float(*base, state);
+    }
+  }
+}
+
+impl Op {
+  /// Whether the operator compares its operands, binding looser than arithmetic
+  pub fn is_comparison(self) -> bool {
+    matches!(self, Op::Gt | Op::Lt | Op::Eq | Op::Ge | Op::Le | Op::Ne)
+  }
+}
+
+impl Func {
+  /// Number of arguments the function takes, the minimum for variadic functions
+  pub fn arity(self) -> usize {
+    match self {
+      Func::SumList | Func::ProdList => 0,
+      Func::Max | Func::Min => 1,
+      Func::LogBase | Func::Atan2 | Func::RandomRange | Func::Choose | Func::Beta | Func::Lbeta | Func::Agm | Func::Map | Func::RoundTo | Func::FloorTo | Func::CeilTo | Func::Get | Func::And | Func::Or | Func::FormatSci => 2,
+      Func::Wrap | Func::Lerp | Func::LerpClamped | Func::Smoothstep | Func::Smootherstep | Func::Zip | Func::Slice | Func::Filter => 3,
+      Func::Random => 0,
+      _ => 1,
+    }
+  }
+
+  /// Whether the function takes any number of arguments from its arity up
+  pub fn is_variadic(self) -> bool {
+    matches!(self, Func::SumList | Func::ProdList | Func::Max | Func::Min)
+  }
+
+  /// Number of arguments of the function expected at `index`, if a function is passed there by name
+  pub fn function_argument(self, index: usize) -> Option<usize> {
+    match (self, index) {
+      (Func::Map, 0) => Some(1),
+      (Func::Zip, 0) => Some(2),
+      _ => None,
+    }
+  }
+
+  /// Whether the argument at `index` must be a list
+  pub fn list_argument(self, index: usize) -> bool {
+    matches!(
+      (self, index),
+      (
+        Func::Mean | Func::Variance | Func::SampleVariance | Func::Stdev | Func::SampleStdev | Func::Median | Func::Mode
+          | Func::Sort | Func::Reverse | Func::Unique | Func::Len | Func::Get | Func::Slice,
+        0,
+      ) | (Func::Filter, 2)
+    )
+  }
+
+  /// Whether the argument at `index` must be a variable, which the function binds
+  pub fn variable_argument(self, index: usize) -> bool {
+    matches!((self, index), (Func::Filter, 1))
+  }
+
+  /// Whether any argument of the function must be a list
+  pub fn takes_lists(self) -> bool {
+    (0..self.arity()).any(|index| self.list_argument(index))
+  }
+
+  /// Whether the function gives text rather than a number
+  pub fn returns_text(self) -> bool {
+    matches!(self, Func::FormatSci | Func::FormatEng)
+  }
+
+  /// Whether the function can be called with `count` arguments
+  pub fn accepts(self, count: usize) -> bool {
+    count == self.arity() || (self.is_variadic() && count > self.arity())
+  }
+
+  /// Error message for a call with the wrong number of arguments
+  pub fn arity_error(self, count: usize) -> String {
+    let at_least = if self.is_variadic() { "at least " } else { "" };
+    format!("{} expects {}{} argument(s), got {}", self, at_least, self.arity(), count)
+  }
+}
+
+impl fmt::Display for Func {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Func::Abs => write!(f, "abs"),
+      Func::Sqrt => write!(f, "sqrt"),
+      Func::Log(base) => write!(f, "log{}", base),
+      Func::LogBase => write!(f, "log"),
+      Func::Sin => write!(f, "sin"),
+      Func::Cos => write!(f, "cos"),
+      Func::SinPi => write!(f, "sinpi"),
+      Func::CosPi => write!(f, "cospi"),
+      Func::Tg => write!(f, "tg"),
+      Func::Ctg => write!(f, "ctg"),
+      Func::Asin => write!(f, "asin"),
+      Func::Acos => write!(f, "acos"),
+      Func::Atan => write!(f, "atan"),
+      Func::Atan2 => write!(f, "atan2"),
+      Func::Exp => write!(f, "exp"),
+      Func::Root(base) => write!(f, "root{}", base),
+      Func::Random | Func::RandomRange => write!(f, "rand"),
+      Func::Seed => write!(f, "seed"),
+      Func::Choose => write!(f, "choose"),
+      Func::Beta => write!(f, "beta"),
+      Func::Lbeta => write!(f, "lbeta"),
+      Func::Zeta => write!(f, "zeta"),
+      Func::LambertW => write!(f, "lambertw"),
+      Func::Agm => write!(f, "agm"),
+      Func::Wrap => write!(f, "wrap"),
+      Func::Lerp => write!(f, "lerp"),
+      Func::LerpClamped => write!(f, "lerp_clamped"),
+      Func::Smoothstep => write!(f, "smoothstep"),
+      Func::Smootherstep => write!(f, "smootherstep"),
+      Func::RoundTo => write!(f, "round_to"),
+      Func::FloorTo => write!(f, "floor_to"),
+      Func::CeilTo => write!(f, "ceil_to"),
+      Func::SumList => write!(f, "sum_list"),
+      Func::ProdList => write!(f, "prod_list"),
+      Func::Max => write!(f, "max"),
+      Func::Min => write!(f, "min"),
+      Func::Map => write!(f, "map"),
+      Func::Zip => write!(f, "zip"),
+      Func::Mean => write!(f, "mean"),
+      Func::Variance => write!(f, "variance"),
+      Func::SampleVariance => write!(f, "svariance"),
+      Func::Stdev => write!(f, "stdev"),
+      Func::SampleStdev => write!(f, "sstdev"),
+      Func::Median => write!(f, "median"),
+      Func::Mode => write!(f, "mode"),
+      Func::Sort => write!(f, "sort"),
+      Func::Reverse => write!(f, "reverse"),
+      Func::Unique => write!(f, "unique"),
+      Func::Len => write!(f, "len"),
+      Func::Get => write!(f, "get"),
+      Func::Slice => write!(f, "slice"),
+      Func::Filter => write!(f, "filter"),
+      Func::And => write!(f, "and"),
+      Func::Or => write!(f, "or"),
+      Func::Not => write!(f, "not"),
+      Func::FormatSci => write!(f, "format_sci"),
+      Func::FormatEng => write!(f, "format_eng"),
+    }
+  }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token {
-  Literal(f64),
+  Literal(#[cfg_attr(feature = "serde", serde(with = "crate::json::float"))] f64),
+  Constant(Constant),
   Operator(Op),
   Function(Func),
+  Variable(String),
+  Percent,
+  Comma,
+  Equals,
+  LeftParen,
+  RightParen,
+  /// Text between double quotes, with `\"` and `\\` standing for a quote and a backslash
+  StringLiteral(String),
+  /// `[`, opening a list
   LeftBracket,
   RightBracket,
+  /// Text following `#` up to the end of the line, only kept with `TokenizeOptions::keep_comments`
+  Comment(String),
   End,
 }
 
+/// Description for error messages, quoted except for comments and the end of input
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Token::Literal(value) => write!(f, "'{}'", value),
+      Token::Constant(constant) => write!(f, "'{}'", constant),
+      Token::Operator(op) => write!(f, "'{}'", op),
+      Token::Function(func) => write!(f, "'{}'", func),
+      Token::Variable(name) => write!(f, "'{}'", name),
+      Token::Percent => write!(f, "'%'"),
+      Token::StringLiteral(text) => write!(f, "{:?}", text),
+      Token::Comma => write!(f, "','"),
+      Token::Equals => write!(f, "'='"),
+      Token::LeftParen => write!(f, "'('"),
+      Token::RightParen => write!(f, "')'"),
+      Token::LeftBracket => write!(f, "'['"),
+      Token::RightBracket => write!(f, "']'"),
+      Token::Comment(_) => write!(f, "a comment"),
+      Token::End => write!(f, "end of input"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenizeOptions {
+  /// Keep comments in the token stream, e.g. for syntax highlighting.
+  /// The parser skips them either way
+  pub keep_comments: bool,
+  /// Longest input in characters, failing with `LimitExceeded::Length` beyond it
+  pub max_length: usize,
+  /// Most tokens, comments aside, failing with `LimitExceeded::Tokens` beyond it
+  pub max_tokens: usize,
+}
+
+impl Default for TokenizeOptions {
+  fn default() -> Self {
+    Self { keep_comments: false, max_length: usize::MAX, max_tokens: usize::MAX }
+  }
+}
+
+#[derive(Clone)]
 struct CharStream<'a> {
   index: usize,
   iterator: Peekable<Fuse<Chars<'a>>>,
@@ -57,29 +440,67 @@ impl<'a> CharStream<'a> {
   fn peek(&mut self) -> char {
     self.iterator.peek().cloned().unwrap_or('\0')
   }
+
+  /// Position to go back to with `restore`
+  fn save(&self) -> Self {
+    self.clone()
+  }
+
+  fn restore(&mut self, checkpoint: Self) {
+    *self = checkpoint;
+  }
 }
 
 #[derive(Debug)]
 pub struct Lexer {
   index: usize,
   tokens: Vec<Token>,
+  /// Character range of each token in the input
+  spans: Vec<Range<usize>>,
+  /// Length of the input in characters
+  length: usize,
+  /// Options the input was tokenized with, which also apply to strings passed to `eval`
+  options: TokenizeOptions,
 }
 
+/// Names of the built-in functions and constants, as the lexer recognizes them
+pub const NAMES: &[&str] = &[
+  "abs", "sqrt", "log", "sin", "cos", "sinpi", "cospi", "tg", "tan", "ctg", "cotan", "asin", "arcsin",
+  "acos", "arccos", "atan", "atan2", "arctan", "exp", "root", "random", "rand", "rnd", "seed", "choose",
+  "ncr", "beta", "lbeta", "zeta", "riemann_zeta", "lambertw", "lamw", "agm", "wrap", "lerp", "mix",
+  "lerp_clamped", "smoothstep", "smootherstep", "round_to", "floor_to", "ceil_to", "sum_list", "prod_list",
+  "max", "min", "map", "zip", "mean", "variance", "svariance", "stdev", "sstdev", "median", "mode", "sort",
+  "reverse", "unique", "len", "get", "slice", "filter", "and", "or", "not",
+  "format_sci", "format_eng",
+  "pi", "e", "phi",
+];
+
+/// Invisible characters skipped like whitespace, though they don't count as such:
+/// zero-width space, non-joiner and joiner, word joiner and byte order mark
+const ZERO_WIDTH: &[char] = &['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
 impl Lexer {
-  fn parse_func_argument(stream: &mut CharStream) -> Result<f64> {
+  /// Parses the base following `log` or `root`, leaving the stream where it was on failure
+  /// so that whatever follows is tokenized on its own
+  fn parse_func_argument(stream: &mut CharStream) -> Result<f64, LexError> {
+    let checkpoint = stream.save();
     match Self::parse_token(stream) {
       Ok(Token::Literal(base)) => Ok(base),
-      _ => Err(Report::msg("Unable to parse function argument")),
+      _ => {
+        stream.restore(checkpoint);
+        Err(LexError::InvalidFunctionArgument)
+      },
     }
   }
 
-  fn parse_token(stream: &mut CharStream) -> Result<Token> {
+  fn parse_token(stream: &mut CharStream) -> Result<Token, LexError> {
     let mut c = stream.peek();
   
     if c.is_ascii_alphabetic() {
       let mut buffer = String::new();
   
-      while c.is_ascii_alphabetic() {
+      // Underscores are allowed after the first letter, as in `round_to`
+      while c.is_ascii_alphabetic() || c == '_' {
         buffer.push(c);
         stream.next();
         c = stream.peek();
@@ -88,22 +509,80 @@ impl Lexer {
       return match buffer.to_ascii_lowercase().as_str() {
         "abs" => Ok(Token::Function(Func::Abs)),
         "sqrt" => Ok(Token::Function(Func::Sqrt)),
-        "log" => Ok(Token::Function(Func::Log(Self::parse_func_argument(stream)?))),
+        // Base is either embedded (`log2(8)`) or passed as an argument (`log(2, 8)`)
+        "log" if stream.peek().is_ascii_digit() || stream.peek() == '.' => {
+          Ok(Token::Function(Self::parse_func_argument(stream).map_or(Func::LogBase, Func::Log)))
+        },
+        "log" => Ok(Token::Function(Func::LogBase)),
         "sin" => Ok(Token::Function(Func::Sin)),
         "cos" => Ok(Token::Function(Func::Cos)),
+        "sinpi" => Ok(Token::Function(Func::SinPi)),
+        "cospi" => Ok(Token::Function(Func::CosPi)),
         "tg" | "tan" => Ok(Token::Function(Func::Tg)),
         "ctg" | "cotan" => Ok(Token::Function(Func::Ctg)),
         "asin" | "arcsin" => Ok(Token::Function(Func::Asin)),
         "acos" | "arccos" => Ok(Token::Function(Func::Acos)),
+        // Unlike in `log2`, the digit is part of the name, but `atan25` is still `atan` of 25
+        "atan" if stream.peek() == '2' => {
+          let checkpoint = stream.save();
+          stream.next();
+          if stream.peek().is_ascii_digit() || stream.peek() == '.' {
+            stream.restore(checkpoint);
+            Ok(Token::Function(Func::Atan))
+          } else {
+            Ok(Token::Function(Func::Atan2))
+          }
+        },
         "atan" | "arctan" => Ok(Token::Function(Func::Atan)),
         "exp" => Ok(Token::Function(Func::Exp)),
         "root" => Ok(Token::Function(Func::Root(Self::parse_func_argument(stream)?))),
+        "random" | "rand" | "rnd" => Ok(Token::Function(Func::Random)),
+        "seed" => Ok(Token::Function(Func::Seed)),
+        "choose" | "ncr" => Ok(Token::Function(Func::Choose)),
+        "beta" => Ok(Token::Function(Func::Beta)),
+        "lbeta" => Ok(Token::Function(Func::Lbeta)),
+        "zeta" | "riemann_zeta" => Ok(Token::Function(Func::Zeta)),
+        "lambertw" | "lamw" => Ok(Token::Function(Func::LambertW)),
+        "agm" => Ok(Token::Function(Func::Agm)),
+        "wrap" => Ok(Token::Function(Func::Wrap)),
+        "lerp" | "mix" => Ok(Token::Function(Func::Lerp)),
+        "lerp_clamped" => Ok(Token::Function(Func::LerpClamped)),
+        "smoothstep" => Ok(Token::Function(Func::Smoothstep)),
+        "smootherstep" => Ok(Token::Function(Func::Smootherstep)),
+        "round_to" => Ok(Token::Function(Func::RoundTo)),
+        "floor_to" => Ok(Token::Function(Func::FloorTo)),
+        "ceil_to" => Ok(Token::Function(Func::CeilTo)),
+        "sum_list" => Ok(Token::Function(Func::SumList)),
+        "prod_list" => Ok(Token::Function(Func::ProdList)),
+        "max" => Ok(Token::Function(Func::Max)),
+        "min" => Ok(Token::Function(Func::Min)),
+        "map" => Ok(Token::Function(Func::Map)),
+        "zip" => Ok(Token::Function(Func::Zip)),
+        "mean" => Ok(Token::Function(Func::Mean)),
+        "variance" => Ok(Token::Function(Func::Variance)),
+        "svariance" => Ok(Token::Function(Func::SampleVariance)),
+        "stdev" => Ok(Token::Function(Func::Stdev)),
+        "sstdev" => Ok(Token::Function(Func::SampleStdev)),
+        "median" => Ok(Token::Function(Func::Median)),
+        "mode" => Ok(Token::Function(Func::Mode)),
+        "sort" => Ok(Token::Function(Func::Sort)),
+        "reverse" => Ok(Token::Function(Func::Reverse)),
+        "unique" => Ok(Token::Function(Func::Unique)),
+        "len" => Ok(Token::Function(Func::Len)),
+        "get" => Ok(Token::Function(Func::Get)),
+        "slice" => Ok(Token::Function(Func::Slice)),
+        "filter" => Ok(Token::Function(Func::Filter)),
+        "and" => Ok(Token::Function(Func::And)),
+        "or" => Ok(Token::Function(Func::Or)),
+        "not" => Ok(Token::Function(Func::Not)),
+        "format_sci" => Ok(Token::Function(Func::FormatSci)),
+        "format_eng" => Ok(Token::Function(Func::FormatEng)),
+
+        "pi" => Ok(Token::Constant(Constant::Pi)),
+        "e" => Ok(Token::Constant(Constant::E)),
+        "phi" => Ok(Token::Constant(Constant::Phi)),
 
-        "pi" => Ok(Token::Literal(PI)),
-        "e" => Ok(Token::Literal(E)),
-        "phi" => Ok(Token::Literal((1. + 5_f64.sqrt()) / 2.)),
-        
-        _ => Err(Report::msg("Unknown identifier")),
+        _ => Ok(Token::Variable(buffer)),
       }
     }
   
@@ -116,30 +595,72 @@ impl Lexer {
         c = stream.peek();
       }
 
-      if buffer.len() == 0 && buffer.as_str().chars().next().unwrap() == '.' {
-        return Err(Report::msg("Invalid numeric literal"));
-      }
-  
-      return Ok(Token::Literal(buffer.parse()?));
+      // A leading or trailing dot is fine (`.2`, `3.`), but not a dot alone or more than one
+      return buffer.parse()
+        .map(Token::Literal)
+        .map_err(|_| LexError::InvalidNumber(buffer));
     }
   
     stream.next();
+
+    if c == '"' {
+      let mut text = String::new();
+      loop {
+        match stream.next() {
+          '"' => return Ok(Token::StringLiteral(text)),
+          '\\' if matches!(stream.peek(), '"' | '\\') => text.push(stream.next()),
+          '\0' => return Err(LexError::UnterminatedString),
+          c => text.push(c),
+        }
+      }
+    }
+
+    // `=` alone is assignment
+    if stream.peek() == '=' {
+      let op = match c {
+        '>' => Some(Op::Ge),
+        '<' => Some(Op::Le),
+        '=' => Some(Op::Eq),
+        '!' => Some(Op::Ne),
+        _ => None,
+      };
+      if let Some(op) = op {
+        stream.next();
+        return Ok(Token::Operator(op))
+      }
+    }
   
     match c {
-      '(' => Ok(Token::LeftBracket),
-      ')' => Ok(Token::RightBracket),
+      '(' => Ok(Token::LeftParen),
+      ')' => Ok(Token::RightParen),
+      '[' => Ok(Token::LeftBracket),
+      ']' => Ok(Token::RightBracket),
+      ',' => Ok(Token::Comma),
+      '=' => Ok(Token::Equals),
       '+' => Ok(Token::Operator(Op::Add)),
       '-' => Ok(Token::Operator(Op::Sub)),
       '*' => Ok(Token::Operator(Op::Mul)),
       '/' => Ok(Token::Operator(Op::Div)),
       '^' => Ok(Token::Operator(Op::Pow)),
-      _ => Err(Report::msg(format!("Unknown token ({})", c)))
+      '>' => Ok(Token::Operator(Op::Gt)),
+      '<' => Ok(Token::Operator(Op::Lt)),
+      '%' => Ok(Token::Percent),
+      _ => Err(LexError::UnknownToken(c))
     }
   }
 
-  fn new(input: &str) -> Result<Self> {
+  fn new(input: &str, options: TokenizeOptions) -> Result<Self, SpanError<LexError>> {
+    // Points at the characters past the limit
+    let length = input.chars().count();
+    if length > options.max_length {
+      return Err(SpanError::new(LexError::Limit(LimitExceeded::Length(options.max_length)), options.max_length..length))
+    }
+
     let mut stream = CharStream::new(input);
     let mut tokens = vec![];
+    let mut spans = vec![];
+    // Tokens other than comments
+    let mut count = 0;
 
     loop {
       let c = stream.peek();
@@ -148,39 +669,182 @@ impl Lexer {
         break
       }
       
-      if c.is_whitespace() {
+      // Text pasted from elsewhere may hold non-breaking or zero-width spaces
+      if c.is_whitespace() || ZERO_WIDTH.contains(&c) {
+        stream.next();
+      } else if c == '#' {
+        let start = stream.index;
         stream.next();
+
+        let mut comment = String::new();
+        while !matches!(stream.peek(), '\0' | '\n') {
+          comment.push(stream.next());
+        }
+
+        if options.keep_comments {
+          tokens.push(Token::Comment(comment.trim_end().to_string()));
+          spans.push(start..stream.index);
+        }
       } else {
-        let token = Self::parse_token(&mut stream)?;
+        let start = stream.index;
+        let token = Self::parse_token(&mut stream)
+          .map_err(|error| SpanError::new(error, start..stream.index))?;
+        if count == options.max_tokens {
+          return Err(SpanError::new(LexError::Limit(LimitExceeded::Tokens(options.max_tokens)), start..stream.index))
+        }
+        count += 1;
         tokens.push(token);
+        spans.push(start..stream.index);
       }
     }
 
     Ok(Self {
       index: 0,
-      tokens
+      tokens,
+      spans,
+      length: stream.index,
+      options,
     })
   }
 
+  /// Index of the first token at or after `index` which isn't a comment
+  fn skip_comments(&self, index: usize) -> usize {
+    let comments = self.tokens.iter().skip(index).take_while(|token| matches!(token, Token::Comment(_))).count();
+    index + comments
+  }
+
   pub fn peek(&self) -> Token {
-    self.tokens.get(self.index).cloned().unwrap_or(Token::End)
+    self.peek_at(0)
+  }
+
+  /// Returns the token `offset` positions after the one returned by `peek`
+  pub fn peek_at(&self, offset: usize) -> Token {
+    self.tokens.iter()
+      .skip(self.index)
+      .filter(|token| !matches!(token, Token::Comment(_)))
+      .nth(offset)
+      .cloned()
+      .unwrap_or(Token::End)
   }
 
+  /// Character range of the token returned by `peek_at(offset)`
+  pub fn peek_span_at(&self, offset: usize) -> Range<usize> {
+    let index = (self.index..self.tokens.len())
+      .filter(|&index| !matches!(self.tokens[index], Token::Comment(_)))
+      .nth(offset)
+      .unwrap_or(self.tokens.len());
+    self.span_at(index)
+  }
+
+  /// Consumes the next token. Not an `Iterator`, since it keeps returning `Token::End` past the end
+  #[allow(clippy::should_implement_trait)]
   pub fn next(&mut self) -> Token {
+    self.index = self.skip_comments(self.index);
     let token = self.peek();
     self.index += 1;
     token
   }
+
+  /// Index into `tokens` of the token returned by `peek`
+  pub fn position(&self) -> usize {
+    self.skip_comments(self.index)
+  }
+
+  /// Undoes the last `next`, which must have returned `token`
+  pub fn push_back(&mut self, token: Token) {
+    assert!(self.index > 0, "push_back before any token was consumed");
+    self.index -= 1;
+    assert_eq!(self.tokens.get(self.index).cloned().unwrap_or(Token::End), token, "push_back of a token that wasn't consumed last");
+  }
+
+  /// Drops the tokens from `index` on, so that the input ends where the first of them started
+  pub fn truncate(&mut self, index: usize) {
+    if index < self.tokens.len() {
+      self.length = self.spans[index].start;
+      self.tokens.truncate(index);
+      self.spans.truncate(index);
+    }
+  }
+
+  /// Options the input was tokenized with
+  pub fn options(&self) -> TokenizeOptions {
+    self.options
+  }
+
+  /// Position to go back to with `restore`
+  pub fn save(&self) -> usize {
+    self.index
+  }
+
+  /// Rewinds to a position returned by `save`, so the same tokens are returned again
+  pub fn restore(&mut self, position: usize) {
+    self.index = position;
+  }
+
+  /// Consumes the remaining tokens, same as calling `next` until it returns `Token::End`
+  pub fn collect_tokens(&mut self) -> Vec<Token> {
+    let tokens = self.tokens.iter()
+      .skip(self.index)
+      .filter(|token| !matches!(token, Token::Comment(_)))
+      .cloned()
+      .collect();
+    self.index = self.tokens.len();
+    tokens
+  }
+
+  /// All tokens including comments, regardless of how many were consumed
+  pub fn tokens(&self) -> &[Token] {
+    &self.tokens
+  }
+
+  /// Character range of the token at `index`, pointing past the input for `Token::End`
+  pub fn span_at(&self, index: usize) -> Range<usize> {
+    self.spans.get(index).cloned().unwrap_or(self.length..self.length + 1)
+  }
+
+  /// Character range of the token returned by `peek`
+  pub fn span(&self) -> Range<usize> {
+    self.span_at(self.skip_comments(self.index))
+  }
+
+  /// Character range of the token last returned by `next`
+  pub fn previous_span(&self) -> Range<usize> {
+    self.span_at(self.index.saturating_sub(1))
+  }
+
+  /// Character range of the first occurrence of `token`
+  pub fn find(&self, token: &Token) -> Option<Range<usize>> {
+    self.tokens.iter().position(|t| t == token).map(|index| self.span_at(index))
+  }
+
+  /// Lists tokens with their index and span, as printed by `tokens`
+  pub fn render(&self) -> String {
+    if self.tokens.is_empty() {
+      return "No tokens".to_string()
+    }
+
+    self.tokens.iter()
+      .zip(&self.spans)
+      .enumerate()
+      .map(|(index, (token, span))| format!("{}: {:?} @ {:?}", index, token, span))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+}
+
+pub fn tokenize(input: &str) -> Result<Lexer, SpanError<LexError>> {
+  tokenize_with_options(input, TokenizeOptions::default())
 }
 
-pub fn tokenize(input: &str) -> Result<Lexer> {
-  Lexer::new(input)
+pub fn tokenize_with_options(input: &str, options: TokenizeOptions) -> Result<Lexer, SpanError<LexError>> {
+  Lexer::new(input, options)
 }
 
 #[cfg(test)]
 #[allow(dead_code, unused_imports)]
 mod tests {
-  use super::{tokenize, Func, Op, Token};
+  use super::{tokenize, tokenize_with_options, Func, Op, Token, TokenizeOptions, NAMES};
+  use crate::error::{LexError, SpanError};
 
   fn test(input: &str, tokens: impl IntoIterator<Item = Token>) {
     let mut lexer = tokenize(input).unwrap();
@@ -211,16 +875,26 @@ mod tests {
 
   #[test]
   fn test_operators() {
-    let input = "+ - * / ^";
+    let input = "+ - * / ^ %";
     let tokens = vec![
       Token::Operator(Op::Add),
       Token::Operator(Op::Sub),
       Token::Operator(Op::Mul),
       Token::Operator(Op::Div),
       Token::Operator(Op::Pow),
+      Token::Percent,
     ];
 
-    test(input, tokens)
+    test(input, tokens);
+    test("> >= < <= == != =", vec![
+      Token::Operator(Op::Gt),
+      Token::Operator(Op::Ge),
+      Token::Operator(Op::Lt),
+      Token::Operator(Op::Le),
+      Token::Operator(Op::Eq),
+      Token::Operator(Op::Ne),
+      Token::Equals,
+    ]);
   }
 
   #[test]
@@ -237,10 +911,226 @@ mod tests {
     test(input, tokens)
   }
 
+  #[test]
+  fn test_names() {
+    // `root` needs its degree right after the name
+    for name in NAMES {
+      let token = tokenize(&format!("{}2", name)).unwrap().next();
+      assert!(matches!(token, Token::Function(_) | Token::Constant(_)), "{}: {:?}", name, token);
+    }
+  }
+
+  #[test]
+  fn test_atan2() {
+    test("atan2(1, 1)", [
+      Token::Function(Func::Atan2),
+      Token::LeftParen,
+      Token::Literal(1.),
+      Token::Comma,
+      Token::Literal(1.),
+      Token::RightParen,
+    ]);
+    test("atan25 atan2.5 arctan2", [
+      Token::Function(Func::Atan),
+      Token::Literal(25.),
+      Token::Function(Func::Atan),
+      Token::Literal(2.5),
+      Token::Function(Func::Atan),
+      Token::Literal(2.),
+    ]);
+  }
+
+  #[test]
+  fn test_strings() {
+    test(r#"eval("1 + 2")"#, [
+      Token::Variable("eval".into()),
+      Token::LeftParen,
+      Token::StringLiteral("1 + 2".into()),
+      Token::RightParen,
+    ]);
+    test(r#""" "a \"b\" \\ \n""#, [Token::StringLiteral("".into()), Token::StringLiteral(r#"a "b" \ \n"#.into())]);
+    assert_eq!(tokenize(r#"1 + "2"#).unwrap_err(), SpanError::new(LexError::UnterminatedString, 4..7));
+  }
+
+  #[test]
+  fn test_lists() {
+    test("[1, (2)]", [
+      Token::LeftBracket,
+      Token::Literal(1.),
+      Token::Comma,
+      Token::LeftParen,
+      Token::Literal(2.),
+      Token::RightParen,
+      Token::RightBracket,
+    ]);
+    test("map(abs,[])", [
+      Token::Function(Func::Map),
+      Token::LeftParen,
+      Token::Function(Func::Abs),
+      Token::Comma,
+      Token::LeftBracket,
+      Token::RightBracket,
+      Token::RightParen,
+    ]);
+  }
+
+  #[test]
+  fn test_variables() {
+    let input = "x + unknownfunction(727)";
+    let tokens = vec![
+      Token::Variable("x".into()),
+      Token::Operator(Op::Add),
+      Token::Variable("unknownfunction".into()),
+      Token::LeftParen,
+      Token::Literal(727.),
+      Token::RightParen,
+    ];
+
+    test(input, tokens)
+  }
+
+  #[test]
+  fn test_log_base_argument() {
+    let input = "log(2, 8)";
+    let tokens = vec![
+      Token::Function(Func::LogBase),
+      Token::LeftParen,
+      Token::Literal(2.),
+      Token::Comma,
+      Token::Literal(8.),
+      Token::RightParen,
+    ];
+
+    test(input, tokens)
+  }
+
   #[test]
   fn test_invalid_input() {
-    test_fail("log(10)");
     test_fail("root(4)");
-    test_fail("unknownfunction(727)");
+    test_fail(".");
+    test_fail("1 + .");
+    test_fail("1.2.3");
+    assert_eq!(tokenize("2 * .").unwrap_err().to_string(), "Invalid numeric literal: .");
+    assert_eq!(tokenize("1.2.3").unwrap_err().error, LexError::InvalidNumber("1.2.3".to_string()));
+    assert_eq!(tokenize("2 $ 3").unwrap_err(), SpanError::new(LexError::UnknownToken('$'), 2..3));
+  }
+
+  #[test]
+  fn test_unicode_whitespace() {
+    let tokens = [Token::Literal(2.), Token::Operator(Op::Mul), Token::Literal(3.)];
+    test("2\u{A0}*\u{A0}3", tokens.clone());
+    test("2\u{200B}*\u{2003}3\u{FEFF}", tokens.clone());
+    test("\u{2060}2 *\u{200D}\t3", tokens);
+
+    // Characters which remain unknown are named by code point
+    assert_eq!(tokenize("2 \u{1F600} 3").unwrap_err(), SpanError::new(LexError::UnknownToken('\u{1F600}'), 2..3));
+    assert_eq!(tokenize("2 \u{1F600} 3").unwrap_err().to_string(), "Unknown character U+1F600");
+    assert_eq!(tokenize("2\u{2061}3").unwrap_err().to_string(), "Unknown character U+2061");
+    assert_eq!(tokenize("2 € 3").unwrap_err().to_string(), "Unknown character U+20AC");
+  }
+
+  #[test]
+  fn test_render() {
+    assert_eq!(tokenize("log2(x) * 10%").unwrap().render(), [
+      "0: Function(Log(2.0)) @ 0..4",
+      "1: LeftParen @ 4..5",
+      "2: Variable(\"x\") @ 5..6",
+      "3: RightParen @ 6..7",
+      "4: Operator(Mul) @ 8..9",
+      "5: Literal(10.0) @ 10..12",
+      "6: Percent @ 12..13",
+    ].join("\n"));
+    assert_eq!(tokenize("  ").unwrap().render(), "No tokens");
+  }
+
+  #[test]
+  fn test_comments() {
+    test("2 + 3 # five", [Token::Literal(2.), Token::Operator(Op::Add), Token::Literal(3.)]);
+    test("# nothing", []);
+
+    let options = TokenizeOptions { keep_comments: true, ..Default::default() };
+    let mut lexer = tokenize_with_options("2 # two\n+ 3 # three", options).unwrap();
+    assert_eq!(lexer.render(), [
+      "0: Literal(2.0) @ 0..1",
+      "1: Comment(\" two\") @ 2..7",
+      "2: Operator(Add) @ 8..9",
+      "3: Literal(3.0) @ 10..11",
+      "4: Comment(\" three\") @ 12..19",
+    ].join("\n"));
+
+    // The parser never sees comments
+    assert_eq!(lexer.next(), Token::Literal(2.));
+    assert_eq!(lexer.peek_at(1), Token::Literal(3.));
+    assert_eq!(lexer.span(), 8..9);
+    assert_eq!(lexer.next(), Token::Operator(Op::Add));
+    assert_eq!(lexer.next(), Token::Literal(3.));
+    assert_eq!(lexer.next(), Token::End);
+  }
+
+  #[test]
+  fn test_collect_tokens() {
+    for input in ["", "2 + sqrt(x) # root", "log(2, 8) * 10%", "f(x) = x ^ 2"] {
+      let options = TokenizeOptions { keep_comments: true, ..Default::default() };
+      let mut collected = tokenize_with_options(input, options).unwrap();
+      let mut lexer = tokenize_with_options(input, options).unwrap();
+
+      let mut expected = vec![];
+      while lexer.peek() != Token::End {
+        expected.push(lexer.next());
+      }
+
+      assert_eq!(collected.collect_tokens(), expected);
+      assert_eq!(collected.next(), Token::End);
+      assert_eq!(collected.tokens().len(), expected.len() + input.contains('#') as usize);
+    }
+
+    let mut lexer = tokenize("1 + 2").unwrap();
+    lexer.next();
+    assert_eq!(lexer.collect_tokens(), vec![Token::Operator(Op::Add), Token::Literal(2.)]);
+    assert!(lexer.collect_tokens().is_empty());
+    assert_eq!(lexer.tokens().len(), 3);
+  }
+
+  #[test]
+  fn test_backtracking() {
+    let options = TokenizeOptions { keep_comments: true, ..Default::default() };
+    let mut lexer = tokenize_with_options("f(x) = x # comment\n + 1", options).unwrap();
+    let position = lexer.save();
+    let consumed: Vec<_> = (0..6).map(|_| lexer.next()).collect();
+    assert_eq!(consumed[0], Token::Variable("f".to_string()));
+
+    lexer.restore(position);
+    assert_eq!((0..6).map(|_| lexer.next()).collect::<Vec<_>>(), consumed);
+
+    // Comments are skipped in both directions
+    let token = lexer.next();
+    assert_eq!(token, Token::Operator(Op::Add));
+    lexer.push_back(token);
+    assert_eq!(lexer.peek(), Token::Operator(Op::Add));
+    assert_eq!(lexer.collect_tokens(), [Token::Operator(Op::Add), Token::Literal(1.)]);
+
+    let token = lexer.next();
+    assert_eq!(token, Token::End);
+    lexer.push_back(token);
+    assert_eq!(lexer.next(), Token::End);
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_push_back_mismatch() {
+    let mut lexer = tokenize("1 + 2").unwrap();
+    lexer.next();
+    lexer.push_back(Token::Literal(2.));
+  }
+
+  #[test]
+  fn test_func_argument_recovery() {
+    // Without a digit or `.` after it, `log` doesn't try to read a base
+    test("log + 2", [Token::Function(Func::LogBase), Token::Operator(Op::Add), Token::Literal(2.)]);
+
+    // The base `.` fails to parse and the stream is restored before it, so it's tokenized
+    // again on its own and the error points at it rather than at `log`
+    assert_eq!(tokenize("log.x").unwrap_err(), SpanError::new(LexError::InvalidNumber(".".to_string()), 3..4));
+    assert_eq!(tokenize("root + 2").unwrap_err(), SpanError::new(LexError::InvalidFunctionArgument, 0..4));
   }
 }
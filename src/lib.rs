@@ -0,0 +1,94 @@
+//! Calculator library: tokenizing, parsing and evaluating expressions,
+//! along with sessions that keep variables and user-defined functions between lines
+
+use eyre::{eyre, Result};
+
+use crate::session::Session;
+
+pub mod bytecode;
+mod derive;
+pub mod diagnostic;
+pub mod diff;
+pub mod display;
+pub mod error;
+pub mod eval;
+#[cfg(feature = "serde")]
+pub mod json;
+mod latex;
+pub mod lexer;
+mod normalize;
+pub mod parser;
+pub mod random;
+mod rpn;
+pub mod session;
+pub mod settings;
+mod simplify;
+mod substitute;
+#[cfg(test)]
+mod testing;
+pub mod units;
+pub mod walk;
+
+pub trait RoundWithPrecision {
+  fn round_with_precision(&self, precision: u32) -> Self;
+}
+
+impl RoundWithPrecision for f64 {
+  fn round_with_precision(&self, precision: u32) -> Self {
+    if self.is_infinite() || self.is_nan() {
+      return *self
+    }
+
+    let m = 10_f64.powi(precision as i32);
+    let scaled = self * m;
+    // Values this large have no fractional digits to round anyway
+    if scaled.is_infinite() {
+      return *self
+    }
+    scaled.round() / m
+  }
+}
+
+/// Tokenizes, parses and evaluates a single expression in a fresh session.
+/// Errors tell which of the stages failed
+///
+/// ```
+/// assert_eq!(calc::calculate("2 + 3 * 4").unwrap(), 14.);
+/// assert_eq!(calc::calculate("sqrt(16) + 2 ^ 3").unwrap(), 12.);
+/// assert_eq!(calc::calculate("1 km to m").unwrap(), 1000.);
+/// ```
+///
+/// ```
+/// use calc::error::{EvalError, ExpressionError};
+///
+/// let report = calc::calculate("2 * (1 / 0)").unwrap_err();
+/// assert_eq!(format!("{:#}", report), "Error during evaluation: Invalid operation: division by zero while evaluating `1 / 0`");
+/// let error = report.downcast_ref::<ExpressionError>().unwrap();
+/// assert_eq!(error.error, EvalError::DivisionByZero);
+/// assert_eq!(error.expression.as_deref(), Some("1 / 0"));
+/// ```
+pub fn calculate(input: &str) -> Result<f64> {
+  Ok(Session::default().execute(input)?.ok_or_else(|| eyre!("Expected an expression"))?.into_number()?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{calculate, RoundWithPrecision};
+
+  #[test]
+  fn test_calculate() {
+    assert_eq!(calculate("(1 + 2) * 3").unwrap(), 9.);
+    assert!(calculate("f(x) = x").is_err());
+    assert!(calculate("[1, 2]").is_err());
+  }
+
+  #[test]
+  fn test_round_with_precision() {
+    assert_eq!(1.23456.round_with_precision(2), 1.23);
+    assert_eq!(f64::INFINITY.round_with_precision(5), f64::INFINITY);
+    assert_eq!(f64::NEG_INFINITY.round_with_precision(5), f64::NEG_INFINITY);
+    assert!(f64::NAN.round_with_precision(5).is_nan());
+    assert_eq!(1e305.round_with_precision(5), 1e305);
+    assert_eq!(f64::MAX.round_with_precision(15), f64::MAX);
+  }
+}
@@ -1,104 +1,185 @@
-use std::io::{stdin, stdout, Write as _};
+use std::{io::{stderr, stdin, stdout, BufRead as _, IsTerminal as _, Write as _}, process::ExitCode};
 use eyre::Result;
+use rustyline::error::ReadlineError;
 
-use crate::{lexer::tokenize, parser::parse_expression};
+use calc::{diagnostic::Diagnostic, session::{Session, Timings}, settings::Settings};
 
-mod eval;
-mod lexer;
-mod parser;
+use crate::{args::Args, clipboard::Clipboard, input::Input, repl::Repl};
 
-trait RoundWithPrecision {
-  fn round_with_precision(&self, precision: u32) -> Self;
-}
+mod args;
+mod clipboard;
+mod help;
+mod history;
+mod input;
+mod plot;
+mod repl;
+mod table;
+
+/// Formats an error message, re-printing the input with a caret under the offending span if there is one,
+/// followed by a hint on fixing it if there is one
+fn render_error(diagnostic: &Diagnostic, color: bool) -> String {
+  let message = match &diagnostic.span {
+    Some(span) => {
+      let marker = format!("{}{}", " ".repeat(span.start), "^".repeat(span.len().max(1)));
+      match color {
+        true => format!("{}\n\x1b[31m{} {}\x1b[0m", diagnostic.source, marker, diagnostic.message),
+        false => format!("{}\n{} {}", diagnostic.source, marker, diagnostic.message),
+      }
+    },
+    None => diagnostic.message.clone(),
+  };
 
-impl RoundWithPrecision for f64 {
-  fn round_with_precision(&self, precision: u32) -> Self {
-    let m = 10_i32.pow(precision) as f64;
-    (self * m).round() / m
+  match &diagnostic.help {
+    Some(help) => format!("{}\nhelp: {}", message, help),
+    None => message,
   }
 }
 
-fn main() -> Result<()> {
-  let mut input = String::new();
+/// Evaluates each line of standard input without any prompts, copying the last result if `copy` is set.
+/// Fails if any of the lines failed
+fn run_filter(settings: Settings, time: bool, copy: bool) -> Result<ExitCode> {
+  let mut session = Session {
+    settings,
+    ..Default::default()
+  };
+  let mut failed = false;
+  let color = stderr().is_terminal();
 
-  println!("Calculator. Use \"funcs\", \"ops\", or \"consts\" for help.");
-  println!("\"exit\" to exit");
-
-  loop {
-    input.clear();
+  for line in stdin().lock().lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue
+    }
 
-    print!("> ");
-    stdout().flush()?;
-    stdin().read_line(&mut input)?;
-    if let Some('\n') = input.chars().next_back() {
-      input.pop();
+    let mut timings = Timings::default();
+    match session.execute_timed(&line, &mut timings) {
+      Ok(Some(result)) => println!("{}", session.settings.format_value(&result)),
+      Ok(None) => {},
+      Err(report) => {
+        eprintln!("{}", render_error(&session.diagnose(&line, &report), color));
+        failed = true;
+      },
     }
-    if let Some('\r') = input.chars().next_back() {
-      input.pop();
+
+    // Timings go to stderr so that the results can still be piped
+    if time {
+      eprintln!("{}", timings);
     }
+  }
 
-    match input.as_str() {
-      "" => {
-        println!("Author: Гаврилович Владислав");
-        println!("For help, type \"funcs\", \"ops\", or \"consts\"");
+  if copy {
+    match session.ans {
+      Some(ans) => {
+        if let Err(report) = Clipboard::default().set(&ans.to_string()) {
+          eprintln!("{}", report);
+          failed = true;
+        }
       },
-      "funcs" => {
-        println!("Available functions:");
-        println!("* abs(x) - absolute value");
-        println!("* sqrt(x) - square root");
-        println!("* logX(y) - base X logarithm of y");
-        println!("* sin(x) - sine");
-        println!("* cos(x) - cosine");
-        println!("* tg(x) / tan(x) - tangent");
-        println!("* ctg(x) / cotan(x) - cotangent");
-        println!("* asin(x) / arcsin(x) - arcsine");
-        println!("* acos(x) / arccos(x) - arccosine");
-        println!("* atan(x) / arctan(x) - arctangent");
-        println!("* exp(x) - exponent (e^x)");
-        println!("* rootX(y) - root of y with base X");
+      None => {
+        eprintln!("No result to copy");
+        failed = true;
       },
-      "ops" => {
-        println!("Available operators:");
-        println!("* Addition (+)");
-        println!("* Subtraction (-)");
-        println!("* Multiplication (*)");
-        println!("* Division (/)");
-        println!("* Power (^)");
+    }
+  }
+
+  Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+/// Prints the syntax tree of each line of standard input as JSON
+#[cfg(feature = "serde")]
+fn run_ast_json() -> Result<ExitCode> {
+  let mut failed = false;
+  let color = stderr().is_terminal();
+
+  for line in stdin().lock().lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue
+    }
+
+    match calc::json::ast_json(&line) {
+      Ok(json) => println!("{}", json),
+      Err(report) => {
+        eprintln!("{}", render_error(&Diagnostic::new(&line, &report, &[]), color));
+        failed = true;
       },
-      "consts" => {
-        println!("Available constants:");
-        println!("* pi - 3.14159...");
-        println!("* e - 2.71828...");
-        println!("* phi - golden ratio (1.61803...)");
+    }
+  }
+
+  Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+fn main() -> Result<ExitCode> {
+  let args = Args::parse()?;
+  let mut settings = Settings::load();
+  // Flags override the config file
+  if args.no_history {
+    settings.history = false;
+  }
+  if let Some(prompt) = args.prompt {
+    settings.prompt = prompt;
+  }
+  if let Some(lang) = args.lang {
+    settings.lang = lang;
+  }
+
+  #[cfg(feature = "serde")]
+  if args.ast_json {
+    return run_ast_json()
+  }
+
+  if args.stdin || !stdin().is_terminal() {
+    return run_filter(settings, args.time, args.copy)
+  }
+
+  let mut editor = Input::new(settings.history)?;
+  let prompt = settings.prompt.clone();
+  let mut repl = Repl::new(settings, stdout().is_terminal());
+  repl.quiet = args.quiet;
+  repl.time = args.time;
+
+  if !args.quiet {
+    println!("{}", help::BANNER.get(repl.session.settings.lang));
+  }
+
+  loop {
+    let input = match editor.read_expression(&prompt) {
+      Ok(Some(input)) => input,
+      Ok(None) => continue,
+      Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+      Err(err) => return Err(err.into()),
+    };
+    editor.add_history(&input);
+
+    match repl.handle(&input) {
+      Some(output) => {
+        print!("{}", output);
+        stdout().flush()?;
       },
-      "exit" => break,
-      input => {
-        let mut lexer = match tokenize(input) {
-          Ok(lexer) => lexer,
-          Err(report) => {
-            println!("Error during tokenization: {:?}", report);
-            continue
-          }
-        };
-        let ast = match parse_expression(&mut lexer) {
-          Ok(ast) => ast,
-          Err(report) => {
-            println!("Error during AST construction: {:?}", report);
-            continue
-          }
-        };
-        let result = match ast.evaluate() {
-          Ok(result) => result,
-          Err(report) => {
-            println!("Error during evaluation: {:?}", report);
-            continue
-          }
-        };
-      
-        println!("{}", result.round_with_precision(5));
-      }
+      None => break,
     }
-  }  
+  }
+
+  Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+  use calc::{calculate, diagnostic::Diagnostic};
+  use super::render_error;
 
-  Ok(())
+  fn test(input: &str, expected: &str) {
+    let report = calculate(input).unwrap_err();
+    assert_eq!(render_error(&Diagnostic::new(input, &report, &[]), false), expected)
+  }
+
+  #[test]
+  fn test_render_error() {
+    test("2 + foo", "2 + foo\n    ^^^ Error during evaluation: Undefined variable: foo");
+    test("(*2 + 3", "(*2 + 3\n ^ Error during AST construction: Unexpected token: expected a number, a name, '(', '[' or a sign, found '*'");
+    test("1 + $ 2", "1 + $ 2\n    ^ Error during tokenization: Unknown character $");
+    test("(1 + 2", "(1 + 2\n^ Error during AST construction: Unclosed '(' opened at column 1");
+    test("1 / 0", "Error during evaluation: Invalid operation: division by zero while evaluating `1 / 0`");
+    test("sqr(2)", "sqr(2)\n^^^ Error during evaluation: Undefined function: sqr\nhelp: did you mean `sqrt`?");
+  }
 }
@@ -0,0 +1,145 @@
+use std::cmp::Ordering;
+
+use crate::{lexer::Op, parser::Node};
+
+/// Collects the operands of a chain of `op`, looking through nested chains.
+/// Subtractions are added as negations, which evaluates exactly the same
+fn collect(mut node: Node, op: Op, operands: &mut Vec<Node>) {
+  match &mut node {
+    Node::BinOp(node_op, left, right) if *node_op == op => {
+      collect(left.take(), op, operands);
+      collect(right.take(), op, operands);
+    },
+    Node::BinOp(Op::Sub, left, right) if op == Op::Add => {
+      collect(left.take(), op, operands);
+      operands.push(negate(right.take()));
+    },
+    _ => operands.push(node),
+  }
+}
+
+fn negate(mut node: Node) -> Node {
+  match &mut node {
+    Node::Neg(negated) => negated.take(),
+    _ => Node::Neg(Box::new(node)),
+  }
+}
+
+/// Literals first by value, then everything else by how it's printed.
+/// Negations are ordered by what they negate, right after it
+fn compare(a: &Node, b: &Node) -> Ordering {
+  let strip = |node: &Node| match node {
+    Node::Neg(node) => (node.as_ref().clone(), true),
+    node => (node.clone(), false),
+  };
+  let ((a, a_negated), (b, b_negated)) = (strip(a), strip(b));
+
+  let ordering = match (&a, &b) {
+    (Node::Immediate(a), Node::Immediate(b)) => a.total_cmp(b),
+    (Node::Immediate(_), _) => Ordering::Less,
+    (_, Node::Immediate(_)) => Ordering::Greater,
+    (a, b) => a.to_string().cmp(&b.to_string()),
+  };
+  ordering.then(a_negated.cmp(&b_negated))
+}
+
+impl Node {
+  /// Rewrites the expression into a canonical form, so that expressions which only differ
+  /// in the order of `+` and `*` operands normalize to the same tree.
+  /// Chains of `+` and `*` are flattened and sorted, and subtraction becomes addition of a negation.
+  /// Division isn't turned into multiplication by a reciprocal, which would round differently.
+  /// Reordering may still change the result in the last bits, and the order of `rand()` calls.
+  /// Trees deeper than `MAX_DEPTH` are kept as they are
+  pub fn normalize(&self) -> Node {
+    match self.check_depth() {
+      Ok(()) => self.normalize_unchecked(),
+      Err(_) => self.clone(),
+    }
+  }
+
+  fn normalize_unchecked(&self) -> Node {
+    match self {
+      Node::Neg(node) => negate(node.normalize_unchecked()),
+      Node::BinOp(op @ (Op::Add | Op::Sub | Op::Mul), _, _) => {
+        let op = if *op == Op::Mul { Op::Mul } else { Op::Add };
+        let mut operands = vec![];
+        collect(self.clone(), op, &mut operands);
+
+        let mut operands: Vec<Node> = operands.iter().map(Node::normalize_unchecked).collect();
+        operands.sort_by(compare);
+        operands.into_iter()
+          .reduce(|left, right| Node::BinOp(op, Box::new(left), Box::new(right)))
+          .expect("a chain has at least two operands")
+      },
+      Node::BinOp(op, left, right) => Node::BinOp(*op, Box::new(left.normalize_unchecked()), Box::new(right.normalize_unchecked())),
+      Node::Func(func, args) => Node::Func(*func, args.iter().map(Node::normalize_unchecked).collect()),
+      Node::Call(name, args) => Node::Call(name.clone(), args.iter().map(Node::normalize_unchecked).collect()),
+      Node::List(items) => Node::List(items.iter().map(Node::normalize_unchecked).collect()),
+      Node::If { cond, then, else_ } => Node::If {
+        cond: Box::new(cond.normalize_unchecked()),
+        then: Box::new(then.normalize_unchecked()),
+        else_: Box::new(else_.normalize_unchecked()),
+      },
+      Node::Let { name, value, body } => Node::Let { name: name.clone(), value: Box::new(value.normalize_unchecked()), body: Box::new(body.normalize_unchecked()) },
+      Node::Piecewise(cases, default) => Node::Piecewise(
+        cases.iter().map(|(cond, value)| (cond.normalize_unchecked(), value.normalize_unchecked())).collect(),
+        default.as_ref().map(|default| Box::new(default.normalize_unchecked())),
+      ),
+      node => node.clone(),
+    }
+  }
+
+  /// Whether both expressions normalize to the same tree, as `a + b` and `b + a` do
+  pub fn structurally_equal(&self, other: &Node) -> bool {
+    self.normalize() == other.normalize()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{lexer::tokenize, parser::{parse_expression, Node}, testing::{assert_agree, close}};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  fn normalize(input: &str) -> String {
+    parse(input).normalize().to_string()
+  }
+
+  #[test]
+  fn test_normalize() {
+    assert_eq!(normalize("3*x*2"), "2 * 3 * x");
+    assert_eq!(normalize("2*3*x"), "2 * 3 * x");
+    assert_eq!(normalize("c - b + a"), "a + -b + c");
+    assert_eq!(normalize("x * (y + 1) - -z"), "(1 + y) * x + z");
+    assert_eq!(normalize("(b + a) / (d * c) ^ (2 + 1)"), "(a + b) / (c * d) ^ (1 + 2)");
+    assert_eq!(normalize("sin(y * x) - 1"), "-1 + sin(x * y)");
+    assert_eq!(normalize("x - y + y"), "x + y + -y");
+
+    let equal = |a, b| parse(a).structurally_equal(&parse(b));
+    assert!(equal("a + b * c", "c * b + a"));
+    assert!(equal("a - (b - c)", "a + -(b - c)"));
+    assert!(equal("f(x + 1)", "f(1 + x)"));
+    assert!(!equal("x - y", "y - x"));
+    assert!(!equal("x / 2", "2 / x"));
+    assert!(!equal("2 ^ x", "x ^ 2"));
+  }
+
+  #[test]
+  fn test_semantics() {
+    let expressions = [
+      "3 * x * 2 + y - 1",
+      "x - (y - 2 * x) * (y + 1)",
+      "-(x * y) - -x + y / (x - 3)",
+      "(x + y) ^ 2 % 7 - sqrt(abs(x * y))",
+      "atan2(y + x, x * 2 - y) * lerp(x, y, 0.5)",
+    ];
+    for input in expressions {
+      let original = parse(input);
+      let normalized = original.normalize();
+      assert_eq!(normalized.normalize(), normalized, "{}", input);
+      assert_agree(input, 2, |options| original.evaluate(options), |options| normalized.evaluate(options), close);
+    }
+  }
+}
@@ -1,89 +1,1006 @@
-use crate::lexer::{Func, Lexer, Op, Token};
-use eyre::{Report, Result};
+use std::{fmt, hash::{Hash, Hasher}, mem, ops::Range};
 
-#[derive(Debug)]
+use crate::{error::{LimitExceeded, ParseError, SpanError}, lexer::{hash_float, same_float, tokenize_with_options, Func, Lexer, Op, Token, TokenizeOptions}};
+
+type Result<T> = std::result::Result<T, SpanError<ParseError>>;
+
+/// Syntax tree of an expression. Equality is structural, with literals compared by `same_float`,
+/// so a tree containing a NaN literal is still equal to itself
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
 pub enum Node {
-  Immediate(f64),
+  Immediate(#[cfg_attr(feature = "serde", serde(with = "crate::json::float"))] f64),
+  Variable(String),
+  Neg(Box<Node>),
   BinOp(Op, Box<Node>, Box<Node>),
-  Func(Func, Box<Node>),
+  Func(Func, Vec<Node>),
+  /// Call of a user-defined function
+  Call(String, Vec<Node>),
+  /// Built-in function passed by name, like `sin` in `map(sin, x)`
+  FuncRef(Func),
+  /// Operator passed as a function of two arguments, like `+` in `zip(+, a, b)`
+  OpRef(Op),
+  /// `[a, b, ...]`
+  List(Vec<Node>),
+  /// `if(cond, then, else)`, evaluating only the branch that is taken
+  If { cond: Box<Node>, then: Box<Node>, else_: Box<Node> },
+  /// `piecewise(cond, value, ..., default)`, the value of the first condition which holds,
+  /// otherwise the default if there is one. Only the conditions checked and the value taken are evaluated
+  Piecewise(Vec<(Node, Node)>, Option<Box<Node>>),
+  /// `let name = value in body`, with `name` bound to the value only within the body
+  Let { name: String, value: Box<Node>, body: Box<Node> },
+}
+
+/// Compares the nodes in the order of `iter`, so that trees of any depth can be compared.
+/// Each node is compared along with its number of children, so once all pairs match, both trees have ended
+impl PartialEq for Node {
+  fn eq(&self, other: &Self) -> bool {
+    self.iter().zip(other.iter()).all(|pair| match pair {
+      (Node::Immediate(a), Node::Immediate(b)) => same_float(*a, *b),
+      (Node::Variable(a), Node::Variable(b)) => a == b,
+      (Node::Neg(_), Node::Neg(_)) | (Node::If { .. }, Node::If { .. }) => true,
+      (Node::BinOp(a, _, _), Node::BinOp(b, _, _)) | (Node::OpRef(a), Node::OpRef(b)) => a == b,
+      (Node::Func(a, a_args), Node::Func(b, b_args)) => a == b && a_args.len() == b_args.len(),
+      (Node::Call(a, a_args), Node::Call(b, b_args)) => a == b && a_args.len() == b_args.len(),
+      (Node::FuncRef(a), Node::FuncRef(b)) => a == b,
+      (Node::List(a), Node::List(b)) => a.len() == b.len(),
+      (Node::Piecewise(a, a_default), Node::Piecewise(b, b_default)) => a.len() == b.len() && a_default.is_some() == b_default.is_some(),
+      (Node::Let { name: a, .. }, Node::Let { name: b, .. }) => a == b,
+      _ => false,
+    })
+  }
+}
+
+/// Allows trees as `HashMap` keys, e.g. for memoizing their values
+impl Eq for Node {}
+
+/// Structural, consistent with equality: literals hash by their bits, with both zeros alike.
+/// Nodes are hashed in the order of `iter` along with their number of children, so that trees of any depth can be hashed
+impl Hash for Node {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    for node in self.iter() {
+      mem::discriminant(node).hash(state);
+      match node {
+        Node::Immediate(value) => hash_float(*value, state),
+        Node::Variable(name) => name.hash(state),
+        Node::Neg(_) | Node::If { .. } => {},
+        Node::BinOp(op, _, _) | Node::OpRef(op) => op.hash(state),
+        Node::Func(func, args) => {
+          func.hash(state);
+          args.len().hash(state);
+        },
+        Node::Call(name, args) => {
+          name.hash(state);
+          args.len().hash(state);
+        },
+        Node::FuncRef(func) => func.hash(state),
+        Node::List(items) => items.len().hash(state),
+        Node::Piecewise(cases, default) => {
+          cases.len().hash(state);
+          default.is_some().hash(state);
+        },
+        Node::Let { name, .. } => name.hash(state),
+      }
+    }
+  }
+}
+
+/// Formats like a derived `Debug`, except that trees deeper than `MAX_DEPTH` are summarized by their depth,
+/// since formatting recurses once per level
+impl fmt::Debug for Node {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.check_depth() {
+      Ok(()) => Unchecked(self).fmt(f),
+      Err(_) => write!(f, "Node {{ depth: {}, .. }}", self.depth()),
+    }
+  }
+}
+
+/// Node formatted without checking its depth
+struct Unchecked<'a>(&'a Node);
+
+/// Arguments or list items, formatted as a list
+struct Nodes<'a>(&'a [Node]);
+
+/// Conditions and values of `Node::Piecewise`, formatted as a list of pairs
+struct Cases<'a>(&'a [(Node, Node)]);
+
+impl fmt::Debug for Unchecked<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.0 {
+      Node::Immediate(value) => f.debug_tuple("Immediate").field(value).finish(),
+      Node::Variable(name) => f.debug_tuple("Variable").field(name).finish(),
+      Node::Neg(node) => f.debug_tuple("Neg").field(&Unchecked(node)).finish(),
+      Node::BinOp(op, left, right) => f.debug_tuple("BinOp").field(op).field(&Unchecked(left)).field(&Unchecked(right)).finish(),
+      Node::Func(func, args) => f.debug_tuple("Func").field(func).field(&Nodes(args)).finish(),
+      Node::Call(name, args) => f.debug_tuple("Call").field(name).field(&Nodes(args)).finish(),
+      Node::FuncRef(func) => f.debug_tuple("FuncRef").field(func).finish(),
+      Node::OpRef(op) => f.debug_tuple("OpRef").field(op).finish(),
+      Node::List(items) => f.debug_tuple("List").field(&Nodes(items)).finish(),
+      Node::If { cond, then, else_ } => f.debug_struct("If")
+        .field("cond", &Unchecked(cond))
+        .field("then", &Unchecked(then))
+        .field("else_", &Unchecked(else_))
+        .finish(),
+      Node::Piecewise(cases, default) => f.debug_tuple("Piecewise").field(&Cases(cases)).field(&default.as_deref().map(Unchecked)).finish(),
+      Node::Let { name, value, body } => f.debug_struct("Let")
+        .field("name", name)
+        .field("value", &Unchecked(value))
+        .field("body", &Unchecked(body))
+        .finish(),
+    }
+  }
+}
+
+impl fmt::Debug for Nodes<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_list().entries(self.0.iter().map(Unchecked)).finish()
+  }
+}
+
+impl fmt::Debug for Cases<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_list().entries(self.0.iter().map(|(cond, value)| (Unchecked(cond), Unchecked(value)))).finish()
+  }
+}
+
+/// Arguments of `Node::Piecewise` in the order they're written, each condition followed by its value
+pub(crate) fn piecewise_arguments<'a>(cases: &'a [(Node, Node)], default: &'a Option<Box<Node>>) -> impl Iterator<Item = &'a Node> {
+  cases.iter().flat_map(|(cond, value)| [cond, value]).chain(default.as_deref())
+}
+
+/// A single line of input
+#[derive(Debug)]
+pub enum Statement {
+  Expression(Node),
+  /// `name = expression`
+  Assign(String, Node),
+  /// `name(params) = body`
+  Define(String, Vec<String>, Node),
+}
+
+/// Limits applied while parsing
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+  /// How deeply parentheses, brackets, arguments and negations may nest.
+  /// Every level takes several stack frames, so unlimited nesting could overflow the stack.
+  /// The default is small enough for an unoptimized build on a 2 MB thread, which takes up to about 10 KB per level
+  pub max_depth: usize,
+  /// Most nodes in the syntax tree, failing with `LimitExceeded::Nodes` beyond it
+  pub max_nodes: usize,
+}
+
+impl Default for ParseOptions {
+  fn default() -> Self {
+    Self { max_depth: 100, max_nodes: usize::MAX }
+  }
+}
+
+/// Fails if the tree has more nodes than allowed, pointing at everything parsed
+fn check_nodes(node: Node, options: ParseOptions, lexer: &Lexer) -> Result<Node> {
+  if node.iter().nth(options.max_nodes).is_some() {
+    return Err(SpanError::new(ParseError::Limit(LimitExceeded::Nodes(options.max_nodes)), 0..lexer.previous_span().end))
+  }
+  Ok(node)
+}
+
+/// Nesting level of the part being parsed
+#[derive(Clone, Copy)]
+struct Depth {
+  level: usize,
+  limit: usize,
+  /// Parentheses and brackets enclosing the part, so a closing one outside all of them is unmatched
+  brackets: usize,
+}
+
+impl Depth {
+  fn new(options: ParseOptions) -> Self {
+    Self { level: 0, limit: options.max_depth, brackets: 0 }
+  }
+
+  /// One level deeper, failing past the limit at the token just consumed
+  fn nested(self, lexer: &Lexer) -> Result<Depth> {
+    if self.level >= self.limit {
+      return Err(SpanError::new(ParseError::TooDeep(self.limit), lexer.previous_span()))
+    }
+    Ok(Depth { level: self.level + 1, ..self })
+  }
+
+  /// One level deeper, inside a parenthesis or bracket just consumed
+  fn bracketed(self, lexer: &Lexer) -> Result<Depth> {
+    Ok(Depth { brackets: self.brackets + 1, ..self.nested(lexer)? })
+  }
 }
 
-/// `bracket: bool` is used to only parse bracketed expressions when dealing with functions
-/// Otherwise "abs-2" may count as a valid expression
-fn parse_primary(lexer: &mut Lexer, bracket: bool) -> Result<Node> {
+/// Error for input ending before the parenthesis or bracket `open` at `span` is closed
+fn unclosed(open: Token, span: Range<usize>) -> SpanError<ParseError> {
+  SpanError::new(ParseError::Unclosed { open, column: span.start + 1 }, span)
+}
+
+/// What may begin an operand, for error messages
+const OPERAND: &str = "a number, a name, '(', '[' or a sign";
+
+/// What may follow a complete expression at the top level
+const END: &str = "an operator or end of input";
+
+/// Fails if the input ends right after `op`, which was just consumed and needs an operand
+fn expect_operand(lexer: &Lexer, op: Op) -> Result<()> {
+  if lexer.peek() == Token::End {
+    return Err(SpanError::new(ParseError::DanglingOperator(op), lexer.previous_span()))
+  }
+  Ok(())
+}
+
+/// Parses an operand: a literal, a name, a call, or anything in parentheses or brackets.
+/// Constructs spanning several tokens are parsed by functions of their own,
+/// which keeps this frame small, since one is taken for every level of nesting
+fn parse_primary(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
   match lexer.next() {
-    Token::Operator(Op::Sub) if !bracket => {
-      let value = parse_primary(lexer, false)?;
-      Ok(Node::Immediate(-value.evaluate()?))
+    Token::Operator(Op::Sub) => {
+      expect_operand(lexer, Op::Sub)?;
+      let value = parse_primary(lexer, depth.nested(lexer)?)?;
+      Ok(Node::Neg(Box::new(value)))
     },
-    Token::Literal(value) if !bracket => Ok(Node::Immediate(value)),
-    Token::LeftBracket => {
-      let value = parse_expression(lexer)?;
-      match lexer.next() {
-        Token::RightBracket => Ok(value),
-        _ => Err(Report::msg("Parenthesis don't match")),
-      }
+    Token::Operator(Op::Add) => {
+      expect_operand(lexer, Op::Add)?;
+      parse_primary(lexer, depth.nested(lexer)?)
     },
-    _ => Err(Report::msg("Unexpected token"))
+    Token::Literal(value) => Ok(Node::Immediate(value)),
+    Token::Constant(constant) => Ok(Node::Immediate(constant.value())),
+    Token::Function(func) => parse_func(lexer, depth, func),
+    Token::Variable(name) => parse_name(lexer, depth, name),
+    Token::LeftParen => parse_parenthesized(lexer, depth),
+    Token::LeftBracket => Ok(Node::List(parse_list(lexer, depth)?)),
+    found => Err(missing_operand(lexer, depth, found)),
   }
 }
 
-fn parse_func(lexer: &mut Lexer) -> Result<Node> {
-  if let Token::Function(func) = lexer.peek() {
+/// Error for `found`, which was just consumed, standing where an operand should
+fn missing_operand(lexer: &Lexer, depth: Depth, found: Token) -> SpanError<ParseError> {
+  match found {
+    Token::RightParen | Token::RightBracket if depth.brackets == 0 => SpanError::new(ParseError::UnmatchedClosing(found), lexer.previous_span()),
+    found => SpanError::new(ParseError::UnexpectedToken { found, expected: OPERAND }, lexer.previous_span()),
+  }
+}
+
+/// Parses what follows a name: a call, a keyword like `if` or `let` when it's used as one, or nothing for a variable
+fn parse_name(lexer: &mut Lexer, depth: Depth, name: String) -> Result<Node> {
+  let call = lexer.peek() == Token::LeftParen;
+  match name.to_ascii_lowercase().as_str() {
+    "if" if call => parse_if(lexer, depth),
+    "piecewise" if call => parse_piecewise(lexer, depth),
+    "eval" if call => parse_eval(lexer, depth),
+    "let" if matches!((lexer.peek(), lexer.peek_at(1)), (Token::Variable(_) | Token::Function(_), Token::Equals)) => parse_let(lexer, depth),
+    _ if call => {
+      let start = lexer.previous_span().start;
+      let args = parse_arguments(lexer, depth)?;
+      check_function_arguments(None, &args).map_err(|error| SpanError::new(error, start..lexer.previous_span().end))?;
+      Ok(Node::Call(name, args))
+    },
+    _ => Ok(Node::Variable(name)),
+  }
+}
+
+/// Parses the rest of a parenthesized expression after its opening parenthesis
+fn parse_parenthesized(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  let open = lexer.previous_span();
+  if lexer.peek() == Token::RightParen {
     lexer.next();
-    let arg = parse_primary(lexer, true)?;
-    return Ok(Node::Func(func, Box::new(arg)))
+    return Err(SpanError::new(ParseError::EmptyParentheses, open.start..lexer.previous_span().end))
+  }
+
+  let value = parse_nested(lexer, depth.bracketed(lexer)?, "an operator or ')'")?;
+  match lexer.next() {
+    Token::RightParen => Ok(value),
+    Token::End => Err(unclosed(Token::LeftParen, open)),
+    found => Err(SpanError::new(ParseError::UnbalancedParenthesis { found, expected: "')'" }, lexer.previous_span())),
+  }
+}
+
+/// Looks like a call, but only one of the branches is evaluated
+fn parse_if(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  let start = lexer.previous_span().start;
+  let args = parse_arguments(lexer, depth)?;
+  check_function_arguments(None, &args).map_err(|error| SpanError::new(error, start..lexer.previous_span().end))?;
+  match <[Node; 3]>::try_from(args) {
+    Ok([cond, then, else_]) => Ok(Node::If { cond: Box::new(cond), then: Box::new(then), else_: Box::new(else_) }),
+    Err(args) => Err(SpanError::new(ParseError::IfArgumentCount(args.len()), start..lexer.previous_span().end)),
+  }
+}
+
+/// Condition and value pairs, optionally followed by a default
+fn parse_piecewise(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  let start = lexer.previous_span().start;
+  let mut args = parse_arguments(lexer, depth)?;
+  check_function_arguments(None, &args).map_err(|error| SpanError::new(error, start..lexer.previous_span().end))?;
+  if args.len() < 2 {
+    return Err(SpanError::new(ParseError::PiecewiseArgumentCount(args.len()), start..lexer.previous_span().end))
   }
 
-  parse_primary(lexer, false)
+  let default = (args.len() % 2 == 1).then(|| Box::new(args.pop().expect("odd number of arguments")));
+  let mut args = args.into_iter();
+  let cases = std::iter::from_fn(|| Some((args.next()?, args.next()?))).collect();
+  Ok(Node::Piecewise(cases, default))
+}
+
+/// The expression in the string takes the place of the call, one level deeper, so nesting `eval` has a limit
+fn parse_eval(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  lexer.next();
+  let open = lexer.previous_span();
+  let depth = depth.bracketed(lexer)?;
+  let source = match lexer.next() {
+    Token::StringLiteral(source) => source,
+    found => return Err(SpanError::new(ParseError::UnexpectedToken { found, expected: "a string" }, lexer.previous_span())),
+  };
+  let invalid = |error: &dyn std::fmt::Display| SpanError::new(ParseError::InvalidEval(error.to_string()), lexer.previous_span());
+  // Under the same limits as the input it's part of
+  let options = TokenizeOptions { keep_comments: false, ..lexer.options() };
+  let mut inner = tokenize_with_options(&source, options).map_err(|error| invalid(&error))?;
+  let value = parse_expression_to_end(&mut inner, depth).map_err(|error| match error.error {
+    ParseError::TooDeep(_) => SpanError { span: lexer.previous_span(), ..error },
+    error => invalid(&error),
+  })?;
+  match lexer.next() {
+    Token::RightParen => Ok(value),
+    Token::End => Err(unclosed(Token::LeftParen, open)),
+    found => Err(SpanError::new(ParseError::UnbalancedParenthesis { found, expected: "')'" }, lexer.previous_span())),
+  }
 }
 
-fn parse_power(lexer: &mut Lexer) -> Result<Node> {
-  let mut left = parse_func(lexer)?;
+/// The body extends as far as it can, like in `let x = 2 in x + 1`
+fn parse_let(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  let name = match lexer.next() {
+    Token::Variable(name) => name,
+    Token::Function(func) => return Err(SpanError::new(ParseError::ReservedName(func), lexer.previous_span())),
+    _ => unreachable!("the name was peeked"),
+  };
+  lexer.next();
+  let depth = depth.nested(lexer)?;
+  let value = parse_binary(lexer, depth, COMPARISON)?;
+  match lexer.next() {
+    Token::Variable(keyword) if keyword.eq_ignore_ascii_case("in") => {},
+    found => return Err(SpanError::new(ParseError::UnexpectedToken { found, expected: "'in'" }, lexer.previous_span())),
+  }
+  let body = parse_binary(lexer, depth, COMPARISON)?;
+  Ok(Node::Let { name, value: Box::new(value), body: Box::new(body) })
+}
+
+/// Parses the elements of a list after its opening bracket, up to and including the closing one
+fn parse_list(lexer: &mut Lexer, depth: Depth) -> Result<Vec<Node>> {
+  let open = lexer.previous_span();
+  let depth = depth.bracketed(lexer)?;
+  if lexer.peek() == Token::RightBracket {
+    lexer.next();
+    return Ok(vec![])
+  }
+
+  let mut items = vec![parse_nested(lexer, depth, "an operator, ',' or ']'")?];
 
   loop {
-    match lexer.peek() {
-      Token::Operator(Op::Pow) => {
-        lexer.next();
-        let right = parse_func(lexer)?;
-        left = Node::BinOp(Op::Pow, Box::new(left), Box::new(right));
-      }
-      _ => break Ok(left),
+    match lexer.next() {
+      Token::Comma => items.push(parse_nested(lexer, depth, "an operator, ',' or ']'")?),
+      Token::RightBracket => break Ok(items),
+      Token::End => break Err(unclosed(Token::LeftBracket, open)),
+      found => break Err(SpanError::new(ParseError::UnbalancedBrackets { found, expected: "',' or ']'" }, lexer.previous_span())),
     }
   }
 }
 
-fn parse_multiplicative(lexer: &mut Lexer) -> Result<Node> {
-  let mut left = parse_power(lexer)?;
+/// Parses a parenthesized, comma-separated argument list.
+/// Parenthesis are required, otherwise "abs-2" may count as a valid expression
+fn parse_arguments(lexer: &mut Lexer, depth: Depth) -> Result<Vec<Node>> {
+  let found = lexer.next();
+  if found != Token::LeftParen {
+    return Err(SpanError::new(ParseError::UnexpectedToken { found, expected: "'('" }, lexer.previous_span()))
+  }
+  let open = lexer.previous_span();
+  let depth = depth.bracketed(lexer)?;
+
+  // Functions like `rand()` take no arguments
+  if lexer.peek() == Token::RightParen {
+    lexer.next();
+    return Ok(vec![])
+  }
+
+  let mut args = vec![parse_argument(lexer, depth)?];
 
   loop {
-    match lexer.peek() {
-      Token::Operator(op) if op == Op::Mul || op == Op::Div => {
-        lexer.next();
-        let right = parse_power(lexer)?;
-        left = Node::BinOp(op, Box::new(left), Box::new(right));
-      }
-      _ => break Ok(left),
+    match lexer.next() {
+      Token::Comma => args.push(parse_argument(lexer, depth)?),
+      Token::RightParen => break Ok(args),
+      Token::End => break Err(unclosed(Token::LeftParen, open)),
+      found => break Err(SpanError::new(ParseError::UnbalancedParenthesis { found, expected: "',' or ')'" }, lexer.previous_span())),
     }
   }
 }
 
-fn parse_additive(lexer: &mut Lexer) -> Result<Node> {
-  let mut left = parse_multiplicative(lexer)?;
+/// Parses an expression, or a function passed by name like `sin` in `map(sin, x)` or `+` in `zip(+, a, b)`
+fn parse_argument(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  let function = match (lexer.peek(), lexer.peek_at(1)) {
+    (Token::Function(func), Token::Comma | Token::RightParen) => Node::FuncRef(func),
+    (Token::Operator(op), Token::Comma | Token::RightParen) => Node::OpRef(op),
+    (Token::Percent, Token::Comma | Token::RightParen) => Node::OpRef(Op::Mod),
+    _ => return parse_nested(lexer, depth, "an operator, ',' or ')'"),
+  };
 
-  loop {
-    match lexer.peek() {
-      Token::Operator(op) if op == Op::Add || op == Op::Sub => {
-        lexer.next();
-        let right = parse_multiplicative(lexer)?;
-        left = Node::BinOp(op, Box::new(left), Box::new(right))
+  lexer.next();
+  Ok(function)
+}
+
+/// Checks that functions are passed by name exactly where `func` expects them,
+/// taking the right number of arguments, and variables where it binds them. `None` stands for a user-defined function
+fn check_function_arguments(func: Option<Func>, args: &[Node]) -> std::result::Result<(), ParseError> {
+  for (index, arg) in args.iter().enumerate() {
+    if let Some(func) = func.filter(|func| func.variable_argument(index)) {
+      if !matches!(arg, Node::Variable(_)) {
+        return Err(ParseError::ExpectedArgument { func, index: index + 1, expected: "variable" })
       }
-      Token::End | Token::RightBracket => break Ok(left),
-      _ => break Err(Report::msg(format!("Unexpected token: {:?}", lexer.peek()))),
+      continue
     }
-  } 
+
+    let expected = func.and_then(|func| Some((func, func.function_argument(index)?)));
+    match (expected, arg) {
+      (Some((func, arity)), Node::FuncRef(passed)) if !passed.accepts(arity) => {
+        return Err(ParseError::FunctionArity { func, arity, passed: passed.to_string(), takes: passed.arity() })
+      },
+      (Some((func, arity)), Node::OpRef(op)) if arity != 2 => {
+        return Err(ParseError::FunctionArity { func, arity, passed: op.to_string(), takes: 2 })
+      },
+      (Some(_), Node::FuncRef(_) | Node::OpRef(_)) => {},
+      (Some((func, _)), _) => return Err(ParseError::ExpectedArgument { func, index: index + 1, expected: "function" }),
+      (None, Node::FuncRef(passed)) => return Err(ParseError::FunctionAsValue(*passed)),
+      (None, Node::OpRef(op)) => return Err(ParseError::OperatorAsValue(*op)),
+      (None, _) => {},
+    }
+  }
+  Ok(())
+}
+
+/// Parses the arguments of a built-in function whose name was just consumed
+fn parse_func(lexer: &mut Lexer, depth: Depth, func: Func) -> Result<Node> {
+  let start = lexer.previous_span().start;
+  // As in `sin 30`, or `sqrt2`, which is tokenized the same way
+  if lexer.peek() != Token::LeftParen {
+    let call = match lexer.peek() {
+      Token::Literal(value) => Some(format!("{}({})", func, value)),
+      Token::Constant(constant) => Some(format!("{}({})", func, constant)),
+      Token::Variable(name) => Some(format!("{}({})", func, name)),
+      _ => None,
+    };
+    return Err(SpanError::new(ParseError::MissingParentheses { func, call }, lexer.previous_span()))
+  }
+  let args = parse_arguments(lexer, depth)?;
+  // `rand(a, b)` is told apart from `rand()` by the number of arguments
+  let func = match (func, args.len()) {
+    (Func::Random, 2) => Func::RandomRange,
+    (func, _) => func,
+  };
+
+  if args.is_empty() && !func.accepts(0) {
+    return Err(SpanError::new(ParseError::EmptyArguments(func), start..lexer.previous_span().end))
+  }
+  if !func.accepts(args.len()) {
+    return Err(SpanError::new(ParseError::WrongArgumentCount { func, count: args.len() }, start..lexer.previous_span().end))
+  }
+  check_function_arguments(Some(func), &args).map_err(|error| SpanError::new(error, start..lexer.previous_span().end))?;
+
+  Ok(Node::Func(func, args))
+}
+
+/// Whether `token` can begin an operand
+fn starts_operand(token: &Token) -> bool {
+  matches!(token, Token::Literal(_) | Token::Constant(_) | Token::Variable(_) | Token::Function(_) | Token::LeftParen | Token::LeftBracket)
+}
+
+/// Whether the `%` coming next is the remainder operator, which it is when followed by an operand.
+/// A sign counts as part of the operand when it's written against it but apart from the `%`,
+/// so `7 % -3` is a remainder while `50% - 1` and `50%-1` subtract from a percentage
+fn is_remainder(lexer: &Lexer) -> bool {
+  match lexer.peek_at(1) {
+    Token::Operator(Op::Add | Op::Sub) => {
+      let (percent, sign, operand) = (lexer.peek_span_at(0), lexer.peek_span_at(1), lexer.peek_span_at(2));
+      starts_operand(&lexer.peek_at(2)) && percent.end < sign.start && sign.end == operand.start
+    },
+    token => starts_operand(&token),
+  }
+}
+
+/// `%` is a postfix percent operator (`50%` is `0.5`) unless `is_remainder`.
+/// Applies the percent signs after an operand which was just parsed
+fn parse_percent(lexer: &mut Lexer, mut operand: Node) -> Node {
+  while lexer.peek() == Token::Percent && !is_remainder(lexer) {
+    lexer.next();
+    operand = Node::BinOp(Op::Div, Box::new(operand), Box::new(Node::Immediate(100.)));
+  }
+  operand
+}
+
+/// Precedence of comparisons, the loosest binding operators
+const COMPARISON: u8 = 1;
+
+/// The binary operator coming next, with its precedence. Comparisons bind looser than arithmetic,
+/// so `1 + 2 > 2` compares the sum, and all operators are left-associative, `^` included
+fn peek_operator(lexer: &Lexer) -> Option<(Op, u8)> {
+  match lexer.peek() {
+    Token::Operator(op) if op.is_comparison() => Some((op, COMPARISON)),
+    Token::Operator(op @ (Op::Add | Op::Sub)) => Some((op, 2)),
+    Token::Operator(op @ (Op::Mul | Op::Div)) => Some((op, 3)),
+    Token::Percent if is_remainder(lexer) => Some((Op::Mod, 3)),
+    Token::Operator(Op::Pow) => Some((Op::Pow, 4)),
+    _ => None,
+  }
+}
+
+/// Parses operands joined by operators binding at least as tightly as `precedence`.
+/// A single function handles every level, so that each level of nesting costs one frame rather than one per level
+fn parse_binary(lexer: &mut Lexer, depth: Depth, precedence: u8) -> Result<Node> {
+  let operand = parse_primary(lexer, depth)?;
+  let mut left = parse_percent(lexer, operand);
+
+  while let Some((op, binding)) = peek_operator(lexer).filter(|(_, binding)| *binding >= precedence) {
+    lexer.next();
+    expect_operand(lexer, op)?;
+    let right = parse_binary(lexer, depth, binding + 1)?;
+    left = Node::BinOp(op, Box::new(left), Box::new(right));
+  }
+
+  Ok(left)
 }
 
+/// Parses an expression which may only be followed by the end of input, a closing parenthesis or bracket, or a comma
 pub fn parse_expression(lexer: &mut Lexer) -> Result<Node> {
-  parse_additive(lexer)
+  parse_expression_with_options(lexer, ParseOptions::default())
+}
+
+pub fn parse_expression_with_options(lexer: &mut Lexer, options: ParseOptions) -> Result<Node> {
+  parse_nested(lexer, Depth::new(options), END).and_then(|node| check_nodes(node, options, lexer))
+}
+
+/// Parses an expression up to a closing parenthesis or bracket, a comma or the end of input.
+/// Anything else after it fails with `expected`, what may follow the expression where it's nested
+fn parse_nested(lexer: &mut Lexer, depth: Depth, expected: &'static str) -> Result<Node> {
+  let node = parse_binary(lexer, depth, COMPARISON)?;
+  match lexer.peek() {
+    Token::End | Token::RightParen | Token::RightBracket | Token::Comma => Ok(node),
+    found => Err(SpanError::new(ParseError::TrailingToken { found, expected }, lexer.span())),
+  }
+}
+
+/// Parses as long a prefix as forms an expression, returning it along with
+/// the index of the first token which wasn't consumed, `Token::End` included
+pub fn parse_expression_partial(lexer: &mut Lexer) -> Result<(Node, usize)> {
+  let node = parse_binary(lexer, Depth::new(ParseOptions::default()), COMPARISON)?;
+  Ok((node, lexer.position()))
+}
+
+/// Matches the `name(params) =` header of a function definition, consuming it.
+/// Fails if a built-in function's name stands for the function or one of its parameters
+fn parse_definition_header(lexer: &mut Lexer) -> Result<Option<(String, Vec<String>)>> {
+  let (Token::Variable(_) | Token::Function(_), Token::LeftParen) = (lexer.peek(), lexer.peek_at(1)) else {
+    return Ok(None)
+  };
+
+  let mut names = vec![0];
+  let mut offset = 2;
+
+  loop {
+    match lexer.peek_at(offset) {
+      Token::Variable(_) | Token::Function(_) => names.push(offset),
+      _ => return Ok(None),
+    }
+    match lexer.peek_at(offset + 1) {
+      Token::Comma => offset += 2,
+      Token::RightParen => break,
+      _ => return Ok(None),
+    }
+  }
+
+  if lexer.peek_at(offset + 2) != Token::Equals {
+    return Ok(None)
+  }
+
+  let mut names = names.into_iter().map(|offset| match lexer.peek_at(offset) {
+    Token::Variable(name) => Ok(name),
+    Token::Function(func) => Err(SpanError::new(ParseError::ReservedName(func), lexer.peek_span_at(offset))),
+    _ => unreachable!("only names were matched"),
+  }).collect::<Result<Vec<_>>>()?;
+
+  for _ in 0..offset + 3 {
+    lexer.next();
+  }
+
+  let name = names.remove(0);
+  Ok(Some((name, names)))
+}
+
+pub fn parse_statement(lexer: &mut Lexer) -> Result<Statement> {
+  parse_statement_with_options(lexer, ParseOptions::default())
+}
+
+pub fn parse_statement_with_options(lexer: &mut Lexer, options: ParseOptions) -> Result<Statement> {
+  let parse_body = |lexer: &mut Lexer| parse_expression_to_end(lexer, Depth::new(options)).and_then(|node| check_nodes(node, options, lexer));
+  if let Some((name, params)) = parse_definition_header(lexer)? {
+    return Ok(Statement::Define(name, params, parse_body(lexer)?))
+  }
+
+  match (lexer.peek(), lexer.peek_at(1)) {
+    (Token::Variable(name), Token::Equals) => {
+      lexer.next();
+      lexer.next();
+      return Ok(Statement::Assign(name, parse_body(lexer)?))
+    },
+    (Token::Function(func), Token::Equals) => return Err(SpanError::new(ParseError::ReservedName(func), lexer.peek_span_at(0))),
+    _ => {},
+  }
+
+  Ok(Statement::Expression(parse_body(lexer)?))
+}
+
+/// Parses an expression which must span the whole input
+fn parse_expression_to_end(lexer: &mut Lexer, depth: Depth) -> Result<Node> {
+  let node = parse_nested(lexer, depth, END)?;
+  match lexer.peek() {
+    Token::End => Ok(node),
+    // Every parenthesis and bracket opened in the expression has been closed
+    found @ (Token::RightParen | Token::RightBracket) => Err(SpanError::new(ParseError::UnmatchedClosing(found), lexer.span())),
+    found => Err(SpanError::new(ParseError::TrailingToken { found, expected: END }, lexer.span())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_expression, parse_expression_with_options, parse_statement, Node, ParseOptions, Statement};
+  use crate::{error::{ParseError, SpanError}, lexer::{tokenize, tokenize_with_options, Func, Op, Token, TokenizeOptions}};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  fn num(value: f64) -> Node {
+    Node::Immediate(value)
+  }
+
+  fn var(name: &str) -> Node {
+    Node::Variable(name.to_string())
+  }
+
+  fn neg(node: Node) -> Node {
+    Node::Neg(Box::new(node))
+  }
+
+  fn binop(op: Op, left: Node, right: Node) -> Node {
+    Node::BinOp(op, Box::new(left), Box::new(right))
+  }
+
+  #[test]
+  fn test_precedence() {
+    assert_eq!(parse("2+3*4"), binop(Op::Add, num(2.), binop(Op::Mul, num(3.), num(4.))));
+    assert_eq!(parse("(2+3)*4"), binop(Op::Mul, binop(Op::Add, num(2.), num(3.)), num(4.)));
+    assert_eq!(parse("2*3^4"), binop(Op::Mul, num(2.), binop(Op::Pow, num(3.), num(4.))));
+    assert_eq!(parse("1 - 6 / 3 % 2"), binop(Op::Sub, num(1.), binop(Op::Mod, binop(Op::Div, num(6.), num(3.)), num(2.))));
+    assert_eq!(parse("-x^2"), binop(Op::Pow, neg(var("x")), num(2.)));
+    assert_eq!(parse("--x"), neg(neg(var("x"))));
+    assert_eq!(parse("1 + 2 > 2 * x"), binop(Op::Gt, binop(Op::Add, num(1.), num(2.)), binop(Op::Mul, num(2.), var("x"))));
+    assert_eq!(parse("1 < 2 == 1"), binop(Op::Eq, binop(Op::Lt, num(1.), num(2.)), num(1.)));
+  }
+
+  #[test]
+  fn test_associativity() {
+    assert_eq!(parse("1 - 2 - 3"), binop(Op::Sub, binop(Op::Sub, num(1.), num(2.)), num(3.)));
+    assert_eq!(parse("8 / 4 / 2"), binop(Op::Div, binop(Op::Div, num(8.), num(4.)), num(2.)));
+    assert_eq!(parse("2 ^ 3 ^ 2"), binop(Op::Pow, binop(Op::Pow, num(2.), num(3.)), num(2.)));
+    assert_eq!(parse("2 ^ (3 ^ 2)"), binop(Op::Pow, num(2.), binop(Op::Pow, num(3.), num(2.))));
+  }
+
+  #[test]
+  fn test_percent() {
+    assert_eq!(parse("50%"), binop(Op::Div, num(50.), num(100.)));
+    assert_eq!(parse("50%%"), binop(Op::Div, binop(Op::Div, num(50.), num(100.)), num(100.)));
+    assert_eq!(parse("7 % 3"), binop(Op::Mod, num(7.), num(3.)));
+    assert_eq!(parse("50% - 1"), binop(Op::Sub, binop(Op::Div, num(50.), num(100.)), num(1.)));
+    assert_eq!(parse("50%-1"), parse("50% - 1"));
+    assert_eq!(parse("50% + 1"), binop(Op::Add, binop(Op::Div, num(50.), num(100.)), num(1.)));
+
+    // A sign written against the operand after a spaced `%` belongs to the operand
+    assert_eq!(parse("7 % -3"), binop(Op::Mod, num(7.), neg(num(3.))));
+    assert_eq!(parse("7 % +x"), binop(Op::Mod, num(7.), var("x")));
+    assert_eq!(parse("7 % -3 - 1"), binop(Op::Sub, binop(Op::Mod, num(7.), neg(num(3.))), num(1.)));
+  }
+
+  #[test]
+  fn test_functions() {
+    assert_eq!(parse("sqrt(2) * 3"), binop(Op::Mul, Node::Func(Func::Sqrt, vec![num(2.)]), num(3.)));
+    assert_eq!(parse("log2(x + 1)"), Node::Func(Func::Log(2.), vec![binop(Op::Add, var("x"), num(1.))]));
+    assert_eq!(parse("log(3, 9)"), Node::Func(Func::LogBase, vec![num(3.), num(9.)]));
+    assert_eq!(parse("rand()"), Node::Func(Func::Random, vec![]));
+    assert_eq!(parse("rand(1, 6)"), Node::Func(Func::RandomRange, vec![num(1.), num(6.)]));
+    assert_eq!(parse("f(x, g(y))"), Node::Call("f".to_string(), vec![var("x"), Node::Call("g".to_string(), vec![var("y")])]));
+    assert_eq!(parse("sin(x) ^ 2"), binop(Op::Pow, Node::Func(Func::Sin, vec![var("x")]), num(2.)));
+
+    assert!(parse_expression(&mut tokenize("sqrt 2").unwrap()).is_err());
+    assert!(parse_expression(&mut tokenize("log(2)").unwrap()).is_err());
+  }
+
+  #[test]
+  fn test_function_arguments() {
+    assert_eq!(parse("map(sin, x)"), Node::Func(Func::Map, vec![Node::FuncRef(Func::Sin), var("x")]));
+    assert_eq!(parse("map(log2, 8) + 1"), binop(Op::Add, Node::Func(Func::Map, vec![Node::FuncRef(Func::Log(2.)), num(8.)]), num(1.)));
+
+    let error = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap_err().error;
+    assert_eq!(error("map(atan2, x)").to_string(), "map expects a function of 1 argument(s), atan2 takes 2");
+    assert_eq!(error("map(x, sin)"), ParseError::ExpectedArgument { func: Func::Map, index: 1, expected: "function" });
+    assert_eq!(error("sqrt(sin)"), ParseError::FunctionAsValue(Func::Sin));
+    assert_eq!(error("f(sin)"), ParseError::FunctionAsValue(Func::Sin));
+    assert_eq!(error("sqrt(1, 2)"), ParseError::WrongArgumentCount { func: Func::Sqrt, count: 2 });
+
+    assert_eq!(parse("zip(%, 7, 3)"), Node::Func(Func::Zip, vec![Node::OpRef(Op::Mod), num(7.), num(3.)]));
+    assert_eq!(parse("zip(max, x, 2)"), Node::Func(Func::Zip, vec![Node::FuncRef(Func::Max), var("x"), num(2.)]));
+    assert_eq!(error("map(*, x)"), ParseError::FunctionArity { func: Func::Map, arity: 1, passed: "*".to_string(), takes: 2 });
+    assert_eq!(error("zip(sin, x, y)"), ParseError::FunctionArity { func: Func::Zip, arity: 2, passed: "sin".to_string(), takes: 1 });
+    assert_eq!(error("abs(-)"), ParseError::OperatorAsValue(Op::Sub));
+
+    assert_eq!(parse("filter(x > 0, x, l)"), Node::Func(Func::Filter, vec![binop(Op::Gt, var("x"), num(0.)), var("x"), var("l")]));
+    assert_eq!(error("filter(x > 0, 1, l)"), ParseError::ExpectedArgument { func: Func::Filter, index: 2, expected: "variable" });
+  }
+
+  #[test]
+  fn test_if() {
+    let conditional = |cond, then, else_| Node::If { cond: Box::new(cond), then: Box::new(then), else_: Box::new(else_) };
+    assert_eq!(parse("if(x > 0, x, -x)"), conditional(binop(Op::Gt, var("x"), num(0.)), var("x"), Node::Neg(Box::new(var("x")))));
+    assert_eq!(parse("IF(1, 2, 3) + 1"), binop(Op::Add, conditional(num(1.), num(2.), num(3.)), num(1.)));
+    // Only a call is a conditional
+    assert_eq!(parse("if + 1"), binop(Op::Add, var("if"), num(1.)));
+
+    let error = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("if(1, 2)"), SpanError::new(ParseError::IfArgumentCount(2), 0..8));
+    assert_eq!(error("if(1, 2, 3, 4)").error, ParseError::IfArgumentCount(4));
+    assert_eq!(error("if(1, sin, 2)").error, ParseError::FunctionAsValue(Func::Sin));
+  }
+
+  #[test]
+  fn test_let() {
+    let bind = |name: &str, value, body| Node::Let { name: name.to_string(), value: Box::new(value), body: Box::new(body) };
+    assert_eq!(parse("let x = 2 in x + 1"), bind("x", num(2.), binop(Op::Add, var("x"), num(1.))));
+    assert_eq!(parse("LET a = 1 IN let b = a in b"), bind("a", num(1.), bind("b", var("a"), var("b"))));
+    assert_eq!(parse("(let x = 1 > 0 in x) * 2"), binop(Op::Mul, bind("x", binop(Op::Gt, num(1.), num(0.)), var("x")), num(2.)));
+    // Only a binding starts a `let`
+    assert_eq!(parse("let * 2"), binop(Op::Mul, var("let"), num(2.)));
+
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("let x = 2"), SpanError::new(ParseError::UnexpectedToken { found: Token::End, expected: "'in'" }, 9..10));
+    assert_eq!(error("let x = 2 x").to_string(), "Unexpected token: expected 'in', found 'x'");
+    assert_eq!(error("let x = 2 in").to_string(), "Unexpected token: expected a number, a name, '(', '[' or a sign, found end of input");
+  }
+
+  #[test]
+  fn test_piecewise() {
+    assert_eq!(parse("piecewise(x < 0, -x, x)"), Node::Piecewise(vec![(binop(Op::Lt, var("x"), num(0.)), neg(var("x")))], Some(Box::new(var("x")))));
+    assert_eq!(parse("Piecewise(x, 1, y, 2)"), Node::Piecewise(vec![(var("x"), num(1.)), (var("y"), num(2.))], None));
+
+    let error = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("piecewise(1)"), SpanError::new(ParseError::PiecewiseArgumentCount(1), 0..12));
+    assert_eq!(error("piecewise()").error, ParseError::PiecewiseArgumentCount(0));
+    assert_eq!(error("piecewise(1, cos)").error, ParseError::FunctionAsValue(Func::Cos));
+  }
+
+  #[test]
+  fn test_debug() {
+    assert_eq!(format!("{:?}", parse("-x + f(1, [2])")), r#"BinOp(Add, Neg(Variable("x")), Call("f", [Immediate(1.0), List([Immediate(2.0)])]))"#);
+    assert_eq!(format!("{:?}", parse("if(x, 1, sin(2))")), "If { cond: Variable(\"x\"), then: Immediate(1.0), else_: Func(Sin, [Immediate(2.0)]) }");
+    assert_eq!(
+      format!("{:?}", parse("let y = 1 in piecewise(y, 2, 3)")),
+      r#"Let { name: "y", value: Immediate(1.0), body: Piecewise([(Variable("y"), Immediate(2.0))], Some(Immediate(3.0))) }"#,
+    );
+  }
+
+  #[test]
+  fn test_hash() {
+    use std::{collections::HashMap, hash::{BuildHasher, RandomState}};
+
+    let state = RandomState::new();
+    assert_eq!(state.hash_one(parse("sin(x) + 2 * [1, y]")), state.hash_one(parse("sin(x)+2*[1,y]")));
+    assert_eq!(state.hash_one(num(0.)), state.hash_one(num(-0.)));
+    assert_eq!(state.hash_one(parse("log2(x)")), state.hash_one(Node::Func(Func::Log(2.), vec![var("x")])));
+
+    let mut memo = HashMap::new();
+    for input in ["x + 1", "x + 2", "1 + x", "f(x, 1)", "f(x)", "[x, 1]", "log2(x)", "log3(x)", "let x = 1 in x", "piecewise(x, 1)", "piecewise(x, 1, 2)"] {
+      assert_eq!(memo.insert(parse(input), input), None, "{}", input);
+    }
+    assert_eq!(memo.get(&parse("x+1")), Some(&"x + 1"));
+    assert_eq!(memo.get(&parse("[x,1]")), Some(&"[x, 1]"));
+    assert_eq!(memo.get(&parse("x * 1")), None);
+
+    // NaN literals are equal to themselves, so trees containing them can be looked up
+    let nan = parse("sqrt(x) + 0 / 0");
+    let nan = binop(Op::Add, nan.clone(), num(f64::NAN));
+    assert_eq!(nan, nan.clone());
+    assert_eq!(num(-f64::NAN), num(f64::NAN));
+    assert_eq!(Func::Log(f64::NAN), Func::Log(f64::NAN));
+    assert_ne!(Func::Log(2.), Func::Root(2.));
+    assert_eq!(memo.insert(nan.clone(), "NaN"), None);
+    assert_eq!(memo.get(&nan), Some(&"NaN"));
+
+    // Deeper than recursion could handle
+    let mut deep = var("x");
+    for _ in 0..1_000_000 {
+      deep = neg(deep);
+    }
+    state.hash_one(&deep);
+    assert_eq!(deep, deep.clone());
+    assert_ne!(deep, neg(deep.clone()));
+  }
+
+  #[test]
+  fn test_lists() {
+    assert_eq!(parse("[1, x + 2]"), Node::List(vec![num(1.), binop(Op::Add, var("x"), num(2.))]));
+    assert_eq!(parse("[]"), Node::List(vec![]));
+    assert_eq!(parse("[[1], -[2]]"), Node::List(vec![Node::List(vec![num(1.)]), neg(Node::List(vec![num(2.)]))]));
+    assert_eq!(parse("map(sqrt, [4, 9])"), Node::Func(Func::Map, vec![Node::FuncRef(Func::Sqrt), Node::List(vec![num(4.), num(9.)])]));
+
+    let error = |input| parse_expression(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("[1, 2"), SpanError::new(ParseError::Unclosed { open: Token::LeftBracket, column: 1 }, 0..1));
+    assert_eq!(error("[1, 2)").error, ParseError::UnbalancedBrackets { found: Token::RightParen, expected: "',' or ']'" });
+    assert_eq!(error("(1, 2]"), SpanError::new(ParseError::UnbalancedParenthesis { found: Token::Comma, expected: "')'" }, 2..3));
+    assert!(matches!(error("[1,]").error, ParseError::UnexpectedToken { found: Token::RightBracket, .. }));
+    assert!(matches!(error("[1] 2").error, ParseError::TrailingToken { found: Token::Literal(2.), .. }));
+  }
+
+  #[test]
+  fn test_dangling_operator() {
+    let error = |input: &str| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    for op in ["+", "-", "*", "/", "^", ">", "<", "==", ">=", "<=", "!="] {
+      let input = format!("2 {}", op);
+      let error = error(&input);
+      assert_eq!(error.to_string(), format!("Expression ends after '{}'; expected a value", op));
+      assert_eq!(error.span, 2..2 + op.len());
+    }
+
+    assert_eq!(error("(3 -").to_string(), "Expression ends after '-'; expected a value");
+    assert_eq!(error("-"), SpanError::new(ParseError::DanglingOperator(Op::Sub), 0..1));
+    assert_eq!(error("x = 2 * # comment").error, ParseError::DanglingOperator(Op::Mul));
+    // `%` with nothing after it is a percentage
+    assert!(parse_statement(&mut tokenize("2 %").unwrap()).is_ok());
+  }
+
+  #[test]
+  fn test_eval() {
+    assert_eq!(parse(r#"eval("x * 2") + 1"#), parse("(x * 2) + 1"));
+
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error(r#"1 + eval("2 +")"#), SpanError::new(ParseError::InvalidEval("Expression ends after '+'; expected a value".to_string()), 9..14));
+    assert_eq!(error(r#"eval("$")"#).to_string(), "Invalid expression in eval: Unknown character $");
+    assert_eq!(error("eval(x)").error, ParseError::UnexpectedToken { found: Token::Variable("x".to_string()), expected: "a string" });
+    assert_eq!(error(r#"eval("1""#).error, ParseError::Unclosed { open: Token::LeftParen, column: 5 });
+
+    // Every `eval` nests one level deeper
+    let mut nested = "1".to_string();
+    for _ in 0..4 {
+      nested = format!("eval({:?})", nested);
+    }
+    let options = |max_depth| ParseOptions { max_depth, ..ParseOptions::default() };
+    assert!(parse_expression_with_options(&mut tokenize(&nested).unwrap(), options(4)).is_ok());
+    let error = parse_expression_with_options(&mut tokenize(&nested).unwrap(), options(3)).unwrap_err();
+    assert_eq!(error, SpanError::new(ParseError::TooDeep(3), 5..nested.chars().count() - 1));
+
+    // The string is tokenized with the limits of the input
+    let limited = |input, max_tokens| {
+      let mut lexer = tokenize_with_options(input, TokenizeOptions { max_tokens, ..TokenizeOptions::default() }).unwrap();
+      parse_statement(&mut lexer)
+    };
+    assert!(limited(r#"eval("1 + 2")"#, 4).is_ok());
+    assert_eq!(limited(r#"eval("1 + 2 + 3")"#, 4).unwrap_err().to_string(), "Invalid expression in eval: Too many tokens (limit 4)");
+  }
+
+  #[test]
+  fn test_unmatched_brackets() {
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error(")"), SpanError::new(ParseError::UnmatchedClosing(Token::RightParen), 0..1));
+    assert_eq!(error("2+3)"), SpanError::new(ParseError::UnmatchedClosing(Token::RightParen), 3..4));
+    assert_eq!(error("2 + 3)").to_string(), "Unmatched ')': nothing open to close");
+    assert_eq!(error("[1] + 2]").error, ParseError::UnmatchedClosing(Token::RightBracket));
+    assert_eq!(error("((1+2)"), SpanError::new(ParseError::Unclosed { open: Token::LeftParen, column: 1 }, 0..1));
+    assert_eq!(error("((1+2)").to_string(), "Unclosed '(' opened at column 1");
+    assert_eq!(error("1 + max(2, (3)").to_string(), "Unclosed '(' opened at column 8");
+    assert_eq!(error("[1, [2]").error, ParseError::Unclosed { open: Token::LeftBracket, column: 1 });
+
+    let nested = format!("{}1{}", "([max(".repeat(20), ")])".repeat(20));
+    assert!(parse_statement(&mut tokenize(&nested).unwrap()).is_ok());
+  }
+
+  #[test]
+  fn test_missing_parentheses() {
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("sin 30").to_string(), "Function `sin` must be called with parentheses: sin(30)");
+    assert_eq!(error("sqrt2"), error("sqrt 2"));
+    assert_eq!(error("2 * sqrt2"), SpanError::new(ParseError::MissingParentheses { func: Func::Sqrt, call: Some("sqrt(2)".to_string()) }, 4..8));
+    assert_eq!(error("log2 x").to_string(), "Function `log2` must be called with parentheses: log2(x)");
+    assert_eq!(error("abs -1").to_string(), "Function `abs` must be called with parentheses");
+    assert_eq!(error("max").error, ParseError::MissingParentheses { func: Func::Max, call: None });
+  }
+
+  #[test]
+  fn test_empty_parentheses() {
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("()"), SpanError::new(ParseError::EmptyParentheses, 0..2));
+    assert_eq!(error("2 * ( )"), SpanError::new(ParseError::EmptyParentheses, 4..7));
+    assert_eq!(error("(())"), SpanError::new(ParseError::EmptyParentheses, 1..3));
+    assert_eq!(error("sqrt(())").error, ParseError::EmptyParentheses);
+    assert_eq!(error("()").to_string(), "Empty parentheses: expected an expression between ( and )");
+
+    assert_eq!(error("1 + sin()"), SpanError::new(ParseError::EmptyArguments(Func::Sin), 4..9));
+    assert_eq!(error("sin()").to_string(), "sin() requires an argument");
+    assert_eq!(error("atan2()").to_string(), "atan2() requires 2 arguments");
+    assert_eq!(error("max()").to_string(), "max() requires an argument");
+    // Some functions take no arguments
+    assert!(parse_statement(&mut tokenize("rand() + sum_list()").unwrap()).is_ok());
+  }
+
+  #[test]
+  fn test_expected() {
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err().to_string();
+    assert_eq!(error("2 + * 3"), "Unexpected token: expected a number, a name, '(', '[' or a sign, found '*'");
+    assert_eq!(error("2 3"), "Unexpected token: expected an operator or end of input, found '3'");
+    // Constants are named as typed rather than by their value
+    assert_eq!(error("2 pi"), "Unexpected token: expected an operator or end of input, found 'pi'");
+    assert_eq!(error("1e3"), "Unexpected token: expected an operator or end of input, found 'e'");
+    assert_eq!(error("(1, 2)"), "Parenthesis don't match: expected ')', found ','");
+    assert_eq!(error("(1 x)"), "Unexpected token: expected an operator or ')', found 'x'");
+    assert_eq!(error("max(1, 2]"), "Parenthesis don't match: expected ',' or ')', found ']'");
+    assert_eq!(error("[1, 2 = 3]"), "Unexpected token: expected an operator, ',' or ']', found '='");
+    assert_eq!(error("rand + 1"), "Function `rand` must be called with parentheses");
+    assert_eq!(error("(1))"), "Unmatched ')': nothing open to close");
+  }
+
+  #[test]
+  fn test_depth_limit() {
+    // Runs on the test thread, whose stack is as small as any other thread's
+    let nested = |depth| "(".repeat(depth) + "1" + &")".repeat(depth);
+    assert_eq!(parse(&nested(100)), num(1.));
+
+    let error = parse_expression(&mut tokenize(&nested(100_000)).unwrap()).unwrap_err();
+    assert_eq!(error.to_string(), "Expression too deeply nested (limit 100)");
+    assert_eq!(error, SpanError::new(ParseError::TooDeep(100), 100..101));
+
+    let at_limit = |prefix: &str, suffix: &str| prefix.repeat(100) + "1" + &suffix.repeat(100);
+    for input in [at_limit("-", ""), at_limit("sqrt(", ")"), at_limit("[", "]"), at_limit("f(", ")"), at_limit("if(1, ", ", 2)"), at_limit("let x = 1 in ", "")] {
+      assert!(parse_expression(&mut tokenize(&input).unwrap()).is_ok(), "{}", &input[..20]);
+    }
+    for input in ["-".repeat(100_000) + "1", "sqrt(".repeat(100_000) + "1", "[".repeat(100_000)] {
+      let error = parse_expression(&mut tokenize(&input).unwrap()).unwrap_err();
+      assert_eq!(error.error, ParseError::TooDeep(100));
+    }
+
+    let parse_with_limit = |input, max_depth| parse_expression_with_options(&mut tokenize(input).unwrap(), ParseOptions { max_depth, ..Default::default() });
+    assert!(parse_with_limit("sin(-(x))", 3).is_ok());
+    assert_eq!(parse_with_limit("sin(-(-x))", 3).unwrap_err().error, ParseError::TooDeep(3));
+    assert_eq!(parse_with_limit("[[1]]", 1).unwrap_err().error, ParseError::TooDeep(1));
+    assert!(parse_with_limit("1 + 2 * 3", 0).is_ok());
+  }
+
+  #[test]
+  fn test_statements() {
+    let statement = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap();
+    assert!(matches!(statement("x = 1 + 2"), Statement::Assign(name, node) if name == "x" && node == binop(Op::Add, num(1.), num(2.))));
+    assert!(matches!(statement("f(a, b) = a * b"), Statement::Define(name, params, node)
+      if name == "f" && params == ["a", "b"] && node == binop(Op::Mul, var("a"), var("b"))));
+    assert!(matches!(statement("f(2) + 1"), Statement::Expression(_)));
+    assert!(matches!(statement("min(1, 2) + 1"), Statement::Expression(_)));
+
+    // Built-in functions' names can't be bound
+    let error = |input| parse_statement(&mut tokenize(input).unwrap()).unwrap_err();
+    assert_eq!(error("min = 3"), SpanError::new(ParseError::ReservedName(Func::Min), 0..3));
+    assert_eq!(error("median(x) = x"), SpanError::new(ParseError::ReservedName(Func::Median), 0..6));
+    assert_eq!(error("f(x, max) = x + max"), SpanError::new(ParseError::ReservedName(Func::Max), 5..8));
+    assert_eq!(error("median = 1"), SpanError::new(ParseError::ReservedName(Func::Median), 0..6));
+    assert_eq!(error("let mode = 2 in mode"), SpanError::new(ParseError::ReservedName(Func::Mode), 4..8));
+    assert_eq!(error("sort = [3, 1, 2]"), SpanError::new(ParseError::ReservedName(Func::Sort), 0..4));
+    assert_eq!(error("f(map, reverse) = 1"), SpanError::new(ParseError::ReservedName(Func::Map), 2..5));
+    assert_eq!(error("let unique = 1 in unique"), SpanError::new(ParseError::ReservedName(Func::Unique), 4..10));
+    assert_eq!(error("len = 2"), SpanError::new(ParseError::ReservedName(Func::Len), 0..3));
+    assert_eq!(error("f(list, get) = get"), SpanError::new(ParseError::ReservedName(Func::Get), 8..11));
+    assert_eq!(error("slice(a, b) = a - b"), SpanError::new(ParseError::ReservedName(Func::Slice), 0..5));
+    assert_eq!(error("and = 1"), SpanError::new(ParseError::ReservedName(Func::And), 0..3));
+    assert_eq!(error("f(or) = not(or)"), SpanError::new(ParseError::ReservedName(Func::Or), 2..4));
+    assert_eq!(error("let zip = 2 in zip"), SpanError::new(ParseError::ReservedName(Func::Zip), 4..7));
+    assert_eq!(error("mean = 3").to_string(), "`mean` is the name of a built-in function and can't be used as a name");
+  }
 }
@@ -0,0 +1,172 @@
+use eyre::{eyre, Result};
+use terminal_size::{Height, Width};
+
+use calc::settings::Settings;
+
+/// Parsed `plot <expr> from <a> to <b> [width N] [height N] [samples N]` command
+#[derive(Debug, PartialEq)]
+pub struct Plot<'a> {
+  pub expression: &'a str,
+  pub from: &'a str,
+  pub to: &'a str,
+  /// Columns of the plot area
+  pub width: usize,
+  /// Rows of the plot area
+  pub height: usize,
+  /// Number of evaluated points, one per column by default
+  pub samples: usize,
+}
+
+/// Size of the terminal, or if it can't be queried, as when the output is redirected,
+/// from `COLUMNS` and `LINES`, 80×24 if they aren't set either
+fn terminal_size() -> (usize, usize) {
+  if let Some((Width(columns), Height(lines))) = terminal_size::terminal_size() {
+    return (columns.into(), lines.into())
+  }
+  let var = |name| std::env::var(name).ok().and_then(|value| value.parse().ok());
+  (var("COLUMNS").unwrap_or(80), var("LINES").unwrap_or(24))
+}
+
+const USAGE: &str = "Usage: plot <expr> from <a> to <b> [width N] [height N] [samples N]";
+
+impl<'a> Plot<'a> {
+  pub fn parse(input: &'a str) -> Result<Self> {
+    let (expression, range) = input.split_once(" from ").ok_or_else(|| eyre!(USAGE))?;
+    let (from, mut to) = range.split_once(" to ").ok_or_else(|| eyre!(USAGE))?;
+
+    let (columns, lines) = terminal_size();
+    let mut width = None;
+    let mut height = None;
+    let mut samples = None;
+
+    // Options follow the upper bound, which may itself contain spaces
+    loop {
+      let mut words = to.trim_end().rsplitn(3, ' ');
+      let (Some(value), Some(name), Some(rest)) = (words.next(), words.next(), words.next()) else { break };
+      let option = match name {
+        "width" => &mut width,
+        "height" => &mut height,
+        "samples" => &mut samples,
+        _ => break,
+      };
+      match value.parse() {
+        Ok(value) if value >= 2 => *option = Some(value),
+        _ => return Err(eyre!("{} must be a whole number of at least 2", name)),
+      }
+      to = rest;
+    }
+
+    // Leave room for the labels and the prompt
+    let width = width.unwrap_or(columns.saturating_sub(10).max(2));
+    Ok(Self {
+      expression: expression.trim(),
+      from: from.trim(),
+      to: to.trim(),
+      width,
+      height: height.unwrap_or(lines.saturating_sub(4).max(2)),
+      samples: samples.unwrap_or(width),
+    })
+  }
+
+  /// Points at which the expression is evaluated, evenly spaced from `from` to `to`
+  pub fn points(&self, from: f64, to: f64) -> Vec<f64> {
+    (0..self.samples)
+      .map(|index| from + (to - from) * index as f64 / (self.samples - 1) as f64)
+      .collect()
+  }
+
+  /// Draws the samples with `*`, leaving gaps for the ones which failed or aren't finite,
+  /// along with axis lines and the value range on the left
+  pub fn render(&self, from: f64, to: f64, values: &[Result<f64>], settings: &Settings) -> Result<String> {
+    let finite = || values.iter().filter_map(|value| value.as_ref().ok()).filter(|value| value.is_finite());
+    let (min, max) = finite().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &value| (min.min(value), max.max(value)));
+    if min > max {
+      return Err(eyre!("Nothing to plot: no finite values from {} to {}", self.from, self.to))
+    }
+    // A constant is drawn in the middle
+    let (min, max) = if min == max { (min - 1., max + 1.) } else { (min, max) };
+
+    let row = |y: f64| ((max - y) / (max - min) * (self.height - 1) as f64).round() as usize;
+    let column = |x: f64| ((x - from) / (to - from) * (self.width - 1) as f64).round() as usize;
+
+    let mut grid = vec![vec![' '; self.width]; self.height];
+    if min <= 0. && 0. <= max {
+      grid[row(0.)].fill('-');
+    }
+    if from <= 0. && 0. <= to {
+      let column = column(0.);
+      for line in grid.iter_mut() {
+        line[column] = if line[column] == '-' { '+' } else { '|' };
+      }
+    }
+    for (index, value) in values.iter().enumerate() {
+      if let Ok(&value) = value.as_ref() {
+        if value.is_finite() {
+          let x = from + (to - from) * index as f64 / (values.len() - 1) as f64;
+          grid[row(value)][column(x)] = '*';
+        }
+      }
+    }
+
+    let (max_label, min_label) = (settings.format_number(max), settings.format_number(min));
+    let margin = max_label.chars().count().max(min_label.chars().count());
+    let mut lines: Vec<String> = grid.into_iter().enumerate().map(|(index, line)| {
+      let label = match index {
+        0 => &max_label,
+        index if index == self.height - 1 => &min_label,
+        _ => "",
+      };
+      format!("{:>margin$} {}", label, line.into_iter().collect::<String>()).trim_end().to_string()
+    }).collect();
+
+    let (from_label, to_label) = (settings.format_number(from), settings.format_number(to));
+    let gap = self.width.saturating_sub(from_label.chars().count() + to_label.chars().count()).max(1);
+    lines.push(format!("{:margin$} {}{}{}", "", from_label, " ".repeat(gap), to_label));
+
+    Ok(lines.join("\n"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Plot;
+  use calc::settings::Settings;
+
+  #[test]
+  fn test_parse() {
+    let plot = Plot::parse("sin(x) from -pi to 2 * pi width 40 height 10").unwrap();
+    assert_eq!((plot.expression, plot.from, plot.to), ("sin(x)", "-pi", "2 * pi"));
+    assert_eq!((plot.width, plot.height, plot.samples), (40, 10, 40));
+
+    assert_eq!(Plot::parse("x from 0 to 1 samples 5").unwrap().samples, 5);
+    assert!(Plot::parse("x from 0").is_err());
+    assert!(Plot::parse("x from 0 to 1 width 1").is_err());
+  }
+
+  #[test]
+  fn test_render() {
+    let plot = Plot::parse("x from -2 to 2 width 5 height 5").unwrap();
+    let values: Vec<_> = plot.points(-2., 2.).into_iter().map(Ok).collect();
+    assert_eq!(plot.render(-2., 2., &values, &Settings::default()).unwrap(), [
+      " 2   | *",
+      "     |*",
+      "   --*--",
+      "    *|",
+      "-2 * |",
+      "   -2  2",
+    ].join("\n"));
+
+    // Failed and infinite samples are left out
+    let values = vec![Ok(1.), Err(eyre::eyre!("Invalid operation")), Ok(f64::INFINITY), Ok(3.)];
+    let plot = Plot::parse("x from 1 to 4 width 4 height 3").unwrap();
+    assert_eq!(plot.render(1., 4., &values, &Settings::default()).unwrap(), [
+      "3    *",
+      "",
+      "1 *",
+      "  1  4",
+    ].join("\n"));
+
+    let values = vec![Ok(f64::NAN), Ok(f64::NAN)];
+    assert!(plot.render(1., 4., &values, &Settings::default()).is_err());
+  }
+}
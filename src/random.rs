@@ -0,0 +1,32 @@
+use std::{cell::Cell, time::{SystemTime, UNIX_EPOCH}};
+
+thread_local! {
+  /// State of the SplitMix64 generator behind `rand`, seeded from the clock on first use.
+  /// Per thread rather than a global atomic so that tests running in parallel don't reseed each other
+  static STATE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+const GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+/// Restarts the sequence from `seed`, making the following numbers reproducible
+pub fn seed(seed: u64) {
+  STATE.set(Some(seed));
+}
+
+/// Next pseudo-random number in `[0, 1)`
+pub fn next() -> f64 {
+  let state = STATE.get().unwrap_or_else(|| {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_nanos() as u64
+  });
+  let state = state.wrapping_add(GAMMA);
+  STATE.set(Some(state));
+
+  let mut z = state;
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+  z ^= z >> 31;
+
+  // The top 53 bits fill the mantissa exactly
+  (z >> 11) as f64 / (1_u64 << 53) as f64
+}
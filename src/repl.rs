@@ -0,0 +1,844 @@
+use std::{collections::HashMap, fs};
+use eyre::{eyre, Result, WrapErr as _};
+
+use calc::{
+  display::render_steps,
+  diagnostic::Diagnostic,
+  diff::diff_ast,
+  lexer::{tokenize, tokenize_with_options, Token, TokenizeOptions},
+  parser::{parse_statement, Statement},
+  session::{Function, Session, Timings},
+  settings::Settings,
+  units,
+};
+
+use crate::{clipboard::Clipboard, help, history::History, plot::Plot, render_error, table::Table};
+
+/// State of the interactive session
+#[derive(Debug, Default)]
+pub struct Repl {
+  pub session: Session,
+  pub history: History,
+  /// Whether the output is a terminal, enabling colors and screen clearing
+  pub terminal: bool,
+  /// Suppresses the author message on empty input
+  pub quiet: bool,
+  /// Follows every result with the duration of each stage, as if it were run with `time`
+  pub time: bool,
+  /// Settings the session started with, restored by `reset`
+  pub defaults: Settings,
+  pub clipboard: Clipboard,
+}
+
+/// Splits a memory command like `m+` or `m2c` into the register name and the operation.
+/// `mr` isn't a command since it's usable inside expressions, and neither is the name of a variable
+/// like `mc`, alone or followed by `+` or `-`, so that it's evaluated instead
+fn memory_command<'a>(input: &'a str, variables: &HashMap<String, f64>) -> Option<(&'a str, char)> {
+  if variables.contains_key(input.strip_suffix(['+', '-']).unwrap_or(input)) {
+    return None
+  }
+  let rest = input.strip_prefix('m')?;
+  let operation = rest.chars().last()?;
+  let register = &rest[..rest.len() - operation.len_utf8()];
+
+  let valid = register.chars().all(|c| c.is_ascii_digit()) && match operation {
+    '+' | '-' | 'c' => true,
+    'r' => !register.is_empty(),
+    _ => false,
+  };
+  valid.then_some((register, operation))
+}
+
+/// Whether `input` is `command`, optionally followed by a space and an argument
+fn command(input: &str, command: &str) -> bool {
+  match input.strip_prefix(command) {
+    Some(rest) => rest.is_empty() || rest.starts_with(' '),
+    None => false,
+  }
+}
+
+/// Tokenizes and parses a line, whichever kind of statement it is
+fn parse_line(input: &str) -> Result<Statement> {
+  let mut lexer = tokenize(input)?;
+  Ok(parse_statement(&mut lexer)?)
+}
+
+impl Repl {
+  pub fn new(settings: Settings, terminal: bool) -> Self {
+    Self {
+      session: Session {
+        settings: settings.clone(),
+        ..Default::default()
+      },
+      terminal,
+      defaults: settings,
+      ..Default::default()
+    }
+  }
+
+  /// Handles a line of input, returning the text to print or `None` to exit
+  pub fn handle(&mut self, input: &str) -> Option<String> {
+    let lang = self.session.settings.lang;
+
+    let output = match input {
+      "" if self.quiet => String::new(),
+      "" => help::AUTHOR.get(lang).to_string(),
+      "exit" => return None,
+      "funcs" => help::render_list(&help::AVAILABLE_FUNCTIONS, help::FUNCTIONS, lang),
+      "ops" => help::render_list(&help::AVAILABLE_OPERATORS, help::OPERATORS, lang),
+      "consts" => help::render_list(&help::AVAILABLE_CONSTANTS, help::CONSTANTS, lang),
+      "help" => help::HELP.get(lang).to_string(),
+      input if command(input, "help") => help::render_topic(&input["help".len()..], lang),
+      "units" => units::render_list(),
+      "vars" => self.session.render_definitions(),
+      input if command(input, "unset") => self.unset(input["unset".len()..].trim()),
+      "history" => self.history.render(None, &self.session.settings),
+      input if command(input, "history") => match input["history".len()..].trim().parse() {
+        Ok(count) => self.history.render(Some(count), &self.session.settings),
+        Err(_) => "Usage: history [count]".to_string(),
+      },
+      input if command(input, "tokens") => self.tokens(input["tokens".len()..].trim()),
+      input if command(input, "ast") => self.ast(input["ast".len()..].trim()),
+      input if ["set", "precision", "mode", "lang"].iter().any(|name| command(input, name)) => self.set(input)
+        .unwrap_or_else(|| Err(eyre!("Usage: set precision | mode | lang | strict_division [value]")))
+        .unwrap_or_else(|report| report.to_string()),
+      input if command(input, "save") => self.save(input["save".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      input if command(input, "load") => self.load(input["load".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      "clear" => return Some(match self.terminal {
+        true => "\x1b[2J\x1b[H".to_string(),
+        false => String::new(),
+      }),
+      input if command(input, "reset") => self.reset(input["reset".len()..].trim()),
+      input if memory_command(input, &self.session.variables).is_some() => {
+        let (register, operation) = memory_command(input, &self.session.variables).unwrap();
+        self.memory(register, operation).unwrap_or_else(|report| report.to_string())
+      },
+      input if command(input, "plot") => self.plot(input["plot".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      input if command(input, "table") => self.table(input["table".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      input if command(input, "steps") => self.steps(input["steps".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      input if command(input, "simplify") => match self.session.simplify(input["simplify".len()..].trim()) {
+        Ok(node) => node.to_string(),
+        Err(report) => format!("{:#}", report),
+      },
+      input if command(input, "latex") => match self.session.parse(input["latex".len()..].trim()).and_then(|node| Ok(node.to_latex()?)) {
+        Ok(latex) => latex,
+        Err(report) => format!("{:#}", report),
+      },
+      input if command(input, "rpn") => match self.session.parse(input["rpn".len()..].trim()) {
+        Ok(node) => node.to_rpn(),
+        Err(report) => format!("{:#}", report),
+      },
+      input if command(input, "diff") => self.diff(input["diff".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      input if command(input, "derive") => self.derive(input["derive".len()..].trim()).unwrap_or_else(|report| format!("{:#}", report)),
+      input if command(input, "copy") => self.copy(input["copy".len()..].trim()).unwrap_or_else(|report| report.to_string()),
+      input if command(input, "time") => self.evaluate(input["time".len()..].trim(), true),
+      input => self.evaluate(input, self.time),
+    };
+
+    match output.is_empty() {
+      true => Some(output),
+      false => Some(output + "\n"),
+    }
+  }
+
+  /// Evaluates a line, followed by the duration of each stage if `timed` is set
+  fn evaluate(&mut self, input: &str, timed: bool) -> String {
+    let mut timings = Timings::default();
+    let outcome = self.session.execute_timed(input, &mut timings);
+    let mut output = match &outcome {
+      Ok(Some(result)) => self.session.settings.format_value(result),
+      Ok(None) => String::new(),
+      Err(report) => render_error(&self.session.diagnose(input, report), self.terminal),
+    };
+    self.history.push(input, outcome.map_err(|report| report.root_cause().to_string()));
+
+    if timed {
+      if !output.is_empty() {
+        output.push('\n');
+      }
+      output.push_str(&timings.to_string());
+    }
+    output
+  }
+
+  fn tokens(&self, input: &str) -> String {
+    match tokenize_with_options(input, TokenizeOptions { keep_comments: true, ..Default::default() }) {
+      Ok(lexer) => lexer.render(),
+      Err(error) => render_error(&Diagnostic::new(input, &error.into(), &[]), self.terminal),
+    }
+  }
+
+  fn ast(&self, input: &str) -> String {
+    match parse_line(input) {
+      Ok(Statement::Expression(node)) => node.render_tree(),
+      Ok(Statement::Assign(name, node)) => format!("Assign({})\n{}", name, node.render_tree()),
+      Ok(Statement::Define(name, params, body)) => format!("Define({}({}))\n{}", name, params.join(", "), body.render_tree()),
+      Err(report) => render_error(&self.session.diagnose(input, &report), self.terminal),
+    }
+  }
+
+  fn unset(&mut self, name: &str) -> String {
+    match self.session.unset(name) {
+      Ok(count) if name == "*" || name == "all" => format!("Removed {} definition(s)", count),
+      Ok(_) => format!("Removed {}", name),
+      Err(report) => report.to_string(),
+    }
+  }
+
+  /// Plots an expression of `x` over a range
+  fn plot(&self, input: &str) -> Result<String> {
+    let plot = Plot::parse(input)?;
+    let from = self.session.evaluate_expression(plot.from)?;
+    let to = self.session.evaluate_expression(plot.to)?;
+    if !from.is_finite() || !to.is_finite() || from >= to {
+      return Err(eyre!("The bounds must be finite, with the lower one less than the upper one"))
+    }
+
+    let values = self.session.sample(plot.expression, "x", &plot.points(from, to))?;
+    plot.render(from, to, &values, &self.session.settings)
+  }
+
+  /// Tabulates an expression over a range of values of a variable
+  fn table(&self, input: &str) -> Result<String> {
+    let table = Table::parse(input)?;
+    let from = self.session.evaluate_expression(table.from)?;
+    let to = self.session.evaluate_expression(table.to)?;
+    let step = table.step.map(|step| self.session.evaluate_expression(step)).transpose()?;
+
+    let points = Table::points(from, to, step)?;
+    let values = self.session.sample(table.expression, table.variable, &points)?;
+    Ok(table.render(&points, &values, &self.session.settings))
+  }
+
+  /// Shows each operation of the evaluation along with its result
+  fn steps(&self, input: &str) -> Result<String> {
+    let (steps, result) = self.session.steps(input)?;
+    let settings = &self.session.settings;
+
+    let mut output = render_steps(&steps, settings);
+    let last = match result {
+      // A lone number or variable takes no steps
+      Ok(result) if steps.is_empty() => settings.format_number(result),
+      Ok(_) => return Ok(output),
+      Err(report) => report.to_string(),
+    };
+    if !output.is_empty() {
+      output.push('\n');
+    }
+    output.push_str(&last);
+    Ok(output)
+  }
+
+  /// Differentiates `<expr> by <var>`, with respect to `x` if the variable is omitted
+  fn derive(&self, input: &str) -> Result<String> {
+    let (expression, var) = input.rsplit_once(" by ").unwrap_or((input, "x"));
+    let var = var.trim();
+    if !matches!(tokenize(var)?.collect_tokens().as_slice(), [Token::Variable(_)]) {
+      return Err(eyre!("Invalid variable name: {}", var))
+    }
+
+    Ok(self.session.derive(expression, var)?.to_string())
+  }
+
+  /// Structural differences between two expressions separated by `::`
+  fn diff(&self, input: &str) -> Result<String> {
+    let Some((left, right)) = input.split_once("::") else {
+      return Err(eyre!("Usage: diff <expression> :: <expression>"))
+    };
+
+    let differences = diff_ast(&self.session.parse(left.trim())?, &self.session.parse(right.trim())?);
+    if differences.is_empty() {
+      return Ok("No differences".to_string())
+    }
+    Ok(differences.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))
+  }
+
+  /// Copies the last result to the clipboard, in full precision or as displayed
+  fn copy(&mut self, form: &str) -> Result<String> {
+    let text = self.copy_text(form)?;
+    self.clipboard.set(&text)?;
+    Ok(format!("Copied {}", text))
+  }
+
+  /// Text placed on the clipboard by `copy`
+  fn copy_text(&self, form: &str) -> Result<String> {
+    let Some(ans) = self.session.ans else {
+      return Err(eyre!("No result to copy"))
+    };
+
+    match form {
+      "" => Ok(ans.to_string()),
+      "rounded" => Ok(self.session.settings.format_number(ans)),
+      _ => Err(eyre!("Usage: copy [rounded]")),
+    }
+  }
+
+  /// Adds the last result to a memory register, subtracts it, recalls or clears the register
+  fn memory(&mut self, register: &str, operation: char) -> Result<String> {
+    let session = &mut self.session;
+    let label = format!("M{}", register);
+    let value = session.memory.get(register).copied().unwrap_or(0.);
+
+    let value = match operation {
+      '+' | '-' => {
+        let Some(ans) = session.ans else {
+          return Err(eyre!("No result to store in {}", label))
+        };
+        let value = if operation == '+' { value + ans } else { value - ans };
+        session.memory.insert(register.to_string(), value);
+        value
+      },
+      'r' => value,
+      _ => {
+        session.memory.remove(register);
+        return Ok(format!("{} cleared", label))
+      },
+    };
+
+    Ok(format!("{} = {}", label, session.settings.format_number(value)))
+  }
+
+  /// Applies a settings command such as `precision 8`, returning `None` for other input
+  fn set(&mut self, input: &str) -> Option<Result<String>> {
+    let input = input.strip_prefix("set ").map(str::trim_start).unwrap_or(input);
+    let (name, value) = input.split_once(' ').unwrap_or((input, ""));
+    let value = value.trim();
+    let settings = &mut self.session.settings;
+
+    let show = value.is_empty();
+    let result = match name {
+      "precision" if show => Ok(format!("Precision: {}", settings.precision)),
+      "precision" => match value.parse() {
+        Ok(precision) if precision <= Settings::MAX_PRECISION => {
+          settings.precision = precision;
+          Ok(format!("Precision set to {}", precision))
+        },
+        _ => Err(eyre!("Precision must be a number from 0 to {}", Settings::MAX_PRECISION)),
+      },
+      "mode" if show => Ok(format!("Angle mode: {}", settings.angle_mode)),
+      "mode" => value.parse().map(|mode| {
+        settings.angle_mode = mode;
+        format!("Angle mode set to {}", mode)
+      }),
+      "lang" if show => Ok(format!("Language: {}", settings.lang)),
+      "lang" => value.parse().map(|lang| {
+        settings.lang = lang;
+        format!("Language set to {}", lang)
+      }),
+      "strict_division" if show => Ok(format!("Strict division: {}", settings.strict_division)),
+      "strict_division" => match value.parse() {
+        Ok(strict) => {
+          settings.strict_division = strict;
+          Ok(format!("Strict division set to {}", strict))
+        },
+        Err(_) => Err(eyre!("Strict division must be true or false")),
+      },
+      _ => return None,
+    };
+
+    Some(result)
+  }
+
+  /// Writes settings, variables, functions and `ans` to `path` as lines which `load` can read back
+  fn save(&self, path: &str) -> Result<String> {
+    let session = &self.session;
+    let settings = &session.settings;
+
+    let mut lines = vec![
+      "# uni-calc session".to_string(),
+      format!("precision {}", settings.precision),
+      format!("mode {}", settings.angle_mode),
+      format!("lang {}", settings.lang),
+      format!("set strict_division {}", settings.strict_division),
+    ];
+
+    let mut variables: Vec<_> = session.variables.iter().collect();
+    variables.sort_by(|a, b| a.0.cmp(b.0));
+    // Non-finite values print as `inf`, `-inf` and `NaN`, which read back as the `inf` and `nan` constants
+    lines.extend(variables.iter().map(|(name, value)| format!("{} = {}", name, value)));
+
+    let mut functions: Vec<_> = session.functions.iter().collect();
+    functions.sort_by(|a, b| a.0.cmp(b.0));
+    lines.extend(functions.iter().map(|(name, function)| format!("{}({}) = {}", name, function.params.join(", "), function.body)));
+
+    // A bare expression restores `ans` without defining anything
+    if let Some(ans) = session.ans {
+      lines.push(format!("{} # ans", ans));
+    }
+
+    fs::write(path, lines.join("\n") + "\n").wrap_err_with(|| format!("Unable to write {}", path))?;
+    Ok(format!("Saved {} definition(s) to {}", variables.len() + functions.len(), path))
+  }
+
+  /// Restores the lines of a file written by `save`, reporting failed lines without stopping
+  fn load(&mut self, path: &str) -> Result<String> {
+    let text = fs::read_to_string(path).wrap_err_with(|| format!("Unable to read {}", path))?;
+    let mut restored = 0;
+    let mut errors = vec![];
+
+    for (index, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue
+      }
+
+      match self.load_line(line) {
+        Ok(definition) => restored += definition as usize,
+        Err(report) => errors.push(format!("Line {}: {:#}", index + 1, report)),
+      }
+    }
+
+    let summary = format!("Restored {} definition(s) from {}", restored, path);
+    Ok(std::iter::once(summary).chain(errors).collect::<Vec<_>>().join("\n"))
+  }
+
+  /// Restores a setting, `ans`, or a variable or function, returning whether it was a definition.
+  /// Values are read as `save` wrote them rather than evaluated, so `ans` is only set by its own line
+  fn load_line(&mut self, line: &str) -> Result<bool> {
+    let number = |text: &str| text.trim().parse::<f64>().map_err(|_| eyre!("Expected a number, found {}", text.trim()));
+
+    if let Some(result) = self.set(line) {
+      return result.map(|_| false)
+    }
+    if let Some(ans) = line.strip_suffix("# ans") {
+      self.session.ans = Some(number(ans)?);
+      return Ok(false)
+    }
+
+    match parse_line(line)? {
+      Statement::Assign(name, _) => {
+        let (_, value) = line.split_once('=').expect("an assignment has an equals sign");
+        let value = number(value).wrap_err_with(|| format!("Invalid value of {}", name))?;
+        self.session.variables.insert(name, value);
+      },
+      Statement::Define(name, params, body) => {
+        self.session.functions.insert(name, Function { params, body });
+      },
+      Statement::Expression(_) => return Err(eyre!("Expected a setting, a definition or ans")),
+    }
+    Ok(true)
+  }
+
+  /// Restores the whole session, or a single setting, to the startup state
+  fn reset(&mut self, target: &str) -> String {
+    let defaults = self.defaults.clone();
+
+    match target {
+      "" => {
+        let summary = format!(
+          "Reset {} variable(s), {} function(s), {} history entries and all settings",
+          self.session.variables.len(),
+          self.session.functions.len(),
+          self.history.len(),
+        );
+        *self = Self {
+          quiet: self.quiet,
+          time: self.time,
+          ..Self::new(defaults, self.terminal)
+        };
+        summary
+      },
+      "precision" => {
+        self.session.settings.precision = defaults.precision;
+        format!("Precision reset to {}", defaults.precision)
+      },
+      "mode" => {
+        self.session.settings.angle_mode = defaults.angle_mode;
+        format!("Angle mode reset to {}", defaults.angle_mode)
+      },
+      "lang" => {
+        self.session.settings.lang = defaults.lang;
+        format!("Language reset to {}", defaults.lang)
+      },
+      "strict_division" => {
+        self.session.settings.strict_division = defaults.strict_division;
+        format!("Strict division reset to {}", defaults.strict_division)
+      },
+      _ => "Usage: reset [precision | mode | lang | strict_division]".to_string(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Repl;
+  use calc::settings::Settings;
+
+  fn handle(repl: &mut Repl, input: &str) -> String {
+    repl.handle(input).unwrap().trim_end().to_string()
+  }
+
+  #[test]
+  fn test_settings() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "1 / 3"), "0.33333");
+    assert_eq!(handle(&mut repl, "precision 8"), "Precision set to 8");
+    assert_eq!(handle(&mut repl, "1 / 3"), "0.33333333");
+    assert_eq!(handle(&mut repl, "precision 99"), "Precision must be a number from 0 to 15");
+
+    assert_eq!(handle(&mut repl, "mode deg"), "Angle mode set to deg");
+    assert_eq!(handle(&mut repl, "sin(30)"), "0.5");
+    assert_eq!(handle(&mut repl, "cos(180)"), "-1");
+    assert_eq!(handle(&mut repl, "atan(1)"), "45");
+    assert_eq!(handle(&mut repl, "atan2(1, -1)"), "135");
+    assert_eq!(handle(&mut repl, "mode grad"), "Unknown angle mode: grad (expected \"rad\" or \"deg\")");
+
+    assert_eq!(handle(&mut repl, "reset precision"), "Precision reset to 5");
+    assert_eq!(handle(&mut repl, "1 / 3"), "0.33333");
+    assert_eq!(handle(&mut repl, "reset mode"), "Angle mode reset to rad");
+    assert_eq!(handle(&mut repl, "cos(pi)"), "-1");
+  }
+
+  #[test]
+  fn test_strict_division() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "1 / 0"), "Error during evaluation: Invalid operation: division by zero while evaluating `1 / 0`");
+    assert_eq!(handle(&mut repl, "set strict_division false"), "Strict division set to false");
+    assert_eq!(handle(&mut repl, "1 / 0"), "inf");
+    assert_eq!(handle(&mut repl, "-1 / 0"), "-inf");
+    assert_eq!(handle(&mut repl, "0 / 0"), "NaN");
+    assert_eq!(handle(&mut repl, "set strict_division"), "Strict division: false");
+    assert_eq!(handle(&mut repl, "set strict_division maybe"), "Strict division must be true or false");
+    assert_eq!(handle(&mut repl, "set precision 2"), "Precision set to 2");
+    assert_eq!(handle(&mut repl, "set volume 11"), "Usage: set precision | mode | lang | strict_division [value]");
+
+    assert_eq!(handle(&mut repl, "reset strict_division"), "Strict division reset to true");
+    assert!(handle(&mut repl, "1 / 0").contains("division by zero"));
+  }
+
+  #[test]
+  fn test_defaults() {
+    let settings = Settings { precision: 2, ..Default::default() };
+    let mut repl = Repl::new(settings, false);
+
+    assert_eq!(handle(&mut repl, "1 / 3"), "0.33");
+    handle(&mut repl, "precision 4");
+    assert_eq!(handle(&mut repl, "1000 / 3"), "333.3333");
+    assert_eq!(handle(&mut repl, "reset precision"), "Precision reset to 2");
+    handle(&mut repl, "reset");
+    assert_eq!(handle(&mut repl, "1000 / 3"), "333.33");
+  }
+
+  #[test]
+  fn test_reset() {
+    let mut repl = Repl::default();
+
+    handle(&mut repl, "x = 2");
+    handle(&mut repl, "f(t) = t * x");
+    handle(&mut repl, "f(3)");
+    handle(&mut repl, "precision 2");
+    assert_eq!(handle(&mut repl, "ans + 1"), "7");
+
+    assert_eq!(handle(&mut repl, "reset"), "Reset 1 variable(s), 1 function(s), 4 history entries and all settings");
+    assert_eq!(handle(&mut repl, "vars"), "No variables or functions defined");
+    assert_eq!(handle(&mut repl, "history"), "History is empty");
+    assert!(handle(&mut repl, "ans").contains("Undefined variable: ans"));
+    assert_eq!(handle(&mut repl, "1 / 3"), "0.33333");
+  }
+
+  #[test]
+  fn test_tokens() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "tokens 2 ^ x"), "0: Literal(2.0) @ 0..1\n1: Operator(Pow) @ 2..3\n2: Variable(\"x\") @ 4..5");
+    assert_eq!(handle(&mut repl, "tokens 1 $ 2"), "1 $ 2\n  ^ Unknown character $");
+  }
+
+  #[test]
+  fn test_ast() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "ast -x"), "Neg\n  Variable(x)");
+    assert_eq!(handle(&mut repl, "ast f(x) = x"), "Define(f(x))\nVariable(x)");
+    assert_eq!(handle(&mut repl, "ast (1"), "(1\n^ Unclosed '(' opened at column 1");
+  }
+
+  #[test]
+  fn test_time() {
+    let mut repl = Repl::default();
+
+    let output = handle(&mut repl, "time 2 ^ 10");
+    let (result, timings) = output.split_once('\n').unwrap();
+    assert_eq!(result, "1024");
+    assert!(timings.starts_with("tokenize ") && timings.contains(", total "));
+    assert_eq!(handle(&mut repl, "history"), "  1: 2 ^ 10 = 1024");
+
+    // As with `--time`
+    let mut repl = Repl { time: true, ..Default::default() };
+    assert!(handle(&mut repl, "2 ^ 10").starts_with("1024\ntokenize "));
+    handle(&mut repl, "reset");
+    assert!(handle(&mut repl, "2 + 2").starts_with("4\ntokenize "));
+  }
+
+  #[test]
+  fn test_quiet() {
+    let mut repl = Repl { quiet: true, ..Default::default() };
+
+    assert_eq!(repl.handle("").unwrap(), "");
+    handle(&mut repl, "reset");
+    assert!(repl.quiet);
+    assert!(Repl::default().handle("").unwrap().starts_with("Author"));
+  }
+
+  #[test]
+  fn test_lang() {
+    let mut repl = Repl::default();
+
+    assert!(handle(&mut repl, "funcs").starts_with("Available functions:\n* abs(x) - absolute value"));
+    assert_eq!(handle(&mut repl, "lang ru"), "Language set to ru");
+    assert!(handle(&mut repl, "funcs").starts_with("Доступные функции:\n* abs(x) - модуль числа"));
+    assert!(handle(&mut repl, "").starts_with("Автор: "));
+    assert_eq!(handle(&mut repl, "lang fr"), "Unknown language: fr (expected \"en\" or \"ru\")");
+  }
+
+  #[test]
+  fn test_save_load() {
+    let path = std::env::temp_dir().join(format!("uni-calc-test-{}.calc", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut repl = Repl::default();
+    handle(&mut repl, "x = 1 / 3");
+    handle(&mut repl, "y = -2");
+    handle(&mut repl, "f(t, u) = (t + x) * -u ^ 2");
+    handle(&mut repl, "mode deg");
+    handle(&mut repl, "precision 3");
+    handle(&mut repl, "f(1, 2)");
+    assert_eq!(handle(&mut repl, &format!("save {}", path)), format!("Saved 3 definition(s) to {}", path));
+
+    assert_eq!(std::fs::read_to_string(path).unwrap(), [
+      "# uni-calc session",
+      "precision 3",
+      "mode deg",
+      "lang en",
+      "set strict_division true",
+      "x = 0.3333333333333333",
+      "y = -2",
+      "f(t, u) = (t + x) * -u ^ 2",
+      "5.333333333333333 # ans",
+      "",
+    ].join("\n"));
+
+    let mut loaded = Repl::default();
+    assert_eq!(handle(&mut loaded, &format!("load {}", path)), format!("Restored 3 definition(s) from {}", path));
+    assert_eq!(loaded.session.render_definitions(), repl.session.render_definitions());
+    assert_eq!(handle(&mut loaded, "ans + sin(30)"), "5.833");
+
+    // Values are read rather than evaluated, and `ans` is left alone
+    std::fs::write(path, "x = 2\nprecision 99\ny = x +\nz = x * 2\nf(x) = x +\n1 + 1\n").unwrap();
+    let mut loaded = Repl::default();
+    assert_eq!(handle(&mut loaded, &format!("load {}", path)), [
+      format!("Restored 1 definition(s) from {}", path),
+      "Line 2: Precision must be a number from 0 to 15".to_string(),
+      "Line 3: Expression ends after '+'; expected a value".to_string(),
+      "Line 4: Invalid value of z: Expected a number, found x * 2".to_string(),
+      "Line 5: Expression ends after '+'; expected a value".to_string(),
+      "Line 6: Expected a setting, a definition or ans".to_string(),
+    ].join("\n"));
+    assert_eq!(loaded.session.ans, None);
+    assert_eq!(handle(&mut loaded, "x"), "2");
+
+    let mut repl = Repl::default();
+    handle(&mut repl, "set strict_division false");
+    handle(&mut repl, "x = 1 / 0");
+    handle(&mut repl, "y = -1 / 0");
+    handle(&mut repl, "0 / 0");
+    handle(&mut repl, &format!("save {}", path));
+    assert!(std::fs::read_to_string(path).unwrap().ends_with("x = inf\ny = -inf\nNaN # ans\n"));
+
+    let mut loaded = Repl::default();
+    assert_eq!(handle(&mut loaded, &format!("load {}", path)), format!("Restored 2 definition(s) from {}", path));
+    assert_eq!(loaded.session.render_definitions(), repl.session.render_definitions());
+    assert!(loaded.session.ans.unwrap().is_nan());
+
+    std::fs::remove_file(path).unwrap();
+    assert!(handle(&mut loaded, &format!("load {}", path)).starts_with("Unable to read"));
+  }
+
+  #[test]
+  fn test_memory() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "mr"), "0");
+    assert_eq!(handle(&mut repl, "mc"), "M cleared");
+    assert_eq!(handle(&mut repl, "m+"), "M = 0");
+
+    handle(&mut repl, "2 + 3");
+    assert_eq!(handle(&mut repl, "m+"), "M = 5");
+    assert_eq!(handle(&mut repl, "m+"), "M = 10");
+    // Memory commands don't change `ans`
+    assert_eq!(handle(&mut repl, "ans"), "5");
+    assert_eq!(handle(&mut repl, "mr * 2"), "20");
+    assert_eq!(handle(&mut repl, "m-"), "M = -10");
+    assert_eq!(handle(&mut repl, "mc"), "M cleared");
+    assert_eq!(handle(&mut repl, "mr"), "0");
+
+    // Named registers are recalled with a command, which leaves `ans` as it is
+    handle(&mut repl, "7");
+    assert_eq!(handle(&mut repl, "m2+"), "M2 = 7");
+    handle(&mut repl, "1");
+    assert_eq!(handle(&mut repl, "m2r"), "M2 = 7");
+    assert_eq!(handle(&mut repl, "ans + mr"), "1");
+    assert_eq!(handle(&mut repl, "m2c"), "M2 cleared");
+
+    // Variables take precedence
+    handle(&mut repl, "mr = 3");
+    assert_eq!(handle(&mut repl, "mr"), "3");
+    handle(&mut repl, "mc = 4");
+    assert_eq!(handle(&mut repl, "mc"), "4");
+    handle(&mut repl, "m = 6");
+    assert!(handle(&mut repl, "m+").contains("Expression ends after '+'"));
+    assert_eq!(handle(&mut repl, "m2+"), "M2 = 6");
+
+    handle(&mut repl, "reset");
+    assert_eq!(handle(&mut repl, "m+"), "No result to store in M");
+  }
+
+  #[test]
+  fn test_table() {
+    let mut repl = Repl::default();
+    handle(&mut repl, "precision 2");
+
+    assert_eq!(handle(&mut repl, "table x^2 for x = 0 to 3 step 1"), [
+      "x | x^2",
+      "0 | 0",
+      "1 | 1",
+      "2 | 4",
+      "3 | 9",
+    ].join("\n"));
+    assert_eq!(handle(&mut repl, "table sqrt(t) for t = 1 to -1 step -1"), [
+      " t | sqrt(t)",
+      " 1 | 1",
+      " 0 | 0",
+      "-1 | Invalid operation: square root of negative number",
+    ].join("\n"));
+    assert_eq!(handle(&mut repl, "table x for x = 0 to 1").lines().count(), 22);
+    assert_eq!(handle(&mut repl, "table x for x = 0 to 1 step -1"), "The step must be positive to go from 0 to 1");
+  }
+
+  #[test]
+  fn test_steps() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "steps 2 + 3 * 4^2"), "4 ^ 2 = 16\n3 * 16 = 48\n2 + 48 = 50");
+    assert_eq!(handle(&mut repl, "steps -(1 + 2) * -2"), "1 + 2 = 3\n-(3) = -3\n(-3) * (-2) = 6");
+    assert_eq!(handle(&mut repl, "steps log2(2 ^ 3) / 3"), "2 ^ 3 = 8\nlog2(8) = 3\n3 / 3 = 1");
+    assert_eq!(handle(&mut repl, "steps 1 / 3"), "1 / 3 = 0.33333");
+    assert_eq!(handle(&mut repl, "steps pi"), "3.14159");
+    assert_eq!(handle(&mut repl, "steps 1 + 1 / 0"), "Invalid operation: division by zero while evaluating `1 / 0`");
+    assert_eq!(handle(&mut repl, "steps 1 + 2 + 1 / 0"), "1 + 2 = 3\nInvalid operation: division by zero while evaluating `1 / 0`");
+
+    let output = handle(&mut repl, &format!("steps 0{}", " + 1".repeat(60)));
+    assert_eq!(output.lines().count(), 51);
+    assert_eq!(output.lines().last(), Some("… 10 more steps"));
+  }
+
+  #[test]
+  fn test_simplify() {
+    let mut repl = Repl::default();
+    handle(&mut repl, "f(t) = t ^ 2");
+
+    assert_eq!(handle(&mut repl, "simplify 1 * x + 0 - -y"), "x + y");
+    assert_eq!(handle(&mut repl, "simplify f(3) * x"), "9 * x");
+    handle(&mut repl, "mode deg");
+    assert_eq!(handle(&mut repl, "simplify sin(90) * x"), "x");
+    assert_eq!(handle(&mut repl, "simplify x = 1"), "Expected an expression");
+  }
+
+  #[test]
+  fn test_latex() {
+    let mut repl = Repl::default();
+    handle(&mut repl, "f(t) = t ^ 2");
+
+    assert_eq!(handle(&mut repl, "latex sqrt(2)/3 + f(x)"), "\\frac{\\sqrt{2}}{3} + f\\left(x\\right)");
+    assert_eq!(handle(&mut repl, "latex x = 1"), "Expected an expression");
+  }
+
+  #[test]
+  fn test_rpn() {
+    let mut repl = Repl::default();
+    assert_eq!(handle(&mut repl, "rpn (1+2)*3"), "1 2 + 3 *");
+    assert_eq!(handle(&mut repl, "rpn x = 1"), "Expected an expression");
+  }
+
+  #[test]
+  fn test_diff() {
+    let mut repl = Repl::default();
+    assert_eq!(handle(&mut repl, "diff 2 + 3 :: 2 + 4"), "@ right\n- 3\n+ 4");
+    assert_eq!(handle(&mut repl, "diff (x+1)*2 :: x + 1 * 2"), "@ root\n- (x + 1) * 2\n+ x + 1 * 2");
+    assert_eq!(handle(&mut repl, "diff sin(x) :: sin(x)"), "No differences");
+    assert_eq!(handle(&mut repl, "diff 1 + 2"), "Usage: diff <expression> :: <expression>");
+    assert_eq!(handle(&mut repl, "diff 1 :: x = 2"), "Expected an expression");
+  }
+
+  #[test]
+  fn test_derive() {
+    let mut repl = Repl::default();
+    handle(&mut repl, "f(t) = t ^ 3");
+
+    assert_eq!(handle(&mut repl, "derive x^2 + sin(x) by x"), "2 * x + cos(x)");
+    assert_eq!(handle(&mut repl, "derive f(t) + t by t"), "3 * t ^ 2 + 1");
+    assert_eq!(handle(&mut repl, "derive log2(x)"), "1 / (x * 0.6931471805599453)");
+    assert_eq!(handle(&mut repl, "derive x % 3 by x"), "Error during differentiation: No derivative rule for %");
+    assert_eq!(handle(&mut repl, "derive x by 2"), "Invalid variable name: 2");
+  }
+
+  #[test]
+  fn test_copy() {
+    let mut repl = Repl::default();
+    assert_eq!(handle(&mut repl, "copy"), "No result to copy");
+
+    handle(&mut repl, "sqrt(2)");
+    assert_eq!(repl.copy_text("").unwrap(), "1.4142135623730951");
+    assert_eq!(repl.copy_text("rounded").unwrap(), "1.41421");
+    assert_eq!(handle(&mut repl, "copy exact"), "Usage: copy [rounded]");
+  }
+
+  #[test]
+  fn test_units() {
+    let mut repl = Repl::default();
+
+    assert_eq!(handle(&mut repl, "5 km to mi"), "3.10686");
+    assert_eq!(handle(&mut repl, "ans * 2"), "6.21371");
+    assert_eq!(handle(&mut repl, "100c to f"), "212");
+    assert_eq!(handle(&mut repl, "t = (20 + 5) celsius to kelvin"), "298.15");
+    assert_eq!(handle(&mut repl, "t"), "298.15");
+    assert_eq!(handle(&mut repl, "5 kg to km"), "Error during unit conversion: Cannot convert mass (kg) to length (km)");
+    assert!(handle(&mut repl, "units").starts_with("Available units"));
+  }
+
+  #[test]
+  fn test_plot() {
+    let mut repl = Repl::default();
+    handle(&mut repl, "a = 2");
+    handle(&mut repl, "f(t) = a * t");
+
+    assert_eq!(handle(&mut repl, "plot f(x) from -1 to 1 width 3 height 3"), [
+      " 2  |*",
+      "   -*-",
+      "-2 *|",
+      "   -1 1",
+    ].join("\n"));
+    // Samples where the expression fails are left out
+    assert_eq!(handle(&mut repl, "plot 1 / x from -1 to 1 width 3 height 3"), [
+      " 1  |*",
+      "   -+-",
+      "-1 *|",
+      "   -1 1",
+    ].join("\n"));
+    // Plotting doesn't change `ans`
+    assert_eq!(handle(&mut repl, "ans"), "2");
+
+    assert_eq!(handle(&mut repl, "plot x from 1 to 0"), "The bounds must be finite, with the lower one less than the upper one");
+    assert_eq!(handle(&mut repl, "plot g(x) from 0 to 1"), "Error during evaluation: Undefined function: g");
+    assert!(handle(&mut repl, "plot x").starts_with("Usage: plot"));
+  }
+
+  #[test]
+  fn test_clear() {
+    assert_eq!(Repl::new(Settings::default(), false).handle("clear").unwrap(), "");
+    assert_eq!(Repl::new(Settings::default(), true).handle("clear").unwrap(), "\x1b[2J\x1b[H");
+    assert_eq!(Repl::default().handle("exit"), None);
+  }
+}
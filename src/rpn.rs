@@ -0,0 +1,109 @@
+use crate::{lexer::Func, parser::{piecewise_arguments, Node}};
+
+/// Name of a built-in function in postfix notation, with the base in brackets for `log[2]` and `root[3]`.
+/// `rand` with bounds and functions taking any number of arguments are written with their argument count
+fn func_name(func: Func, count: usize) -> String {
+  match func {
+    Func::Log(base) => format!("log[{}]", base),
+    Func::Root(base) => format!("root[{}]", base),
+    Func::RandomRange => "rand:2".to_string(),
+    func if func.is_variadic() => format!("{}:{}", func, count),
+    func => func.to_string(),
+  }
+}
+
+impl Node {
+  /// Renders the expression in postfix notation, with tokens separated by spaces:
+  /// operands come before their operator (`1 2 + 3 *`), unary minus is `neg`,
+  /// functions follow their arguments (`2 sqrt`, `8 log[2]`, `3 81 log`), calls
+  /// of user-defined functions carry their argument count (`1 2 f:2`), and functions
+  /// passed by name are quoted (`'sin x map`). Lists carry their length (`1 2 list:2`),
+  /// conditionals follow the condition and both branches (`x 1 2 if`), and `piecewise`
+  /// follows its arguments with their count (`x 1 2 piecewise:3`). `let` follows the value
+  /// and the body with the name it binds (`2 x x + let[x]`)
+  pub fn to_rpn(&self) -> String {
+    enum Task<'a> {
+      Visit(&'a Node),
+      /// Token written after all of the node's operands
+      Emit(String),
+    }
+
+    let mut tasks = vec![Task::Visit(self)];
+    let mut tokens = vec![];
+
+    while let Some(task) = tasks.pop() {
+      let node = match task {
+        Task::Visit(node) => node,
+        Task::Emit(token) => {
+          tokens.push(token);
+          continue
+        },
+      };
+
+      let (token, operands): (String, Vec<&Node>) = match node {
+        Node::Immediate(value) if value.is_sign_negative() => {
+          tokens.push((-value).to_string());
+          ("neg".to_string(), vec![])
+        },
+        Node::Immediate(value) => (value.to_string(), vec![]),
+        Node::Variable(name) => (name.clone(), vec![]),
+        Node::Neg(node) => ("neg".to_string(), vec![node]),
+        Node::BinOp(op, left, right) => (op.to_string(), vec![left, right]),
+        Node::Func(func, args) => (func_name(*func, args.len()), args.iter().collect()),
+        Node::Call(name, args) => (format!("{}:{}", name, args.len()), args.iter().collect()),
+        Node::FuncRef(func) => (format!("'{}", func_name(*func, 0)), vec![]),
+        Node::OpRef(op) => (format!("'{}", op), vec![]),
+        Node::List(items) => (format!("list:{}", items.len()), items.iter().collect()),
+        Node::If { cond, then, else_ } => ("if".to_string(), vec![cond, then, else_]),
+        Node::Let { name, value, body } => (format!("let[{}]", name), vec![value, body]),
+        Node::Piecewise(cases, default) => (
+          format!("piecewise:{}", cases.len() * 2 + default.is_some() as usize),
+          piecewise_arguments(cases, default).collect(),
+        ),
+      };
+
+      // Kept on a stack rather than recursing, so that trees of any depth can be written.
+      // Operands are pushed in reverse so that they come out left to right
+      tasks.push(Task::Emit(token));
+      tasks.extend(operands.into_iter().rev().map(Task::Visit));
+    }
+
+    tokens.join(" ")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{lexer::tokenize, parser::parse_expression};
+
+  fn rpn(input: &str) -> String {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap().to_rpn()
+  }
+
+  #[test]
+  fn test_rpn() {
+    assert_eq!(rpn("(1+2)*3"), "1 2 + 3 *");
+    assert_eq!(rpn("1 + 2 * 3"), "1 2 3 * +");
+    assert_eq!(rpn("2 ^ 3 ^ 2"), "2 3 ^ 2 ^");
+    assert_eq!(rpn("1 - (2 - 3)"), "1 2 3 - -");
+    assert_eq!(rpn("-x ^ 2 + -(y % 3)"), "x neg 2 ^ y 3 % neg +");
+    assert_eq!(rpn("50% + 0.5"), "50 100 / 0.5 +");
+    assert_eq!(rpn("1 + 2 >= 3 == x"), "1 2 + 3 >= x ==");
+  }
+
+  #[test]
+  fn test_rpn_functions() {
+    assert_eq!(rpn("sqrt(2)"), "2 sqrt");
+    assert_eq!(rpn("log2(8) + root3(27)"), "8 log[2] 27 root[3] +");
+    assert_eq!(rpn("log(3, 81)"), "3 81 log");
+    assert_eq!(rpn("rand() + rand(1, 6)"), "rand 1 6 rand:2 +");
+    assert_eq!(rpn("f(1, g(x)) * h()"), "1 x g:1 f:2 h:0 *");
+    assert_eq!(rpn("map(log2, x)"), "'log[2] x map");
+    assert_eq!(rpn("zip(-, 1, 2)"), "'- 1 2 zip");
+    assert_eq!(rpn("max(1, 2, 3) + sum_list()"), "1 2 3 max:3 sum_list:0 +");
+    assert_eq!(rpn("zip(*, [1, 2], [])"), "'* 1 2 list:2 list:0 zip");
+    assert_eq!(rpn("if(x > 0, x, -x)"), "x 0 > x x neg if");
+    assert_eq!(rpn("piecewise(x < 0, 0, 1)"), "x 0 < 0 1 piecewise:3");
+    assert_eq!(rpn("let y = 2 in y * x"), "2 y x * let[y]");
+  }
+}
@@ -0,0 +1,893 @@
+use std::{borrow::Cow, collections::HashMap, f64::consts::PI, fmt, time::{Duration, Instant}};
+use eyre::{eyre, Report, Result, WrapErr as _};
+
+use crate::{
+  bytecode::compile_with_options,
+  diagnostic::Diagnostic,
+  error::{EvalError, ExpressionError, LimitExceeded, SpanError},
+  eval::{builtin, EvalOptions, Step, Value, DEADLINE_INTERVAL},
+  lexer::{tokenize, tokenize_with_options, Func, Lexer, Op, Token, TokenizeOptions},
+  parser::{parse_statement_with_options, Node, ParseOptions, Statement},
+  settings::{AngleMode, Settings},
+  units::Conversion,
+};
+
+/// Maximum depth of nested user-defined function calls
+const MAX_CALL_DEPTH: usize = 64;
+
+/// User-defined function, e.g. `f(x) = x ^ 2`
+#[derive(Debug)]
+pub struct Function {
+  pub params: Vec<String>,
+  pub body: Node,
+}
+
+/// Caps on the size of a line and the time spent on it, for evaluating untrusted input.
+/// Exceeding a size fails with `LimitExceeded` naming it. Unlimited by default
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+  /// Longest line in characters
+  pub max_length: usize,
+  /// Most tokens in a line, comments aside
+  pub max_tokens: usize,
+  /// Most nodes in the syntax tree of a line, both as parsed and with user-defined functions expanded
+  pub max_nodes: usize,
+  /// Longest an evaluation may run before failing with `EvalError::TimedOut`
+  pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self { max_length: usize::MAX, max_tokens: usize::MAX, max_nodes: usize::MAX, timeout: None }
+  }
+}
+
+impl Limits {
+  fn tokenize_options(self) -> TokenizeOptions {
+    TokenizeOptions { max_length: self.max_length, max_tokens: self.max_tokens, ..Default::default() }
+  }
+
+  fn parse_options(self) -> ParseOptions {
+    ParseOptions { max_nodes: self.max_nodes, ..Default::default() }
+  }
+}
+
+/// State shared between lines of input
+#[derive(Debug, Default)]
+pub struct Session {
+  /// Variables defined by assignments
+  pub variables: HashMap<String, f64>,
+  /// Functions defined by `name(params) = body`
+  pub functions: HashMap<String, Function>,
+  pub settings: Settings,
+  /// Result of the last evaluated expression, available as `ans`
+  pub ans: Option<f64>,
+  /// Memory registers by name, with `""` for the default one available as `mr`
+  pub memory: HashMap<String, f64>,
+  pub limits: Limits,
+}
+
+/// Time spent in each stage of `Session::execute_timed`
+#[derive(Debug, Default)]
+pub struct Timings {
+  pub tokenize: Duration,
+  pub parse: Duration,
+  pub evaluate: Duration,
+}
+
+/// Formats a duration in the coarsest unit that keeps it above 1
+fn format_duration(duration: Duration) -> String {
+  let seconds = duration.as_secs_f64();
+  if seconds >= 1.0 {
+    format!("{:.2}s", seconds)
+  } else if seconds >= 1e-3 {
+    format!("{:.2}ms", seconds * 1e3)
+  } else if seconds >= 1e-6 {
+    format!("{:.2}µs", seconds * 1e6)
+  } else {
+    "<1µs".to_string()
+  }
+}
+
+impl fmt::Display for Timings {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "tokenize {}, parse {}, evaluate {}, total {}",
+      format_duration(self.tokenize),
+      format_duration(self.parse),
+      format_duration(self.evaluate),
+      format_duration(self.tokenize + self.parse + self.evaluate),
+    )
+  }
+}
+
+fn scale(node: Node, factor: f64) -> Node {
+  Node::BinOp(Op::Mul, Box::new(node), Box::new(Node::Immediate(factor)))
+}
+
+/// Converts arguments and results of trigonometric functions from and to degrees
+fn to_degrees(func: Func, mut args: Vec<Node>) -> Node {
+  match func {
+    Func::Sin | Func::Cos => {
+      let func = if func == Func::Sin { Func::SinPi } else { Func::CosPi };
+      let arg = Node::BinOp(Op::Div, Box::new(args.remove(0)), Box::new(Node::Immediate(180.)));
+      Node::Func(func, vec![arg])
+    },
+    Func::Tg | Func::Ctg => Node::Func(func, vec![scale(args.remove(0), PI / 180.)]),
+    Func::Asin | Func::Acos | Func::Atan | Func::Atan2 => scale(Node::Func(func, args), 180. / PI),
+    func => Node::Func(func, args),
+  }
+}
+
+/// Pending work of `Session::expand`
+enum Expansion<'a> {
+  /// Expands the node, pushing the result
+  Visit(&'a Node),
+  /// Replaces the expanded children of the node with the node rebuilt around them
+  Build(&'a Node),
+  /// Takes the branch of the `if` if its expanded condition is known, otherwise expands both
+  Branch(&'a Node),
+  /// Checks the expanded condition of the case at the index of a `piecewise`, with the cases kept so far
+  Case(&'a Node, usize, Vec<(Node, Node)>),
+  /// Keeps the case at the index along with its expanded condition and value, going on to the next one
+  Keep(&'a Node, usize, Vec<(Node, Node)>, Node),
+  /// Finishes a `piecewise` with the kept cases and the expanded value of the last, which becomes the default
+  Default(Vec<(Node, Node)>),
+  /// Replaces the expanded arguments of a call with the expanded body of the function
+  Inline(&'a Node),
+  /// Enters the scope of a name bound by `let` or `filter`
+  Bind(&'a str),
+  /// Leaves the scope of the name bound last
+  Unbind,
+}
+
+/// State of expanding a line, shared with the expansions of the functions it inlines:
+/// the nodes of the tree, which grows as calls are inlined, the time left, and what conditions can be known from
+struct Expander<'a> {
+  /// Variables of the session, which conditions are folded with
+  context: Cow<'a, HashMap<String, f64>>,
+  /// Names bound by the `let`s and `filter`s enclosing the node being expanded, along with the ones
+  /// not known until evaluation, like the variable being sampled. Session variables of the same name don't apply to them
+  bound: Vec<String>,
+  /// Nodes expanded so far which are still part of the tree, so that calls nested in calls can't multiply it unchecked
+  nodes: usize,
+  max_nodes: usize,
+  /// Options of the evaluation which follows, whose deadline the expansion counts towards
+  options: EvalOptions<'a>,
+  /// Tasks worked through so far, for checking the deadline every so often
+  tasks: usize,
+}
+
+impl Expander<'_> {
+  /// Counts a task, failing once the deadline has passed
+  fn tick(&mut self) -> Result<()> {
+    if self.tasks.is_multiple_of(DEADLINE_INTERVAL) {
+      self.options.check_deadline()?;
+    }
+    self.tasks += 1;
+    Ok(())
+  }
+
+  /// Counts new nodes, failing as soon as there are more than allowed
+  fn add(&mut self, count: usize) -> Result<()> {
+    self.nodes += count;
+    if self.nodes > self.max_nodes {
+      return Err(LimitExceeded::Nodes(self.max_nodes).into())
+    }
+    Ok(())
+  }
+
+  /// Stops counting nodes dropped from the tree
+  fn remove(&mut self, count: usize) {
+    self.nodes -= count;
+  }
+
+  /// Value of an expanded condition, if it's known before evaluation: it may depend on
+  /// session variables, but not on bound names or random numbers. Conditions deeper than `MAX_DEPTH` are left unknown
+  fn known(&self, cond: &Node) -> Option<f64> {
+    cond.check_depth().ok()?;
+    let free = cond.variables();
+    if free.iter().any(|name| self.bound.contains(name)) {
+      return None
+    }
+    let values: Vec<(&str, Node)> = free.iter()
+      .filter_map(|name| Some((name.as_str(), Node::Immediate(self.context.get(name).copied().or_else(|| builtin(name))?))))
+      .collect();
+    let cond = cond.substitute_all(&values.iter().map(|(name, value)| (*name, value)).collect());
+    Some(cond).filter(Node::is_constant)?.evaluate(self.options).ok()
+  }
+}
+
+/// Goes on to the condition of the case at `index` of a `piecewise`, or finishes it past the last case
+fn next_case<'a>(
+  tasks: &mut Vec<Expansion<'a>>,
+  values: &mut Vec<Node>,
+  expander: &mut Expander,
+  node: &'a Node,
+  index: usize,
+  kept: Vec<(Node, Node)>,
+) -> Result<()> {
+  let Node::Piecewise(cases, default) = node else {
+    unreachable!("only piecewise nodes have cases")
+  };
+  match (cases.get(index), default) {
+    (Some((cond, _)), _) => tasks.extend([Expansion::Case(node, index, kept), Expansion::Visit(cond)]),
+    (None, Some(default)) => tasks.extend([Expansion::Default(kept), Expansion::Visit(default)]),
+    (None, None) if kept.is_empty() => return Err(EvalError::NoMatchingCase.into()),
+    (None, None) => {
+      expander.add(1)?;
+      values.push(Node::Piecewise(kept, None));
+    },
+  }
+  Ok(())
+}
+
+impl Session {
+  /// Runs the whole tokenize → parse → evaluate pipeline on a single line,
+  /// which is either an expression, an assignment (`x = 2 + 3`) or a function definition (`f(x) = x ^ 2`).
+  /// Expressions and assignments may end with a unit conversion like `km to mi`.
+  /// Returns `None` for function definitions
+  pub fn execute(&mut self, input: &str) -> Result<Option<Value>> {
+    self.execute_timed(input, &mut Timings::default())
+  }
+
+  /// Same as `execute`, recording the duration of each stage that was reached into `timings`
+  pub fn execute_timed(&mut self, input: &str, timings: &mut Timings) -> Result<Option<Value>> {
+    let start = Instant::now();
+    let lexer = tokenize_with_options(input, self.limits.tokenize_options()).wrap_err("Error during tokenization");
+    timings.tokenize = start.elapsed();
+    let mut lexer = lexer?;
+
+    let conversion = Conversion::split(&lexer, input).map(|(count, conversion)| {
+      lexer.truncate(count);
+      conversion
+    });
+
+    let start = Instant::now();
+    let statement = parse_statement_with_options(&mut lexer, self.limits.parse_options()).wrap_err("Error during AST construction");
+    timings.parse = start.elapsed();
+
+    let start = Instant::now();
+    let result = self.run(statement?, &lexer, conversion.as_ref());
+    timings.evaluate = start.elapsed();
+    result
+  }
+
+  fn run(&mut self, statement: Statement, lexer: &Lexer, conversion: Option<&Conversion>) -> Result<Option<Value>> {
+    // The conversion applies to the result, so it's the converted value that's stored
+    let convert = |value: Value| match conversion {
+      Some(conversion) => value.try_map(|value| conversion.apply(value)).wrap_err("Error during unit conversion"),
+      None => Ok(value),
+    };
+
+    match statement {
+      // Only numbers are kept as `ans`
+      Statement::Expression(node) => {
+        let value = convert(self.evaluate(&node, lexer)?)?;
+        if let Value::Number(number) = value {
+          self.ans = Some(number);
+        }
+        Ok(Some(value))
+      },
+      Statement::Assign(name, node) => {
+        let value = match convert(self.evaluate(&node, lexer)?)? {
+          Value::Number(value) => value,
+          Value::List(_) => return Err(eyre!("Cannot assign a list to {}, variables only hold numbers", name)),
+          Value::Text(_) => return Err(eyre!("Cannot assign text to {}, variables only hold numbers", name)),
+        };
+        self.variables.insert(name, value);
+        self.ans = Some(value);
+        Ok(Some(value.into()))
+      },
+      Statement::Define(..) if conversion.is_some() => Err(eyre!("Cannot convert units of a function definition")),
+      Statement::Define(name, params, body) => {
+        self.functions.insert(name, Function { params, body });
+        Ok(None)
+      },
+    }
+  }
+
+  /// Inlines calls to user-defined functions and applies the angle mode, failing if the result
+  /// has more nodes than the limits allow or the deadline of `options` passes.
+  /// Conditions are folded with the session variables, except for the `unknown` ones
+  fn expand(&self, node: &Node, options: EvalOptions, unknown: &[&str]) -> Result<Node> {
+    let mut expander = Expander {
+      context: self.context(),
+      bound: unknown.iter().map(|name| name.to_string()).collect(),
+      nodes: 0,
+      max_nodes: self.limits.max_nodes,
+      options,
+      tasks: 0,
+    };
+    self.expand_calls(node, 0, &mut expander)
+  }
+
+  /// Works through the tree on its own stack, recursing only into the bodies of inlined functions
+  fn expand_calls(&self, node: &Node, depth: usize, expander: &mut Expander) -> Result<Node> {
+    let mut tasks = vec![Expansion::Visit(node)];
+    let mut values: Vec<Node> = vec![];
+
+    while let Some(task) = tasks.pop() {
+      expander.tick()?;
+      match task {
+        Expansion::Visit(node) => match node {
+          Node::Neg(operand) => tasks.extend([Expansion::Build(node), Expansion::Visit(operand)]),
+          Node::BinOp(_, left, right) => tasks.extend([Expansion::Build(node), Expansion::Visit(right), Expansion::Visit(left)]),
+          Node::Let { name, value, body } => tasks.extend([
+            Expansion::Build(node),
+            Expansion::Unbind,
+            Expansion::Visit(body),
+            Expansion::Bind(name),
+            Expansion::Visit(value),
+          ]),
+          // The variable is bound in the condition
+          Node::Func(Func::Filter, args) if matches!(args.as_slice(), [_, Node::Variable(_), _]) => {
+            let Node::Variable(var) = &args[1] else { unreachable!() };
+            tasks.extend([
+              Expansion::Build(node),
+              Expansion::Visit(&args[2]),
+              Expansion::Visit(&args[1]),
+              Expansion::Unbind,
+              Expansion::Visit(&args[0]),
+              Expansion::Bind(var),
+            ]);
+          },
+          Node::Func(_, args) | Node::List(args) => {
+            tasks.push(Expansion::Build(node));
+            tasks.extend(args.iter().rev().map(Expansion::Visit));
+          },
+          Node::If { cond, .. } => tasks.extend([Expansion::Branch(node), Expansion::Visit(cond)]),
+          Node::Piecewise(..) => next_case(&mut tasks, &mut values, expander, node, 0, vec![])?,
+          Node::Call(name, args) => {
+            let Some(function) = self.functions.get(name) else {
+              return Err(EvalError::UndefinedFunction(name.clone()).into())
+            };
+            if args.len() != function.params.len() {
+              return Err(eyre!("{} expects {} argument(s), got {}", name, function.params.len(), args.len()))
+            }
+            if depth >= MAX_CALL_DEPTH {
+              return Err(eyre!("Too many nested calls of {}", name))
+            }
+            tasks.push(Expansion::Inline(node));
+            tasks.extend(args.iter().rev().map(Expansion::Visit));
+          },
+          node => {
+            expander.add(1)?;
+            values.push(node.clone());
+          },
+        },
+        Expansion::Build(node) => {
+          let count = match node {
+            Node::Neg(_) => 1,
+            Node::BinOp(..) | Node::Let { .. } => 2,
+            Node::If { .. } => 3,
+            Node::Func(_, args) | Node::List(args) => args.len(),
+            _ => unreachable!("only nodes with children are built"),
+          };
+          let children = values.split_off(values.len() - count);
+          let node = match node {
+            Node::Func(func, _) => match self.settings.angle_mode {
+              AngleMode::Radians => Node::Func(*func, children),
+              AngleMode::Degrees => to_degrees(*func, children),
+            },
+            Node::List(_) => Node::List(children),
+            node => {
+              let mut children = children.into_iter().map(Box::new);
+              let mut next = || children.next().expect("every child was expanded");
+              match node {
+                Node::Neg(_) => Node::Neg(next()),
+                Node::BinOp(op, _, _) => Node::BinOp(*op, next(), next()),
+                Node::Let { name, .. } => Node::Let { name: name.clone(), value: next(), body: next() },
+                _ => Node::If { cond: next(), then: next(), else_: next() },
+              }
+            },
+          };
+          expander.add(1)?;
+          values.push(node);
+        },
+        // Picking the branch while the condition is known lets functions call themselves
+        Expansion::Branch(node) => {
+          let Node::If { then, else_, .. } = node else {
+            unreachable!("only if nodes have branches")
+          };
+          let cond = values.last().expect("the condition was expanded");
+          match expander.known(cond) {
+            Some(value) => {
+              expander.remove(cond.count_nodes());
+              values.pop();
+              tasks.push(Expansion::Visit(if value != 0. { then } else { else_ }));
+            },
+            None => tasks.extend([Expansion::Build(node), Expansion::Visit(else_), Expansion::Visit(then)]),
+          }
+        },
+        // Same for the cases of `piecewise`, the first one known to hold becoming the default
+        Expansion::Case(node, index, kept) => {
+          let Node::Piecewise(cases, _) = node else {
+            unreachable!("only piecewise nodes have cases")
+          };
+          let cond = values.pop().expect("the condition was expanded");
+          let value = &cases[index].1;
+          let known_value = expander.known(&cond);
+          if known_value.is_some() {
+            expander.remove(cond.count_nodes());
+          }
+          match known_value {
+            Some(0.) => next_case(&mut tasks, &mut values, expander, node, index + 1, kept)?,
+            Some(_) if kept.is_empty() => tasks.push(Expansion::Visit(value)),
+            Some(_) => tasks.extend([Expansion::Default(kept), Expansion::Visit(value)]),
+            None => tasks.extend([Expansion::Keep(node, index, kept, cond), Expansion::Visit(value)]),
+          }
+        },
+        Expansion::Keep(node, index, mut kept, cond) => {
+          kept.push((cond, values.pop().expect("the value was expanded")));
+          next_case(&mut tasks, &mut values, expander, node, index + 1, kept)?;
+        },
+        Expansion::Default(kept) => {
+          let default = values.pop().expect("the default was expanded");
+          if kept.is_empty() {
+            values.push(default);
+          } else {
+            expander.add(1)?;
+            values.push(Node::Piecewise(kept, Some(Box::new(default))));
+          }
+        },
+        Expansion::Inline(node) => {
+          let Node::Call(name, args) = node else {
+            unreachable!("only calls are inlined")
+          };
+          // Substituting may take long, so the deadline is checked before every call
+          expander.options.check_deadline()?;
+          let args = values.split_off(values.len() - args.len());
+          // The arguments are counted again wherever they are substituted
+          expander.remove(args.iter().map(Node::count_nodes).sum());
+          let function = &self.functions[name];
+          let bindings = function.params.iter().map(String::as_str).zip(args.iter()).collect();
+          values.push(self.expand_calls(&function.body.substitute_all(&bindings), depth + 1, expander)?);
+        },
+        Expansion::Bind(name) => expander.bound.push(name.to_string()),
+        Expansion::Unbind => {
+          expander.bound.pop();
+        },
+      }
+    }
+
+    Ok(values.pop().expect("expansion leaves a single node"))
+  }
+
+  /// Variables along with `ans` and `mr`
+  fn context(&self) -> Cow<'_, HashMap<String, f64>> {
+    // `ans` and `mr` don't shadow variables with the same name.
+    // Like on a physical calculator, memory is 0 until something is stored
+    let builtins = [("ans", self.ans), ("mr", Some(self.memory.get("").copied().unwrap_or(0.)))];
+    let mut context = Cow::Borrowed(&self.variables);
+    for (name, value) in builtins {
+      if let (Some(value), false) = (value, self.variables.contains_key(name)) {
+        context.to_mut().insert(name.to_string(), value);
+      }
+    }
+    context
+  }
+
+  /// Options for an evaluation starting now, along with the expansion before it
+  fn options(&self) -> EvalOptions<'static> {
+    EvalOptions {
+      strict_division: self.settings.strict_division,
+      deadline: self.limits.timeout.map(|timeout| Instant::now() + timeout),
+      variables: None,
+    }
+  }
+
+  fn evaluate(&self, node: &Node, lexer: &Lexer) -> Result<Value> {
+    let options = self.options();
+    let context = self.context();
+    self.expand(node, options, &[])
+      .and_then(|node| match node.evaluate_located(EvalOptions { variables: Some(&context), ..options }) {
+        Err(ExpressionError { error, expression: None }) => Err(error.into()),
+        result => Ok(result?),
+      })
+      .map_err(|report| {
+        let error = match report.downcast::<EvalError>() {
+          Ok(error) => error,
+          Err(report) => return report,
+        };
+        // Point at the first occurrence of an undefined name
+        let span = match &error {
+          EvalError::UndefinedVariable(name) | EvalError::UndefinedFunction(name) => lexer.find(&Token::Variable(name.clone())),
+          _ => None,
+        };
+        match span {
+          Some(span) => Report::new(SpanError::new(error, span)),
+          None => Report::new(error),
+        }
+      })
+      .wrap_err("Error during evaluation")
+  }
+
+  /// Tokenizes and parses a line which must be a plain expression
+  fn parse_expression(&self, input: &str) -> Result<(Node, Lexer)> {
+    let mut lexer = tokenize_with_options(input, self.limits.tokenize_options()).wrap_err("Error during tokenization")?;
+    match parse_statement_with_options(&mut lexer, self.limits.parse_options()).wrap_err("Error during AST construction")? {
+      Statement::Expression(node) => Ok((node, lexer)),
+      _ => Err(eyre!("Expected an expression")),
+    }
+  }
+
+  /// Parses an expression without expanding user-defined functions or evaluating it
+  pub fn parse(&self, input: &str) -> Result<Node> {
+    self.parse_expression(input).map(|(node, _)| node)
+  }
+
+  /// Evaluates an expression without storing the result in `ans`
+  pub fn evaluate_expression(&self, input: &str) -> Result<f64> {
+    let (node, lexer) = self.parse_expression(input)?;
+    Ok(self.evaluate(&node, &lexer)?.into_number()?)
+  }
+
+  /// Evaluates an expression with `variable` bound to each of `values`, without storing anything in `ans`.
+  /// Fails if the expression is invalid as a whole, otherwise each value gets its own result
+  pub fn sample(&self, input: &str, variable: &str, values: &[f64]) -> Result<Vec<Result<f64>>> {
+    let (node, _) = self.parse_expression(input)?;
+    let options = self.options();
+    let node = self.expand(&node, options, &[variable]).wrap_err("Error during evaluation")?;
+
+    let context = self.context();
+    let program = match node.check_depth() {
+      Ok(()) => Some(compile_with_options(&node, options)?),
+      Err(_) => None,
+    };
+    let vars: Option<Vec<f64>> = program.as_ref().and_then(|program| {
+      program.variables().iter()
+        .map(|name| if name == variable { Some(0.) } else { context.get(name).copied().or_else(|| builtin(name)) })
+        .collect()
+    });
+
+    match (program, vars) {
+      (Some(program), Some(mut vars)) => {
+        let slot = program.variables().iter().position(|name| name == variable);
+        Ok(values.iter().map(|&value| {
+          if let Some(slot) = slot {
+            vars[slot] = value;
+          }
+          program.run(&vars)
+        }).collect())
+      },
+      // Evaluating the tree reports the undefined variable, or whichever error comes first.
+      // It also takes trees too deep to compile
+      _ => {
+        let mut context = context.into_owned();
+        Ok(values.iter().map(|&value| {
+          context.insert(variable.to_string(), value);
+          node.evaluate(EvalOptions { variables: Some(&context), ..options }).map_err(Report::from)
+        }).collect())
+      },
+    }
+  }
+
+  /// Evaluates an expression one operation at a time, without storing the result in `ans`.
+  /// Fails if the expression is invalid as a whole, otherwise returns the steps taken before the result or error
+  pub fn steps(&self, input: &str) -> Result<(Vec<Step>, Result<f64>)> {
+    let (node, _) = self.parse_expression(input)?;
+    let options = self.options();
+    let node = self.expand(&node, options, &[]).wrap_err("Error during evaluation")?;
+
+    let mut steps = vec![];
+    let result = node.evaluate_steps(EvalOptions { variables: Some(&self.context()), ..options }, &mut steps).map_err(Report::from);
+    Ok((steps, result))
+  }
+
+  /// Simplifies an expression without evaluating it, folding constants according to the settings
+  pub fn simplify(&self, input: &str) -> Result<Node> {
+    let (node, _) = self.parse_expression(input)?;
+    let options = self.options();
+    let fold = |node: &Node| {
+      self.expand(node, options, &[]).ok()?.evaluate(options).ok()
+    };
+    Ok(node.simplify_with(&fold))
+  }
+
+  /// Differentiates an expression with respect to `var` and simplifies the result
+  pub fn derive(&self, input: &str, var: &str) -> Result<Node> {
+    let (node, _) = self.parse_expression(input)?;
+    let options = self.options();
+    let derivative = self.expand(&node, options, &[var])
+      .and_then(|node| node.differentiate(var))
+      .wrap_err("Error during differentiation")?;
+
+    let fold = |node: &Node| {
+      self.expand(node, options, &[]).ok()?.evaluate(options).ok()
+    };
+    Ok(derivative.simplify_with(&fold))
+  }
+
+  /// Describes an error from executing `input`, suggesting the variables and functions of the session
+  /// along with the built-in names in place of undefined ones
+  pub fn diagnose(&self, input: &str, report: &Report) -> Diagnostic {
+    let context = self.context();
+    let mut known: Vec<&str> = context.keys().chain(self.functions.keys()).map(String::as_str).collect();
+    known.sort_unstable();
+    Diagnostic::new(input, report, &known)
+  }
+
+  /// Removes a variable and/or function by name, or every definition for `*` and `all`.
+  /// Returns the number of removed definitions
+  pub fn unset(&mut self, name: &str) -> Result<usize> {
+    if name == "*" || name == "all" {
+      let count = self.variables.len() + self.functions.len();
+      self.variables.clear();
+      self.functions.clear();
+      return Ok(count)
+    }
+
+    match tokenize(name)?.collect_tokens().as_slice() {
+      [Token::Variable(_)] => {},
+      [Token::Constant(_) | Token::Function(_)] => return Err(eyre!("Cannot unset built-in {}", name)),
+      _ => return Err(eyre!("Invalid name: {}", name)),
+    }
+
+    let count = self.variables.remove(name).is_some() as usize + self.functions.remove(name).is_some() as usize;
+    if count == 0 {
+      return Err(eyre!("{} is not defined", name))
+    }
+
+    Ok(count)
+  }
+
+  /// Lists variables and functions sorted by name, as printed by `vars`
+  pub fn render_definitions(&self) -> String {
+    if self.variables.is_empty() && self.functions.is_empty() {
+      return "No variables or functions defined".to_string()
+    }
+
+    let mut lines = vec![];
+
+    if !self.variables.is_empty() {
+      let mut variables: Vec<_> = self.variables.iter().collect();
+      variables.sort_by(|a, b| a.0.cmp(b.0));
+
+      lines.push("Variables:".to_string());
+      lines.extend(variables.into_iter().map(|(name, value)| format!("  {} = {}", name, self.settings.format_number(*value))));
+    }
+
+    if !self.functions.is_empty() {
+      let mut functions: Vec<_> = self.functions.iter().collect();
+      functions.sort_by(|a, b| a.0.cmp(b.0));
+
+      lines.push("Functions:".to_string());
+      lines.extend(functions.into_iter().map(|(name, function)| format!("  {}({}) = {}", name, function.params.join(", "), function.body)));
+    }
+
+    lines.join("\n")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+  use super::{format_duration, Limits, Session, Timings};
+  use crate::{error::{EvalError, LexError, LimitExceeded, ParseError, SpanError}, eval::Value, walk::MAX_DEPTH};
+
+  #[test]
+  fn test_assignment() {
+    let mut session = Session::default();
+
+    assert_eq!(session.execute("x = 2 + 3").unwrap(), Some(5.0.into()));
+    assert_eq!(session.execute("y = x * 2").unwrap(), Some(10.0.into()));
+    assert_eq!(session.execute("x + y").unwrap(), Some(15.0.into()));
+    assert_eq!(session.execute("x = x + 1").unwrap(), Some(6.0.into()));
+    assert_eq!(session.variables["x"], 6.);
+    // Comparison rather than assignment
+    assert_eq!(session.execute("x == 6").unwrap(), Some(1.0.into()));
+    assert_eq!(session.variables["x"], 6.);
+
+    assert!(session.execute("z + 1").is_err());
+    assert!(session.execute("pi = 3").is_err());
+    assert!(session.execute("x = ").is_err());
+
+    // `inf` and `nan` are only there until a variable takes the name
+    assert_eq!(session.execute("-INF < x").unwrap(), Some(1.0.into()));
+    assert_eq!(session.execute("inf = 3").unwrap(), Some(3.0.into()));
+    assert_eq!(session.execute("inf + 1").unwrap(), Some(4.0.into()));
+    assert_eq!(session.sample("inf * t", "t", &[2.]).unwrap()[0].as_ref().unwrap(), &6.);
+    assert!(session.execute("INF").unwrap().unwrap().into_number().unwrap().is_infinite());
+    assert!(session.execute("nan").unwrap().unwrap().into_number().unwrap().is_nan());
+    assert!(session.execute("2 + 3)").is_err());
+  }
+
+  #[test]
+  fn test_lists() {
+    let mut session = Session::default();
+    session.execute("x = 2").unwrap();
+    session.execute("f(t) = t ^ 2").unwrap();
+
+    assert_eq!(session.execute("[x, f(x), -1]").unwrap(), Some(Value::List(vec![2., 4., -1.])));
+    assert_eq!(session.execute("[1, 2] km to m").unwrap(), Some(Value::List(vec![1000., 2000.])));
+    // Text has no number left to convert
+    let report = session.execute("format_eng(1500) km to m").unwrap_err();
+    assert_eq!(report.root_cause().downcast_ref::<EvalError>(), Some(&EvalError::UnexpectedText));
+    // Only numbers become `ans`
+    assert_eq!(session.ans, Some(2.));
+    assert_eq!(session.execute("y = [1, 2]").unwrap_err().to_string(), "Cannot assign a list to y, variables only hold numbers");
+  }
+
+  #[test]
+  fn test_functions() {
+    let mut session = Session::default();
+
+    session.execute("f(x) = x ^ 2").unwrap();
+    session.execute("g(x, y) = f(x) + y").unwrap();
+    session.execute("y = 10").unwrap();
+
+    assert_eq!(session.execute("f(3)").unwrap(), Some(9.0.into()));
+    assert_eq!(session.execute("g(2, 1)").unwrap(), Some(5.0.into()));
+    // Arguments referring to parameter names aren't captured
+    assert_eq!(session.execute("g(y, 2)").unwrap(), Some(102.0.into()));
+
+    assert!(session.execute("f(1, 2)").is_err());
+    assert!(session.execute("h(1)").is_err());
+
+    session.execute("r(x) = r(x) + 1").unwrap();
+    assert!(session.execute("r(1)").is_err());
+
+    // Recursion ends where the condition is known, which it is for session variables
+    session.execute("fact(n) = if(n <= 1, 1, n * fact(n - 1))").unwrap();
+    assert_eq!(session.execute("fact(5)").unwrap(), Some(120.0.into()));
+    session.execute("m = 5").unwrap();
+    assert_eq!(session.execute("fact(m) + fact(m - 2)").unwrap(), Some(126.0.into()));
+    // but not for names bound in the expression, which may differ from the variables
+    assert!(session.execute("let m = 3 in fact(m)").is_err());
+    assert_eq!(session.sample("fact(m)", "m", &[3.]).unwrap_err().root_cause().to_string(), "Too many nested calls of fact");
+    session.execute("clamp(v) = if(v > 1, 1, v)").unwrap();
+    assert_eq!(session.execute("x = 3").unwrap(), Some(3.0.into()));
+    assert_eq!(session.execute("clamp(x) + clamp(0.5)").unwrap(), Some(1.5.into()));
+    // Bound names don't leak out of `let`
+    assert_eq!(session.execute("let k = 4 in k * x").unwrap(), Some(12.0.into()));
+    assert!(session.execute("k").is_err());
+    session.execute("fib(n) = piecewise(n < 1, 0, n < 2, 1, fib(n - 1) + fib(n - 2))").unwrap();
+    assert_eq!(session.execute("fib(10)").unwrap(), Some(55.0.into()));
+    assert_eq!(session.execute("fib(m + 5)").unwrap(), Some(55.0.into()));
+    assert!(session.execute("let m = 10 in fib(m)").is_err());
+    // Arguments aren't captured by names bound inside the body either
+    session.execute("x = 1").unwrap();
+    session.execute("above(y) = len(filter(x > y, x, [1, 2, 3]))").unwrap();
+    assert_eq!(session.execute("above(1)").unwrap(), Some(2.0.into()));
+    assert_eq!(session.execute("above(x)").unwrap(), Some(2.0.into()));
+    session.execute("shifted(y) = let x = 5 in x + y").unwrap();
+    assert_eq!(session.execute("shifted(x)").unwrap(), Some(6.0.into()));
+  }
+
+  #[test]
+  fn test_unset() {
+    let mut session = Session::default();
+
+    session.execute("x = 2").unwrap();
+    session.execute("y = 3").unwrap();
+    session.execute("f(x) = x + y").unwrap();
+    assert_eq!(session.execute("f(x)").unwrap(), Some(5.0.into()));
+
+    assert_eq!(session.unset("y").unwrap(), 1);
+    assert!(session.execute("f(x)").is_err());
+    assert!(session.unset("y").is_err());
+
+    assert!(session.unset("pi").is_err());
+    assert!(session.unset("sqrt").is_err());
+    assert!(session.unset("2 + 2").is_err());
+
+    session.execute("y = 1").unwrap();
+    session.execute("g(t) = t").unwrap();
+    assert_eq!(session.unset("*").unwrap(), 4);
+    assert!(session.execute("x").is_err());
+    assert!(session.execute("g(1)").is_err());
+    assert_eq!(session.unset("all").unwrap(), 0);
+  }
+
+  #[test]
+  fn test_render_definitions() {
+    let mut session = Session::default();
+    assert_eq!(session.render_definitions(), "No variables or functions defined");
+
+    session.execute("y = 1 / 3").unwrap();
+    session.execute("x = 2").unwrap();
+    session.execute("f(x) = (x + 1) * sqrt(x) - log2(x)").unwrap();
+
+    assert_eq!(session.render_definitions(), [
+      "Variables:",
+      "  x = 2",
+      "  y = 0.33333",
+      "Functions:",
+      "  f(x) = (x + 1) * sqrt(x) - log2(x)",
+    ].join("\n"));
+  }
+
+  #[test]
+  fn test_limits() {
+    let limited = |limits| Session { limits, ..Default::default() };
+    let exceeded = |session: &mut Session, input| {
+      let report = session.execute(input).unwrap_err();
+      match (report.downcast_ref::<SpanError<LexError>>(), report.downcast_ref::<SpanError<ParseError>>()) {
+        (Some(SpanError { error: LexError::Limit(limit), span }), _) | (_, Some(SpanError { error: ParseError::Limit(limit), span })) => (*limit, span.clone()),
+        _ => panic!("{:#}", report),
+      }
+    };
+
+    // "1 + 2 * 3" is 9 characters, 5 tokens and 5 nodes
+    let mut session = limited(Limits { max_length: 9, ..Default::default() });
+    assert_eq!(session.execute("1 + 2 * 3").unwrap(), Some(Value::Number(7.)));
+    assert_eq!(exceeded(&mut session, "1 + 2 * 3 "), (LimitExceeded::Length(9), 9..10));
+
+    let mut session = limited(Limits { max_tokens: 5, ..Default::default() });
+    assert_eq!(session.execute("1 + 2 * 3   # comment").unwrap(), Some(Value::Number(7.)));
+    assert_eq!(exceeded(&mut session, "-1 + 2 * 3"), (LimitExceeded::Tokens(5), 9..10));
+
+    let mut session = limited(Limits { max_nodes: 5, ..Default::default() });
+    assert_eq!(session.execute("f(x) = x * x * x").unwrap(), None);
+    assert_eq!(session.execute("x = 1 + 2 * 3").unwrap(), Some(Value::Number(7.)));
+    assert_eq!(exceeded(&mut session, "x = 1 + 2 * -3"), (LimitExceeded::Nodes(5), 0..14));
+    assert_eq!(exceeded(&mut session, "f(x) = -x * x * x"), (LimitExceeded::Nodes(5), 0..17));
+    // Calls count as the nodes they expand to
+    assert_eq!(session.evaluate_expression("f(2)").unwrap(), 8.);
+    let report = session.evaluate_expression("f(f(2))").unwrap_err();
+    assert_eq!(report.downcast_ref::<LimitExceeded>(), Some(&LimitExceeded::Nodes(5)));
+    // Fails early rather than expanding 8 ^ 8 copies of the argument
+    let mut session = limited(Limits { max_nodes: 100, ..Default::default() });
+    session.execute("f(x) = x * x * x * x * x * x * x * x").unwrap();
+    let report = session.execute("f(f(f(f(f(f(f(f(1))))))))").unwrap_err();
+    assert_eq!(report.downcast_ref::<LimitExceeded>(), Some(&LimitExceeded::Nodes(100)));
+
+    let error = limited(Limits { max_nodes: 2, ..Default::default() }).execute("1 + 1").unwrap_err();
+    assert_eq!(error.root_cause().to_string(), "Expression too large (limit 2 nodes)");
+
+    // Expanding calls counts towards the timeout too
+    let mut session = limited(Limits { timeout: Some(Duration::from_millis(100)), ..Default::default() });
+    session.execute("f(x) = x * x * x * x * x * x * x * x").unwrap();
+    let report = session.execute("f(f(f(f(f(f(f(f(1))))))))").unwrap_err();
+    assert_eq!(report.downcast_ref::<EvalError>(), Some(&EvalError::TimedOut));
+
+    let mut session = limited(Limits { timeout: Some(Duration::ZERO), ..Default::default() });
+    assert_eq!(session.execute("1 + 1").unwrap_err().downcast_ref::<EvalError>(), Some(&EvalError::TimedOut));
+    session.limits.timeout = Some(Duration::from_secs(600));
+    assert_eq!(session.execute("1 + 1").unwrap(), Some(Value::Number(2.)));
+  }
+
+  #[test]
+  fn test_deep_input() {
+    let mut session = Session::default();
+    let chain = "1 + ".repeat(100_000) + "1";
+    assert_eq!(session.execute(&chain).unwrap(), Some(Value::Number(100_001.)));
+    session.execute("f(x) = x * 2").unwrap();
+    assert_eq!(session.execute(&format!("f({}) - if(1, 2, 3)", chain)).unwrap(), Some(Value::Number(200_000.)));
+
+    // The failing sub-expression is named in the message
+    let report = session.execute(&format!("({}) / 0", chain)).unwrap_err();
+    let message = format!("{:#}", report);
+    assert!(message.starts_with("Error during evaluation: Invalid operation: division by zero while evaluating `(1 + 1 + 1"));
+    assert!(message.ends_with(" + 1) / 0`"));
+
+    // Calls substitute into bodies of any depth, and sampling evaluates trees too deep to compile
+    session.execute(&format!("g(x) = {}x", "x + ".repeat(100_000))).unwrap();
+    assert_eq!(session.execute("g(2)").unwrap(), Some(Value::Number(200_002.)));
+    let chain = "t + ".repeat(100_000) + "t";
+    let samples: Vec<f64> = session.sample(&chain, "t", &[1., 2.]).unwrap().into_iter().map(Result::unwrap).collect();
+    assert_eq!(samples, [100_001., 200_002.]);
+    assert_eq!(session.simplify(&chain).unwrap().count_nodes(), 200_001);
+    let report = session.derive(&chain, "t").unwrap_err();
+    assert_eq!(report.root_cause().downcast_ref::<LimitExceeded>(), Some(&LimitExceeded::Depth(MAX_DEPTH)));
+  }
+
+  #[test]
+  fn test_timings() {
+    assert_eq!(format_duration(Duration::from_nanos(300)), "<1µs");
+    assert_eq!(format_duration(Duration::from_nanos(12_340)), "12.34µs");
+    assert_eq!(format_duration(Duration::from_micros(1_500)), "1.50ms");
+    assert_eq!(format_duration(Duration::from_millis(2_000)), "2.00s");
+
+    let mut session = Session::default();
+    let mut timings = Timings::default();
+    assert_eq!(session.execute_timed("2 + 2", &mut timings).unwrap(), Some(4.0.into()));
+    assert!(timings.to_string().starts_with("tokenize "));
+
+    let mut timings = Timings::default();
+    assert!(session.execute_timed("2 +", &mut timings).is_err());
+    assert_eq!(timings.evaluate, Duration::ZERO);
+  }
+}
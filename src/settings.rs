@@ -0,0 +1,278 @@
+use std::{fmt, fs, path::PathBuf, str::FromStr};
+use eyre::{eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{eval::Value, RoundWithPrecision};
+
+/// Unit of angles taken and returned by trigonometric functions
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum AngleMode {
+  #[default]
+  #[serde(rename = "rad", alias = "radians")]
+  Radians,
+  #[serde(rename = "deg", alias = "degrees")]
+  Degrees,
+}
+
+impl FromStr for AngleMode {
+  type Err = Report;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "rad" | "radians" => Ok(AngleMode::Radians),
+      "deg" | "degrees" => Ok(AngleMode::Degrees),
+      _ => Err(eyre!("Unknown angle mode: {} (expected \"rad\" or \"deg\")", s)),
+    }
+  }
+}
+
+impl fmt::Display for AngleMode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      AngleMode::Radians => write!(f, "rad"),
+      AngleMode::Degrees => write!(f, "deg"),
+    }
+  }
+}
+
+/// Notation of printed results
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Format {
+  /// `1234.5`
+  #[default]
+  #[serde(rename = "fixed")]
+  Fixed,
+  /// `1.2345e3`
+  #[serde(rename = "sci", alias = "scientific")]
+  Scientific,
+}
+
+impl FromStr for Format {
+  type Err = Report;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "fixed" => Ok(Format::Fixed),
+      "sci" | "scientific" => Ok(Format::Scientific),
+      _ => Err(eyre!("Unknown format: {} (expected \"fixed\" or \"sci\")", s)),
+    }
+  }
+}
+
+impl fmt::Display for Format {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Format::Fixed => write!(f, "fixed"),
+      Format::Scientific => write!(f, "sci"),
+    }
+  }
+}
+
+/// Language of the banner and help text
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Lang {
+  #[default]
+  #[serde(rename = "en")]
+  En,
+  #[serde(rename = "ru")]
+  Ru,
+}
+
+impl Lang {
+  /// Language of the `LANG` environment variable, English if it's not supported
+  pub fn from_env() -> Self {
+    match std::env::var("LANG") {
+      Ok(lang) if lang.starts_with("ru") => Lang::Ru,
+      _ => Lang::En,
+    }
+  }
+}
+
+impl FromStr for Lang {
+  type Err = Report;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "en" => Ok(Lang::En),
+      "ru" => Ok(Lang::Ru),
+      _ => Err(eyre!("Unknown language: {} (expected \"en\" or \"ru\")", s)),
+    }
+  }
+}
+
+impl fmt::Display for Lang {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Lang::En => write!(f, "en"),
+      Lang::Ru => write!(f, "ru"),
+    }
+  }
+}
+
+/// User-adjustable settings, loaded from the config file and overridden by flags and commands
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+  /// Number of decimal places shown in results
+  pub precision: u32,
+  pub angle_mode: AngleMode,
+  pub format: Format,
+  /// Character printed in place of the decimal point
+  pub decimal_separator: char,
+  /// Prompt text, with an empty string meaning no prompt
+  pub prompt: String,
+  /// Whether input history is saved between sessions
+  pub history: bool,
+  /// Follows `LANG` unless set in the config file
+  #[serde(default = "Lang::from_env")]
+  pub lang: Lang,
+  /// Whether division by zero is an error rather than an infinity or NaN
+  pub strict_division: bool,
+}
+
+impl Settings {
+  pub const MAX_PRECISION: u32 = 15;
+
+  /// Platform-appropriate location of the config file,
+  /// e.g. `~/.config/uni-calc/config.toml` on Linux
+  fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("uni-calc").join("config.toml"))
+  }
+
+  /// Parses the config file contents, naming the offending key on failure
+  pub fn parse(text: &str) -> Result<Self> {
+    let settings: Self = serde_path_to_error::deserialize(toml::Deserializer::new(text))
+      .map_err(|err| match err.path().to_string().as_str() {
+        "." => eyre!("{}", err.inner().message()),
+        path => eyre!("{}: {}", path, err.inner().message()),
+      })?;
+
+    if settings.precision > Self::MAX_PRECISION {
+      return Err(eyre!("precision: must be at most {}", Self::MAX_PRECISION))
+    }
+
+    Ok(settings)
+  }
+
+  /// Loads the config file if it exists, falling back to defaults with a warning if it's malformed
+  pub fn load() -> Self {
+    let Some(path) = Self::path().filter(|path| path.exists()) else {
+      return Self::from_env()
+    };
+
+    let settings = fs::read_to_string(&path)
+      .map_err(Report::from)
+      .and_then(|text| Self::parse(&text));
+
+    match settings {
+      Ok(settings) => settings,
+      Err(report) => {
+        eprintln!("Warning: ignoring {} ({})", path.display(), report);
+        Self::from_env()
+      },
+    }
+  }
+
+  /// Default settings adjusted to the environment
+  fn from_env() -> Self {
+    Self {
+      lang: Lang::from_env(),
+      ..Default::default()
+    }
+  }
+
+  /// Formats a result according to the precision, notation and decimal separator
+  pub fn format_number(&self, value: f64) -> String {
+    let text = match self.format {
+      Format::Scientific if value != 0. && value.is_finite() => {
+        let text = format!("{:.*e}", self.precision as usize, value);
+        let (mantissa, exponent) = text.split_once('e').unwrap();
+        let mantissa = match mantissa.contains('.') {
+          true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+          false => mantissa,
+        };
+        format!("{}e{}", mantissa, exponent)
+      },
+      _ => value.round_with_precision(self.precision).to_string(),
+    };
+
+    match self.decimal_separator {
+      '.' => text,
+      separator => text.replace('.', &separator.to_string()),
+    }
+  }
+
+  /// Formats a number like `format_number`, or a list of them in brackets, leaving text as it is.
+  /// Elements are separated by semicolons when commas separate decimals
+  pub fn format_value(&self, value: &Value) -> String {
+    match value {
+      Value::Number(value) => self.format_number(*value),
+      Value::List(values) => {
+        let separator = if self.decimal_separator == ',' { "; " } else { ", " };
+        let values: Vec<_> = values.iter().map(|&value| self.format_number(value)).collect();
+        format!("[{}]", values.join(separator))
+      },
+      Value::Text(text) => text.clone(),
+    }
+  }
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Self {
+      precision: 5,
+      angle_mode: AngleMode::default(),
+      format: Format::default(),
+      decimal_separator: '.',
+      prompt: "> ".to_string(),
+      history: true,
+      lang: Lang::default(),
+      strict_division: true,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{AngleMode, Format, Lang, Settings};
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(Settings::parse("").unwrap(), Settings::from_env());
+    assert_eq!(Settings::parse("lang = \"ru\"").unwrap().lang, Lang::Ru);
+
+    let settings = Settings::parse("precision = 8\nangle_mode = \"deg\"\nprompt = \"\"\nhistory = false\nstrict_division = false").unwrap();
+    assert_eq!(settings, Settings {
+      precision: 8,
+      angle_mode: AngleMode::Degrees,
+      prompt: String::new(),
+      history: false,
+      strict_division: false,
+      ..Settings::from_env()
+    });
+
+    let error = |text| Settings::parse(text).unwrap_err().to_string();
+    assert_eq!(error("precision = \"high\""), "precision: invalid type: string \"high\", expected u32");
+    assert_eq!(error("precision = 20"), "precision: must be at most 15");
+    assert_eq!(error("angle_mode = \"grad\""), "angle_mode: unknown variant `grad`, expected one of `rad`, `radians`, `deg`, `degrees`");
+    assert!(error("precison = 8").starts_with("precison: unknown field `precison`, expected one of"));
+    assert!(Settings::parse("precision = ").is_err());
+  }
+
+  #[test]
+  fn test_format_number() {
+    let mut settings = Settings::default();
+    assert_eq!(settings.format_number(1. / 3.), "0.33333");
+    assert_eq!(settings.format_number(1234.5), "1234.5");
+
+    settings.decimal_separator = ',';
+    assert_eq!(settings.format_number(1234.5), "1234,5");
+
+    settings.format = Format::Scientific;
+    assert_eq!(settings.format_number(1234.5), "1,2345e3");
+    settings.decimal_separator = '.';
+    assert_eq!(settings.format_number(-0.00012), "-1.2e-4");
+    assert_eq!(settings.format_number(1e21), "1e21");
+    assert_eq!(settings.format_number(0.), "0");
+  }
+}
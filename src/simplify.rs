@@ -0,0 +1,262 @@
+use eyre::Result;
+
+use crate::{eval::EvalOptions, lexer::{Func, Op}, parser::{piecewise_arguments, Node}};
+
+/// Rewrites are repeated until nothing changes, but no more than this many times
+const MAX_PASSES: usize = 100;
+
+impl Node {
+  /// Whether the node has no variables and no side effects, so it can be replaced by its value
+  pub(crate) fn is_constant(&self) -> bool {
+    match self {
+      Node::Immediate(_) | Node::FuncRef(_) | Node::OpRef(_) => true,
+      Node::Variable(_) => false,
+      Node::Neg(node) => node.is_constant(),
+      Node::BinOp(_, left, right) => left.is_constant() && right.is_constant(),
+      Node::If { cond, then, else_ } => cond.is_constant() && then.is_constant() && else_.is_constant(),
+      Node::Piecewise(cases, default) => piecewise_arguments(cases, default).all(Node::is_constant),
+      Node::Let { name, value, body } => value.is_constant() && body.substitute(name, value).is_constant(),
+      Node::Func(Func::Random | Func::RandomRange | Func::Seed, _) => false,
+      // Text has no literal to stand in for it
+      Node::Func(func, _) if func.returns_text() => false,
+      Node::Func(_, args) | Node::Call(_, args) | Node::List(args) => args.iter().all(Node::is_constant),
+    }
+  }
+
+  /// Whether evaluating the node can't fail, so dropping it (as in `x * 0`) doesn't hide an error
+  fn is_total(&self) -> bool {
+    match self {
+      Node::Immediate(_) | Node::Variable(_) => true,
+      Node::Neg(node) => node.is_total(),
+      Node::BinOp(Op::Add | Op::Sub | Op::Mul, left, right) => left.is_total() && right.is_total(),
+      _ => false,
+    }
+  }
+
+  /// Replaces every subtree without variables by its value, failing if any of them fails to evaluate
+  /// or the tree is deeper than `MAX_DEPTH`. Unlike `simplify`, no identities are applied,
+  /// so the result evaluates exactly like the original
+  pub fn fold_constants(&self) -> Result<Node> {
+    self.check_depth()?;
+    self.fold_unchecked()
+  }
+
+  fn fold_unchecked(&self) -> Result<Node> {
+    let fold_all = |args: &[Node]| args.iter().map(Node::fold_unchecked).collect::<Result<Vec<_>>>();
+
+    match self {
+      // User-defined functions only exist in a session
+      Node::Call(name, args) => Ok(Node::Call(name.clone(), fold_all(args)?)),
+      // Only has a value as an argument
+      Node::FuncRef(_) | Node::OpRef(_) => Ok(self.clone()),
+      node if node.is_constant() => Ok(Node::try_from(&node.evaluate_value(EvalOptions::default())?)?),
+      Node::Neg(node) => Ok(Node::Neg(Box::new(node.fold_unchecked()?))),
+      Node::BinOp(op, left, right) => Ok(Node::BinOp(*op, Box::new(left.fold_unchecked()?), Box::new(right.fold_unchecked()?))),
+      Node::Func(func, args) => Ok(Node::Func(*func, fold_all(args)?)),
+      // A branch which fails may never be taken
+      Node::If { cond, then, else_ } => Ok(Node::If {
+        cond: Box::new(cond.fold_unchecked()?),
+        then: Box::new(then.fold_unchecked().unwrap_or_else(|_| (**then).clone())),
+        else_: Box::new(else_.fold_unchecked().unwrap_or_else(|_| (**else_).clone())),
+      }),
+      Node::Let { name, value, body } => Ok(Node::Let {
+        name: name.clone(),
+        value: Box::new(value.fold_unchecked()?),
+        body: Box::new(body.fold_unchecked()?),
+      }),
+      // Only the first condition is always evaluated
+      Node::Piecewise(cases, default) => {
+        let lenient = |node: &Node| node.fold_unchecked().unwrap_or_else(|_| node.clone());
+        let cases = cases.iter().enumerate()
+          .map(|(index, (cond, value))| Ok((if index == 0 { cond.fold_unchecked()? } else { lenient(cond) }, lenient(value))))
+          .collect::<Result<_>>()?;
+        Ok(Node::Piecewise(cases, default.as_deref().map(|default| Box::new(lenient(default)))))
+      },
+      node => Ok(node.clone()),
+    }
+  }
+
+  /// Simplifies the expression, folding constants with the default evaluation
+  pub fn simplify(&self) -> Node {
+    self.simplify_with(&|node| node.evaluate(EvalOptions::default()).ok())
+  }
+
+  /// Applies algebraic identities like `x + 0 = x` and `--x = x` until nothing changes,
+  /// replacing constant subtrees with what `fold` evaluates them to.
+  /// Subtrees which fail to evaluate or aren't finite are kept as they are, and so are trees deeper than `MAX_DEPTH`
+  pub fn simplify_with(&self, fold: &dyn Fn(&Node) -> Option<f64>) -> Node {
+    let mut node = self.clone();
+    for _ in 0..MAX_PASSES {
+      if node.check_depth().is_err() {
+        break
+      }
+      let next = node.simplify_once(fold);
+      if next == node {
+        break
+      }
+      node = next;
+    }
+    node
+  }
+
+  fn simplify_once(&self, fold: &dyn Fn(&Node) -> Option<f64>) -> Node {
+    if !matches!(self, Node::Immediate(_) | Node::Neg(_)) && self.is_constant() {
+      if let Some(value) = fold(self).filter(|value| value.is_finite()) {
+        return immediate(value)
+      }
+    }
+
+    let simplify = |node: &Node| node.simplify_once(fold);
+    match self {
+      Node::Neg(node) => {
+        let mut node = simplify(node);
+        match &mut node {
+          Node::Neg(negated) => negated.take(),
+          _ => Node::Neg(Box::new(node)),
+        }
+      },
+      Node::BinOp(op, left, right) => simplify_binop(*op, simplify(left), simplify(right)),
+      Node::Func(func, args) => Node::Func(*func, args.iter().map(simplify).collect()),
+      Node::Call(name, args) => Node::Call(name.clone(), args.iter().map(simplify).collect()),
+      Node::List(items) => Node::List(items.iter().map(simplify).collect()),
+      // The branch is known once the condition is
+      Node::If { cond, then, else_ } => match simplify(cond) {
+        Node::Immediate(value) if value != 0. => simplify(then),
+        Node::Immediate(_) => simplify(else_),
+        cond => Node::If { cond: Box::new(cond), then: Box::new(simplify(then)), else_: Box::new(simplify(else_)) },
+      },
+      Node::Let { name, value, body } => Node::Let { name: name.clone(), value: Box::new(simplify(value)), body: Box::new(simplify(body)) },
+      // Cases whose condition is known not to hold are dropped, and the first one known to hold becomes the default
+      Node::Piecewise(cases, default) => {
+        let mut remaining = vec![];
+        let mut default = default.as_deref().map(simplify);
+        for (cond, value) in cases {
+          match simplify(cond) {
+            Node::Immediate(0.) => {},
+            Node::Immediate(_) => {
+              default = Some(simplify(value));
+              break
+            },
+            cond => remaining.push((cond, simplify(value))),
+          }
+        }
+        match (remaining.is_empty(), default) {
+          (true, Some(default)) => default,
+          // Fails to evaluate either way
+          (true, None) => self.clone(),
+          (false, default) => Node::Piecewise(remaining, default.map(Box::new)),
+        }
+      },
+      node => node.clone(),
+    }
+  }
+}
+
+/// Negative values are kept as negations so that they print correctly after `%`
+fn immediate(value: f64) -> Node {
+  if value < 0. {
+    Node::Neg(Box::new(Node::Immediate(-value)))
+  } else {
+    Node::Immediate(value)
+  }
+}
+
+fn is(node: &Node, value: f64) -> bool {
+  matches!(node, Node::Immediate(immediate) if *immediate == value)
+}
+
+fn simplify_binop(op: Op, mut left: Node, mut right: Node) -> Node {
+  match (op, &mut left, &mut right) {
+    (Op::Add | Op::Sub, _, zero) if is(zero, 0.) => left,
+    (Op::Add, zero, _) if is(zero, 0.) => right,
+    (Op::Sub, zero, _) if is(zero, 0.) => Node::Neg(Box::new(right)),
+    (Op::Add, _, Node::Neg(negated)) => Node::BinOp(Op::Sub, Box::new(left), Box::new(negated.take())),
+    (Op::Sub, _, Node::Neg(negated)) => Node::BinOp(Op::Add, Box::new(left), Box::new(negated.take())),
+
+    (Op::Mul | Op::Div, _, one) if is(one, 1.) => left,
+    (Op::Mul, one, _) if is(one, 1.) => right,
+    (Op::Mul, node, zero) | (Op::Mul, zero, node) if is(zero, 0.) && node.is_total() => Node::Immediate(0.),
+    (Op::Mul | Op::Div, Node::Neg(left), Node::Neg(right)) => Node::BinOp(op, Box::new(left.take()), Box::new(right.take())),
+
+    (Op::Pow, _, one) if is(one, 1.) => left,
+    // `0 ^ 0 = 1` as well
+    (Op::Pow, node, zero) if is(zero, 0.) && node.is_total() => Node::Immediate(1.),
+
+    _ => Node::BinOp(op, Box::new(left), Box::new(right)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{eval::EvalOptions, lexer::tokenize, parser::{parse_expression, Node}, testing::{assert_agree, close}};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  fn simplify(input: &str) -> String {
+    parse(input).simplify().to_string()
+  }
+
+  #[test]
+  fn test_identities() {
+    assert_eq!(simplify("x + 0"), "x");
+    assert_eq!(simplify("0 - x"), "-x");
+    assert_eq!(simplify("1 * x / 1"), "x");
+    assert_eq!(simplify("x * 0 + y"), "y");
+    assert_eq!(simplify("x ^ 1 + y ^ 0"), "x + 1");
+    assert_eq!(simplify("--x"), "x");
+    assert_eq!(simplify("x - -y"), "x + y");
+    assert_eq!(simplify("-x * -y"), "x * y");
+    assert_eq!(simplify("(2 + 3) * x"), "5 * x");
+    assert_eq!(simplify("x * (2 - 3)"), "x * -1");
+    assert_eq!(simplify("x % (1 - 3)"), "x % (-2)");
+    assert_eq!(simplify("x * (2 - 2) ^ 1 + (x - x)"), "x - x");
+    assert_eq!(simplify("if(2 > 1, x + 0, y) * if(x, 1 * y, 2)"), "x * if(x, y, 2)");
+    assert_eq!(simplify("piecewise(1 > 2, x, y, 0 * y, 3 > 2, 1 * x, 5)"), "piecewise(y, 0, x)");
+    assert_eq!(simplify("piecewise(0, x, 2 - 1, y)"), "y");
+
+    // Errors, side effects and failing subtrees are left alone
+    assert_eq!(simplify("sqrt(x) * 0"), "sqrt(x) * 0");
+    assert_eq!(simplify("1 / 0 + x"), "1 / 0 + x");
+    assert_eq!(simplify("rand() * 1"), "rand()");
+  }
+
+  #[test]
+  fn test_fold_constants() {
+    let fold = |input| parse(input).fold_constants().map(|node| node.to_string());
+    assert_eq!(fold("2 * 3 + x").unwrap(), "6 + x");
+    assert_eq!(fold("(1 - 3) * x ^ (1 / 4)").unwrap(), "-2 * x ^ 0.25");
+    assert_eq!(fold("sqrt(16) + f(2 * 3, y - 1 * 2)").unwrap(), "4 + f(6, y - 2)");
+    assert_eq!(fold("x * 0 + rand() * 2").unwrap(), "x * 0 + rand() * 2");
+    assert_eq!(parse("log2(8) ^ 2").fold_constants().unwrap(), Node::Immediate(9.));
+
+    // Failures are reported rather than hidden
+    assert_eq!(fold("1 / 0 + x").unwrap_err().to_string(), "Invalid operation: division by zero");
+    assert!(fold("0 * log2(-1)").is_err());
+    // Unless they may never be evaluated
+    assert_eq!(fold("if(x, 1 / 0, 2 + 2)").unwrap(), "if(x, 1 / 0, 4)");
+    assert_eq!(fold("piecewise(x, 1 + 1, log2(0), 3, 1 / 0)").unwrap(), "piecewise(x, 2, log2(0), 3, 1 / 0)");
+
+    for input in ["1 + 2 * 3 ^ 2", "-(4 - 6) % 3", "choose(5, 2) / 50%", "sin(pi / 2) - atan2(1, 1)"] {
+      assert_eq!(parse(input).fold_constants().unwrap(), Node::Immediate(parse(input).evaluate(EvalOptions::default()).unwrap()));
+    }
+  }
+
+  #[test]
+  fn test_semantics() {
+    let expressions = [
+      "x + 0 * y - --x",
+      "(x - -y) ^ 1 * 1 + (2 ^ 3 - 8)",
+      "sqrt(x) * 0 + y ^ 0",
+      "-(-(x * -y)) / (1 * 1)",
+      "log2(x) + (0 - x) ^ 2",
+      "(x + 0) % (y * 1)",
+    ];
+    for input in expressions {
+      let original = parse(input);
+      let simplified = original.simplify();
+      assert_agree(input, 1, |options| original.evaluate(options), |options| simplified.evaluate(options), close);
+    }
+  }
+}
@@ -0,0 +1,198 @@
+use std::{borrow::Cow, collections::{BTreeSet, HashMap}, iter};
+
+use crate::{lexer::Func, parser::Node, walk::{children, rebuild}};
+
+/// Bindings in effect within part of a tree
+type Scope<'a> = HashMap<&'a str, Cow<'a, Node>>;
+
+/// Bindings within `body`, in which `var` is bound and so shadows any binding of it.
+/// A replacement mentioning `var` would be captured by it, in which case `var` is renamed
+/// in the body to a name used nowhere else, made by appending underscores.
+/// Returns the name bound in the result along with the bindings
+fn bind<'a>(var: &'a str, body: &Node, bindings: &Scope<'a>) -> (String, Scope<'a>) {
+  let free = body.variables();
+  let replacements: Vec<BTreeSet<String>> = bindings.iter()
+    .filter(|(name, _)| **name != var && free.contains(**name))
+    .map(|(_, replacement)| replacement.variables())
+    .collect();
+
+  let mut name = var.to_string();
+  if replacements.iter().any(|variables| variables.contains(var)) {
+    while name == var || free.contains(&name) || replacements.iter().any(|variables| variables.contains(&name)) {
+      name.push('_');
+    }
+  }
+
+  let mut inner = bindings.clone();
+  inner.remove(var);
+  if name != var {
+    inner.insert(var, Cow::Owned(Node::Variable(name.clone())));
+  }
+  (name, inner)
+}
+
+/// The bound variable and the condition of `filter(cond, var, list)`, in which the variable shadows the one outside
+fn filter_binding(node: &Node) -> Option<(&str, &Node)> {
+  match node {
+    Node::Func(Func::Filter, args) => match args.as_slice() {
+      [cond, Node::Variable(var), _] => Some((var, cond)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+impl Node {
+  /// Copy of the tree with every occurrence of `var` replaced by `replacement`.
+  /// Variables inside `replacement` are left as they are, even if one of them is `var`
+  pub fn substitute(&self, var: &str, replacement: &Node) -> Node {
+    self.substitute_all(&HashMap::from([(var, replacement)]))
+  }
+
+  /// Replaces variables with their bound subtrees, all at once so that
+  /// bound subtrees referring to other bound variables aren't substituted again.
+  /// Copies children before their parents on a stack, like `clone`, so that trees of any depth can be substituted into
+  pub fn substitute_all<'a>(&'a self, bindings: &HashMap<&'a str, &'a Node>) -> Node {
+    enum Task<'a> {
+      /// Node to copy with the bindings of the scope at the given index
+      Visit(&'a Node, usize),
+      /// Replaces the copies of the node's children with a copy of the node, binding the given name if it's a `let`
+      Build(&'a Node, Option<String>),
+    }
+
+    let mut scopes: Vec<Scope> = vec![bindings.iter().map(|(name, node)| (*name, Cow::Borrowed(*node))).collect()];
+    let mut tasks = vec![Task::Visit(self, 0)];
+    let mut copies: Vec<Node> = vec![];
+
+    while let Some(task) = tasks.pop() {
+      match task {
+        Task::Visit(Node::Variable(name), scope) => copies.push(match scopes[scope].get(name.as_str()) {
+          Some(bound) => bound.clone().into_owned(),
+          None => Node::Variable(name.clone()),
+        }),
+        Task::Visit(node, scope) => {
+          let (name, scoped): (Option<String>, Vec<(&Node, usize)>) = match node {
+            // The bound name shadows the one outside in the body
+            Node::Let { name, value, body } => {
+              let (name, inner) = bind(name, body, &scopes[scope]);
+              scopes.push(inner);
+              (Some(name), vec![(value, scope), (body, scopes.len() - 1)])
+            },
+            // Only the condition is in the scope of the variable, which is renamed along with it
+            Node::Func(_, args) if filter_binding(node).is_some() => {
+              let (var, cond) = filter_binding(node).expect("checked above");
+              let (_, inner) = bind(var, cond, &scopes[scope]);
+              scopes.push(inner);
+              (None, vec![(&args[0], scopes.len() - 1), (&args[1], scopes.len() - 1), (&args[2], scope)])
+            },
+            node => (None, children(node).into_iter().map(|child| (child, scope)).collect()),
+          };
+          tasks.push(Task::Build(node, name));
+          // Pushed in reverse so that copies come out left to right
+          tasks.extend(scoped.into_iter().rev().map(|(child, scope)| Task::Visit(child, scope)));
+        },
+        Task::Build(node, name) => {
+          let count = children(node).len();
+          let mut copy = rebuild(node, copies.split_off(copies.len() - count));
+          if let (Node::Let { name: bound, .. }, Some(name)) = (&mut copy, name) {
+            *bound = name;
+          }
+          copies.push(copy);
+        },
+      }
+    }
+
+    copies.pop().expect("substituting leaves a single node")
+  }
+
+  /// Names of the free variables appearing in the tree, sorted
+  pub fn variables(&self) -> BTreeSet<String> {
+    // Names bound by `let` and `filter`, each with the index of the one it's nested in
+    let mut bound: Vec<(&str, Option<usize>)> = vec![];
+    // Nodes still to visit, with the innermost name bound around them
+    let mut pending: Vec<(&Node, Option<usize>)> = vec![(self, None)];
+    let mut variables = BTreeSet::new();
+
+    while let Some((node, scope)) = pending.pop() {
+      match node {
+        Node::Variable(name) => {
+          if !iter::successors(scope, |&index| bound[index].1).any(|index| bound[index].0 == name) {
+            variables.insert(name.clone());
+          }
+        },
+        Node::Let { name, value, body } => {
+          bound.push((name, scope));
+          pending.extend([(&**value, scope), (&**body, Some(bound.len() - 1))]);
+        },
+        Node::Func(_, args) if filter_binding(node).is_some() => {
+          let (var, cond) = filter_binding(node).expect("checked above");
+          bound.push((var, scope));
+          pending.extend([(cond, Some(bound.len() - 1)), (&args[2], scope)]);
+        },
+        node => pending.extend(children(node).into_iter().map(|child| (child, scope))),
+      }
+    }
+    variables
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::{BTreeSet, HashMap};
+  use crate::{eval::EvalOptions, lexer::tokenize, parser::{parse_expression, Node}};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_substitute() {
+    let node = parse("x ^ 2 + f(x, y) - sqrt(-x)");
+    assert_eq!(node.substitute("x", &parse("y + 1")).to_string(), "(y + 1) ^ 2 + f(y + 1, y) - sqrt(-(y + 1))");
+    // The original is untouched
+    assert_eq!(node.to_string(), "x ^ 2 + f(x, y) - sqrt(-x)");
+
+    // The replacement isn't substituted into again
+    assert_eq!(parse("x * y").substitute("x", &parse("x + 1")).to_string(), "(x + 1) * y");
+    let (x, y) = (parse("x"), parse("y"));
+    let swap = HashMap::from([("x", &y), ("y", &x)]);
+    assert_eq!(parse("x - y").substitute_all(&swap).to_string(), "y - x");
+
+    // Only the free occurrences are replaced
+    let node = parse("filter(x > y, x, [x, y])");
+    assert_eq!(node.substitute("x", &parse("2")).to_string(), "filter(x > y, x, [2, y])");
+    assert_eq!(node.substitute("y", &parse("2")).to_string(), "filter(x > 2, x, [x, 2])");
+    // The bound variable is renamed rather than capturing the one in the replacement
+    assert_eq!(node.substitute("y", &parse("x + x_")).to_string(), "filter(x__ > x + x_, x__, [x, x + x_])");
+    assert_eq!(parse("let x = x + 1 in x * y").substitute("x", &parse("3")).to_string(), "let x = 3 + 1 in x * y");
+    assert_eq!(parse("let x = 5 in x + y").substitute("y", &parse("x")).to_string(), "let x_ = 5 in x_ + x");
+  }
+
+  #[test]
+  fn test_variables() {
+    let names = |input| parse(input).variables().into_iter().collect::<Vec<_>>();
+    assert_eq!(names("b * a + log(c, a) - g(d)"), ["a", "b", "c", "d"]);
+    assert_eq!(parse("2 + pi").variables(), BTreeSet::new());
+    assert_eq!(names("filter(x > y, x, [x, 1])"), ["x", "y"]);
+    assert_eq!(names("filter(x > y, x, l)"), ["l", "y"]);
+    assert_eq!(names("let x = y in x + z"), ["y", "z"]);
+    assert_eq!(names("let x = x in x"), ["x"]);
+  }
+
+  #[test]
+  fn test_substitute_evaluate() {
+    let node = parse("x ^ 2 - 3 * x * y + sin(x)");
+    let replacement = parse("t / 2 + 1");
+
+    for t in [-2., 0., 0.5, 3.] {
+      let env = HashMap::from([("t".to_string(), t), ("y".to_string(), 1.5)]);
+      let x = replacement.evaluate(EvalOptions { variables: Some(&env), ..Default::default() }).unwrap();
+      let bound = HashMap::from([("x".to_string(), x), ("y".to_string(), 1.5)]);
+
+      assert_eq!(
+        node.substitute("x", &replacement).evaluate(EvalOptions { variables: Some(&env), ..Default::default() }).unwrap(),
+        node.evaluate(EvalOptions { variables: Some(&bound), ..Default::default() }).unwrap(),
+      );
+    }
+  }
+}
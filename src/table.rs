@@ -0,0 +1,132 @@
+use eyre::{eyre, Result};
+
+use calc::{lexer::{tokenize, Token}, settings::Settings};
+
+/// Parsed `table <expr> for <var> = <a> to <b> [step <s>]` command
+#[derive(Debug, PartialEq)]
+pub struct Table<'a> {
+  pub expression: &'a str,
+  pub variable: &'a str,
+  pub from: &'a str,
+  pub to: &'a str,
+  pub step: Option<&'a str>,
+}
+
+const USAGE: &str = "Usage: table <expr> for <var> = <a> to <b> [step <s>]";
+
+impl<'a> Table<'a> {
+  pub const MAX_ROWS: usize = 10_000;
+  /// Number of steps when `step` is omitted
+  const DEFAULT_STEPS: f64 = 20.;
+
+  pub fn parse(input: &'a str) -> Result<Self> {
+    let (expression, rest) = input.split_once(" for ").ok_or_else(|| eyre!(USAGE))?;
+    let (variable, range) = rest.split_once('=').ok_or_else(|| eyre!(USAGE))?;
+    let (from, to) = range.split_once(" to ").ok_or_else(|| eyre!(USAGE))?;
+    let (to, step) = match to.rsplit_once(" step ") {
+      Some((to, step)) => (to, Some(step.trim())),
+      None => (to, None),
+    };
+
+    let variable = variable.trim();
+    if !matches!(tokenize(variable)?.collect_tokens().as_slice(), [Token::Variable(_)]) {
+      return Err(eyre!("Invalid variable name: {}", variable))
+    }
+
+    Ok(Self { expression: expression.trim(), variable, from: from.trim(), to: to.trim(), step })
+  }
+
+  /// Values of the variable from `from` to `to` inclusive, about 20 rows if `step` is `None`
+  pub fn points(from: f64, to: f64, step: Option<f64>) -> Result<Vec<f64>> {
+    if !from.is_finite() || !to.is_finite() {
+      return Err(eyre!("The bounds must be finite"))
+    }
+
+    let step = match step {
+      Some(step) if step == 0. || !step.is_finite() => return Err(eyre!("The step must be finite and non-zero")),
+      Some(step) if (to - from) * step < 0. => {
+        return Err(eyre!("The step must be {} to go from {} to {}", if from < to { "positive" } else { "negative" }, from, to))
+      },
+      Some(step) => step,
+      None if from == to => 1.,
+      None => (to - from) / Self::DEFAULT_STEPS,
+    };
+
+    // Tolerates rounding errors so that `0 to 1 step 0.1` includes 1
+    let steps = ((to - from) / step * (1. + 1e-12)).floor();
+    if steps >= Self::MAX_ROWS as f64 {
+      return Err(eyre!("Too many rows: at most {} are allowed", Self::MAX_ROWS))
+    }
+
+    Ok((0..=steps as usize).map(|index| from + step * index as f64).collect())
+  }
+
+  /// Two aligned columns of the variable and the expression, with errors in place of failed values
+  pub fn render(&self, points: &[f64], values: &[Result<f64>], settings: &Settings) -> String {
+    let rows: Vec<(String, String)> = points.iter().zip(values).map(|(&point, value)| {
+      let value = match value {
+        Ok(value) => settings.format_number(*value),
+        Err(report) => report.to_string(),
+      };
+      (settings.format_number(point), value)
+    }).collect();
+
+    let width = rows.iter()
+      .map(|(point, _)| point.chars().count())
+      .chain(std::iter::once(self.variable.chars().count()))
+      .max()
+      .unwrap_or(0);
+
+    std::iter::once((self.variable.to_string(), self.expression.to_string()))
+      .chain(rows)
+      .map(|(point, value)| format!("{:>width$} | {}", point, value))
+      .collect::<Vec<_>>()
+      .join("\n")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Table;
+  use calc::settings::Settings;
+
+  #[test]
+  fn test_parse() {
+    assert_eq!(Table::parse("x^2 for x = 0 to 2 * pi step pi / 4").unwrap(), Table {
+      expression: "x^2",
+      variable: "x",
+      from: "0",
+      to: "2 * pi",
+      step: Some("pi / 4"),
+    });
+    assert_eq!(Table::parse("t for t=1 to 2").unwrap().step, None);
+    assert_eq!(Table::parse("x for pi = 1 to 2").unwrap_err().to_string(), "Invalid variable name: pi");
+    assert!(Table::parse("x for x = 1").is_err());
+  }
+
+  #[test]
+  fn test_points() {
+    assert_eq!(Table::points(0., 1., Some(0.25)).unwrap(), [0., 0.25, 0.5, 0.75, 1.]);
+    assert_eq!(Table::points(0., 1., Some(0.1)).unwrap().len(), 11);
+    assert_eq!(Table::points(3., 0., Some(-2.)).unwrap(), [3., 1.]);
+    assert_eq!(Table::points(0., 10., None).unwrap().len(), 21);
+    assert_eq!(Table::points(5., 5., None).unwrap(), [5.]);
+
+    let error = |from, to, step| Table::points(from, to, step).unwrap_err().to_string();
+    assert_eq!(error(0., 1., Some(0.)), "The step must be finite and non-zero");
+    assert_eq!(error(0., 1., Some(-1.)), "The step must be positive to go from 0 to 1");
+    assert_eq!(error(0., 1e6, Some(1.)), "Too many rows: at most 10000 are allowed");
+  }
+
+  #[test]
+  fn test_render() {
+    let table = Table::parse("1 / x for x = -1 to 1").unwrap();
+    let values = vec![Ok(-1.), Err(eyre::eyre!("Invalid operation: division by zero")), Ok(1.)];
+    assert_eq!(table.render(&[-1., 0., 1.], &values, &Settings::default()), [
+      " x | 1 / x",
+      "-1 | -1",
+      " 0 | Invalid operation: division by zero",
+      " 1 | 1",
+    ].join("\n"));
+  }
+}
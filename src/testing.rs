@@ -0,0 +1,34 @@
+//! Helpers shared by the tests of several modules
+
+use std::{collections::HashMap, fmt::Debug};
+
+use crate::{eval::EvalOptions, random};
+
+/// Evaluates both sides of a rewrite of `input` at 50 points, given as the options' variables, with `x` and `y`
+/// drawn from [-10, 10) and the same points for the same seed. Fails at the first point where `agree` rejects the results
+pub(crate) fn assert_agree<A: Debug, B: Debug>(
+  input: &str,
+  seed: u64,
+  left: impl Fn(EvalOptions) -> Result<f64, A>,
+  right: impl Fn(EvalOptions) -> Result<f64, B>,
+  agree: impl Fn(&Result<f64, A>, &Result<f64, B>) -> bool,
+) {
+  random::seed(seed);
+
+  for _ in 0..50 {
+    let (x, y) = (random::next() * 20. - 10., random::next() * 20. - 10.);
+    let ctx = HashMap::from([("x".to_string(), x), ("y".to_string(), y)]);
+    let options = EvalOptions { variables: Some(&ctx), ..Default::default() };
+    let (a, b) = (left(options), right(options));
+    assert!(agree(&a, &b), "{} at x = {}, y = {}: {:?} ≠ {:?}", input, x, y, a, b);
+  }
+}
+
+/// Whether both results are errors, or values equal up to rounding
+pub(crate) fn close<A, B>(a: &Result<f64, A>, b: &Result<f64, B>) -> bool {
+  match (a, b) {
+    (Ok(a), Ok(b)) => (a - b).abs() <= 1e-9 * a.abs().max(1.),
+    (Err(_), Err(_)) => true,
+    _ => false,
+  }
+}
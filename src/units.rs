@@ -0,0 +1,276 @@
+use std::fmt;
+use eyre::{eyre, Result};
+
+use crate::lexer::{Lexer, Token};
+
+use self::Dimension::*;
+
+/// Physical quantity measured by a unit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dimension {
+  Length,
+  Mass,
+  Temperature,
+  Time,
+  Data,
+  Area,
+}
+
+impl fmt::Display for Dimension {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Dimension::Length => write!(f, "length"),
+      Dimension::Mass => write!(f, "mass"),
+      Dimension::Temperature => write!(f, "temperature"),
+      Dimension::Time => write!(f, "time"),
+      Dimension::Data => write!(f, "data size"),
+      Dimension::Area => write!(f, "area"),
+    }
+  }
+}
+
+/// Unit of measurement, where a value `x` in this unit is `x * scale + offset` in the base unit of its dimension
+#[derive(Debug, PartialEq)]
+pub struct Unit {
+  /// Abbreviation first, then full names
+  pub names: &'static [&'static str],
+  pub dimension: Dimension,
+  pub scale: f64,
+  /// Only non-zero for temperatures
+  pub offset: f64,
+}
+
+const fn unit(names: &'static [&'static str], dimension: Dimension, scale: f64) -> Unit {
+  Unit { names, dimension, scale, offset: 0. }
+}
+
+/// Base units are metres, kilograms, kelvins, seconds, bytes and square metres
+pub const UNITS: &[Unit] = &[
+  unit(&["m", "meter", "metre"], Length, 1.),
+  unit(&["km", "kilometer", "kilometre"], Length, 1e3),
+  unit(&["cm", "centimeter", "centimetre"], Length, 1e-2),
+  unit(&["mm", "millimeter", "millimetre"], Length, 1e-3),
+  unit(&["um", "micrometer", "micrometre", "micron"], Length, 1e-6),
+  unit(&["nm", "nanometer", "nanometre"], Length, 1e-9),
+  unit(&["in", "inch"], Length, 0.0254),
+  unit(&["ft", "foot", "feet"], Length, 0.3048),
+  unit(&["yd", "yard"], Length, 0.9144),
+  unit(&["mi", "mile"], Length, 1609.344),
+  unit(&["nmi", "nautical_mile"], Length, 1852.),
+
+  unit(&["kg", "kilogram"], Mass, 1.),
+  unit(&["g", "gram"], Mass, 1e-3),
+  unit(&["mg", "milligram"], Mass, 1e-6),
+  unit(&["t", "tonne"], Mass, 1e3),
+  unit(&["lb", "lbs", "pound"], Mass, 0.45359237),
+  unit(&["oz", "ounce"], Mass, 0.028349523125),
+  unit(&["st", "stone"], Mass, 6.35029318),
+
+  Unit { names: &["c", "celsius"], dimension: Temperature, scale: 1., offset: 273.15 },
+  Unit { names: &["f", "fahrenheit"], dimension: Temperature, scale: 5. / 9., offset: 273.15 - 32. * 5. / 9. },
+  unit(&["k", "kelvin"], Temperature, 1.),
+
+  unit(&["s", "sec", "second"], Time, 1.),
+  unit(&["ms", "millisecond"], Time, 1e-3),
+  unit(&["us", "microsecond"], Time, 1e-6),
+  unit(&["min", "minute"], Time, 60.),
+  unit(&["h", "hr", "hour"], Time, 3600.),
+  unit(&["d", "day"], Time, 86400.),
+  unit(&["wk", "week"], Time, 604800.),
+  // Julian year of 365.25 days
+  unit(&["yr", "year"], Time, 31557600.),
+
+  unit(&["b", "bit"], Data, 0.125),
+  unit(&["B", "byte"], Data, 1.),
+  unit(&["kB", "KB", "kilobyte"], Data, 1e3),
+  unit(&["MB", "megabyte"], Data, 1e6),
+  unit(&["GB", "gigabyte"], Data, 1e9),
+  unit(&["TB", "terabyte"], Data, 1e12),
+  unit(&["KiB", "kibibyte"], Data, 1024.),
+  unit(&["MiB", "mebibyte"], Data, 1048576.),
+  unit(&["GiB", "gibibyte"], Data, 1073741824.),
+  unit(&["TiB", "tebibyte"], Data, 1099511627776.),
+
+  unit(&["m2", "sqm"], Area, 1.),
+  unit(&["km2", "sqkm"], Area, 1e6),
+  unit(&["cm2", "sqcm"], Area, 1e-4),
+  unit(&["in2", "sqin"], Area, 0.00064516),
+  unit(&["ft2", "sqft"], Area, 0.09290304),
+  unit(&["yd2", "sqyd"], Area, 0.83612736),
+  unit(&["mi2", "sqmi"], Area, 2589988.110336),
+  unit(&["ha", "hectare"], Area, 1e4),
+  unit(&["ac", "acre"], Area, 4046.8564224),
+];
+
+/// Looks up a unit by abbreviation or name, also accepting plurals like `miles` and `inches`.
+/// Case only matters where it tells units apart, as in `b` and `B`, so `KM` is `km`
+pub fn find(name: &str) -> Option<&'static Unit> {
+  let exact = |name: &str| UNITS.iter().find(|unit| unit.names.contains(&name)).or_else(|| {
+    let mut matches = UNITS.iter().filter(|unit| unit.names.iter().any(|n| n.eq_ignore_ascii_case(name)));
+    let first = matches.next()?;
+    matches.all(|unit| unit == first).then_some(first)
+  });
+
+  exact(name)
+    // Single-letter abbreviations like `s` aren't pluralized
+    .or_else(|| name.strip_suffix(['s', 'S']).filter(|name| name.len() > 1).and_then(exact))
+    .or_else(|| name.strip_suffix(['s', 'S'])?.strip_suffix(['e', 'E']).filter(|name| name.len() > 1).and_then(exact))
+}
+
+/// Conversion requested by a trailing `<unit> to <unit>`, applied to the result of the rest of the line
+#[derive(Debug)]
+pub struct Conversion {
+  pub from: &'static Unit,
+  pub to: String,
+}
+
+/// Whether `token` can be part of a unit name, which may take several tokens, like `m2` or `KiB`
+fn in_unit(token: &Token) -> bool {
+  matches!(token, Token::Variable(_) | Token::Function(_) | Token::Constant(_) | Token::Literal(_))
+}
+
+/// Whether `token` can end an operand, so that a unit can follow it where an operator would otherwise have to
+fn ends_operand(token: &Token) -> bool {
+  matches!(token, Token::Literal(_) | Token::Constant(_) | Token::Variable(_) | Token::RightParen | Token::RightBracket)
+}
+
+impl Conversion {
+  /// Finds a trailing `<unit> to <unit>` in the tokens of `input`, as in `5 km to mi`,
+  /// returning the number of tokens before it along with the conversion.
+  /// The first unit has to stand right after an operand, where an operator would otherwise be,
+  /// so variables and strings are left alone, as in `5 + m to km` or `eval("5 km to mi")`.
+  /// Units are read from `input`, since the lexer lowercases the names of functions like `min`
+  pub fn split(lexer: &Lexer, input: &str) -> Option<(usize, Conversion)> {
+    let tokens = lexer.tokens();
+    if tokens.iter().any(|token| matches!(token, Token::Comment(_))) {
+      return None
+    }
+    let text = |start: usize, end: usize| {
+      // Tokens of a unit are written together, without spaces
+      let glued = (start + 1..end).all(|index| lexer.span_at(index - 1).end == lexer.span_at(index).start);
+      let (from, to) = (lexer.span_at(start).start, lexer.span_at(end - 1).end);
+      glued.then(|| input.chars().skip(from).take(to - from).collect::<String>())
+    };
+
+    let to = tokens.iter().rposition(|token| matches!(token, Token::Variable(name) if name.eq_ignore_ascii_case("to")))?;
+    let target = &tokens[to + 1..];
+    if !matches!(target.first(), Some(Token::Variable(_) | Token::Function(_))) || !target.iter().all(in_unit) {
+      return None
+    }
+
+    // The unit may be glued to a number, as in `100c to f`, and may end with a digit, as in `m2`
+    let (start, from) = (to.saturating_sub(2)..to).rev()
+      .filter(|&start| start > 0 && ends_operand(&tokens[start - 1]) && tokens[start..to].iter().all(in_unit))
+      .find_map(|start| text(start, to).and_then(|name| find(&name)).map(|unit| (start, unit)))?;
+
+    Some((start, Conversion { from, to: text(to + 1, tokens.len())? }))
+  }
+
+  pub fn apply(&self, value: f64) -> Result<f64> {
+    let to = find(&self.to).ok_or_else(|| eyre!("Unknown unit: {}", self.to))?;
+    if to.dimension != self.from.dimension {
+      return Err(eyre!(
+        "Cannot convert {} ({}) to {} ({})",
+        self.from.dimension, self.from.names[0], to.dimension, to.names[0],
+      ))
+    }
+
+    Ok((value * self.from.scale + self.from.offset - to.offset) / to.scale)
+  }
+}
+
+/// Supported units grouped by dimension, as printed by `units`
+pub fn render_list() -> String {
+  let dimensions = [Length, Mass, Temperature, Time, Data, Area];
+  let lines = dimensions.iter().map(|&dimension| {
+    let names: Vec<_> = UNITS.iter()
+      .filter(|unit| unit.dimension == dimension)
+      .map(|unit| unit.names.join("/"))
+      .collect();
+    format!("* {}: {}", dimension, names.join(", "))
+  });
+
+  std::iter::once("Available units (convert with `<expr> <unit> to <unit>`):".to_string())
+    .chain(lines)
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::lexer::tokenize;
+  use super::{find, Conversion};
+
+  /// The expression before the conversion, and the conversion
+  fn split(input: &str) -> Option<(String, Conversion)> {
+    let lexer = tokenize(input).unwrap();
+    let (count, conversion) = Conversion::split(&lexer, input)?;
+    let end = lexer.span_at(count).start;
+    Some((input.chars().take(end).collect(), conversion))
+  }
+
+  fn convert(input: &str) -> eyre::Result<f64> {
+    let (expression, conversion) = split(input).unwrap();
+    conversion.apply(expression.trim().parse().unwrap())
+  }
+
+  fn test(input: &str, expected: f64) {
+    let result = convert(input).unwrap();
+    assert!((result - expected).abs() < 1e-9, "{} = {}, expected {}", input, result, expected)
+  }
+
+  #[test]
+  fn test_find() {
+    assert_eq!(find("miles"), find("mi"));
+    assert_eq!(find("inches"), find("in"));
+    assert_eq!(find("feet"), find("ft"));
+    assert_eq!(find("lbs"), find("pound"));
+    assert_ne!(find("b"), find("B"));
+    assert_eq!(find("KM"), find("km"));
+    assert_eq!(find("Miles"), find("mi"));
+    assert_eq!(find("kb"), find("kB"));
+    assert_eq!(find("mib"), find("MiB"));
+    assert!(find("parsec").is_none());
+  }
+
+  #[test]
+  fn test_split() {
+    let (expression, conversion) = split("(2 + 3) km to mi").unwrap();
+    assert_eq!(expression, "(2 + 3) ");
+    assert_eq!(conversion.from.names[0], "km");
+    assert_eq!(conversion.to, "mi");
+
+    assert_eq!(split("100c to f").unwrap().0, "100");
+    assert_eq!(split("1 m2 to ft2").unwrap().1.to, "ft2");
+    assert_eq!(split("2 HOURS To min").unwrap().1.to, "min");
+    assert!(split("2 + to").is_none());
+    assert!(split("km to mi").is_none());
+
+    // Only where an operator would otherwise have to be
+    assert!(split("5 + m to km").is_none());
+    assert!(split("x * t to kg").is_none());
+    assert!(split("(5 km to mi)").is_none());
+    assert!(split(r#"eval("5 km to mi")"#).is_none());
+    assert!(split("5 km to mi + 1").is_none());
+    assert!(split("5 km to m i").is_none());
+  }
+
+  #[test]
+  fn test_convert() {
+    test("5 km to mi", 3.1068559611866697);
+    test("72 kg to lb", 158.73282877311186);
+    test("100 c to f", 212.);
+    test("-40 f to c", -40.);
+    test("0 k to celsius", -273.15);
+    test("2 hours to minutes", 120.);
+    test("1 GiB to MB", 1073.741824);
+    test("8 bits to bytes", 1.);
+    test("1 acre to sqft", 43560.);
+    test("1 ha to m2", 10000.);
+    test("1 MB to KB", 1000.);
+    test("5 KM TO M", 5000.);
+
+    assert_eq!(convert("5 kg to km").unwrap_err().to_string(), "Cannot convert mass (kg) to length (km)");
+    assert_eq!(convert("5 km to parsec").unwrap_err().to_string(), "Unknown unit: parsec");
+  }
+}
@@ -0,0 +1,278 @@
+use std::mem;
+
+use crate::{error::LimitExceeded, parser::Node};
+
+/// Deepest tree taken on by the passes which recurse once per level, like `simplify`, `differentiate` and `compile`,
+/// so that they fit on a 2 MB thread stack even in unoptimized builds. Parsing limits nesting rather than depth,
+/// so long chains like `1 + 1 + … + 1` and expanded user-defined functions can be deeper
+pub const MAX_DEPTH: usize = 128;
+
+/// Pre-order iterator over a tree, returned by `Node::iter`.
+/// Keeps pending nodes on its own stack, so it works on trees of any depth
+pub struct Iter<'a> {
+  stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+  type Item = &'a Node;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let node = self.stack.pop()?;
+    // Pushed in reverse so that the leftmost child comes out first
+    match node {
+      Node::Immediate(_) | Node::Variable(_) | Node::FuncRef(_) | Node::OpRef(_) => {},
+      Node::Neg(node) => self.stack.push(node),
+      Node::BinOp(_, left, right) => self.stack.extend([&**right, &**left]),
+      Node::If { cond, then, else_ } => self.stack.extend([&**else_, &**then, &**cond]),
+      Node::Let { value, body, .. } => self.stack.extend([&**body, &**value]),
+      Node::Piecewise(cases, default) => {
+        self.stack.extend(default.as_deref());
+        self.stack.extend(cases.iter().rev().flat_map(|(cond, value)| [value, cond]));
+      },
+      Node::Func(_, args) | Node::Call(_, args) | Node::List(args) => self.stack.extend(args.iter().rev()),
+    }
+    Some(node)
+  }
+}
+
+/// Moves the children of `node` onto `stack`, leaving it without any
+fn take_children(node: &mut Node, stack: &mut Vec<Node>) {
+  match node {
+    Node::Immediate(_) | Node::Variable(_) | Node::FuncRef(_) | Node::OpRef(_) => {},
+    Node::Neg(child) => stack.push(child.take()),
+    Node::BinOp(_, left, right) => {
+      stack.push(left.take());
+      stack.push(right.take());
+    },
+    Node::If { cond, then, else_ } => stack.extend([cond.take(), then.take(), else_.take()]),
+    Node::Let { value, body, .. } => stack.extend([value.take(), body.take()]),
+    Node::Piecewise(cases, default) => {
+      stack.extend(cases.drain(..).flat_map(|(cond, value)| [cond, value]));
+      stack.extend(default.as_mut().map(|default| default.take()));
+    },
+    Node::Func(_, args) | Node::Call(_, args) | Node::List(args) => stack.append(args),
+  }
+}
+
+/// Frees the tree one node at a time, since dropping the boxes recursively overflows the stack on deep trees
+impl Drop for Node {
+  fn drop(&mut self) {
+    let mut stack = vec![];
+    take_children(self, &mut stack);
+    while let Some(mut node) = stack.pop() {
+      take_children(&mut node, &mut stack);
+    }
+  }
+}
+
+/// Copies the tree with children copied before their parents, on a stack like in `Drop`
+impl Clone for Node {
+  fn clone(&self) -> Self {
+    enum Task<'a> {
+      Visit(&'a Node),
+      /// Replaces the copies of the node's children with a copy of the node
+      Build(&'a Node),
+    }
+
+    let mut tasks = vec![Task::Visit(self)];
+    let mut copies: Vec<Node> = vec![];
+
+    while let Some(task) = tasks.pop() {
+      match task {
+        Task::Visit(node) => {
+          tasks.push(Task::Build(node));
+          // Pushed in reverse so that copies come out left to right
+          tasks.extend(children(node).into_iter().rev().map(Task::Visit));
+        },
+        Task::Build(node) => {
+          let count = children(node).len();
+          let copy = rebuild(node, copies.split_off(copies.len() - count));
+          copies.push(copy);
+        },
+      }
+    }
+
+    copies.pop().expect("copying leaves a single node")
+  }
+}
+
+/// Copy of `node` with the given children in place of its own, which must be as many and in the order of `children`
+pub(crate) fn rebuild(node: &Node, children: Vec<Node>) -> Node {
+  match node {
+    Node::Func(func, _) => Node::Func(*func, children),
+    Node::Call(name, _) => Node::Call(name.clone(), children),
+    Node::List(_) => Node::List(children),
+    node => {
+      let mut children = children.into_iter();
+      let mut next = || Box::new(children.next().expect("every child was given"));
+      match node {
+        Node::Immediate(value) => Node::Immediate(*value),
+        Node::Variable(name) => Node::Variable(name.clone()),
+        Node::FuncRef(func) => Node::FuncRef(*func),
+        Node::OpRef(op) => Node::OpRef(*op),
+        Node::Neg(_) => Node::Neg(next()),
+        Node::BinOp(op, _, _) => Node::BinOp(*op, next(), next()),
+        Node::If { .. } => Node::If { cond: next(), then: next(), else_: next() },
+        Node::Let { name, .. } => Node::Let { name: name.clone(), value: next(), body: next() },
+        Node::Piecewise(cases, default) => Node::Piecewise(
+          (0..cases.len()).map(|_| (*next(), *next())).collect(),
+          default.as_ref().map(|_| next()),
+        ),
+        Node::Func(..) | Node::Call(..) | Node::List(_) => unreachable!("handled above"),
+      }
+    },
+  }
+}
+
+/// Direct children of the node, left to right
+pub(crate) fn children(node: &Node) -> Vec<&Node> {
+  match node {
+    Node::Immediate(_) | Node::Variable(_) | Node::FuncRef(_) | Node::OpRef(_) => vec![],
+    Node::Neg(node) => vec![node],
+    Node::BinOp(_, left, right) => vec![left, right],
+    Node::If { cond, then, else_ } => vec![cond, then, else_],
+    Node::Let { value, body, .. } => vec![value, body],
+    Node::Piecewise(cases, default) => cases.iter().flat_map(|(cond, value)| [cond, value]).chain(default.as_deref()).collect(),
+    Node::Func(_, args) | Node::Call(_, args) | Node::List(args) => args.iter().collect(),
+  }
+}
+
+impl Node {
+  /// Moves the node out, leaving a literal in its place.
+  /// Since `Node` implements `Drop`, this is how children are moved out of their parent
+  pub fn take(&mut self) -> Node {
+    mem::replace(self, Node::Immediate(0.))
+  }
+
+  /// Iterates over the node and all of its descendants, parents before children and left to right
+  pub fn iter(&self) -> Iter<'_> {
+    Iter { stack: vec![self] }
+  }
+
+  /// Calls `f` on every node in the order of `iter`
+  pub fn walk(&self, f: impl FnMut(&Node)) {
+    self.iter().for_each(f)
+  }
+
+  /// Number of nodes in the tree, including this one
+  pub fn count_nodes(&self) -> usize {
+    self.iter().count()
+  }
+
+  /// Number of nodes on the longest path from this node down to a leaf, 1 for a leaf
+  pub fn depth(&self) -> usize {
+    let mut stack = vec![(self, 1)];
+    let mut depth = 0;
+    while let Some((node, level)) = stack.pop() {
+      depth = depth.max(level);
+      match node {
+        Node::Immediate(_) | Node::Variable(_) | Node::FuncRef(_) | Node::OpRef(_) => {},
+        Node::Neg(node) => stack.push((node, level + 1)),
+        Node::BinOp(_, left, right) => stack.extend([(&**left, level + 1), (&**right, level + 1)]),
+        Node::If { cond, then, else_ } => stack.extend([(&**cond, level + 1), (&**then, level + 1), (&**else_, level + 1)]),
+        Node::Let { value, body, .. } => stack.extend([(&**value, level + 1), (&**body, level + 1)]),
+        Node::Piecewise(cases, default) => {
+          stack.extend(cases.iter().flat_map(|(cond, value)| [(cond, level + 1), (value, level + 1)]));
+          stack.extend(default.as_deref().map(|default| (default, level + 1)));
+        },
+        Node::Func(_, args) | Node::Call(_, args) | Node::List(args) => stack.extend(args.iter().map(|arg| (arg, level + 1))),
+      }
+    }
+    depth
+  }
+
+  /// Fails with `LimitExceeded::Depth` if the tree is deeper than `MAX_DEPTH`
+  pub fn check_depth(&self) -> Result<(), LimitExceeded> {
+    match self.depth() > MAX_DEPTH {
+      true => Err(LimitExceeded::Depth(MAX_DEPTH)),
+      false => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MAX_DEPTH;
+  use crate::{bytecode::compile, error::LimitExceeded, eval::EvalOptions, lexer::{tokenize, Func, Op}, parser::{parse_expression, Node}};
+
+  fn parse(input: &str) -> Node {
+    parse_expression(&mut tokenize(input).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_iter() {
+    let node = parse("sqrt(x) + log2(8) * -f(1, sin(y))");
+    assert_eq!(node.count_nodes(), 11);
+    assert_eq!(node.depth(), 6);
+    assert_eq!(parse("x").depth(), 1);
+
+    let funcs: Vec<Func> = node.iter().filter_map(|node| match node {
+      Node::Func(func, _) => Some(*func),
+      _ => None,
+    }).collect();
+    assert_eq!(funcs, [Func::Sqrt, Func::Log(2.), Func::Sin]);
+
+    let mut leaves = vec![];
+    node.walk(|node| match node {
+      Node::Immediate(value) => leaves.push(value.to_string()),
+      Node::Variable(name) => leaves.push(name.clone()),
+      _ => {},
+    });
+    assert_eq!(leaves, ["x", "8", "1", "y"]);
+  }
+
+  #[test]
+  fn test_deep_tree() {
+    let mut node = Node::Variable("x".to_string());
+    for _ in 0..100_000 {
+      node = Node::Neg(Box::new(node));
+    }
+
+    assert_eq!(node.count_nodes(), 100_001);
+    assert_eq!(node.depth(), 100_001);
+    let copy = node.clone();
+    assert_eq!(copy.depth(), 100_001);
+    drop(node);
+  }
+  #[test]
+  fn test_deep_passes() {
+    // 1 + 1 + … + 1
+    let mut node = Node::Immediate(1.);
+    for _ in 0..200_000 {
+      node = Node::BinOp(Op::Add, Box::new(node), Box::new(Node::Immediate(1.)));
+    }
+    assert_eq!(node.clone().evaluate(EvalOptions::default()).unwrap(), 200_001.);
+    assert_eq!(node.to_rpn().len(), 800_001);
+    assert_eq!(node, node.clone());
+
+    // Passes which recurse once per level fail, or keep the tree as it is
+    let exceeded = LimitExceeded::Depth(MAX_DEPTH);
+    assert_eq!(node.check_depth(), Err(exceeded));
+    assert_eq!(node.fold_constants().unwrap_err().downcast::<LimitExceeded>().unwrap(), exceeded);
+    assert_eq!(node.differentiate("x").unwrap_err().downcast::<LimitExceeded>().unwrap(), exceeded);
+    assert_eq!(compile(&node).unwrap_err().downcast::<LimitExceeded>().unwrap(), exceeded);
+    assert_eq!(node.to_latex(), Err(exceeded));
+    assert_eq!(node.simplify(), node);
+    assert_eq!(node.normalize(), node);
+    assert_eq!(format!("{:?}", node), "Node { depth: 200001, .. }");
+    #[cfg(feature = "serde")]
+    assert_eq!(serde_json::to_string(&node).unwrap_err().to_string(), "Expression too deeply nested (limit 128 levels)");
+
+    // Rendered one node per line, indented by depth
+    let mut node = Node::Variable("x".to_string());
+    for _ in 0..1_000 {
+      node = Node::Neg(Box::new(node));
+    }
+    assert_eq!(node.render_tree().lines().last(), Some(format!("{}Variable(x)", "  ".repeat(1_000)).as_str()));
+
+    // Deep enough to be taken on, but not deeper
+    let mut node = Node::Variable("x".to_string());
+    for _ in 1..MAX_DEPTH {
+      node = Node::Func(Func::Sin, vec![node]);
+    }
+    assert_eq!(node.depth(), MAX_DEPTH);
+    assert!(node.differentiate("x").is_ok());
+    assert!(compile(&node).is_ok());
+    assert!(Node::Neg(Box::new(node)).differentiate("x").is_err());
+  }
+}